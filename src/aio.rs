@@ -0,0 +1,307 @@
+use crate::btree::{Index, Scan};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload};
+use futures_core::Stream;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::task::Wake;
+use std::task::{Context, Poll, Waker};
+#[cfg(test)]
+use std::thread::{self, Thread};
+
+/// Blocking runs the blocking calls of the async API off the threads of the async executor. It's
+/// the only thing the async API needs from a runtime, implement it to use another one.
+pub(crate) trait Blocking: Send + Sync {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+/// Runs every task on a thread of its own, works with any executor.
+pub(crate) struct ThreadBlocking;
+
+impl Blocking for ThreadBlocking {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        std::thread::spawn(task);
+    }
+}
+
+/// Runs the tasks on the blocking pool of the tokio runtime the caller is running on.
+#[cfg(feature = "tokio")]
+pub(crate) struct TokioBlocking;
+
+#[cfg(feature = "tokio")]
+impl Blocking for TokioBlocking {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(task);
+    }
+}
+
+/// Runs the tasks on the blocking pool of async-std.
+#[cfg(feature = "async-std")]
+pub(crate) struct AsyncStdBlocking;
+
+#[cfg(feature = "async-std")]
+impl Blocking for AsyncStdBlocking {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        async_std::task::spawn_blocking(task);
+    }
+}
+
+/// Runs the tasks on the blocking pool shared by smol and the crates built on it.
+#[cfg(feature = "smol")]
+pub(crate) struct SmolBlocking;
+
+#[cfg(feature = "smol")]
+impl Blocking for SmolBlocking {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        blocking::unblock(task).detach();
+    }
+}
+
+struct Shared<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Completion is the future of a call handed to `Blocking`, it resolves to the result of the call.
+pub(crate) struct Completion<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Completion<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+type Pending<T> = Completion<Result<T, Error>>;
+
+fn spawn<T: Send + 'static>(
+    blocking: &Arc<dyn Blocking>,
+    call: impl FnOnce() -> T + Send + 'static,
+) -> Completion<T> {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let completed = shared.clone();
+    blocking.spawn_blocking(Box::new(move || {
+        let result = call();
+        let mut shared = completed.lock().unwrap_or_else(|e| e.into_inner());
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }));
+    Completion { shared }
+}
+
+type Entry = Result<(Vec<u8>, Payload), Error>;
+
+// A batch of entries along with the scan to read the next one from, None once it's exhausted.
+type Batch = (Option<Scan>, Vec<Entry>);
+
+fn read_batch(scan: Result<Scan, Error>, batch_size: usize) -> Batch {
+    let mut scan = match scan {
+        Ok(scan) => scan,
+        Err(e) => return (None, vec![Err(e)]),
+    };
+    let batch: Vec<Entry> = scan.by_ref().take(batch_size).collect();
+    let exhausted = batch.len() < batch_size || batch.last().is_some_and(|entry| entry.is_err());
+    (if exhausted { None } else { Some(scan) }, batch)
+}
+
+/// ScanStream yields the entries of a range scan. They are read in batches on the threads of the
+/// `Blocking`, and the next batch is read while the current one is consumed: a consumer keeping
+/// up doesn't wait for page IO, and one falling behind holds the scan back, as no more than two
+/// batches are buffered.
+pub(crate) struct ScanStream {
+    blocking: Arc<dyn Blocking>,
+    batch: VecDeque<Entry>,
+    next: Option<Completion<Batch>>,
+    batch_size: usize,
+}
+
+impl Stream for ScanStream {
+    type Item = Entry;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Entry>> {
+        let stream = self.get_mut();
+        loop {
+            if let Some(entry) = stream.batch.pop_front() {
+                return Poll::Ready(Some(entry));
+            }
+            let Some(next) = stream.next.as_mut() else {
+                return Poll::Ready(None);
+            };
+            let (scan, batch) = match Pin::new(next).poll(cx) {
+                Poll::Ready(batch) => batch,
+                Poll::Pending => return Poll::Pending,
+            };
+            let batch_size = stream.batch_size;
+            stream.next =
+                scan.map(|scan| spawn(&stream.blocking, move || read_batch(Ok(scan), batch_size)));
+            stream.batch = batch.into();
+        }
+    }
+}
+
+/// AsyncIndex is the async API of the index. The calls run on the threads of the `Blocking` it
+/// was created with, so that page IO doesn't stall the executor.
+pub(crate) struct AsyncIndex {
+    blocking: Arc<dyn Blocking>,
+}
+
+impl AsyncIndex {
+    pub(crate) fn new(blocking: impl Blocking + 'static) -> Self {
+        AsyncIndex {
+            blocking: Arc::new(blocking),
+        }
+    }
+
+    fn run<T: Send + 'static>(&self, call: impl FnOnce() -> T + Send + 'static) -> Completion<T> {
+        spawn(&self.blocking, call)
+    }
+
+    pub(crate) fn get(&self, key: Vec<u8>) -> Pending<Option<Payload>> {
+        self.run(move || Index::open()?.get(Key::from(key.as_slice())))
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, payload: Payload) -> Pending<()> {
+        self.run(move || Index::open()?.insert(Key::from(key.as_slice()), payload))
+    }
+
+    pub(crate) fn delete(&self, key: Vec<u8>) -> Pending<bool> {
+        self.run(move || Index::open()?.delete(Key::from(key.as_slice())))
+    }
+
+    /// Returns the entries in the range, the scan runs to completion before the future resolves.
+    pub(crate) fn scan(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Pending<Vec<(Vec<u8>, Payload)>> {
+        self.run(move || {
+            let range = (key_bound(&range.0), key_bound(&range.1));
+            Index::open()?.scan(range)?.collect()
+        })
+    }
+
+    /// Returns the entries in the range as a stream, read batch_size entries at a time, see
+    /// `ScanStream`.
+    pub(crate) fn scan_stream(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        batch_size: usize,
+    ) -> ScanStream {
+        let batch_size = batch_size.max(1);
+        let first = self.run(move || {
+            let range = (key_bound(&range.0), key_bound(&range.1));
+            read_batch(
+                Index::open().and_then(|index| index.scan(range)),
+                batch_size,
+            )
+        });
+        ScanStream {
+            blocking: self.blocking.clone(),
+            batch: VecDeque::new(),
+            next: Some(first),
+            batch_size,
+        }
+    }
+}
+
+fn key_bound(bound: &Bound<Vec<u8>>) -> Bound<Key<'_>> {
+    bound.as_ref().map(|key| Key::from(key.as_slice()))
+}
+
+#[cfg(test)]
+struct Unpark(Thread);
+
+#[cfg(test)]
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Polls the future on the calling thread, standing in for an executor.
+#[cfg(test)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn verify_async_index_on_threads() {
+    delete_index();
+    let index = AsyncIndex::new(ThreadBlocking);
+    for i in 0..30u32 {
+        let key = format!("{:03}", i).into_bytes();
+        block_on(index.insert(key, Payload::from_u32(i))).unwrap();
+    }
+    let payload = block_on(index.get(b"007".to_vec())).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &7u32.to_le_bytes());
+    assert!(block_on(index.delete(b"007".to_vec())).unwrap());
+    assert!(block_on(index.get(b"007".to_vec())).unwrap().is_none());
+    let range = (
+        Bound::Included(b"010".to_vec()),
+        Bound::Excluded(b"020".to_vec()),
+    );
+    assert_eq!(block_on(index.scan(range)).unwrap().len(), 10);
+}
+
+#[test]
+#[serial]
+fn verify_scan_streams_read_ahead_in_batches() {
+    delete_index();
+    let index = AsyncIndex::new(ThreadBlocking);
+    for i in 0..30u32 {
+        let key = format!("{:03}", i).into_bytes();
+        block_on(index.insert(key, Payload::from_u32(i))).unwrap();
+    }
+    let range = (Bound::Included(b"005".to_vec()), Bound::Unbounded);
+    let mut stream = index.scan_stream(range, 4);
+    let mut keys = Vec::new();
+    while let Some(entry) = block_on(std::future::poll_fn(|cx| {
+        Pin::new(&mut stream).poll_next(cx)
+    })) {
+        keys.push(entry.unwrap().0);
+    }
+    let expected: Vec<Vec<u8>> = (5..30).map(|i| format!("{:03}", i).into_bytes()).collect();
+    assert_eq!(keys, expected);
+    let range = (
+        Bound::Included(b"100".to_vec()),
+        Bound::Excluded(b"200".to_vec()),
+    );
+    let mut stream = index.scan_stream(range, 4);
+    assert!(
+        block_on(std::future::poll_fn(
+            |cx| Pin::new(&mut stream).poll_next(cx)
+        ))
+        .is_none()
+    );
+}
@@ -0,0 +1,79 @@
+use crate::config::{get_next_page_id, update_next_page_id};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::pagetrace;
+use crate::types::Offset;
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// PageAllocator hands out the ids of the pages past the end of the file, once the free list ran
+/// dry. The id of the last page handed out is kept in the config, so that it survives restarts and
+/// is rolled back along with the pages of an uncommitted checkpoint. The allocator serializes its
+/// read and update, the free list shards have locks of their own. Each open database has one, see
+/// `io::page_allocator`, which is passed to the pages created.
+#[derive(Default)]
+pub(crate) struct PageAllocator {
+    extending: Mutex<()>,
+}
+
+impl PageAllocator {
+    /// Allocates the page past the last one, regardless of the free list. Fails with OutOfRange
+    /// once the page ids ran out, the file holds as many pages as page ids can address.
+    pub(crate) fn allocate(&self) -> Result<Offset, Error> {
+        let _extending = self.extending.lock().unwrap_or_else(|e| e.into_inner());
+        let page_id = get_next_page_id().checked_add(1)?;
+        update_next_page_id(page_id);
+        pagetrace::allocated(page_id);
+        Ok(page_id)
+    }
+}
+
+#[test]
+#[serial]
+fn verify_page_ids_are_handed_out_once_and_survive_a_restart() {
+    delete_index();
+    let allocator = io::page_allocator();
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let allocator = allocator.clone();
+            std::thread::spawn(move || {
+                (0..50).map(|_| allocator.allocate().unwrap()).collect::<Vec<Offset>>()
+            })
+        })
+        .collect();
+    let allocated: HashSet<Offset> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+    // the ids are unique and follow each other without gaps.
+    assert_eq!(allocated, (1..=400).map(Offset).collect());
+
+    io::commit();
+    io::close();
+    assert_eq!(io::page_allocator().allocate().unwrap(), Offset(401));
+    // ids allocated since the last commit are handed out again once they're rolled back.
+    io::commit();
+    io::page_allocator().allocate().unwrap();
+    io::close();
+    let expected = match io::durability_mode() {
+        io::DurabilityMode::WriteThrough => Offset(403),
+        io::DurabilityMode::Shadow => Offset(402),
+    };
+    assert_eq!(io::page_allocator().allocate().unwrap(), expected);
+}
+
+#[test]
+#[serial]
+fn verify_page_ids_run_out_with_an_error() {
+    delete_index();
+    let allocator = PageAllocator::default();
+    update_next_page_id(Offset(u16::MAX - 1));
+    assert_eq!(allocator.allocate().unwrap(), Offset(u16::MAX));
+    assert!(matches!(allocator.allocate(), Err(Error::OutOfRange)));
+    // the last page id stays handed out.
+    assert_eq!(get_next_page_id(), Offset(u16::MAX));
+    io::close();
+}
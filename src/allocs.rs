@@ -0,0 +1,62 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// CountingAllocator hands the allocations to the system allocator and counts them per thread, so
+/// that tests and the bench can tell whether a code path allocates, see `count`. Counting costs a
+/// thread local increment per allocation.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// SAFETY: the allocations are passed through to the system allocator unchanged.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record();
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record();
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record();
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+// the count is gone once the thread is torn down, its last allocations aren't counted.
+fn record() {
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+}
+
+/// Runs the function and returns its result along with the number of heap allocations it made on
+/// this thread, reallocations included.
+pub(crate) fn count<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+#[test]
+fn verify_allocations_are_counted() {
+    let (_, allocations) = count(|| 1 + 1);
+    assert_eq!(allocations, 0);
+    let (buffer, allocations) = count(|| {
+        let mut buffer = Vec::with_capacity(1);
+        buffer.extend_from_slice(&[1u8, 2, 3]);
+        buffer
+    });
+    assert_eq!(allocations, 2);
+    assert_eq!(buffer.len(), 3);
+}
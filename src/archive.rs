@@ -0,0 +1,91 @@
+use crate::btree::{self, CompressionPolicy, Index, KeyLayout};
+use crate::config;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::treefile;
+#[cfg(test)]
+use crate::treestats;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+
+/// Freezes the index into an archive, for data sets which are built once and read from then on.
+/// The entries are loaded anew into pages filled up to their last slot, with the values stored
+/// along with their checksums, which `Index::get_verified` checks, and compressed if asked for.
+/// The database refuses writes with Archived from then on, also once it's opened again. The pages
+/// the index held before are returned to the free list, compacting the archive into a new
+/// database, see `cli`, leaves them behind.
+pub(crate) fn freeze(compress: bool) -> Result<(), Error> {
+    io::check_writable()?;
+    let index = Index::open()?;
+    // only keys of variable length can be bulk loaded.
+    if index.layout() != KeyLayout::Variable {
+        return Err(Error::KeyLayoutMismatch);
+    }
+    let entries = index.scan(..)?.collect::<Result<Vec<_>, _>>()?;
+    treefile::clear()?;
+
+    let (policy, checksums) = (btree::compression_policy(), btree::value_checksums());
+    btree::set_compression_policy(CompressionPolicy {
+        leaf_values: compress || policy.leaf_values,
+        ..policy
+    });
+    btree::set_value_checksums(true);
+    let loaded = Index::open().and_then(|mut index| index.bulk_load(entries, 1.0));
+    btree::set_compression_policy(policy);
+    btree::set_value_checksums(checksums);
+    loaded?;
+    config::update_archived(true);
+    io::commit();
+    match io::failure() {
+        Some(kind) => Err(Error::Failed(kind)),
+        None => Ok(()),
+    }
+}
+
+/// Returns true if the index was frozen into an archive.
+pub(crate) fn is_archived() -> bool {
+    config::get_archived()
+}
+
+#[cfg(test)]
+fn tree_pages() -> u64 {
+    treestats::stats().unwrap().level_pages.iter().sum()
+}
+
+#[test]
+#[serial]
+fn verify_archives_are_packed_and_refuse_writes() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let value = "archived value ".repeat(8);
+    // keys inserted out of order leave the pages of the splits half full.
+    for i in (0..400u32).rev() {
+        let key = format!("{:04}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_str(value.clone())).unwrap();
+    }
+    let pages = tree_pages();
+    freeze(true).unwrap();
+    assert!(is_archived());
+    assert!(tree_pages() < pages);
+
+    io::close();
+    let mut index = Index::open().unwrap();
+    for i in 0..400u32 {
+        let payload = index.get_verified(Key::from(format!("{:04}", i).as_str())).unwrap();
+        assert_eq!(payload.unwrap().to_bytes(), value.as_bytes());
+    }
+    assert_eq!(index.scan(..).unwrap().count(), 400);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+    assert!(matches!(
+        index.insert(Key::from("0400"), Payload::from_u32(400)),
+        Err(Error::Archived)
+    ));
+    assert!(matches!(index.delete(Key::from("0000")), Err(Error::Archived)));
+    assert!(matches!(freeze(false), Err(Error::Archived)));
+}
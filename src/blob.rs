@@ -0,0 +1,198 @@
+use crate::btree::Index;
+use crate::checksum::xxh64;
+use crate::errors::Error;
+use crate::io;
+#[cfg(test)]
+use crate::io::{delete_index, DurabilityMode};
+use crate::sys;
+use crate::types::{Key, Payload, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+pub(crate) const BLOB_DIR: &str = "blobs";
+// the hash and the length in front of the path.
+const HEADER_SIZE: usize = 2 * size_of::<u64>();
+
+/// BlobRef references a value stored in a file of its own next to the index, for values of
+/// megabytes which would take up pages by the hundreds. The index holds the path of the file along
+/// with the XXH64 and the length of the blob, which are verified when the blob is read. Blobs are
+/// named after their hash and length, so a blob stored under several keys is stored once.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct BlobRef {
+    pub(crate) path: PathBuf,
+    pub(crate) hash: u64,
+    pub(crate) len: u64,
+}
+
+impl BlobRef {
+    fn new(data: &[u8]) -> Self {
+        let hash = xxh64(data, 0);
+        BlobRef {
+            path: Path::new(BLOB_DIR).join(format!("{:016x}-{}", hash, data.len())),
+            hash,
+            len: data.len() as u64,
+        }
+    }
+
+    fn to_payload(&self) -> Payload {
+        let path = self.path.to_string_lossy();
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + path.len());
+        buffer.extend_from_slice(&self.hash.to_le_bytes());
+        buffer.extend_from_slice(&self.len.to_le_bytes());
+        buffer.extend_from_slice(path.as_bytes());
+        Payload::from_vec(buffer, PayloadType::BlobRef)
+    }
+
+    /// Reads the reference from the payload, failing with MalformedPayload if it holds none.
+    pub(crate) fn from_payload(payload: &Payload) -> Result<Self, Error> {
+        let bytes = payload.to_bytes();
+        if payload.payload_type != PayloadType::BlobRef || bytes.len() < HEADER_SIZE {
+            return Err(Error::MalformedPayload);
+        }
+        let path = std::str::from_utf8(&bytes[HEADER_SIZE..])
+            .map_err(|_| Error::MalformedPayload)?;
+        Ok(BlobRef {
+            path: PathBuf::from(path),
+            hash: u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes")),
+            len: u64::from_le_bytes(bytes[8..HEADER_SIZE].try_into().expect("8 bytes")),
+        })
+    }
+
+    /// Reads the blob, failing with ValueChecksumMismatch if its length or hash don't match the
+    /// reference.
+    pub(crate) fn read(&self) -> Result<Vec<u8>, Error> {
+        let data = fs::read(&self.path)?;
+        if data.len() as u64 != self.len || xxh64(&data, 0) != self.hash {
+            return Err(Error::ValueChecksumMismatch);
+        }
+        Ok(data)
+    }
+}
+
+/// Stores the data in a blob file and a reference to it under the key. The blob is synced before
+/// the reference is inserted, so a crash leaves at most a blob no key refers to, which
+/// `collect_garbage` removes. Blobs already stored aren't written again.
+#[allow(dead_code)]
+pub(crate) fn put(
+    index: &mut Index,
+    key: Key,
+    data: &[u8],
+) -> Result<BlobRef, Error> {
+    let _write = io::write_operation();
+    io::check_writable()?;
+    let blob = BlobRef::new(data);
+    match fs::metadata(&blob.path) {
+        Ok(metadata) if metadata.len() == blob.len => {}
+        _ => write(&blob.path, data)?,
+    }
+    index.insert(key, blob.to_payload())?;
+    Ok(blob)
+}
+
+/// Returns the blob stored under the key, verified against its reference, see `BlobRef::read`.
+/// Fails with MalformedPayload if the key holds a value rather than a blob.
+#[allow(dead_code)]
+pub(crate) fn get(index: &Index, key: Key) -> Result<Option<Vec<u8>>, Error> {
+    match index.get_verified(key)? {
+        Some(payload) => BlobRef::from_payload(&payload)?.read().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Removes the blob files no key refers to, e.g. the blobs of keys overwritten or deleted, and
+/// returns the number of files removed. Writes are held off meanwhile, so that a blob stored isn't
+/// removed before its reference is inserted.
+///
+/// Fails with Uncommitted while shadow pages wait for a commit: a rollback could bring back a
+/// reference to a blob removed by then, or drop the reference to a blob kept.
+pub(crate) fn collect_garbage(index: &Index) -> Result<usize, Error> {
+    let _quiesce = io::quiesce();
+    if io::shadow_pages() > 0 {
+        return Err(Error::Uncommitted);
+    }
+    let mut referenced = HashSet::new();
+    for entry in index.scan(..)? {
+        let (_, payload) = entry?;
+        if payload.payload_type == PayloadType::BlobRef {
+            referenced.insert(BlobRef::from_payload(&payload)?.path);
+        }
+    }
+    let files = match fs::read_dir(BLOB_DIR) {
+        Ok(files) => files,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    for file in files {
+        let path = file?.path();
+        if !referenced.contains(&path) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+pub(crate) fn delete() {
+    let _ = fs::remove_dir_all(BLOB_DIR);
+}
+
+// Written into a temporary file which is renamed, so that a blob file is never seen half written.
+fn write(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(BLOB_DIR)?;
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sys::sync_dir(Path::new(BLOB_DIR))
+}
+
+#[test]
+#[serial]
+fn verify_blobs_are_verified_and_collected() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let data = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let blob = put(&mut index, Key::from("large"), &data).unwrap();
+    assert_eq!(blob.len, data.len() as u64);
+    // the same blob under another key is stored once.
+    assert_eq!(put(&mut index, Key::from("copy"), &data).unwrap(), blob);
+    put(&mut index, Key::from("small"), b"small blob").unwrap();
+    assert_eq!(get(&index, Key::from("large")).unwrap().unwrap(), data);
+    assert!(get(&index, Key::from("missing")).unwrap().is_none());
+
+    // the blobs are referenced from the index once it's opened again.
+    io::commit();
+    io::close();
+    let mut index = Index::open().unwrap();
+    assert_eq!(collect_garbage(&index).unwrap(), 0);
+    index.delete(Key::from("large")).unwrap();
+    io::commit();
+    assert_eq!(collect_garbage(&index).unwrap(), 0);
+    index.insert(Key::from("copy"), Payload::from_u32(1)).unwrap();
+    index.delete(Key::from("small")).unwrap();
+    if io::durability_mode() == DurabilityMode::Shadow {
+        assert!(matches!(collect_garbage(&index), Err(Error::Uncommitted)));
+        io::commit();
+    }
+    assert_eq!(collect_garbage(&index).unwrap(), 2);
+    assert!(!blob.path.exists());
+    assert!(matches!(
+        get(&index, Key::from("copy")),
+        Err(Error::MalformedPayload)
+    ));
+
+    let blob = put(&mut index, Key::from("large"), &data).unwrap();
+    let mut corrupted = data.clone();
+    corrupted[1024] ^= 1;
+    fs::write(&blob.path, corrupted).unwrap();
+    assert!(matches!(
+        get(&index, Key::from("large")),
+        Err(Error::ValueChecksumMismatch)
+    ));
+}
@@ -1,28 +1,2365 @@
-use std::sync::Arc;
+use crate::allocator::PageAllocator;
+#[cfg(test)]
+use crate::allocs;
+use crate::cancel::CancellationToken;
+use crate::checksum::xxh64;
+use crate::config::{get_key_layout, get_root_page_id, update_key_layout, update_root_page_id};
+#[cfg(test)]
+use crate::config::get_next_page_id;
+use crate::errors::Error;
+use crate::events::{self, BulkOperation};
+#[cfg(test)]
+use crate::events::Progress;
+use crate::freelist;
+use crate::fsm;
+#[cfg(test)]
+use crate::fsck;
+use crate::intern::{key_parts_at, resolved_key_at, Interner};
 use crate::io;
-use crate::paging::Page;
+#[cfg(test)]
+use crate::io::{delete_index, DurabilityMode};
+use crate::latch;
+use crate::misses;
+use crate::pins;
+use crate::paging::{check_value_size, dense_key, Page, MAX_FAN_OUT, MAX_KEY_SIZE, ZERO};
+#[cfg(test)]
+use crate::paging::PAGE_SIZE_USIZE;
+use crate::poison::{self, Violation};
+use crate::stats::{self, Operation};
+use crate::treestats;
+use crate::types::{Key, Offset, Payload, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::ops::{Bound, RangeBounds};
+
+// Optimistic lookups racing with writers more often than this take the latches instead.
+const OPTIMISTIC_RETRIES: usize = 4;
+// Pages of the level the split points are picked from, per partition.
+const SPLIT_PAGES_PER_PARTITION: usize = 8;
+
+// A page along with the smallest key it may hold, None for the left most page of a level.
+type Bounded<T> = (Option<Vec<u8>>, T);
+
+/// CompressionPolicy chooses what the tree compresses, separately for the keys of the inner pages
+/// and the values of the leaves. Separators sharing a long prefix with their neighbours are
+/// interned into the key dictionary, so that inner pages hold more of them at the cost of resolving
+/// them during the binary search of every descent. Values are compressed with snappy if that makes
+/// them smaller, and decompressed when they are read. The policy applies to the writes made after
+/// it was set, values and separators written before are read either way.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct CompressionPolicy {
+    pub(crate) inner_keys: bool,
+    pub(crate) leaf_values: bool,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            inner_keys: true,
+            leaf_values: false,
+        }
+    }
+}
+
+static COMPRESS_INNER_KEYS: AtomicBool = AtomicBool::new(true);
+static COMPRESS_LEAF_VALUES: AtomicBool = AtomicBool::new(false);
+static VALUE_CHECKSUMS: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_compression_policy(policy: CompressionPolicy) {
+    COMPRESS_INNER_KEYS.store(policy.inner_keys, Ordering::Relaxed);
+    COMPRESS_LEAF_VALUES.store(policy.leaf_values, Ordering::Relaxed);
+}
+
+pub(crate) fn compression_policy() -> CompressionPolicy {
+    CompressionPolicy {
+        inner_keys: COMPRESS_INNER_KEYS.load(Ordering::Relaxed),
+        leaf_values: COMPRESS_LEAF_VALUES.load(Ordering::Relaxed),
+    }
+}
+
+/// Stores the values written from now on along with their checksum, which `Index::get_verified`
+/// checks. It guards values against corruption the pages don't notice, e.g. a bug writing a wrong
+/// overflow chain, at the cost of eight bytes per value.
+pub(crate) fn set_value_checksums(enabled: bool) {
+    VALUE_CHECKSUMS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn value_checksums() -> bool {
+    VALUE_CHECKSUMS.load(Ordering::Relaxed)
+}
+
+// Values are checksummed before they are compressed, so that the checksum covers the decompression.
+fn encode_value(payload: Payload) -> Payload {
+    if !value_checksums() {
+        return compress_value(payload);
+    }
+    let mut buffer = Vec::with_capacity(payload.len() + 1 + size_of::<u64>());
+    buffer.push(payload.payload_type as u8);
+    buffer.extend_from_slice(&xxh64(payload.to_bytes(), 0).to_le_bytes());
+    buffer.extend_from_slice(payload.to_bytes());
+    compress_value(Payload::from_vec(buffer, PayloadType::Checksummed))
+}
+
+// Restores the value as it was written, verifying its checksum if asked to and if it has one.
+fn decode_value(payload: Payload, verify: bool) -> Result<Payload, Error> {
+    let payload = decompress_value(payload)?;
+    if payload.payload_type != PayloadType::Checksummed {
+        return Ok(payload);
+    }
+    let bytes = payload.to_bytes();
+    if bytes.len() < 1 + size_of::<u64>() {
+        return Err(Error::MalformedPayload);
+    }
+    let (checksum, value) = bytes[1..].split_at(size_of::<u64>());
+    if verify && xxh64(value, 0).to_le_bytes() != checksum {
+        return Err(Error::ValueChecksumMismatch);
+    }
+    let payload_type = PayloadType::try_from(bytes[0])?;
+    Ok(payload.slice(1 + size_of::<u64>()..bytes.len(), payload_type))
+}
+
+// Compressed values are stored with the type of the value in front of the compressed bytes.
+fn compress_value(payload: Payload) -> Payload {
+    if !compression_policy().leaf_values {
+        return payload;
+    }
+    let Ok(compressed) = snap::raw::Encoder::new().compress_vec(payload.to_bytes()) else {
+        return payload;
+    };
+    if compressed.len() + 1 >= payload.len() {
+        return payload;
+    }
+    let mut buffer = Vec::with_capacity(compressed.len() + 1);
+    buffer.push(payload.payload_type as u8);
+    buffer.extend_from_slice(&compressed);
+    Payload::from_vec(buffer, PayloadType::Compressed)
+}
+
+fn decompress_value(payload: Payload) -> Result<Payload, Error> {
+    if payload.payload_type != PayloadType::Compressed {
+        return Ok(payload);
+    }
+    let (payload_type, compressed) = payload
+        .to_bytes()
+        .split_first()
+        .ok_or(Error::MalformedPayload)?;
+    let value = snap::raw::Decoder::new()
+        .decompress_vec(compressed)
+        .map_err(|_| Error::MalformedPayload)?;
+    Ok(Payload::from_vec(value, PayloadType::try_from(*payload_type)?))
+}
+
+/// KeyLayout is declared when the tree is created and persisted along with it.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum KeyLayout {
+    /// Keys of any length up to MAX_KEY_SIZE, compared byte-wise.
+    Variable = 0,
+    /// Big-endian u64 keys, inner pages use the dense fixed-stride layout which raises the fan-out
+    /// from a handful of slots to DENSE_CAPACITY.
+    U64 = 1,
+}
 
-const FAN_OUT: u8 = 1 << 3;
+impl TryFrom<u8> for KeyLayout {
+    type Error = Error;
 
-struct Index {
-    root: Option<Box<Node>>,
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            0 => Ok(KeyLayout::Variable),
+            1 => Ok(KeyLayout::U64),
+            _ => Err(Error::KeyLayoutMismatch),
+        }
+    }
+}
+
+/// Index is a B+Tree built on slotted pages. Inner pages hold separator keys referencing the child
+/// page which covers the keys greater or equal to the separator, the child holding the keys smaller
+/// than all separators is the left most page in the header. Leaf pages hold the key-payload pairs
+/// and are chained through their siblings for range scans.
+pub(crate) struct Index {
+    root: Offset,
+    interner: Interner,
+    layout: KeyLayout,
+    // allocates the pages the tree grows by, the one of the database it was opened in.
+    allocator: Arc<PageAllocator>,
 }
 
 impl Index {
+    /// Opens the index persisted in the database files, or creates an empty one with a single leaf.
+    pub(crate) fn open() -> Result<Self, Error> {
+        let layout = get_key_layout().try_into()?;
+        Self::open_with_layout(layout)
+    }
+
+    /// Opens the index, creating an empty one with the given key layout if none exists yet. An
+    /// existing index must have been created with the same layout.
+    pub(crate) fn open_with_layout(layout: KeyLayout) -> Result<Self, Error> {
+        let interner = Interner::load()?;
+        let allocator = io::page_allocator();
+        treestats::open()?;
+        fsm::open()?;
+        let root = get_root_page_id();
+        if root != ZERO {
+            if KeyLayout::try_from(get_key_layout())? != layout {
+                return Err(Error::KeyLayoutMismatch);
+            }
+            return Ok(Index {
+                root,
+                interner,
+                layout,
+                allocator,
+            });
+        }
+        let root_page = Page::new_data(&allocator)?;
+        io::write(&root_page);
+        update_key_layout(layout as u8);
+        update_root_page_id(root_page.page_id());
+        treestats::record_split(0);
+        Ok(Index {
+            root: root_page.page_id(),
+            interner,
+            layout,
+            allocator,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn root(&self) -> Offset {
+        self.root
+    }
+
+    pub(crate) fn layout(&self) -> KeyLayout {
+        self.layout
+    }
+
+    pub(crate) fn get(&self, key: Key) -> Result<Option<Payload>, Error> {
+        self.lookup(key, false)
+    }
+
+    /// Looks the key up like `get`, and verifies the value against its checksum if it was stored
+    /// with one, failing with ValueChecksumMismatch if it doesn't match. See `set_value_checksums`.
+    pub(crate) fn get_verified(&self, key: Key) -> Result<Option<Payload>, Error> {
+        self.lookup(key, true)
+    }
+
+    fn lookup(&self, key: Key, verify: bool) -> Result<Option<Payload>, Error> {
+        let _operation = stats::begin(Operation::Get, key.len());
+        if misses::known_absent(self.root, key.as_bytes()) {
+            return Ok(None);
+        }
+        let path = self.path_to_leaf(Some(key))?;
+        let leaf = load(path[path.len() - 1])?;
+        match leaf.get(key.as_bytes())? {
+            Some(payload) => Ok(Some(decode_value(payload, verify)?)),
+            None => {
+                misses::record(self.root, key.as_bytes(), leaf.page_id());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Looks the key up like `get` and passes the value to the function as it's stored in the leaf,
+    /// rather than copying it out. Lookups of values stored inline, neither compressed nor
+    /// checksummed, don't allocate, which `verify_point_reads_dont_allocate` and the bench assert;
+    /// other values are copied out to be decoded.
+    pub(crate) fn get_with<T>(
+        &self,
+        key: Key,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> Result<Option<T>, Error> {
+        let _operation = stats::begin(Operation::Get, key.len());
+        if misses::known_absent(self.root, key.as_bytes()) {
+            return Ok(None);
+        }
+        // the pages are copied onto the stack one level after the other, the path isn't kept.
+        let mut page = load(self.root)?;
+        while !page.is_leaf() {
+            page = load(child_for(&page, key, &self.interner)?)?;
+        }
+        let index = match page.find_slot(key)? {
+            Some(index) if !page.is_tombstone_at(index)? => index,
+            _ => {
+                misses::record(self.root, key.as_bytes(), page.page_id());
+                return Ok(None);
+            }
+        };
+        let encoded = matches!(
+            page.payload_type_at(index)?,
+            PayloadType::Compressed | PayloadType::Checksummed
+        );
+        match page.inline_value_at(index)? {
+            Some(value) if !encoded => Ok(Some(f(value))),
+            _ => Ok(Some(f(decode_value(page.value_at(index)?, false)?.to_bytes()))),
+        }
+    }
+
+    /// Looks up the key without waiting for page latches. The pages on the path are copied along
+    /// with their versions, which are validated once the payload is read. Lookups racing with writers
+    /// are retried, and fall back to `get` after `OPTIMISTIC_RETRIES` attempts.
+    #[allow(dead_code)]
+    pub(crate) fn get_optimistic(&self, key: Key) -> Result<Option<Payload>, Error> {
+        let _operation = stats::begin(Operation::Get, key.len());
+        for _ in 0..OPTIMISTIC_RETRIES {
+            if let Some(payload) = self.try_get_optimistic(key)? {
+                return Ok(payload);
+            }
+        }
+        self.get(key)
+    }
+
+    // Returns None if the read has to be retried.
+    fn try_get_optimistic(&self, key: Key) -> Result<Option<Option<Payload>>, Error> {
+        let mut versions = Vec::new();
+        let mut page_id = self.root;
+        let leaf = loop {
+            let page = match load_optimistic(page_id)? {
+                Some((page, version)) => {
+                    versions.push((page_id, version));
+                    page
+                }
+                None => return Ok(None),
+            };
+            if !page.has_known_page_type() {
+                return Ok(None);
+            }
+            // a leaf split since its parent was read holds the keys from its high key on in its
+            // right sibling.
+            if page.is_leaf()
+                && let Some(high_key) = page.high_key()
+                && key.as_bytes() >= high_key
+                && page.right_sibling() != ZERO
+            {
+                page_id = page.right_sibling();
+                continue;
+            }
+            if page.is_leaf() {
+                break page;
+            }
+            // a torn path may lead anywhere, errors are only reported by validated reads.
+            page_id = match child_for(&page, key, &self.interner) {
+                Ok(child) => child,
+                Err(_) => return Ok(None),
+            };
+        };
+        let payload = match leaf.find_slot(key) {
+            Ok(Some(index)) => match leaf.value_at(index) {
+                Ok(payload) if payload.payload_type == PayloadType::Tombstone => None,
+                Ok(payload) => match decode_value(payload, false) {
+                    Ok(payload) => Some(payload),
+                    Err(_) => return Ok(None),
+                },
+                Err(_) => return Ok(None),
+            },
+            Ok(None) => None,
+            Err(_) => return Ok(None),
+        };
+        let valid = versions
+            .iter()
+            .all(|(page_id, version)| io::page_version(*page_id) == Some(*version));
+        if !valid {
+            return Ok(None);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Inserts the key-payload pair, replacing the payload if the key exists. Full pages are split
+    /// in halves, and the separator is pushed up to the parent all the way to the root if needed.
+    pub(crate) fn insert(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let result = self.insert_into_leaf(key, payload);
+        io::check_writable()?;
+        result
+    }
+
+    fn insert_into_leaf(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        let _operation = stats::begin(Operation::Insert, key.len() + payload.len());
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::OutOfRange);
+        }
+        check_value_size(payload.len())?;
+        if self.layout == KeyLayout::U64 {
+            dense_key(key.as_bytes())?;
+        }
+        let payload = encode_value(payload);
+        let path = self.path_to_leaf(Some(key))?;
+        let mut leaf = load(path[path.len() - 1])?;
+        let mut replaced_len = None;
+        // the overflow pages of the replaced value are freed once the new one is written.
+        let mut replaced_pages = Vec::new();
+        if let Some(index) = leaf.find_slot(key)? {
+            if !leaf.is_tombstone_at(index)? {
+                replaced_len = Some(treestats::value_len(&leaf, index)?);
+            }
+            replaced_pages = leaf.overflow_page_ids(index)?;
+            leaf.delete_slot(index)?;
+        }
+        treestats::record_insert(key.len(), payload.len(), replaced_len);
+        if !leaf.is_full()? && leaf.has_room_for(key.as_bytes())? {
+            match leaf.add(key, payload.clone()) {
+                // the leaf is split below.
+                Err(Error::NoSpace { .. }) => {}
+                result => {
+                    result?;
+                    return freelist::push_all(&replaced_pages);
+                }
+            }
+        }
+
+        let (mut left, mut right, separator) = split(&leaf, &self.interner, &self.allocator)?;
+        if key.as_bytes() < separator.as_slice() {
+            io::write(&right);
+            left.add(key, payload)?;
+        } else {
+            io::write(&left);
+            right.add(key, payload)?;
+        }
+        treestats::record_split(0);
+        self.insert_separator(
+            &path[..path.len() - 1],
+            0,
+            left.page_id(),
+            separator,
+            right.page_id(),
+        )?;
+        freelist::push_all(&replaced_pages)
+    }
+
+    /// Removes the key from its leaf. Pages are not merged, an emptied leaf stays in the chain. A
+    /// tombstone of the key is removed as well, the key counts as absent then.
+    pub(crate) fn delete(&mut self, key: Key) -> Result<bool, Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let result = self.delete_from_leaf(key);
+        io::check_writable()?;
+        result
+    }
+
+    fn delete_from_leaf(&mut self, key: Key) -> Result<bool, Error> {
+        let _operation = stats::begin(Operation::Delete, key.len());
+        let path = self.path_to_leaf(Some(key))?;
+        let mut leaf = load(path[path.len() - 1])?;
+        match leaf.find_slot(key)? {
+            Some(index) => {
+                let present = !leaf.is_tombstone_at(index)?;
+                if present {
+                    treestats::record_delete(key.len(), treestats::value_len(&leaf, index)?);
+                }
+                let overflow_page_ids = leaf.overflow_page_ids(index)?;
+                leaf.delete_slot(index)?;
+                io::write(&leaf);
+                freelist::push_all(&overflow_page_ids)?;
+                Ok(present)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes the key logically, replacing it with a tombstone which carries the stamp of the
+    /// delete, e.g. the index of the log entry or the commit timestamp. The key reads as absent,
+    /// while the tombstone is kept for replicas and snapshots behind the delete until
+    /// `purge_tombstones` removes it. Returns whether the key was present.
+    pub(crate) fn tombstone(&mut self, key: Key, stamp: u64) -> Result<bool, Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let result = self.tombstone_in_leaf(key, stamp);
+        io::check_writable()?;
+        result
+    }
+
+    fn tombstone_in_leaf(&mut self, key: Key, stamp: u64) -> Result<bool, Error> {
+        let _operation = stats::begin(Operation::Delete, key.len());
+        let path = self.path_to_leaf(Some(key))?;
+        let mut leaf = load(path[path.len() - 1])?;
+        let Some(index) = leaf.find_slot(key)? else {
+            return Ok(false);
+        };
+        if leaf.is_tombstone_at(index)? {
+            return Ok(false);
+        }
+        treestats::record_delete(key.len(), treestats::value_len(&leaf, index)?);
+        // the tombstone takes the slot of the key, so the leaf has room for it.
+        let overflow_page_ids = leaf.overflow_page_ids(index)?;
+        leaf.delete_slot(index)?;
+        leaf.add(key, Payload::tombstone(stamp))?;
+        freelist::push_all(&overflow_page_ids)?;
+        Ok(true)
+    }
+
+    /// Removes the tombstones stamped before the watermark by walking the leaf chain, the oldest
+    /// stamp a snapshot or replica may still need. Returns the number of tombstones removed.
+    pub(crate) fn purge_tombstones(&mut self, watermark: u64) -> Result<usize, Error> {
+        self.purge_tombstones_cancellable(watermark, &CancellationToken::new())
+    }
+
+    /// Purges the tombstones like `purge_tombstones` until the token is cancelled. The leaves
+    /// purged before are left purged, the tombstones of the others are purged by the next run.
+    pub(crate) fn purge_tombstones_cancellable(
+        &mut self,
+        watermark: u64,
+        cancel: &CancellationToken,
+    ) -> Result<usize, Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let path = self.path_to_leaf(None)?;
+        let mut next = path[path.len() - 1];
+        let (mut purged, mut leaves) = (0, 0);
+        while next != ZERO {
+            cancel.check()?;
+            let mut leaf = load(next)?;
+            next = leaf.right_sibling();
+            let mut expired = Vec::new();
+            for index in 0..leaf.num_of_slots().get() {
+                if leaf.is_tombstone_at(index)?
+                    && le_u64(leaf.value_at(index)?.to_bytes()).is_some_and(|stamp| stamp < watermark)
+                {
+                    expired.push(index);
+                }
+            }
+            if !expired.is_empty() {
+                let mut overflow_page_ids = Vec::new();
+                // slots behind a deleted one move down, so they are deleted from the last one on.
+                for index in expired.iter().rev() {
+                    overflow_page_ids.extend(leaf.overflow_page_ids(*index)?);
+                    leaf.delete_slot(*index)?;
+                }
+                io::write(&leaf);
+                freelist::push_all(&overflow_page_ids)?;
+                purged += expired.len();
+            }
+            leaves += 1;
+            events::progress(BulkOperation::Purge, leaves, None);
+        }
+        io::check_writable()?;
+        Ok(purged)
+    }
+
+    /// Returns the stamps of the tombstones in key order, along with the bytes of their keys and
+    /// payloads.
+    pub(crate) fn tombstones(&self) -> Result<Vec<(u64, usize)>, Error> {
+        let path = self.path_to_leaf(None)?;
+        let mut next = path[path.len() - 1];
+        let mut tombstones = Vec::new();
+        while next != ZERO {
+            let leaf = load(next)?;
+            next = leaf.right_sibling();
+            for index in 0..leaf.num_of_slots().get() {
+                if !leaf.is_tombstone_at(index)? {
+                    continue;
+                }
+                let payload = leaf.value_at(index)?;
+                if let Some(stamp) = le_u64(payload.to_bytes()) {
+                    tombstones.push((stamp, leaf.key_at(index)?.len() + payload.len()));
+                }
+            }
+        }
+        Ok(tombstones)
+    }
+
+    /// Returns the key-payload pairs within the range in key order by walking the leaf chain.
+    pub(crate) fn scan<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+    ) -> Result<Scan, Error> {
+        self.scan_with_options(range, ScanOptions::default())
+    }
+
+    pub(crate) fn scan_with_options<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+        options: ScanOptions,
+    ) -> Result<Scan, Error> {
+        let _operation = stats::begin(Operation::Scan, 0);
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(*key),
+            Bound::Unbounded => None,
+        };
+        let cursor = pins::open(options.max_pinned_pages);
+        let path = self.path_to_leaf(start)?;
+        Ok(Scan {
+            next_leaf: path[path.len() - 1],
+            entries: VecDeque::new(),
+            start: range.start_bound().map(|key| key.as_bytes().to_vec()),
+            end: range.end_bound().map(|key| key.as_bytes().to_vec()),
+            cursor,
+            readahead: ReadAhead::None,
+            ahead: VecDeque::new(),
+            cancel: None,
+        })
+    }
+
+    /// Reads the leaves holding the range into the page cache, so that a scan of the range which
+    /// follows finds them there. Returns the number of leaves read.
+    pub(crate) fn advise_range<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+    ) -> Result<usize, Error> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(*key),
+            Bound::Unbounded => None,
+        };
+        let end = range.end_bound().map(|key| key.as_bytes());
+        let path = self.path_to_leaf(start)?;
+        let mut next = path[path.len() - 1];
+        let mut leaves = 0;
+        while next != ZERO {
+            let leaf = prefetch(next)?;
+            leaves += 1;
+            next = next_leaf_within(&leaf, end);
+        }
+        Ok(leaves)
+    }
+
+    /// Returns the ids of the leaves holding the range and of their overflow pages, e.g. to move
+    /// the range into another storage tier, see `tier::migrate`.
+    pub(crate) fn range_pages<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+    ) -> Result<Vec<Offset>, Error> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(*key),
+            Bound::Unbounded => None,
+        };
+        let end = range.end_bound().map(|key| key.as_bytes());
+        let path = self.path_to_leaf(start)?;
+        let mut next = path[path.len() - 1];
+        let mut pages = Vec::new();
+        while next != ZERO {
+            let leaf = load(next)?;
+            pages.push(next);
+            for i in 0..leaf.num_of_slots().get() {
+                pages.extend(leaf.overflow_page_ids(i)?);
+            }
+            next = next_leaf_within(&leaf, end);
+        }
+        Ok(pages)
+    }
+
+    /// Scans the range, passing each key and its value to the filter while the leaf is read, so
+    /// that only the values of the entries the filter includes are copied out of the page. Values
+    /// spilled into overflow pages are read before they are passed to the filter.
+    #[allow(dead_code)]
+    pub(crate) fn scan_filtered<'a, F: FnMut(&[u8], &[u8]) -> FilterDecision>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+        filter: F,
+    ) -> Result<FilteredScan<F>, Error> {
+        Ok(FilteredScan {
+            scan: self.scan(range)?,
+            filter,
+        })
+    }
+
+    /// Builds the tree from entries sorted by key. Pages are filled up to the fill factor, instead
+    /// of being left half full by the splits of one by one inserts, which makes it the way to
+    /// compact an index into a new database. The index must be empty.
+    pub(crate) fn bulk_load(
+        &mut self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Payload)>,
+        fill_factor: f64,
+    ) -> Result<(), Error> {
+        self.bulk_load_cancellable(entries, fill_factor, &CancellationToken::new())
+    }
+
+    /// Builds the tree like `bulk_load` until the token is cancelled, which is checked before each
+    /// page is filled. A cancelled load is undone: the root is emptied again and the pages
+    /// allocated are freed.
+    pub(crate) fn bulk_load_cancellable(
+        &mut self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Payload)>,
+        fill_factor: f64,
+        cancel: &CancellationToken,
+    ) -> Result<(), Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let root = load(self.root)?;
+        if self.layout != KeyLayout::Variable {
+            return Err(Error::KeyLayoutMismatch);
+        }
+        if !root.is_leaf() || root.num_of_slots().get() > 0 {
+            return Err(Error::IndexNotEmpty);
+        }
+        let per_page = ((fill_factor.clamp(0.0, 1.0) * MAX_FAN_OUT as f64).round() as usize).max(1);
+        let empty_root = root;
+        let mut allocated = Vec::new();
+        let undo = |allocated: &[Offset]| {
+            io::write(&empty_root);
+            for page_id in allocated {
+                freelist::push(*page_id)?;
+            }
+            Err(Error::Cancelled)
+        };
+
+        // (smallest key, page id) of the pages of the level being built.
+        let mut level: Vec<(Vec<u8>, Offset)> = Vec::new();
+        let mut entries = entries.into_iter().peekable();
+        let (_, total) = entries.size_hint();
+        let mut loaded = 0;
+        let mut leaf = root;
+        let mut previous: Option<Vec<u8>> = None;
+        while entries.peek().is_some() {
+            if cancel.is_cancelled() {
+                return undo(&allocated);
+            }
+            let mut count = 0;
+            while count < per_page && !leaf.is_full()? {
+                let Some((key, payload)) = entries.next() else {
+                    break;
+                };
+                if key.len() > MAX_KEY_SIZE {
+                    return Err(Error::OutOfRange);
+                }
+                check_value_size(payload.len())?;
+                if previous.as_ref().is_some_and(|previous| *previous >= key) {
+                    return Err(Error::UnsortedInput);
+                }
+                if count == 0 {
+                    level.push((key.clone(), leaf.page_id()));
+                }
+                leaf.add(Key::from(key.as_slice()), encode_value(payload))?;
+                previous = Some(key);
+                count += 1;
+            }
+            loaded += count;
+            if entries.peek().is_some() {
+                let mut next = Page::new_data(&self.allocator)?;
+                allocated.push(next.page_id());
+                leaf.set_right_sibling(next.page_id());
+                next.set_left_sibling(leaf.page_id());
+                io::write(&leaf);
+                leaf = next;
+            } else {
+                io::write(&leaf);
+            }
+            events::progress(BulkOperation::BulkLoad, loaded, total);
+        }
+
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            let mut children = level.into_iter().peekable();
+            while let Some((min_key, left_most)) = children.next() {
+                if cancel.is_cancelled() {
+                    return undo(&allocated);
+                }
+                let mut parent = Page::new_inner(&self.allocator)?;
+                allocated.push(parent.page_id());
+                parent.add_left_most(left_most);
+                set_parent(left_most, parent.page_id())?;
+                let mut count = 0;
+                while count < per_page && !parent.is_full()? {
+                    let Some((separator, child)) = children.next() else {
+                        break;
+                    };
+                    parent.add_key_ref(Key::from(separator.as_slice()), child)?;
+                    set_parent(child, parent.page_id())?;
+                    count += 1;
+                }
+                io::write(&parent);
+                parents.push((min_key, parent.page_id()));
+            }
+            level = parents;
+        }
+        if let Some((_, root)) = level.pop() {
+            self.root = root;
+            update_root_page_id(root);
+        }
+        treestats::invalidate();
+        io::check_writable()
+    }
+
+    /// Returns the greatest key-payload pair. Leaves emptied by deletes stay in the chain, so the
+    /// search continues on the left siblings of the right most leaf.
+    pub(crate) fn last(&self) -> Result<Option<(Vec<u8>, Payload)>, Error> {
+        let mut page = load(self.root)?;
+        while !page.is_leaf() {
+            page = load(last_child(&page, &self.interner)?)?;
+        }
+        loop {
+            for (key, index) in sorted_keys(&page, None)?.into_iter().rev() {
+                if !page.is_tombstone_at(index)? {
+                    return Ok(Some((key, decode_value(page.value_at(index)?, false)?)));
+                }
+            }
+            if page.left_sibling() == ZERO {
+                return Ok(None);
+            }
+            page = load(page.left_sibling())?;
+        }
+    }
+
+    /// Computes the aggregate over the entries in the range while the leaves are walked, without
+    /// copying any entry out of its page. Min walks up to the first key in the range, and Max walks
+    /// down from the leaf of the end of the range through the left siblings, so that both read a
+    /// handful of pages. SumU64 adds the values as little-endian unsigned integers, and fails with
+    /// MalformedPayload on values longer than 8 bytes.
+    #[allow(dead_code)]
+    pub(crate) fn aggregate<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+        aggregate: Aggregate,
+    ) -> Result<AggregateValue, Error> {
+        let _operation = stats::begin(Operation::Scan, 0);
+        let start = range.start_bound().map(|key| key.as_bytes().to_vec());
+        let end = range.end_bound().map(|key| key.as_bytes().to_vec());
+        if aggregate == Aggregate::Max {
+            return self.max_key(range.end_bound(), (start.as_ref(), end.as_ref()));
+        }
+        let mut scan = self.scan(range)?;
+        let (mut count, mut sum, mut min, mut malformed) = (0u64, 0u64, None, false);
+        let mut filter = |key: &[u8], value: &[u8]| {
+            match aggregate {
+                Aggregate::Count => count += 1,
+                Aggregate::Min => {
+                    min = Some(key.to_vec());
+                    return FilterDecision::Stop;
+                }
+                Aggregate::SumU64 => match le_u64(value) {
+                    Some(value) => sum = sum.wrapping_add(value),
+                    None => {
+                        malformed = true;
+                        return FilterDecision::Stop;
+                    }
+                },
+                Aggregate::Max => unreachable!(),
+            }
+            FilterDecision::Skip
+        };
+        while scan.next_leaf != ZERO {
+            scan.load_next_leaf_filtered(&mut filter)?;
+        }
+        if malformed {
+            return Err(Error::MalformedPayload);
+        }
+        Ok(match aggregate {
+            Aggregate::Count => AggregateValue::Count(count),
+            Aggregate::SumU64 => AggregateValue::Sum(sum),
+            _ => AggregateValue::Key(min),
+        })
+    }
+
+    // The greatest key in the range, found from the leaf of the end bound.
+    fn max_key(
+        &self,
+        end: Bound<&Key>,
+        range: (Bound<&Vec<u8>>, Bound<&Vec<u8>>),
+    ) -> Result<AggregateValue, Error> {
+        let mut page = load(self.root)?;
+        while !page.is_leaf() {
+            let child = match end {
+                Bound::Included(key) | Bound::Excluded(key) => child_for(&page, *key, &self.interner)?,
+                Bound::Unbounded => last_child(&page, &self.interner)?,
+            };
+            page = load(child)?;
+        }
+        loop {
+            for (key, index) in sorted_keys(&page, None)?.into_iter().rev() {
+                if !(Bound::Unbounded, range.1).contains(&key) || page.is_tombstone_at(index)? {
+                    continue;
+                }
+                return Ok(AggregateValue::Key(
+                    (range.0, Bound::Unbounded).contains(&key).then_some(key),
+                ));
+            }
+            if page.left_sibling() == ZERO {
+                return Ok(AggregateValue::Key(None));
+            }
+            page = load(page.left_sibling())?;
+        }
+    }
+
+    /// Returns up to n - 1 keys dividing the tree into n partitions of roughly equal size, for
+    /// sharding the data across databases. Partition i holds the keys from split point i - 1 up to,
+    /// but excluding, split point i. The tree is descended until a level has enough pages to place
+    /// the cuts, where leaves are weighted by their entries and inner pages by their children, so
+    /// that the leaves are only read for small trees. Small trees get fewer split points.
+    #[allow(dead_code)]
+    pub(crate) fn split_points(&self, n: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let mut level: Vec<Bounded<Page>> = vec![(None, load(self.root)?)];
+        while level.len() < n * SPLIT_PAGES_PER_PARTITION && !level[0].1.is_leaf() {
+            let mut next = Vec::new();
+            for (min_key, page) in &level {
+                for (key, child) in self.separated_children(page, min_key)? {
+                    next.push((key, load(child)?));
+                }
+            }
+            level = next;
+        }
+        let weight = |page: &Page| page.num_of_slots().get() + usize::from(!page.is_leaf());
+        let total: usize = level.iter().map(|(_, page)| weight(page)).sum();
+        let mut points = Vec::new();
+        let mut before = 0;
+        for (min_key, page) in &level {
+            // the next cut falls in front of the page once the pages before it hold its share.
+            if let Some(min_key) = min_key
+                && points.len() + 1 < n
+                && before * n >= (points.len() + 1) * total
+            {
+                points.push(min_key.clone());
+            }
+            before += weight(page);
+        }
+        Ok(points)
+    }
+
+    // The children of the inner page in key order, along with the smallest key they may hold.
+    fn separated_children(
+        &self,
+        page: &Page,
+        min_key: &Option<Vec<u8>>,
+    ) -> Result<Vec<Bounded<Offset>>, Error> {
+        let mut children = vec![(min_key.clone(), page.left_most_page_id())];
+        if page.is_dense() {
+            children.extend((0..page.num_of_slots().get()).map(|i| {
+                let key = page.dense_key_at(i).to_be_bytes().to_vec();
+                (Some(key), page.dense_child_at(i))
+            }));
+            return Ok(children);
+        }
+        for (key, index) in sorted_keys(page, Some(&self.interner))? {
+            children.push((Some(key), child_at(page, index)?));
+        }
+        Ok(children)
+    }
+
+    /// Rebuilds the parent pointers of all pages and the sibling chain of the leaves by walking the
+    /// tree level by level from the root. Returns the pages which were fixed.
+    pub(crate) fn repair_links(&self) -> Result<RepairReport, Error> {
+        self.walk_links(true)
+    }
+
+    /// Returns the pages `repair_links` would fix, without writing any.
+    pub(crate) fn check_links(&self) -> Result<RepairReport, Error> {
+        self.walk_links(false)
+    }
+
+    fn walk_links(&self, fix: bool) -> Result<RepairReport, Error> {
+        let mut report = RepairReport::default();
+        let mut level = vec![(self.root, ZERO)];
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            let mut leaves = Vec::new();
+            for (page_id, parent) in level {
+                let mut page = load(page_id)?;
+                if page.parent() != parent {
+                    page.set_parent(parent);
+                    if fix {
+                        io::write(&page);
+                    }
+                    report.parents.push(page_id);
+                }
+                if page.is_leaf() {
+                    leaves.push(page);
+                } else {
+                    for child in ordered_children(&page, &self.interner)? {
+                        next_level.push((child, page_id));
+                    }
+                }
+            }
+            for i in 0..leaves.len() {
+                let left = if i == 0 { ZERO } else { leaves[i - 1].page_id() };
+                let right = leaves.get(i + 1).map_or(ZERO, |leaf| leaf.page_id());
+                let leaf = &mut leaves[i];
+                if leaf.left_sibling() != left || leaf.right_sibling() != right {
+                    leaf.set_left_sibling(left);
+                    leaf.set_right_sibling(right);
+                    if fix {
+                        io::write(leaf);
+                    }
+                    report.siblings.push(leaf.page_id());
+                }
+            }
+            level = next_level;
+        }
+        Ok(report)
+    }
+
+    /// Returns the page ids from the root down to the leaf covering the key, or to the left most
+    /// leaf if no key is given.
+    fn path_to_leaf(&self, key: Option<Key>) -> Result<Vec<Offset>, Error> {
+        path_to_leaf(self.root, key, &self.interner)
+    }
+
+    /// Adds the separator of a split into the parent at the end of the path, splitting the parent
+    /// itself if it is full. A split root grows the tree by one level. The level is the one of the
+    /// split pages, leaves being level zero.
+    fn insert_separator(
+        &mut self,
+        path: &[Offset],
+        level: usize,
+        left: Offset,
+        separator: Vec<u8>,
+        right: Offset,
+    ) -> Result<(), Error> {
+        if path.is_empty() {
+            let mut root = match self.layout {
+                KeyLayout::Variable => Page::new_inner(&self.allocator)?,
+                KeyLayout::U64 => Page::new_dense_inner(&self.allocator)?,
+            };
+            root.add_left_most(left);
+            self.add_separator(&mut root, &separator, right)?;
+            io::write(&root);
+            set_parent(left, root.page_id())?;
+            set_parent(right, root.page_id())?;
+            self.root = root.page_id();
+            update_root_page_id(self.root);
+            treestats::record_split(level + 1);
+            return Ok(());
+        }
+
+        let mut parent = load(path[path.len() - 1])?;
+        if !parent.is_full()? {
+            self.add_separator(&mut parent, &separator, right)?;
+            io::write(&parent);
+            return set_parent(right, parent.page_id());
+        }
+
+        let (mut parent_left, mut parent_right, parent_separator) =
+            split(&parent, &self.interner, &self.allocator)?;
+        let target = if separator < parent_separator {
+            &mut parent_left
+        } else {
+            &mut parent_right
+        };
+        self.add_separator(target, &separator, right)?;
+        io::write(&parent_left);
+        io::write(&parent_right);
+        // the children of the left half, including a new right page which landed there, keep the
+        // parent page id.
+        for child in children(&parent_right)? {
+            set_parent(child, parent_right.page_id())?;
+        }
+        treestats::record_split(level + 1);
+        self.insert_separator(
+            &path[..path.len() - 1],
+            level + 1,
+            parent_left.page_id(),
+            parent_separator,
+            parent_right.page_id(),
+        )
+    }
+
+    // Separators sharing a long prefix with their neighbours are stored interned, unless the
+    // compression policy turned key compression off.
+    fn add_separator(
+        &mut self,
+        page: &mut Page,
+        separator: &[u8],
+        child: Offset,
+    ) -> Result<(), Error> {
+        if page.is_dense() {
+            return page.dense_insert(dense_key(separator)?, child);
+        }
+        if !compression_policy().inner_keys {
+            return page.add_key_ref(Key::from(separator), child);
+        }
+        let neighbours: Vec<Vec<u8>> = sorted_keys(page, Some(&self.interner))?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        match self.interner.encode(separator, &neighbours)? {
+            Some(interned) => page.add_interned_key_ref(Key::from(interned.as_slice()), child),
+            None => page.add_key_ref(Key::from(separator), child),
+        }
+    }
+}
+
+/// RepairReport lists the pages whose links were rebuilt by `Index::repair_links`.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct RepairReport {
+    pub(crate) parents: Vec<Offset>,
+    pub(crate) siblings: Vec<Offset>,
+}
+
+fn path_to_leaf(
+    root: Offset,
+    key: Option<Key>,
+    interner: &Interner,
+) -> Result<Vec<Offset>, Error> {
+    let mut path = vec![root];
+    let mut page = load(root)?;
+    while !page.is_leaf() {
+        let child = match key {
+            Some(key) => child_for(&page, key, interner)?,
+            None => page.left_most_page_id(),
+        };
+        path.push(child);
+        page = load(child)?;
+    }
+    Ok(path)
+}
+
+/// Returns the pages of the tree whose high key isn't the separator bounding them in their parent,
+/// or which hold keys from their high key on. Pages without a high key are unbounded.
+pub(crate) fn misbounded_pages(root: Offset) -> Result<Vec<Offset>, Error> {
+    let mut misbounded = Vec::new();
+    if root == ZERO {
+        return Ok(misbounded);
+    }
+    let interner = Interner::load()?;
+    let mut pending: Vec<(Offset, Option<Vec<u8>>)> = vec![(root, None)];
+    while let Some((page_id, upper)) = pending.pop() {
+        let page = load(page_id)?;
+        let keys: Vec<Vec<u8>> = if page.is_dense() {
+            (0..page.num_of_slots().get())
+                .map(|i| page.dense_key_at(i).to_be_bytes().to_vec())
+                .collect()
+        } else {
+            sorted_keys(&page, Some(&interner))?.into_iter().map(|(key, _)| key).collect()
+        };
+        if let Some(high_key) = page.high_key()
+            && (upper.as_deref() != Some(high_key)
+                || keys.last().is_some_and(|key| key.as_slice() >= high_key))
+        {
+            misbounded.push(page_id);
+        }
+        if !page.is_leaf() {
+            // each child is bounded by the separator of its right neighbour, the last one by the
+            // bound of the page.
+            let uppers = keys.into_iter().map(Some).chain([upper]);
+            pending.extend(ordered_children(&page, &interner)?.into_iter().zip(uppers));
+        }
+    }
+    Ok(misbounded)
+}
+
+const DEFAULT_MAX_PINNED_PAGES: usize = 1024;
+
+/// ScanOptions tunes a scan.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct ScanOptions {
+    /// Pages freed while the scan is open which it keeps from being reused, so that it can go on
+    /// following the leaf chain. Past the budget the scan re-seeks its position by key from the
+    /// root instead, zero re-seeks whenever a page is freed. See `pins`.
+    pub(crate) max_pinned_pages: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            max_pinned_pages: DEFAULT_MAX_PINNED_PAGES,
+        }
+    }
+}
+
+// Number of leaves an aggressive scan keeps read ahead.
+const AGGRESSIVE_READAHEAD_LEAVES: usize = 8;
+
+/// ReadAhead tells a scan how many of the leaves it's about to read to bring into the page cache
+/// ahead of time. Leaves are read following the leaf chain, so unlike the read ahead of
+/// `io::set_readahead_pages` it doesn't depend on the leaves being adjacent in the file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ReadAhead {
+    None,
+    /// Keeps the next leaf read ahead.
+    #[allow(dead_code)]
+    Sequential,
+    /// Keeps the next `AGGRESSIVE_READAHEAD_LEAVES` leaves read ahead.
+    #[allow(dead_code)]
+    Aggressive,
+}
+
+impl ReadAhead {
+    fn leaves(self) -> usize {
+        match self {
+            ReadAhead::None => 0,
+            ReadAhead::Sequential => 1,
+            ReadAhead::Aggressive => AGGRESSIVE_READAHEAD_LEAVES,
+        }
+    }
+}
+
+// The right sibling of the leaf, ZERO if the range ends within the leaf. The leaves to the right
+// hold keys from the high key on.
+fn next_leaf_within(leaf: &Page, end: Bound<&[u8]>) -> Offset {
+    match leaf.high_key() {
+        Some(high_key) if !(Bound::Unbounded, end).contains(high_key) => ZERO,
+        _ => leaf.right_sibling(),
+    }
+}
+
+// Loads the page after reading it into the page cache.
+fn prefetch(page_id: Offset) -> Result<Page, Error> {
+    io::prefetch(page_id.try_into()?);
+    load(page_id)
+}
+
+/// Scan is an iterator over a key range, it buffers one leaf at a time.
+pub(crate) struct Scan {
+    next_leaf: Offset,
+    entries: VecDeque<(Vec<u8>, Payload)>,
+    // advanced past the keys of each buffered leaf, so that a re-seek continues behind them.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    cursor: u64,
+    readahead: ReadAhead,
+    // the leaves read ahead along with the leaf following each of them.
+    ahead: VecDeque<(Offset, Offset)>,
+    cancel: Option<CancellationToken>,
+}
+
+impl Scan {
+    #[allow(dead_code)]
+    pub(crate) fn set_readahead(&mut self, readahead: ReadAhead) {
+        self.readahead = readahead;
+    }
+
+    /// Ends the scan with Cancelled once the token is cancelled, which is checked before each
+    /// leaf is read, the entries of the leaf read before are yielded first.
+    #[allow(dead_code)]
+    pub(crate) fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
+    }
+
+    // Reads the leaves following the next one into the page cache, up to the read ahead.
+    fn read_ahead(&mut self) -> Result<(), Error> {
+        while self.ahead.front().is_some_and(|(leaf, _)| *leaf != self.next_leaf) {
+            self.ahead.pop_front();
+        }
+        let end = self.end.as_ref().map(Vec::as_slice);
+        while self.ahead.len() < self.readahead.leaves() {
+            let next = match self.ahead.back() {
+                Some((_, next)) => *next,
+                None => self.next_leaf,
+            };
+            if next == ZERO {
+                break;
+            }
+            self.ahead.push_back((next, next_leaf_within(&prefetch(next)?, end)));
+        }
+        Ok(())
+    }
+
+    // Descends from the current root to the leaf holding the start of the remaining range.
+    fn reseek(&mut self) -> Result<(), Error> {
+        let root = get_root_page_id();
+        if root == ZERO {
+            self.next_leaf = ZERO;
+            return Ok(());
+        }
+        let start = match &self.start {
+            Bound::Included(key) | Bound::Excluded(key) => Some(Key::from(key.as_slice())),
+            Bound::Unbounded => None,
+        };
+        let path = path_to_leaf(root, start, &Interner::load()?)?;
+        self.next_leaf = path[path.len() - 1];
+        Ok(())
+    }
+
+    fn load_next_leaf(&mut self) -> Result<(), Error> {
+        self.load_next_leaf_filtered(&mut |_, _| FilterDecision::Include)
+    }
+
+    // Decodes the entries of the next leaf which are in the range and included by the filter.
+    fn load_next_leaf_filtered(
+        &mut self,
+        filter: &mut impl FnMut(&[u8], &[u8]) -> FilterDecision,
+    ) -> Result<(), Error> {
+        if pins::take_released(self.cursor) {
+            self.ahead.clear();
+            self.reseek()?;
+            if self.next_leaf == ZERO {
+                return Ok(());
+            }
+        }
+        let leaf = load(self.next_leaf)?;
+        self.next_leaf = next_leaf_within(&leaf, self.end.as_ref().map(Vec::as_slice));
+        self.read_ahead()?;
+        let keys = sorted_keys(&leaf, None)?;
+        let last = keys
+            .iter()
+            .rev()
+            .map(|(key, _)| key)
+            .find(|key| (self.start.as_ref(), self.end.as_ref()).contains(*key))
+            .cloned();
+        for (key, index) in keys {
+            if !(self.start.as_ref(), Bound::Unbounded).contains(&key) {
+                continue;
+            }
+            if !(Bound::Unbounded, self.end.as_ref()).contains(&key) {
+                self.next_leaf = ZERO;
+                break;
+            }
+            let payload_type = leaf.payload_type_at(index)?;
+            if payload_type == PayloadType::Tombstone {
+                continue;
+            }
+            let inline_value = match payload_type {
+                PayloadType::Compressed | PayloadType::Checksummed => None,
+                _ => leaf.inline_value_at(index)?,
+            };
+            let (decision, payload) = match inline_value {
+                Some(value) => (filter(&key, value), None),
+                None => {
+                    let payload = decode_value(leaf.value_at(index)?, false)?;
+                    (filter(&key, payload.to_bytes()), Some(payload))
+                }
+            };
+            match decision {
+                FilterDecision::Include => {
+                    let payload = match payload {
+                        Some(payload) => payload,
+                        None => leaf.value_at(index)?,
+                    };
+                    self.entries.push_back((key, payload));
+                }
+                FilterDecision::Skip => {}
+                FilterDecision::Stop => {
+                    self.next_leaf = ZERO;
+                    break;
+                }
+            }
+        }
+        if let Some(last) = last {
+            self.start = Bound::Excluded(last);
+        }
+        Ok(())
+    }
+}
+
+// Pages the scan was the last to pin are freed, if that fails they are left to fsck.
+impl Drop for Scan {
+    fn drop(&mut self) {
+        let _ = freelist::release(&pins::close(self.cursor));
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Payload), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let _operation = stats::resume(Operation::Scan);
+        while self.entries.is_empty() && self.next_leaf != ZERO {
+            let cancelled = self.cancel.as_ref().map_or(Ok(()), CancellationToken::check);
+            if let Err(e) = cancelled.and_then(|_| self.load_next_leaf()) {
+                self.next_leaf = ZERO;
+                return Some(Err(e));
+            }
+        }
+        let (key, payload) = self.entries.pop_front()?;
+        if !(Bound::Unbounded, self.end.as_ref()).contains(&key) {
+            self.entries.clear();
+            self.next_leaf = ZERO;
+            return None;
+        }
+        Some(Ok((key, payload)))
+    }
+}
+
+/// Aggregate is the function computed by `Index::aggregate`, Min and Max are over the keys.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(dead_code)]
+pub(crate) enum Aggregate {
+    Count,
+    Min,
+    Max,
+    SumU64,
 }
 
-struct Node {
-    // child page IDs.
-    children: Vec<usize>,
-    // Scope of the children, the keys.
-    keys: Vec<usize>,
-    // Node type can be either an inner or a leaf node.
-    node_type: NodeType,
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum AggregateValue {
+    Count(u64),
+    /// The key found by Min or Max, None if the range is empty.
+    Key(Option<Vec<u8>>),
+    Sum(u64),
 }
 
-enum NodeType {
-    Leaf,
-    Inner,
+fn le_u64(value: &[u8]) -> Option<u64> {
+    if value.len() > size_of::<u64>() {
+        return None;
+    }
+    let mut bytes = [0u8; size_of::<u64>()];
+    bytes[..value.len()].copy_from_slice(value);
+    Some(u64::from_le_bytes(bytes))
 }
 
-impl Node {}
+/// FilterDecision is returned by the filter of `Index::scan_filtered` for each entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum FilterDecision {
+    Include,
+    Skip,
+    /// Ends the scan, the entry is skipped.
+    Stop,
+}
+
+/// FilteredScan yields the entries of the range included by its filter.
+pub(crate) struct FilteredScan<F> {
+    scan: Scan,
+    filter: F,
+}
+
+impl<F: FnMut(&[u8], &[u8]) -> FilterDecision> Iterator for FilteredScan<F> {
+    type Item = Result<(Vec<u8>, Payload), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let _operation = stats::resume(Operation::Scan);
+        while self.scan.entries.is_empty() && self.scan.next_leaf != ZERO {
+            if let Err(e) = self.scan.load_next_leaf_filtered(&mut self.filter) {
+                self.scan.next_leaf = ZERO;
+                return Some(Err(e));
+            }
+        }
+        self.scan.entries.pop_front().map(Ok)
+    }
+}
+
+// Copies the page without waiting for its latch, None if the latch is taken or the page has no
+// version to validate the copy against.
+fn load_optimistic(page_id: Offset) -> Result<Option<(Page, u64)>, Error> {
+    let (page, version) =
+        io::read_versioned(page_id.try_into()?).ok_or(Error::OutOfRange)?;
+    match (version, latch::try_copy(&page)) {
+        (Some(version), Some(page)) => Ok(Some((page, version))),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn load(page_id: Offset) -> Result<Page, Error> {
+    let page = io::read_verified(page_id)?;
+    let guard = latch::lock(page_id, &page);
+    let violation = if !guard.has_known_page_type() {
+        Violation::UnknownPageType(guard.page_type())
+    } else if guard.page_id() != page_id {
+        Violation::PageIdMismatch {
+            stored: guard.page_id(),
+        }
+    } else {
+        return Ok(*guard);
+    };
+    Err(poison::corrupted(&guard, page_id, violation))
+}
+
+fn set_parent(page_id: Offset, parent: Offset) -> Result<(), Error> {
+    let mut page = load(page_id)?;
+    page.set_parent(parent);
+    io::write(&page);
+    Ok(())
+}
+
+fn child_at(page: &Page, index: usize) -> Result<Offset, Error> {
+    page.payload_as(index)
+}
+
+pub(crate) fn children(page: &Page) -> Result<Vec<Offset>, Error> {
+    let mut children = vec![page.left_most_page_id()];
+    for i in 0..page.num_of_slots().get() {
+        if page.is_dense() {
+            children.push(page.dense_child_at(i));
+        } else {
+            children.push(child_at(page, i)?);
+        }
+    }
+    Ok(children)
+}
+
+// The children in key order, starting with the left most child.
+fn ordered_children(page: &Page, interner: &Interner) -> Result<Vec<Offset>, Error> {
+    let mut children = vec![page.left_most_page_id()];
+    if page.is_dense() {
+        children.extend((0..page.num_of_slots().get()).map(|i| page.dense_child_at(i)));
+        return Ok(children);
+    }
+    for (_, index) in sorted_keys(page, Some(interner))? {
+        children.push(child_at(page, index)?);
+    }
+    Ok(children)
+}
+
+fn last_child(page: &Page, interner: &Interner) -> Result<Offset, Error> {
+    let len = page.num_of_slots().get();
+    if len == 0 {
+        return Ok(page.left_most_page_id());
+    }
+    if page.is_dense() {
+        return Ok(page.dense_child_at(len - 1));
+    }
+    match sorted_keys(page, Some(interner))?.pop() {
+        Some((_, index)) => child_at(page, index),
+        None => Ok(page.left_most_page_id()),
+    }
+}
+
+// The child covering the key is referenced by the greatest separator less or equal to the key.
+fn child_for(page: &Page, key: Key, interner: &Interner) -> Result<Offset, Error> {
+    // sorted pages hold no interned separators, they are binary searched in place.
+    if let Some(child) = page.child(key.as_bytes())? {
+        return Ok(child);
+    }
+    // interned separators are compared in their parts, so that they aren't copied.
+    let mut child = page.left_most_page_id();
+    let mut best = None;
+    for i in 0..page.num_of_slots().get() {
+        let separator = key_parts_at(page, i, interner)?;
+        if chained(separator).le(key.as_bytes())
+            && best.is_none_or(|best| chained(separator).gt(chained(best)))
+        {
+            child = child_at(page, i)?;
+            best = Some(separator);
+        }
+    }
+    Ok(child)
+}
+
+fn chained<'a>((prefix, suffix): (&'a [u8], &'a [u8])) -> impl Iterator<Item = &'a u8> {
+    prefix.iter().chain(suffix)
+}
+
+// Returns the keys along with their slot index in key order. Only pages whose slots are in
+// insertion order, i.e. pages with interned keys or written before slots were sorted, are sorted.
+fn sorted_keys(
+    page: &Page,
+    interner: Option<&Interner>,
+) -> Result<Vec<(Vec<u8>, usize)>, Error> {
+    let mut keys = Vec::with_capacity(page.num_of_slots().get());
+    for i in 0..page.num_of_slots().get() {
+        keys.push((resolved_key_at(page, i, interner)?, i));
+    }
+    if !page.has_sorted_slots() {
+        keys.sort();
+    }
+    Ok(keys)
+}
+
+/// Splits the page into two halves, the left half keeps the page id. For leaves the separator is
+/// the first key of the right half; for inner pages the middle separator moves up and its child
+/// becomes the left most child of the right half. The separator becomes the high key of the left
+/// half, the right half inherits the high key of the page.
+fn split(
+    page: &Page,
+    interner: &Interner,
+    allocator: &PageAllocator,
+) -> Result<(Page, Page, Vec<u8>), Error> {
+    if page.is_dense() {
+        return split_dense(page, allocator);
+    }
+    let mut keys = sorted_keys(page, Some(interner))?;
+    let mut right_keys = keys.split_off(keys.len() / 2);
+
+    let mut left = Page::new_page(page.page_type(), page.page_id());
+    left.set_slot_layout(page.slot_layout())?;
+    left.set_parent(page.parent());
+    left.set_left_most_page_id(page.left_most_page_id());
+    let mut right = if page.is_leaf() {
+        Page::new_data(allocator)?
+    } else {
+        Page::new_inner(allocator)?
+    };
+    events::emit(|listener| listener.on_page_split(page.page_id(), right.page_id()));
+    right.set_parent(page.parent());
+
+    let separator = if page.is_leaf() {
+        let old_right_sibling = page.right_sibling();
+        left.set_left_sibling(page.left_sibling());
+        left.set_right_sibling(right.page_id());
+        right.set_left_sibling(left.page_id());
+        right.set_right_sibling(old_right_sibling);
+        if old_right_sibling != ZERO {
+            let mut neighbour = load(old_right_sibling)?;
+            neighbour.set_left_sibling(right.page_id());
+            io::write(&neighbour);
+        }
+        right_keys[0].0.clone()
+    } else {
+        let (separator, index) = right_keys.remove(0);
+        right.set_left_most_page_id(child_at(page, index)?);
+        separator
+    };
+
+    left.set_high_key(Some(&separator))?;
+    right.set_high_key(page.high_key())?;
+    // the slots are moved as they're stored, the halves share the prefix of the page. Leaves are
+    // compressed further, the keys of a half usually share a longer prefix.
+    left.set_prefix(page.prefix())?;
+    right.set_prefix(page.prefix())?;
+    for (_, index) in keys {
+        left.push_slot(&page.slot_at(index)?)?;
+    }
+    for (_, index) in right_keys {
+        right.push_slot(&page.slot_at(index)?)?;
+    }
+    left.compress_prefix()?;
+    right.compress_prefix()?;
+    Ok((left, right, separator))
+}
+
+// Dense pages are sorted already, the middle key moves up like for slotted inner pages.
+fn split_dense(
+    page: &Page,
+    allocator: &PageAllocator,
+) -> Result<(Page, Page, Vec<u8>), Error> {
+    let len = page.num_of_slots().get();
+    let middle = len / 2;
+    let mut left = Page::new_page(page.page_type(), page.page_id());
+    left.set_parent(page.parent());
+    left.set_left_most_page_id(page.left_most_page_id());
+    let mut right = Page::new_dense_inner(allocator)?;
+    events::emit(|listener| listener.on_page_split(page.page_id(), right.page_id()));
+    right.set_parent(page.parent());
+    right.set_left_most_page_id(page.dense_child_at(middle));
+    for i in 0..middle {
+        left.dense_insert(page.dense_key_at(i), page.dense_child_at(i))?;
+    }
+    for i in middle + 1..len {
+        right.dense_insert(page.dense_key_at(i), page.dense_child_at(i))?;
+    }
+    Ok((left, right, page.dense_key_at(middle).to_be_bytes().to_vec()))
+}
+
+#[test]
+#[serial]
+fn verify_insert_and_get() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..200u32 {
+        let key = format!("key-{:05}", i);
+        index
+            .insert(Key::from(key.as_str()), Payload::from_u32(i))
+            .unwrap();
+    }
+    assert!(!load(index.root()).unwrap().is_leaf());
+    for i in 0..200u32 {
+        let key = format!("key-{:05}", i);
+        let payload = index.get(Key::from(key.as_str())).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), &i.to_le_bytes().to_vec());
+    }
+    assert!(index.get(Key::from("missing")).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn verify_insert_replaces_existing_key() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_str("1".to_string())).unwrap();
+    index.insert(Key::from("a"), Payload::from_str("2".to_string())).unwrap();
+    let payload = index.get(Key::from("a")).unwrap().unwrap();
+    assert_eq!(payload.to_str(), "2");
+    assert_eq!(index.scan(..).unwrap().count(), 1);
+}
+
+#[test]
+#[serial]
+fn verify_scan_in_key_order() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    // insert in a scrambled order to exercise splits in the middle of the key space.
+    for i in 0..100u32 {
+        let n = (i * 37) % 100;
+        let key = format!("{:03}", n);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(n)).unwrap();
+    }
+    let keys: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    let expected: Vec<Vec<u8>> = (0..100).map(|n| format!("{:03}", n).into_bytes()).collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<Vec<u8>> = index
+        .scan(Key::from("010")..Key::from("015"))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = (10..15).map(|n| format!("{:03}", n).into_bytes()).collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<Vec<u8>> = index
+        .scan((Bound::Excluded(Key::from("097")), Bound::Unbounded))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"098".to_vec(), b"099".to_vec()]);
+}
+
+#[test]
+#[serial]
+fn verify_split_leaves_are_bounded_by_their_high_keys() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..200u32 {
+        let n = (i * 37) % 200;
+        let key = format!("{:03}", n);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(n)).unwrap();
+    }
+    let path = index.path_to_leaf(None).unwrap();
+    let mut leaf = load(path[path.len() - 1]).unwrap();
+    while leaf.right_sibling() != ZERO {
+        let right = load(leaf.right_sibling()).unwrap();
+        let high_key = leaf.high_key().unwrap().to_vec();
+        let keys = sorted_keys(&leaf, None).unwrap();
+        assert!(keys.iter().all(|(key, _)| *key < high_key));
+        assert_eq!(sorted_keys(&right, None).unwrap()[0].0, high_key);
+        leaf = right;
+    }
+    assert_eq!(leaf.high_key(), None);
+    assert!(misbounded_pages(index.root()).unwrap().is_empty());
+
+    // scans ending at a high key stop at its leaf.
+    let keys: Vec<Vec<u8>> = index
+        .scan(Key::from("000")..Key::from("003"))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"000".to_vec(), b"001".to_vec(), b"002".to_vec()]);
+}
+
+#[test]
+#[serial]
+fn verify_delete() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..50u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    for i in (0..50u32).step_by(2) {
+        let key = format!("{:03}", i);
+        assert!(index.delete(Key::from(key.as_str())).unwrap());
+    }
+    assert!(!index.delete(Key::from("000")).unwrap());
+    assert_eq!(index.scan(..).unwrap().count(), 25);
+    assert!(index.get(Key::from("002")).unwrap().is_none());
+    assert!(index.get(Key::from("003")).unwrap().is_some());
+}
+
+#[test]
+#[serial]
+fn verify_reopen_index() {
+    delete_index();
+    let root = {
+        let mut index = Index::open().unwrap();
+        for i in 0..30u32 {
+            let key = format!("{:03}", i);
+            index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+        }
+        index.root()
+    };
+    let index = Index::open().unwrap();
+    assert_eq!(index.root(), root);
+    assert!(index.get(Key::from("029")).unwrap().is_some());
+}
+
+#[test]
+#[serial]
+fn verify_large_payloads_survive_splits() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let value = "x".repeat(20_000);
+    for i in 0..12u32 {
+        let key = format!("{:03}", i);
+        index
+            .insert(Key::from(key.as_str()), Payload::from_str(value.clone()))
+            .unwrap();
+    }
+    for i in 0..12u32 {
+        let key = format!("{:03}", i);
+        let payload = index.get(Key::from(key.as_str())).unwrap().unwrap();
+        assert_eq!(payload.to_str(), value);
+    }
+}
+
+#[test]
+#[serial]
+fn verify_overflow_pages_of_replaced_and_deleted_values_are_freed() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let value = "x".repeat(20_000);
+    for i in 0..12u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_str(value.clone())).unwrap();
+    }
+    // values are replaced by spilled and small ones, deleted, and replaced by tombstones.
+    for i in 0..12u32 {
+        let key = format!("{:03}", i);
+        let key = Key::from(key.as_str());
+        match i % 4 {
+            0 => index.insert(key, Payload::from_str("y".repeat(20_000))).unwrap(),
+            1 => index.insert(key, Payload::from_u32(i)).unwrap(),
+            2 => assert!(index.delete(key).unwrap()),
+            _ => assert!(index.tombstone(key, 1).unwrap()),
+        }
+    }
+    assert_eq!(index.purge_tombstones(2).unwrap(), 3);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+    // the freed pages are allocated again rather than extending the file.
+    let pages = get_next_page_id();
+    index.insert(Key::from("100"), Payload::from_str(value)).unwrap();
+    assert_eq!(get_next_page_id(), pages);
+}
+
+#[test]
+#[serial]
+fn verify_keys_and_values_follow_their_compression_policies() {
+    delete_index();
+    set_compression_policy(CompressionPolicy {
+        inner_keys: false,
+        leaf_values: true,
+    });
+    let mut index = Index::open().unwrap();
+    let key = |i: u32| format!("tenant-0001/orders/{:06}", i);
+    let value = |i: u32| format!("order {} ", i).repeat(100);
+    for i in 0..100u32 {
+        index
+            .insert(Key::from(key(i).as_str()), Payload::from_str(value(i)))
+            .unwrap();
+    }
+    let root = load(index.root()).unwrap();
+    let key_types: Vec<PayloadType> = (0..root.num_of_slots().get())
+        .map(|i| root.key_type_at(i).unwrap())
+        .collect();
+    assert!(key_types.iter().all(|key_type| *key_type == PayloadType::Bytes));
+    let path = index.path_to_leaf(None).unwrap();
+    let leaf = load(path[path.len() - 1]).unwrap();
+    assert_eq!(leaf.payload_type_at(0).unwrap(), PayloadType::Compressed);
+    assert!(leaf.value_at(0).unwrap().len() < value(0).len());
+
+    // values are decompressed by lookups and scans, whatever the policy is now.
+    set_compression_policy(CompressionPolicy::default());
+    let payload = index.get(Key::from(key(42).as_str())).unwrap().unwrap();
+    assert_eq!(payload.payload_type, PayloadType::Str);
+    assert_eq!(payload.to_str(), value(42));
+    let values: Vec<String> =
+        index.scan(..).unwrap().map(|entry| entry.unwrap().1.to_str()).collect();
+    assert_eq!(values, (0..100).map(value).collect::<Vec<_>>());
+    assert_eq!(index.last().unwrap().unwrap().1.to_str(), value(99));
+    index.insert(Key::from(key(0).as_str()), Payload::from_str(value(0))).unwrap();
+    let path = index.path_to_leaf(None).unwrap();
+    let leaf = load(path[path.len() - 1]).unwrap();
+    let slot = leaf.find_slot(Key::from(key(0).as_str())).unwrap().unwrap();
+    assert_eq!(leaf.payload_type_at(slot).unwrap(), PayloadType::Str);
+}
+
+#[test]
+#[serial]
+fn verify_values_are_checked_against_their_checksums() {
+    delete_index();
+    set_value_checksums(true);
+    let mut index = Index::open().unwrap();
+    let large: String = (0..4_000).map(|i| format!("{:05}", i)).collect();
+    index.insert(Key::from("large"), Payload::from_str(large.clone())).unwrap();
+    index.insert(Key::from("small"), Payload::from_u32(7)).unwrap();
+    set_compression_policy(CompressionPolicy {
+        inner_keys: true,
+        leaf_values: true,
+    });
+    index.insert(Key::from("compressed"), Payload::from_str("z".repeat(500))).unwrap();
+    set_compression_policy(CompressionPolicy::default());
+    set_value_checksums(false);
+    index.insert(Key::from("unchecked"), Payload::from_u32(8)).unwrap();
+
+    let verified = |key: &str| index.get_verified(Key::from(key));
+    assert_eq!(verified("large").unwrap().unwrap().to_str(), large);
+    assert_eq!(verified("small").unwrap().unwrap().to_bytes(), &7u32.to_le_bytes().to_vec());
+    assert_eq!(verified("compressed").unwrap().unwrap().to_str(), "z".repeat(500));
+    assert_eq!(verified("unchecked").unwrap().unwrap().to_bytes(), &8u32.to_le_bytes().to_vec());
+    // scans strip the checksums.
+    let values: Vec<Vec<u8>> = index
+        .scan(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().1.to_bytes().to_vec())
+        .collect();
+    assert_eq!(values[1], large.as_bytes());
+    assert_eq!(values[2], 7u32.to_le_bytes().to_vec());
+
+    // a corrupted overflow page goes unnoticed by get, but not by get_verified.
+    let leaf = load(index.root()).unwrap();
+    let slot = leaf.find_slot(Key::from("large")).unwrap().unwrap();
+    let overflow = leaf.overflow_page_ids(slot).unwrap()[0];
+    let mut buffer = [0u8; PAGE_SIZE_USIZE];
+    buffer.copy_from_slice(load(overflow).unwrap().buffer());
+    buffer[PAGE_SIZE_USIZE - 1] ^= 1;
+    io::write(&Page::new_from(buffer));
+    assert_ne!(index.get(Key::from("large")).unwrap().unwrap().to_str(), large);
+    assert!(matches!(
+        verified("large"),
+        Err(Error::ValueChecksumMismatch)
+    ));
+}
+
+#[test]
+#[serial]
+fn verify_separators_are_interned() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let key = |i: u32| format!("tenant-0001/orders/{:06}", i);
+    for i in 0..100u32 {
+        index
+            .insert(Key::from(key(i).as_str()), Payload::from_u32(i))
+            .unwrap();
+    }
+    let root = load(index.root()).unwrap();
+    let interned: Vec<usize> = (0..root.num_of_slots().get())
+        .filter(|i| root.key_type_at(*i).unwrap() == PayloadType::Interned)
+        .collect();
+    assert!(!interned.is_empty());
+    for i in interned {
+        assert!(root.key_at(i).unwrap().len() < key(0).len());
+    }
+
+    // the dictionary is loaded again when the index is reopened.
+    let index = Index::open().unwrap();
+    assert!(index.interner.len() > 0);
+    for i in 0..100u32 {
+        let payload = index.get(Key::from(key(i).as_str())).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), &i.to_le_bytes().to_vec());
+    }
+    assert_eq!(index.scan(..).unwrap().count(), 100);
+}
+
+#[test]
+#[serial]
+fn verify_last_skips_emptied_leaves() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    assert!(index.last().unwrap().is_none());
+    for i in 0..40u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert_eq!(index.last().unwrap().unwrap().0, b"039".to_vec());
+    for i in 20..40u32 {
+        let key = format!("{:03}", i);
+        index.delete(Key::from(key.as_str())).unwrap();
+    }
+    assert_eq!(index.last().unwrap().unwrap().0, b"019".to_vec());
+}
+
+#[test]
+#[serial]
+fn verify_repair_links() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..60u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert_eq!(index.repair_links().unwrap(), RepairReport::default());
+
+    let leaf_id = *index.path_to_leaf(Some(Key::from("030"))).unwrap().last().unwrap();
+    let mut leaf = load(leaf_id).unwrap();
+    let right_sibling = leaf.right_sibling();
+    leaf.set_right_sibling(ZERO);
+    leaf.set_parent(leaf_id);
+    io::write(&leaf);
+    assert!(index.scan(..).unwrap().count() < 60);
+
+    let report = index.repair_links().unwrap();
+    assert_eq!(report.parents, vec![leaf_id]);
+    assert_eq!(report.siblings, vec![leaf_id]);
+    assert_eq!(load(leaf_id).unwrap().right_sibling(), right_sibling);
+    assert_eq!(index.scan(..).unwrap().count(), 60);
+}
+
+#[cfg(test)]
+struct SplitCounter(AtomicUsize);
+
+#[cfg(test)]
+impl events::EventListener for SplitCounter {
+    fn on_page_split(&self, _page_id: Offset, _new_page_id: Offset) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+#[serial]
+fn verify_splits_are_reported() {
+    delete_index();
+    let counter = Arc::new(SplitCounter(AtomicUsize::new(0)));
+    let id = events::register(counter.clone());
+    let mut index = Index::open().unwrap();
+    for i in 0..50u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let splits = counter.0.load(Ordering::Relaxed);
+    assert!(splits > 0);
+    events::unregister(id);
+    for i in 50..100u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert_eq!(counter.0.load(Ordering::Relaxed), splits);
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct ProgressRecorder(std::sync::Mutex<Vec<(BulkOperation, Progress)>>);
+
+#[cfg(test)]
+impl events::EventListener for ProgressRecorder {
+    fn on_progress(&self, operation: BulkOperation, progress: Progress) {
+        self.0.lock().unwrap().push((operation, progress));
+    }
+}
+
+#[test]
+#[serial]
+fn verify_bulk_operations_report_progress() {
+    delete_index();
+    let recorder = Arc::new(ProgressRecorder::default());
+    let id = events::register(recorder.clone());
+    let entries: Vec<(Vec<u8>, Payload)> = (0..300u32)
+        .map(|i| (format!("{:04}", i).into_bytes(), Payload::from_u32(i)))
+        .collect();
+    let mut index = Index::open().unwrap();
+    index.bulk_load(entries, 1.0).unwrap();
+    index.purge_tombstones(u64::MAX).unwrap();
+    events::unregister(id);
+
+    let reports = recorder.0.lock().unwrap().clone();
+    let of = |operation| {
+        let reports = reports.iter().filter(move |(reported, _)| *reported == operation);
+        reports.map(|(_, progress)| *progress).collect::<Vec<_>>()
+    };
+    let loaded = of(BulkOperation::BulkLoad);
+    assert!(loaded.len() > 1);
+    assert!(loaded.windows(2).all(|pair| pair[0].done < pair[1].done));
+    assert_eq!(loaded.last(), Some(&Progress { done: 300, total: Some(300) }));
+    let leaves = treestats::stats().unwrap().level_pages[0];
+    assert_eq!(of(BulkOperation::Purge).last(), Some(&Progress { done: leaves, total: None }));
+}
+
+#[test]
+#[serial]
+fn verify_u64_layout_uses_dense_inner_pages() {
+    delete_index();
+    let mut index = Index::open_with_layout(KeyLayout::U64).unwrap();
+    let count = 5_000u64;
+    for i in 0..count {
+        let n = (i * 7919) % count;
+        index
+            .insert(Key::from(&n.to_be_bytes()), Payload::from_u32(n as u32))
+            .unwrap();
+    }
+    let root = load(index.root()).unwrap();
+    assert!(root.is_dense());
+    assert!(matches!(
+        index.insert(Key::from("short"), Payload::from_u32(0)),
+        Err(Error::KeyLayoutMismatch)
+    ));
+
+    let index = Index::open().unwrap();
+    assert_eq!(index.layout(), KeyLayout::U64);
+    for n in [0u64, 1, 2_500, count - 1] {
+        let payload = index.get(Key::from(&n.to_be_bytes())).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), &(n as u32).to_le_bytes().to_vec());
+    }
+    let keys: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    let expected: Vec<Vec<u8>> = (0..count).map(|n| n.to_be_bytes().to_vec()).collect();
+    assert_eq!(keys, expected);
+    assert!(matches!(
+        Index::open_with_layout(KeyLayout::Variable),
+        Err(Error::KeyLayoutMismatch)
+    ));
+}
+
+#[test]
+#[serial]
+fn verify_optimistic_reads_during_updates() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..50u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let payload = index.get_optimistic(Key::from("042")).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &42u32.to_le_bytes().to_vec());
+    assert!(index.get_optimistic(Key::from("999")).unwrap().is_none());
+    let reader = Index::open().unwrap();
+    let writer = std::thread::spawn(move || {
+        for round in 0..20u32 {
+            for i in 0..50u32 {
+                let key = format!("{:03}", i);
+                index.insert(Key::from(key.as_str()), Payload::from_u32(round)).unwrap();
+            }
+        }
+    });
+    for _ in 0..20 {
+        for i in 0..50u32 {
+            let key = format!("{:03}", i);
+            assert!(reader.get_optimistic(Key::from(key.as_str())).unwrap().is_some());
+        }
+    }
+    writer.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_bulk_load() {
+    delete_index();
+    let entries: Vec<(Vec<u8>, Payload)> = (0..300u32)
+        .map(|i| (format!("{:04}", i).into_bytes(), Payload::from_u32(i)))
+        .collect();
+    let mut index = Index::open().unwrap();
+    index.bulk_load(entries.clone(), 1.0).unwrap();
+    let loaded_pages = get_next_page_id().get();
+    assert_eq!(index.repair_links().unwrap(), RepairReport::default());
+    let scanned: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(scanned.len(), 300);
+    assert!(scanned.iter().zip(&entries).all(|(key, (expected, _))| key == expected));
+    let payload = index.get(Key::from("0123")).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &123u32.to_le_bytes().to_vec());
+    index.insert(Key::from("0123a"), Payload::from_u32(0)).unwrap();
+    assert_eq!(index.scan(..).unwrap().count(), 301);
+    assert!(matches!(
+        index.bulk_load(entries.clone(), 1.0),
+        Err(Error::IndexNotEmpty)
+    ));
+
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for (key, payload) in entries.clone() {
+        index.insert(Key::from(key.as_slice()), payload).unwrap();
+    }
+    // splits leave the pages half full.
+    assert!(get_next_page_id().get() > loaded_pages);
+
+    delete_index();
+    let mut unsorted = entries;
+    unsorted.swap(10, 20);
+    assert!(matches!(
+        Index::open().unwrap().bulk_load(unsorted, 1.0),
+        Err(Error::UnsortedInput)
+    ));
+}
+
+#[test]
+#[serial]
+fn verify_long_operations_are_cancelled() {
+    delete_index();
+    let entries: Vec<(Vec<u8>, Payload)> = (0..300u32)
+        .map(|i| (format!("{:04}", i).into_bytes(), Payload::from_u32(i)))
+        .collect();
+    // the load is cancelled by its input halfway through, and undone.
+    let cancel = CancellationToken::new();
+    let mut index = Index::open().unwrap();
+    let input = entries.iter().cloned().inspect(|(key, _)| {
+        if key.as_slice() == b"0150" {
+            cancel.cancel();
+        }
+    });
+    assert!(matches!(
+        index.bulk_load_cancellable(input, 1.0, &cancel),
+        Err(Error::Cancelled)
+    ));
+    assert_eq!(index.scan(..).unwrap().count(), 0);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+    index.bulk_load(entries, 1.0).unwrap();
+
+    let mut scan = index.scan(..).unwrap();
+    scan.set_cancellation(cancel.clone());
+    assert!(matches!(scan.next(), Some(Err(Error::Cancelled))));
+    assert!(scan.next().is_none());
+    assert!(matches!(
+        index.purge_tombstones_cancellable(u64::MAX, &cancel),
+        Err(Error::Cancelled)
+    ));
+    assert!(matches!(
+        fsck::check_cancellable(true, &cancel),
+        Err(Error::Cancelled)
+    ));
+    assert_eq!(index.scan(..).unwrap().count(), 300);
+}
+
+#[test]
+#[serial]
+fn verify_split_points() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    assert!(index.split_points(4).unwrap().is_empty());
+    for i in 0..1000u32 {
+        let key = format!("{:04}", (i * 7) % 1000);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert!(index.split_points(1).unwrap().is_empty());
+    let points = index.split_points(4).unwrap();
+    assert_eq!(points.len(), 3);
+    assert!(points.windows(2).all(|pair| pair[0] < pair[1]));
+    let mut bounds: Vec<Bound<Key>> = vec![Bound::Unbounded];
+    bounds.extend(points.iter().map(|point| Bound::Included(Key::from(point.as_slice()))));
+    bounds.push(Bound::Unbounded);
+    for pair in bounds.windows(2) {
+        let end = match pair[1] {
+            Bound::Included(key) => Bound::Excluded(key),
+            bound => bound,
+        };
+        let count = index.scan((pair[0], end)).unwrap().count();
+        assert!((150..=350).contains(&count), "{}", count);
+    }
+}
+
+#[test]
+#[serial]
+fn verify_scan_filtered() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..40u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let large = "x".repeat(20_000);
+    index
+        .insert(Key::from("025"), Payload::from_str(large.clone()))
+        .unwrap();
+    let mut seen = 0;
+    let even: Vec<Vec<u8>> = index
+        .scan_filtered(Key::from("010")..Key::from("030"), |_, value| {
+            seen += 1;
+            match value.first() {
+                Some(byte) if byte % 2 == 0 => FilterDecision::Include,
+                _ => FilterDecision::Skip,
+            }
+        })
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(seen, 20);
+    // the large value starts with 'x', which is even.
+    let mut expected: Vec<Vec<u8>> = (10..30u32)
+        .filter(|i| i % 2 == 0)
+        .map(|i| format!("{:03}", i).into_bytes())
+        .collect();
+    expected.push(b"025".to_vec());
+    expected.sort();
+    assert_eq!(even, expected);
+
+    let first: Vec<(Vec<u8>, Payload)> = index
+        .scan_filtered(.., |key, _| match key {
+            b"003" => FilterDecision::Stop,
+            _ => FilterDecision::Include,
+        })
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(first.len(), 3);
+    let large_entry = index
+        .scan_filtered(Key::from("025")..=Key::from("025"), |_, _| FilterDecision::Include)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(large_entry.1.to_str(), large);
+}
+
+#[test]
+#[serial]
+fn verify_aggregates() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..60u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let range = || Key::from("010")..Key::from("020");
+    assert_eq!(index.aggregate(range(), Aggregate::Count).unwrap(), AggregateValue::Count(10));
+    assert_eq!(index.aggregate(.., Aggregate::Count).unwrap(), AggregateValue::Count(60));
+    assert_eq!(
+        index.aggregate(range(), Aggregate::SumU64).unwrap(),
+        AggregateValue::Sum((10..20).sum())
+    );
+    let key = |key: &str| AggregateValue::Key(Some(key.as_bytes().to_vec()));
+    assert_eq!(index.aggregate(range(), Aggregate::Min).unwrap(), key("010"));
+    assert_eq!(index.aggregate(range(), Aggregate::Max).unwrap(), key("019"));
+    assert_eq!(
+        index.aggregate(Key::from("010")..=Key::from("020"), Aggregate::Max).unwrap(),
+        key("020")
+    );
+    assert_eq!(index.aggregate(.., Aggregate::Max).unwrap(), key("059"));
+    assert_eq!(index.aggregate(Key::from("0105").., Aggregate::Min).unwrap(), key("011"));
+    let empty = || Key::from("0105")..Key::from("011");
+    assert_eq!(index.aggregate(empty(), Aggregate::Max).unwrap(), AggregateValue::Key(None));
+    assert_eq!(index.aggregate(empty(), Aggregate::Min).unwrap(), AggregateValue::Key(None));
+    index
+        .insert(Key::from("015"), Payload::from_str("too long for a u64".to_string()))
+        .unwrap();
+    assert!(matches!(
+        index.aggregate(range(), Aggregate::SumU64),
+        Err(Error::MalformedPayload)
+    ));
+}
+
+#[test]
+#[serial]
+fn verify_scans_read_ahead_as_advised() {
+    for mode in [DurabilityMode::WriteThrough, DurabilityMode::Shadow] {
+        delete_index();
+        io::set_durability_mode(mode);
+        check_scans_read_ahead_as_advised();
+    }
+    io::set_durability_mode(DurabilityMode::default());
+}
+
+#[cfg(test)]
+fn check_scans_read_ahead_as_advised() {
+    let mut index = Index::open().unwrap();
+    for i in 0..200u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    io::commit();
+    let expected: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    // the number of pages cached once the first entry of a scan of the whole tree was read.
+    let cached_by_scan = |readahead: ReadAhead| {
+        io::close();
+        let index = Index::open().unwrap();
+        let mut scan = index.scan(..).unwrap();
+        scan.set_readahead(readahead);
+        scan.next().unwrap().unwrap();
+        let cached = io::cached_pages();
+        let mut keys = vec![b"000".to_vec()];
+        keys.extend(scan.map(|entry| entry.unwrap().0));
+        assert_eq!(keys, expected);
+        cached
+    };
+    let none = cached_by_scan(ReadAhead::None);
+    assert_eq!(cached_by_scan(ReadAhead::Sequential), none + 1);
+    assert_eq!(cached_by_scan(ReadAhead::Aggressive), none + AGGRESSIVE_READAHEAD_LEAVES);
+
+    io::close();
+    let mut index = Index::open().unwrap();
+    let cached = io::cached_pages();
+    let leaves = index.advise_range(Key::from("100")..Key::from("150")).unwrap();
+    assert!(leaves >= 50 / 5);
+    assert!(io::cached_pages() >= cached + leaves);
+    let leaves = index.advise_range(Key::from("100")..Key::from("100")).unwrap();
+    assert_eq!(leaves, 1);
+
+    // pages changed since the commit are read ahead as changed, even once evicted from the cache.
+    for i in 100..150u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i + 1000)).unwrap();
+    }
+    io::set_cache_capacity(1);
+    io::set_cache_capacity(0);
+    let leaves = index.advise_range(Key::from("100")..Key::from("150")).unwrap();
+    assert!(io::cached_pages() >= leaves);
+    let mut scan = index.scan(Key::from("100")..Key::from("150")).unwrap();
+    scan.set_readahead(ReadAhead::Aggressive);
+    for (i, entry) in (100..).zip(scan) {
+        assert_eq!(entry.unwrap().1.to_bytes(), (i + 1000u32).to_le_bytes());
+    }
+    io::commit();
+}
+
+#[test]
+#[serial]
+fn verify_point_reads_dont_allocate() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let keys = (0..500u32).map(|i| format!("{:04}", i)).collect::<Vec<_>>();
+    for (i, key) in (0..).zip(&keys) {
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    index.insert(Key::from("large"), Payload::from_str("x".repeat(20_000))).unwrap();
+    assert!(treestats::stats().unwrap().height() > 2);
+    let decode = |value: &[u8]| u32::from_le_bytes(value.try_into().unwrap());
+    for (i, key) in (0..).zip(&keys) {
+        let (value, allocations) =
+            allocs::count(|| index.get_with(Key::from(key.as_str()), decode).unwrap());
+        assert_eq!(value, Some(i));
+        assert_eq!(allocations, 0);
+    }
+    // values spilled into overflow pages are copied out.
+    let len = index.get_with(Key::from("large"), <[u8]>::len).unwrap();
+    assert_eq!(len, Some(20_000));
+    assert!(index.get_with(Key::from("missing"), decode).unwrap().is_none());
+}
@@ -0,0 +1,181 @@
+use crate::btree::Index;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// An entry is looked up by key and evicted by the tick of its last use.
+struct Lru {
+    entries: HashMap<Vec<u8>, (Option<Payload>, u64)>,
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8]) -> Option<Option<Payload>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let Some((value, last_used)) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        let key = self.recency.remove(last_used).unwrap_or_else(|| key.to_vec());
+        *last_used = tick;
+        self.recency.insert(tick, key);
+        Some(value.clone())
+    }
+
+    fn put(&mut self, key: &[u8], value: Option<Payload>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key);
+        self.evict(self.capacity - 1);
+        self.tick += 1;
+        self.entries.insert(key.to_vec(), (value, self.tick));
+        self.recency.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+        }
+    }
+
+    fn evict(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// CachedIndex wraps an index with an LRU of the values looked up by key, for services dominated by
+/// point lookups. Unlike the page cache, a hit neither descends the tree nor decodes a slot. Keys
+/// which weren't found are cached as well. Writes through the wrapper invalidate the key, writes
+/// through other handles of the index aren't seen until the key is evicted or invalidated.
+pub(crate) struct CachedIndex {
+    index: Index,
+    lru: Mutex<Lru>,
+}
+
+impl CachedIndex {
+    /// Wraps the index with a cache of up to capacity keys, zero disables the cache.
+    pub(crate) fn new(index: Index, capacity: usize) -> Self {
+        CachedIndex {
+            index,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                capacity,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> Index {
+        self.index
+    }
+
+    pub(crate) fn get(&self, key: Key) -> Result<Option<Payload>, Error> {
+        if let Some(value) = self.lru().get(key.as_bytes()) {
+            return Ok(value);
+        }
+        let value = self.index.get(Key::from(key.as_bytes()))?;
+        self.lru().put(key.as_bytes(), value.clone());
+        Ok(value)
+    }
+
+    pub(crate) fn insert(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        self.lru().remove(key.as_bytes());
+        self.index.insert(key, payload)
+    }
+
+    pub(crate) fn delete(&mut self, key: Key) -> Result<bool, Error> {
+        self.lru().remove(key.as_bytes());
+        self.index.delete(key)
+    }
+
+    /// Drops the cached value of the key, after it was written through another handle.
+    pub(crate) fn invalidate(&self, key: Key) {
+        self.lru().remove(key.as_bytes());
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut lru = self.lru();
+        lru.entries.clear();
+        lru.recency.clear();
+    }
+
+    /// Changes the number of cached keys, evicting the least recently used ones if it shrinks.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        let mut lru = self.lru();
+        lru.capacity = capacity;
+        lru.evict(capacity);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lru().entries.len()
+    }
+
+    /// Returns the number of lookups served from the cache and the number which read the index.
+    pub(crate) fn hits_and_misses(&self) -> (u64, u64) {
+        let lru = self.lru();
+        (lru.hits, lru.misses)
+    }
+
+    fn lru(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[test]
+#[serial]
+fn verify_cached_index() {
+    delete_index();
+    let mut index = CachedIndex::new(Index::open().unwrap(), 2);
+    for i in 0..30u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let value = |index: &CachedIndex, key: &str| {
+        index.get(Key::from(key)).unwrap().map(|payload| payload.to_bytes().to_vec())
+    };
+    assert_eq!(value(&index, "001"), Some(1u32.to_le_bytes().to_vec()));
+    assert_eq!(value(&index, "001"), Some(1u32.to_le_bytes().to_vec()));
+    assert_eq!(value(&index, "missing"), None);
+    assert_eq!(value(&index, "missing"), None);
+    assert_eq!(index.hits_and_misses(), (2, 2));
+
+    // writes invalidate the key.
+    index.insert(Key::from("001"), Payload::from_u32(100)).unwrap();
+    assert_eq!(value(&index, "001"), Some(100u32.to_le_bytes().to_vec()));
+    index.delete(Key::from("001")).unwrap();
+    assert_eq!(value(&index, "001"), None);
+    assert_eq!(index.hits_and_misses(), (2, 4));
+
+    // "missing" is the least recently used key.
+    assert_eq!(value(&index, "002"), Some(2u32.to_le_bytes().to_vec()));
+    assert_eq!(index.len(), 2);
+    assert_eq!(value(&index, "001"), None);
+    assert_eq!(index.hits_and_misses(), (3, 5));
+    assert_eq!(value(&index, "missing"), None);
+    assert_eq!(index.hits_and_misses(), (3, 6));
+
+    index.set_capacity(0);
+    assert_eq!(index.len(), 0);
+    assert_eq!(value(&index, "002"), Some(2u32.to_le_bytes().to_vec()));
+    assert_eq!(index.len(), 0);
+}
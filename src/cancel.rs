@@ -0,0 +1,36 @@
+use crate::errors::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// CancellationToken lets an embedder abort a long running operation, e.g. a scan, a purge, fsck
+/// or a bulk load, from another thread. Clones share the token. Operations check it between pages
+/// and fail with Cancelled once it's cancelled, leaving the database consistent: the pages they
+/// wrote before stay written, a bulk load is undone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the operations holding the token, they stop at their next check.
+    #[allow(dead_code)]
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fails with Cancelled if the token was cancelled.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match self.is_cancelled() {
+            true => Err(Error::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
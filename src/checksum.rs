@@ -0,0 +1,134 @@
+const PRIME_1: u64 = 0x9E3779B185EBCA87;
+const PRIME_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME_3: u64 = 0x165667B19E3779F9;
+const PRIME_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME_5: u64 = 0x27D4EB2F165667C5;
+
+fn round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME_1)
+}
+
+fn merge_round(acc: u64, lane: u64) -> u64 {
+    (acc ^ round(0, lane)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes"))
+}
+
+fn read_u32(bytes: &[u8]) -> u64 {
+    u64::from(u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")))
+}
+
+/// Computes the XXH64 hash of the data, the checksum stored along with values. It's the reference
+/// algorithm, so checksums can be verified by tools outside of the database.
+pub(crate) fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+    let mut hash = if data.len() >= 32 {
+        let mut acc = [
+            seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+            seed.wrapping_add(PRIME_2),
+            seed,
+            seed.wrapping_sub(PRIME_1),
+        ];
+        while rest.len() >= 32 {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = round(*acc, read_u64(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let mut hash = acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18));
+        for acc in acc {
+            hash = merge_round(hash, acc);
+        }
+        hash
+    } else {
+        seed.wrapping_add(PRIME_5)
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+    while rest.len() >= 8 {
+        hash = (hash ^ round(0, read_u64(rest)))
+            .rotate_left(27)
+            .wrapping_mul(PRIME_1)
+            .wrapping_add(PRIME_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        hash = (hash ^ read_u32(rest).wrapping_mul(PRIME_1))
+            .rotate_left(23)
+            .wrapping_mul(PRIME_2)
+            .wrapping_add(PRIME_3);
+        rest = &rest[4..];
+    }
+    for byte in rest {
+        hash = (hash ^ u64::from(*byte).wrapping_mul(PRIME_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME_1);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+// CRC32 of the IEEE polynomial, reflected, as used by zlib and Ethernet.
+const CRC32_POLYNOMIAL: u32 = 0xEDB88320;
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC32_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Computes the CRC32 of the data, the checksum stored in the page headers. Like XXH64 it's the
+/// reference algorithm, it matches the CRC32 of zlib.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    crc32_append(0, data)
+}
+
+/// Continues the CRC32 of the data before with the data, `crc32_append(crc32(a), b)` is the CRC32
+/// of a followed by b.
+pub(crate) fn crc32_append(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize];
+    }
+    !crc
+}
+
+#[test]
+fn verify_xxh64_matches_the_reference() {
+    assert_eq!(xxh64(b"", 0), 0xEF46DB3751D8E999);
+    assert_eq!(xxh64(b"a", 0), 0xD24EC4F1A98C6E5B);
+    assert_eq!(xxh64(b"abc", 0), 0x44BC2CF5AD770999);
+    assert_eq!(
+        xxh64(b"Nobody inspects the spammish repetition", 0),
+        0xFBCEA83C8A378BF1
+    );
+}
+
+#[test]
+fn verify_crc32_matches_the_reference() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"a"), 0xE8B7BE43);
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    assert_eq!(crc32_append(crc32(b"1234"), b"56789"), 0xCBF43926);
+}
@@ -0,0 +1,1176 @@
+use crate::allocs;
+use crate::btree::{Index, KeyLayout};
+use crate::config::{
+    file_version, get_dictionary_page_id, get_free_list_page_id, get_free_space_map_page_id,
+    get_hash_directory_page_id, get_key_layout, get_next_page_id, get_root_page_id,
+    get_sequence_page_id, get_shard_catalog_page_id, get_slot_layout, update_slot_layout,
+    FORMAT_VERSION, FREE_LIST_SHARDS,
+};
+use crate::crypt::{self, StaticKeys, KEY_SIZE};
+use crate::errors::Error;
+use crate::events::{self, BulkOperation};
+use crate::fixture;
+use crate::freelist;
+use crate::fsck;
+use crate::hash::hash;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::latch;
+use crate::snapshot;
+use crate::stats::{self, Stats};
+use crate::treestats;
+use crate::tier::{self, Tier};
+use crate::treefile;
+use crate::txn;
+use crate::paging::{Page, SlotLayout, PAGE_SIZE_USIZE};
+use crate::types::{Key, Offset, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{self, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_FILL_FACTOR: f64 = 0.9;
+const DEFAULT_BENCH_ENTRIES: usize = 100_000;
+const DEFAULT_BENCH_VALUE_SIZE: usize = 100;
+
+pub(crate) const USAGE: &str = "usage:
+    teleport stats <db>     prints the config, the page counts and fill factors, and the free list
+    teleport config <db>    prints the options the database was created with
+    teleport compact <src> <dst> [fill factor] [--dry-run]
+                            copies the index into a new database with pages filled up to the
+                            fill factor, 0.9 by default
+    teleport diff <a> <b>   lists the keys only in a (-), only in b (+), and with differing values (~)
+    teleport fixture <version> <dst>
+                            writes the fixture database of the format version into dst
+    teleport check-fixture <version> <db>
+                            verifies that the database holds the fixture of the format version
+    teleport rekey <db> --new-key <id>:<hex> [--key <id>:<hex>].. [file].. [--dry-run]
+                            encrypts the prepared transactions of the database and the given
+                            snapshots and tree files with the new key, the old keys decrypt them
+    teleport bench <dst> [entries] [value size] [--slot-table-at-end]
+                            inserts, updates, deletes and looks up entries in a new database, and
+                            reports the throughput, space and write amplification, and fill factor,
+                            of pages with their slot table at the end if asked for
+    teleport verify-backup <snapshot> [--key <id>:<hex>]..
+                            restores the snapshot into a temporary database, checks its pages and
+                            runs fsck on it, and reports whether the snapshot is restorable
+    teleport migrate <db> <hot|cold> [start] [end]
+                            moves the leaves holding the keys from start up to end, or the whole
+                            index if no keys are given, into the index file or the cold file
+    teleport repair <db> [--dry-run]
+                            fixes the parent pointers and sibling links of the index, and frees
+                            the pages no structure refers to
+    teleport delete <db> [--dry-run]
+                            deletes the files of the database
+
+Destructive commands report what they would change and leave the files alone with --dry-run.
+Commands exit with 3 if the database is corrupt, with 4 if it can't be used until it's reopened or
+migrated, with 75 if running them again later may succeed, and with 1 on other errors.";
+
+/// Command is a subcommand of the command line tool. A database is the directory holding its
+/// index and config files.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Stats(PathBuf),
+    Config(PathBuf),
+    Compact {
+        src: PathBuf,
+        dst: PathBuf,
+        fill_factor: f64,
+        dry_run: bool,
+    },
+    Diff(PathBuf, PathBuf),
+    Fixture { version: u32, dst: PathBuf },
+    CheckFixture { version: u32, db: PathBuf },
+    Rekey {
+        db: PathBuf,
+        new_key: (u32, [u8; KEY_SIZE]),
+        keys: Vec<(u32, [u8; KEY_SIZE])>,
+        files: Vec<PathBuf>,
+        dry_run: bool,
+    },
+    Bench {
+        dst: PathBuf,
+        entries: usize,
+        value_size: usize,
+        slot_layout: SlotLayout,
+    },
+    VerifyBackup {
+        backup: PathBuf,
+        keys: Vec<(u32, [u8; KEY_SIZE])>,
+    },
+    Migrate {
+        db: PathBuf,
+        tier: Tier,
+        start: Option<String>,
+        end: Option<String>,
+    },
+    Repair { db: PathBuf, dry_run: bool },
+    Delete { db: PathBuf, dry_run: bool },
+}
+
+/// Parses the arguments following the program name, None if they don't form a command.
+pub(crate) fn parse(args: &[String]) -> Option<Command> {
+    match args {
+        [command, db] if command == "stats" => Some(Command::Stats(PathBuf::from(db))),
+        [command, db] if command == "config" => Some(Command::Config(PathBuf::from(db))),
+        [command, src, dst, options @ ..] if command == "compact" => {
+            let (dry_run, options) = dry_run(options);
+            if options.len() > 1 {
+                return None;
+            }
+            let fill_factor = match options.first() {
+                Some(fill_factor) => fill_factor.parse().ok().filter(|f| (0.0..=1.0).contains(f))?,
+                None => DEFAULT_FILL_FACTOR,
+            };
+            Some(Command::Compact {
+                src: PathBuf::from(src),
+                dst: PathBuf::from(dst),
+                fill_factor,
+                dry_run,
+            })
+        }
+        [command, a, b] if command == "diff" => {
+            Some(Command::Diff(PathBuf::from(a), PathBuf::from(b)))
+        }
+        [command, version, dst] if command == "fixture" => Some(Command::Fixture {
+            version: version.parse().ok()?,
+            dst: PathBuf::from(dst),
+        }),
+        [command, version, db] if command == "check-fixture" => Some(Command::CheckFixture {
+            version: version.parse().ok()?,
+            db: PathBuf::from(db),
+        }),
+        [command, db, options @ ..] if command == "rekey" => parse_rekey(db, options),
+        [command, backup, options @ ..] if command == "verify-backup" => {
+            let keys = options
+                .chunks(2)
+                .map(|option| match option {
+                    [flag, key] if flag == "--key" => parse_key(key),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?;
+            Some(Command::VerifyBackup {
+                backup: PathBuf::from(backup),
+                keys,
+            })
+        }
+        [command, dst, options @ ..] if command == "bench" => {
+            let (slot_layout, options) = match options {
+                [options @ .., flag] if flag == "--slot-table-at-end" => {
+                    (SlotLayout::TableAtEnd, options)
+                }
+                _ => (SlotLayout::TableAtStart, options),
+            };
+            if options.len() > 2 {
+                return None;
+            }
+            let entries = match options.first() {
+                Some(entries) => entries.parse().ok().filter(|entries| *entries > 0)?,
+                None => DEFAULT_BENCH_ENTRIES,
+            };
+            let value_size = match options.get(1) {
+                Some(value_size) => value_size.parse().ok()?,
+                None => DEFAULT_BENCH_VALUE_SIZE,
+            };
+            Some(Command::Bench {
+                dst: PathBuf::from(dst),
+                entries,
+                value_size,
+                slot_layout,
+            })
+        }
+        [command, db, tier, keys @ ..] if command == "migrate" && keys.len() <= 2 => {
+            let tier = match tier.as_str() {
+                "hot" => Tier::Hot,
+                "cold" => Tier::Cold,
+                _ => return None,
+            };
+            Some(Command::Migrate {
+                db: PathBuf::from(db),
+                tier,
+                start: keys.first().cloned(),
+                end: keys.get(1).cloned(),
+            })
+        }
+        [command, db, options @ ..] if command == "repair" || command == "delete" => {
+            let (dry_run, options) = dry_run(options);
+            if !options.is_empty() {
+                return None;
+            }
+            let db = PathBuf::from(db);
+            match command.as_str() {
+                "repair" => Some(Command::Repair { db, dry_run }),
+                _ => Some(Command::Delete { db, dry_run }),
+            }
+        }
+        _ => None,
+    }
+}
+
+// Splits off the trailing --dry-run flag of a destructive command.
+fn dry_run(options: &[String]) -> (bool, &[String]) {
+    match options {
+        [options @ .., flag] if flag == "--dry-run" => (true, options),
+        _ => (false, options),
+    }
+}
+
+fn parse_rekey(db: &str, options: &[String]) -> Option<Command> {
+    let mut new_key = None;
+    let mut keys = Vec::new();
+    let mut files = Vec::new();
+    let mut dry_run = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--new-key" if new_key.is_none() => new_key = Some(parse_key(options.next()?)?),
+            "--key" => keys.push(parse_key(options.next()?)?),
+            "--dry-run" => dry_run = true,
+            file if !file.starts_with("--") => files.push(PathBuf::from(file)),
+            _ => return None,
+        }
+    }
+    Some(Command::Rekey {
+        db: PathBuf::from(db),
+        new_key: new_key?,
+        keys,
+        files,
+        dry_run,
+    })
+}
+
+// Keys are given as their id and 64 hex digits.
+fn parse_key(arg: &str) -> Option<(u32, [u8; KEY_SIZE])> {
+    let (id, hex) = arg.split_once(':')?;
+    if hex.len() != 2 * KEY_SIZE || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some((id.parse().ok()?, key))
+}
+
+/// Returns the exit code of the command failing with the error, see `USAGE`.
+pub(crate) fn exit_code(error: &Error) -> i32 {
+    if error.is_corruption() {
+        3
+    } else if error.is_fatal() {
+        4
+    } else if error.is_retryable() {
+        75
+    } else {
+        1
+    }
+}
+
+/// Describes the error of the command, naming the corrupt page and the last log index applied
+/// when it was read, which a replica restored from a snapshot replays the log from.
+pub(crate) fn describe(error: &Error) -> String {
+    match (error.page_id(), error.lsn()) {
+        (Some(page_id), Some(lsn)) => {
+            format!("{:?}, page {} is corrupt as of log index {}", error, page_id, lsn)
+        }
+        _ => format!("{:?}", error),
+    }
+}
+
+pub(crate) fn run(command: Command, out: &mut dyn Write) -> Result<(), Error> {
+    match command {
+        Command::Stats(db) => {
+            open(&db)?;
+            print_stats(out)
+        }
+        Command::Config(db) => {
+            open(&db)?;
+            print_config(out)
+        }
+        Command::Compact {
+            src,
+            dst,
+            fill_factor,
+            dry_run,
+        } => compact(&src, &dst, fill_factor, dry_run, out),
+        Command::Diff(a, b) => diff(&a, &b, out),
+        Command::Fixture { version, dst } => write_fixture(version, &dst, out),
+        Command::CheckFixture { version, db } => {
+            open(&db)?;
+            fixture::load(version)?;
+            writeln!(out, "fixture of format version {} verified", version)?;
+            Ok(())
+        }
+        Command::Rekey {
+            db,
+            new_key,
+            keys,
+            files,
+            dry_run,
+        } => rekey(&db, new_key, keys, &files, dry_run, out),
+        Command::Bench {
+            dst,
+            entries,
+            value_size,
+            slot_layout,
+        } => bench(&dst, entries, value_size, slot_layout, out),
+        Command::VerifyBackup { backup, keys } => verify_backup(&backup, &keys, out),
+        Command::Migrate {
+            db,
+            tier,
+            start,
+            end,
+        } => {
+            open(&db)?;
+            migrate(tier, start.as_deref(), end.as_deref(), out)
+        }
+        Command::Repair { db, dry_run } => {
+            open(&db)?;
+            repair(dry_run, out)
+        }
+        Command::Delete { db, dry_run } => {
+            open(&db)?;
+            delete(dry_run, out)
+        }
+    }
+}
+
+// Orphans are looked for after the links were repaired, so that pages only reachable through
+// repaired links aren't freed.
+fn repair(dry_run: bool, out: &mut dyn Write) -> Result<(), Error> {
+    let index = Index::open()?;
+    let links = match dry_run {
+        true => index.check_links()?,
+        false => index.repair_links()?,
+    };
+    let report = fsck::check(!dry_run)?;
+    io::commit();
+    let fix = if dry_run { "would fix" } else { "fixed" };
+    let free = match (dry_run, report.reclaimed) {
+        (true, _) => "would free",
+        (false, true) => "freed",
+        // orphans aren't reclaimed into a damaged free list.
+        (false, false) => "left",
+    };
+    writeln!(
+        out,
+        "{} {} parent pointers and {} sibling links",
+        fix,
+        links.parents.len(),
+        links.siblings.len()
+    )?;
+    let bytes = report.orphans.len() * PAGE_SIZE_USIZE;
+    writeln!(out, "{} {} orphan pages ({} bytes)", free, report.orphans.len(), bytes)?;
+    if !report.free_list_is_sound() {
+        writeln!(
+            out,
+            "free list damaged: {} pages in use, {} looping shards, {} unallocated pages",
+            report.free_but_used.len(),
+            report.looping_free_lists.len(),
+            report.unallocated.len()
+        )?;
+    }
+    Ok(())
+}
+
+fn delete(dry_run: bool, out: &mut dyn Write) -> Result<(), Error> {
+    let (mut files, mut bytes) = (0, 0);
+    for path in io::database_files() {
+        let Some(size) = disk_usage(&path)? else {
+            continue;
+        };
+        writeln!(out, "{} ({} bytes)", path.display(), size)?;
+        files += 1;
+        bytes += size;
+    }
+    if !dry_run {
+        io::delete_index();
+    }
+    let verb = if dry_run { "would delete" } else { "deleted" };
+    writeln!(out, "{} {} files ({} bytes)", verb, files, bytes)?;
+    Ok(())
+}
+
+// The bytes of the file, or of the files in the directory, None if there is none.
+fn disk_usage(path: &Path) -> Result<Option<u64>, Error> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_dir() {
+        return Ok(Some(metadata.len()));
+    }
+    let mut bytes = 0;
+    for entry in fs::read_dir(path)? {
+        bytes += entry?.metadata()?.len();
+    }
+    Ok(Some(bytes))
+}
+
+// Cold pages are stored in the cold file at its default path in the database directory.
+fn migrate(
+    tier: Tier,
+    start: Option<&str>,
+    end: Option<&str>,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let page_ids = match (start, end) {
+        (None, None) => treefile::page_ids()?,
+        _ => {
+            let start = start.map_or(Bound::Unbounded, |key| Bound::Included(Key::from(key)));
+            let end = end.map_or(Bound::Unbounded, |key| Bound::Excluded(Key::from(key)));
+            Index::open()?.range_pages((start, end))?
+        }
+    };
+    let moved = tier::migrate(&page_ids, tier)?;
+    let name = match tier {
+        Tier::Hot => "hot",
+        Tier::Cold => "cold",
+    };
+    writeln!(out, "migrated {} of {} pages to the {} tier", moved, page_ids.len(), name)?;
+    Ok(())
+}
+
+// The snapshot is restored into a temporary database, which is removed afterwards. There is no
+// write-ahead log, snapshots are complete copies of the database. Orphan pages are reported but
+// don't make a snapshot unrestorable, fsck reclaims them.
+fn verify_backup(
+    backup: &Path,
+    keys: &[(u32, [u8; KEY_SIZE])],
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let backup = path::absolute(backup)?;
+    if let Some(((id, key), old_keys)) = keys.split_first() {
+        let provider = old_keys
+            .iter()
+            .fold(StaticKeys::new(*id, *key), |provider, (id, key)| provider.rotate(*id, *key));
+        crypt::set_key_provider(Some(Arc::new(provider)));
+    }
+    let (config, pages) = snapshot::read(&backup)?;
+    let malformed = snapshot::malformed_pages(&pages);
+    writeln!(out, "read {} pages, {} malformed", pages.len(), malformed.len())?;
+    if !malformed.is_empty() {
+        writeln!(out, "backup is not restorable, malformed pages: {:?}", malformed)?;
+        return Err(Error::MalformedPayload);
+    }
+
+    let working_dir = std::env::current_dir()?;
+    let temp_dir = std::env::temp_dir().join(format!("teleport-verify-{}", std::process::id()));
+    fs::create_dir_all(&temp_dir)?;
+    std::env::set_current_dir(&temp_dir)?;
+    let restored = restore_and_check(&config, &pages);
+    io::close();
+    std::env::set_current_dir(working_dir)?;
+    fs::remove_dir_all(&temp_dir)?;
+    match restored {
+        Ok((entries, orphans)) => {
+            writeln!(out, "restored {} entries, {} orphan pages", entries, orphans)?;
+            writeln!(out, "backup is restorable")?;
+            Ok(())
+        }
+        Err(e) => {
+            writeln!(out, "backup is not restorable: {:?}", e)?;
+            Err(e)
+        }
+    }
+}
+
+/// Installs the snapshot into the empty database in the working directory, runs fsck and reads
+/// all entries. Returns the number of entries and of orphan pages.
+fn restore_and_check(
+    config: &[u8],
+    pages: &[Page],
+) -> Result<(usize, usize), Error> {
+    snapshot::install(config, pages)?;
+    let report = fsck::check(false)?;
+    let mut entries = 0;
+    for entry in Index::open()?.scan(..)? {
+        entry?;
+        entries += 1;
+    }
+    Ok((entries, report.orphans.len()))
+}
+
+/// BenchReport holds the figures of a bench run. Amplifications are relative to the bytes of the
+/// keys and values: the live ones for the space, the ones passed in by the writes for the writes.
+#[derive(Debug)]
+struct BenchReport {
+    operations: usize,
+    elapsed: Duration,
+    // the lookups are timed apart from the writes, the layout of the pages favors one or the other.
+    lookups: usize,
+    lookup_elapsed: Duration,
+    // made by the lookups of live entries, which are expected to make none, see `Index::get_with`.
+    lookup_allocations: usize,
+    live_entries: u64,
+    live_bytes: u64,
+    file_bytes: u64,
+    user_bytes: u64,
+    written_bytes: u64,
+    // of the data and inner pages which aren't free.
+    fill_factor: f64,
+    free_pages: usize,
+}
+
+impl BenchReport {
+    fn space_amplification(&self) -> f64 {
+        ratio(self.file_bytes, self.live_bytes)
+    }
+
+    fn write_amplification(&self) -> f64 {
+        ratio(self.written_bytes, self.user_bytes)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        return 0.0;
+    }
+    a as f64 / b as f64
+}
+
+fn bench(
+    dst: &Path,
+    entries: usize,
+    value_size: usize,
+    slot_layout: SlotLayout,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    if dst.join("index.000").exists() {
+        return Err(Error::IndexNotEmpty);
+    }
+    fs::create_dir_all(dst)?;
+    std::env::set_current_dir(dst)?;
+    update_slot_layout(slot_layout);
+    let report = run_bench(entries, value_size)?;
+    let throughput = |operations: usize, elapsed: Duration| {
+        let seconds = elapsed.as_secs_f64();
+        (seconds, operations as f64 / seconds.max(f64::EPSILON))
+    };
+    let (seconds, ops) = throughput(report.operations, report.elapsed);
+    writeln!(out, "{} operations in {:.2}s ({:.0} ops/s)", report.operations, seconds, ops)?;
+    let (seconds, ops) = throughput(report.lookups, report.lookup_elapsed);
+    writeln!(out, "{} lookups in {:.2}s ({:.0} ops/s)", report.lookups, seconds, ops)?;
+    writeln!(out, "allocations by lookups of live entries: {}", report.lookup_allocations)?;
+    writeln!(out, "live entries: {} ({} bytes)", report.live_entries, report.live_bytes)?;
+    writeln!(
+        out,
+        "file size: {} bytes (space amplification {:.2})",
+        report.file_bytes,
+        report.space_amplification()
+    )?;
+    writeln!(
+        out,
+        "bytes written: {} for {} user bytes (write amplification {:.2})",
+        report.written_bytes,
+        report.user_bytes,
+        report.write_amplification()
+    )?;
+    writeln!(out, "fill factor: {:.2}", report.fill_factor)?;
+    writeln!(out, "free pages: {}", report.free_pages)?;
+    Ok(())
+}
+
+/// Runs the bench workload against the empty database in the working directory: inserts the
+/// entries in random order, updates every other one and deletes every fourth one, so that pages
+/// are split, rewritten and freed, then looks every entry up, counting the heap allocations of the
+/// lookups which find their entry.
+fn run_bench(entries: usize, value_size: usize) -> Result<BenchReport, Error> {
+    let key = |i: usize| format!("bench/{:016x}", hash(&i.to_le_bytes()));
+    let value = |i: usize, round: usize| {
+        let byte = char::from(b'a' + ((i + round) % 26) as u8);
+        Payload::from_str(byte.to_string().repeat(value_size))
+    };
+    let before = stats::snapshot();
+    let start = Instant::now();
+    let mut index = Index::open()?;
+    for i in 0..entries {
+        index.insert(Key::from(key(i).as_str()), value(i, 0))?;
+    }
+    for i in (0..entries).step_by(2) {
+        index.insert(Key::from(key(i).as_str()), value(i, 1))?;
+    }
+    for i in (0..entries).step_by(4) {
+        index.delete(Key::from(key(i).as_str()))?;
+    }
+    io::commit();
+    let elapsed = start.elapsed();
+    let after = stats::snapshot();
+    let start = Instant::now();
+    let mut lookup_allocations = 0;
+    for i in 0..entries {
+        let key = key(i);
+        let lookup = || index.get_with(Key::from(key.as_str()), |_| ());
+        let (found, allocations) = allocs::count(lookup);
+        if found?.is_some() {
+            lookup_allocations += allocations;
+        }
+    }
+    let lookup_elapsed = start.elapsed();
+
+    let tree = treestats::stats()?;
+    let free_pages = freelist::pages()?.1;
+    let (pages, used) = page_usage(&free_pages)?
+        .into_iter()
+        .filter(|(type_name, _)| *type_name != "free list" && *type_name != "hash directory")
+        .fold((0, 0), |(pages, used), (_, (count, bytes))| (pages + count, used + bytes));
+    let written = |stats: &Stats| stats.insert.bytes_written + stats.delete.bytes_written;
+    let user = |stats: &Stats| stats.insert.logical_bytes + stats.delete.logical_bytes;
+    Ok(BenchReport {
+        operations: entries + entries.div_ceil(2) + entries.div_ceil(4),
+        elapsed,
+        lookups: entries,
+        lookup_elapsed,
+        lookup_allocations,
+        live_entries: tree.entries,
+        live_bytes: tree.key_bytes + tree.value_bytes,
+        file_bytes: fs::metadata("index.000")?.len(),
+        user_bytes: user(&after) - user(&before),
+        written_bytes: written(&after) - written(&before),
+        fill_factor: ratio(used as u64, (pages * PAGE_SIZE_USIZE) as u64),
+        free_pages: free_pages.len(),
+    })
+}
+
+// Files sealed with the new key only are skipped, so an interrupted rekey is resumed by running it
+// again.
+fn rekey(
+    db: &Path,
+    new_key: (u32, [u8; KEY_SIZE]),
+    keys: Vec<(u32, [u8; KEY_SIZE])>,
+    files: &[PathBuf],
+    dry_run: bool,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let files = files
+        .iter()
+        .map(path::absolute)
+        .collect::<Result<Vec<_>, _>>()?;
+    open(db)?;
+    if dry_run {
+        return plan_rekey(new_key.0, files, out);
+    }
+    let provider = keys
+        .into_iter()
+        .fold(StaticKeys::new(new_key.0, new_key.1), |provider, (id, key)| {
+            provider.rotate(id, key)
+        })
+        .rotate(new_key.0, new_key.1);
+    crypt::set_key_provider(Some(Arc::new(provider)));
+    let prepared = txn::prepared_ids()?.into_iter().map(txn::prepared_path);
+    let paths: Vec<PathBuf> = prepared.chain(files).collect();
+    let (mut resealed, mut skipped) = (0, 0);
+    for path in &paths {
+        if crypt::reseal(path)? {
+            resealed += 1;
+        } else {
+            skipped += 1;
+        }
+        events::progress(BulkOperation::Rekey, resealed + skipped, Some(paths.len()));
+    }
+    writeln!(
+        out,
+        "{} files sealed with key {}, {} already were",
+        resealed, new_key.0, skipped
+    )?;
+    Ok(())
+}
+
+// Lists the files a rekey would seal, the ones not sealed with the new key only. No key is needed
+// to tell, only the segment headers are read.
+fn plan_rekey(
+    new_key_id: u32,
+    files: Vec<PathBuf>,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let prepared = txn::prepared_ids()?.into_iter().map(txn::prepared_path);
+    let sealed = BTreeSet::from([new_key_id]);
+    let (mut resealed, mut skipped) = (0, 0);
+    for path in prepared.chain(files) {
+        if crypt::key_ids(&path)?.is_some_and(|key_ids| key_ids == sealed) {
+            skipped += 1;
+        } else {
+            writeln!(out, "would seal {}", path.display())?;
+            resealed += 1;
+        }
+    }
+    writeln!(
+        out,
+        "{} files would be sealed with key {}, {} already are",
+        resealed, new_key_id, skipped
+    )?;
+    Ok(())
+}
+
+// The fixture is read back from the files, so that the written database is the one verified.
+fn write_fixture(version: u32, dst: &Path, out: &mut dyn Write) -> Result<(), Error> {
+    if dst.join("index.000").exists() {
+        return Err(Error::IndexNotEmpty);
+    }
+    fs::create_dir_all(dst)?;
+    std::env::set_current_dir(dst)?;
+    fixture::create(version)?;
+    io::close();
+    fixture::load(version)?;
+    writeln!(out, "wrote the fixture of format version {}", version)?;
+    Ok(())
+}
+
+// Values are compared by their digests, so that only the keys of the first database are held in
+// memory while the second one is streamed.
+fn diff(a: &Path, b: &Path, out: &mut dyn Write) -> Result<(), Error> {
+    let (a, b) = (path::absolute(a)?, path::absolute(b)?);
+    open(&a)?;
+    let digests = Index::open()?
+        .scan(..)?
+        .map(|entry| entry.map(|(key, payload)| (key, digest(&payload))))
+        .collect::<Result<Vec<_>, _>>()?;
+    io::close();
+    open(&b)?;
+    let entries = Index::open()?
+        .scan(..)?
+        .map(|entry| entry.map(|(key, payload)| (key, digest(&payload))));
+    let (only_a, only_b, differing) = write_diff(digests.into_iter().map(Ok), entries, out)?;
+    writeln!(
+        out,
+        "{} only in a, {} only in b, {} differing",
+        only_a, only_b, differing
+    )?;
+    Ok(())
+}
+
+fn digest(payload: &Payload) -> u64 {
+    hash(payload.to_bytes()) ^ payload.payload_type as u64
+}
+
+/// Merges two streams of (key, value digest) pairs in key order, and writes the keys only in the
+/// first stream, only in the second one, and the ones with differing values. Returns their counts.
+fn write_diff(
+    mut a: impl Iterator<Item = Result<(Vec<u8>, u64), Error>>,
+    mut b: impl Iterator<Item = Result<(Vec<u8>, u64), Error>>,
+    out: &mut dyn Write,
+) -> Result<(usize, usize, usize), Error> {
+    let (mut next_a, mut next_b) = (a.next().transpose()?, b.next().transpose()?);
+    let mut counts = (0, 0, 0);
+    loop {
+        let order = match (&next_a, &next_b) {
+            (None, None) => return Ok(counts),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((key_a, _)), Some((key_b, _))) => key_a.cmp(key_b),
+        };
+        match order {
+            Ordering::Less => {
+                let (key, _) = next_a.take().unwrap();
+                writeln!(out, "- {}", display_key(&key))?;
+                counts.0 += 1;
+                next_a = a.next().transpose()?;
+            }
+            Ordering::Greater => {
+                let (key, _) = next_b.take().unwrap();
+                writeln!(out, "+ {}", display_key(&key))?;
+                counts.1 += 1;
+                next_b = b.next().transpose()?;
+            }
+            Ordering::Equal => {
+                let (key, digest_a) = next_a.take().unwrap();
+                let (_, digest_b) = next_b.take().unwrap();
+                if digest_a != digest_b {
+                    writeln!(out, "~ {}", display_key(&key))?;
+                    counts.2 += 1;
+                }
+                next_a = a.next().transpose()?;
+                next_b = b.next().transpose()?;
+            }
+        }
+    }
+}
+
+// Keys which aren't printable text are written in hex.
+fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.chars().any(char::is_control) => key.to_string(),
+        _ => key.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    }
+}
+
+// Only one database can be open at a time, so the entries of the source are read into memory
+// before the destination is opened. The source files are only read.
+fn compact(
+    src: &Path,
+    dst: &Path,
+    fill_factor: f64,
+    dry_run: bool,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let (src, dst) = (path::absolute(src)?, path::absolute(dst)?);
+    if dst.join("index.000").exists() {
+        return Err(Error::IndexNotEmpty);
+    }
+    open(&src)?;
+    let src_pages = get_next_page_id().get();
+    let entries = Index::open()?.scan(..)?.collect::<Result<Vec<_>, _>>()?;
+    io::close();
+    // a dry run compacts into a temporary database, so that the pages it takes are the ones the
+    // copy would take.
+    let target = match dry_run {
+        true => std::env::temp_dir().join(format!("teleport-compact-{}", std::process::id())),
+        false => dst,
+    };
+    fs::create_dir_all(&target)?;
+    std::env::set_current_dir(&target)?;
+    let loaded = load_and_verify(&entries, fill_factor);
+    let pages = get_next_page_id().get();
+    if dry_run {
+        io::close();
+        std::env::set_current_dir(&src)?;
+        fs::remove_dir_all(&target)?;
+    }
+    loaded?;
+    let verb = if dry_run { "would compact" } else { "compacted" };
+    writeln!(
+        out,
+        "{} {} entries from {} pages into {} pages ({} bytes reclaimed)",
+        verb,
+        entries.len(),
+        src_pages,
+        pages,
+        src_pages.saturating_sub(pages) * PAGE_SIZE_USIZE
+    )?;
+    Ok(())
+}
+
+/// Bulk loads the entries into the empty database in the working directory, and reads them back
+/// to verify the copy.
+fn load_and_verify(
+    entries: &[(Vec<u8>, Payload)],
+    fill_factor: f64,
+) -> Result<(), Error> {
+    let mut index = Index::open()?;
+    index.bulk_load(entries.iter().cloned(), fill_factor)?;
+    let mut copied = 0;
+    for (entry, (key, payload)) in index.scan(..)?.zip(entries) {
+        let (copied_key, copied_payload) = entry?;
+        if copied_key != *key
+            || copied_payload.payload_type != payload.payload_type
+            || copied_payload.to_bytes() != payload.to_bytes()
+        {
+            return Err(Error::MalformedPayload);
+        }
+        copied += 1;
+    }
+    if copied != entries.len() || index.scan(..)?.count() != entries.len() {
+        return Err(Error::MalformedPayload);
+    }
+    Ok(())
+}
+
+// The database files are opened relative to the working directory. Missing files aren't created,
+// so that a mistyped path is reported rather than turned into an empty database.
+fn open(db: &Path) -> Result<(), Error> {
+    if !db.join("index.000").is_file() {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+    }
+    std::env::set_current_dir(db)?;
+    Ok(())
+}
+
+/// Prints the config, the pages of each type with their average fill factor, and the length of
+/// the free list.
+pub(crate) fn print_stats(out: &mut dyn Write) -> Result<(), Error> {
+    writeln!(out, "next page id: {}", get_next_page_id())?;
+    writeln!(out, "root: {}", get_root_page_id())?;
+    writeln!(out, "dictionary: {}", get_dictionary_page_id())?;
+    writeln!(out, "hash directory: {}", get_hash_directory_page_id())?;
+    writeln!(out, "sequences: {}", get_sequence_page_id())?;
+    writeln!(out, "shards: {}", get_shard_catalog_page_id())?;
+    let heads: Vec<String> = (0..FREE_LIST_SHARDS)
+        .map(|shard| get_free_list_page_id(shard).to_string())
+        .collect();
+    writeln!(out, "free list: {}", heads.join(", "))?;
+    writeln!(out, "free space map: {}", get_free_space_map_page_id())?;
+
+    for (type_name, (count, used)) in &page_usage(&[])? {
+        let fill_factor = *used as f64 / (*count * PAGE_SIZE_USIZE) as f64;
+        writeln!(out, "{} pages: {} (fill factor {:.2})", type_name, count, fill_factor)?;
+    }
+    let free_pages = freelist::pages()?.1.len();
+    writeln!(out, "free pages: {}", free_pages)?;
+    writeln!(out, "cold pages: {}", tier::cold_pages())?;
+    Ok(())
+}
+
+/// Returns the number of pages of each type and the bytes they use, leaving out the skipped ones.
+fn page_usage(
+    skip: &[Offset],
+) -> Result<BTreeMap<&'static str, (usize, usize)>, Error> {
+    let skip: HashSet<&Offset> = skip.iter().collect();
+    let mut pages: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for page_id in Offset(1).through(get_next_page_id()) {
+        if skip.contains(&page_id) {
+            continue;
+        }
+        let page = io::read_verified(page_id)?;
+        let page = *latch::lock(page_id, &page);
+        let used = PAGE_SIZE_USIZE.saturating_sub(page.free_size()?.get());
+        let entry = pages.entry(page.type_name()).or_default();
+        entry.0 += 1;
+        entry.1 += used;
+    }
+    Ok(pages)
+}
+
+/// Prints the options fixed when the database was created.
+pub(crate) fn print_config(out: &mut dyn Write) -> Result<(), Error> {
+    let layout = match KeyLayout::try_from(get_key_layout())? {
+        KeyLayout::Variable => "variable",
+        KeyLayout::U64 => "u64",
+    };
+    writeln!(out, "page size: {}", PAGE_SIZE_USIZE)?;
+    writeln!(out, "key layout: {}", layout)?;
+    let slot_table = match get_slot_layout() {
+        SlotLayout::TableAtStart => "start",
+        SlotLayout::TableAtEnd => "end",
+    };
+    writeln!(out, "slot table: at the {} of the pages", slot_table)?;
+    let version = file_version()?.unwrap_or(FORMAT_VERSION);
+    writeln!(out, "format version: {}", version)?;
+    writeln!(out, "engine version: {}", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
+
+#[test]
+fn verify_exit_codes_follow_the_error_class() {
+    let corrupt = Error::ChecksumMismatch { page_id: Offset(7), lsn: 42 };
+    assert_eq!(exit_code(&corrupt), 3);
+    assert!(describe(&corrupt).ends_with("page 7 is corrupt as of log index 42"));
+    assert_eq!(exit_code(&Error::UnsupportedFormatVersion { found: 16, supported: 15 }), 4);
+    assert_eq!(exit_code(&Error::Locked), 75);
+    assert_eq!(exit_code(&Error::KeyLayoutMismatch), 1);
+    assert_eq!(describe(&Error::Locked), "Locked");
+}
+
+#[test]
+fn verify_parse() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(parse(&args(&["stats", "mydb"])), Some(Command::Stats(PathBuf::from("mydb"))));
+    assert_eq!(parse(&args(&["config", "mydb"])), Some(Command::Config(PathBuf::from("mydb"))));
+    assert_eq!(parse(&args(&["stats"])), None);
+    assert_eq!(parse(&args(&["vacuum", "mydb"])), None);
+    assert_eq!(
+        parse(&args(&["compact", "a", "b"])),
+        Some(Command::Compact {
+            src: PathBuf::from("a"),
+            dst: PathBuf::from("b"),
+            fill_factor: DEFAULT_FILL_FACTOR,
+            dry_run: false,
+        })
+    );
+    assert!(matches!(
+        parse(&args(&["compact", "a", "b", "0.5", "--dry-run"])),
+        Some(Command::Compact { fill_factor: 0.5, dry_run: true, .. })
+    ));
+    assert!(matches!(
+        parse(&args(&["compact", "a", "b", "0.5"])),
+        Some(Command::Compact { fill_factor: 0.5, .. })
+    ));
+    assert_eq!(parse(&args(&["compact", "a", "b", "1.5"])), None);
+    assert_eq!(
+        parse(&args(&["fixture", "3", "a"])),
+        Some(Command::Fixture {
+            version: 3,
+            dst: PathBuf::from("a"),
+        })
+    );
+    assert_eq!(parse(&args(&["check-fixture", "v3", "a"])), None);
+    let key = format!("7:{}", "0f".repeat(KEY_SIZE));
+    let old_key = format!("3:{}", "a0".repeat(KEY_SIZE));
+    assert_eq!(
+        parse(&args(&["rekey", "a", "--new-key", &key, "--key", &old_key, "snapshot"])),
+        Some(Command::Rekey {
+            db: PathBuf::from("a"),
+            new_key: (7, [0x0f; KEY_SIZE]),
+            keys: vec![(3, [0xa0; KEY_SIZE])],
+            files: vec![PathBuf::from("snapshot")],
+            dry_run: false,
+        })
+    );
+    assert!(matches!(
+        parse(&args(&["rekey", "a", "--new-key", &key, "--dry-run"])),
+        Some(Command::Rekey { dry_run: true, .. })
+    ));
+    assert_eq!(parse(&args(&["rekey", "a", "--key", &old_key])), None);
+    assert_eq!(parse(&args(&["rekey", "a", "--new-key", "7:0f"])), None);
+    assert_eq!(parse(&args(&["rekey", "a", "--new-key", &key, "--force"])), None);
+    assert_eq!(
+        parse(&args(&["bench", "a", "1000"])),
+        Some(Command::Bench {
+            dst: PathBuf::from("a"),
+            entries: 1000,
+            value_size: DEFAULT_BENCH_VALUE_SIZE,
+            slot_layout: SlotLayout::TableAtStart,
+        })
+    );
+    assert_eq!(
+        parse(&args(&["bench", "a", "1000", "50", "--slot-table-at-end"])),
+        Some(Command::Bench {
+            dst: PathBuf::from("a"),
+            entries: 1000,
+            value_size: 50,
+            slot_layout: SlotLayout::TableAtEnd,
+        })
+    );
+    assert_eq!(parse(&args(&["bench", "a", "0"])), None);
+    assert_eq!(parse(&args(&["bench", "a", "1000", "50", "10"])), None);
+    assert_eq!(
+        parse(&args(&["verify-backup", "snapshot", "--key", &old_key])),
+        Some(Command::VerifyBackup {
+            backup: PathBuf::from("snapshot"),
+            keys: vec![(3, [0xa0; KEY_SIZE])],
+        })
+    );
+    assert_eq!(parse(&args(&["verify-backup", "snapshot", "--key"])), None);
+    assert_eq!(
+        parse(&args(&["migrate", "a", "cold", "k100"])),
+        Some(Command::Migrate {
+            db: PathBuf::from("a"),
+            tier: Tier::Cold,
+            start: Some("k100".to_string()),
+            end: None,
+        })
+    );
+    assert_eq!(parse(&args(&["migrate", "a", "warm"])), None);
+    assert_eq!(
+        parse(&args(&["repair", "a", "--dry-run"])),
+        Some(Command::Repair {
+            db: PathBuf::from("a"),
+            dry_run: true,
+        })
+    );
+    assert_eq!(
+        parse(&args(&["delete", "a"])),
+        Some(Command::Delete {
+            db: PathBuf::from("a"),
+            dry_run: false,
+        })
+    );
+    assert_eq!(parse(&args(&["delete", "a", "--force"])), None);
+}
+
+#[test]
+#[serial]
+fn verify_stats_report() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..50u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let mut out = Vec::new();
+    print_stats(&mut out).unwrap();
+    print_config(&mut out).unwrap();
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.contains(&format!("root: {}\n", index.root())));
+    assert!(report.contains("data pages: "));
+    assert!(report.contains("inner pages: "));
+    assert!(report.contains("free pages: 0\n"));
+    assert!(report.contains(&format!("page size: {}\n", PAGE_SIZE_USIZE)));
+    assert!(report.contains("key layout: variable\n"));
+    assert!(report.contains("format version: "));
+}
+
+#[test]
+#[serial]
+fn verify_load_and_verify() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in (0..100u32).rev() {
+        let payload = Payload::from_str("x".repeat(i as usize * 100));
+        index.insert(Key::from(format!("{:03}", i).as_str()), payload).unwrap();
+    }
+    let entries = index.scan(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    let pages = get_next_page_id().get();
+    delete_index();
+    load_and_verify(&entries, 1.0).unwrap();
+    assert!(get_next_page_id().get() < pages);
+}
+
+#[test]
+fn verify_write_diff() {
+    let a = vec![(b"a".to_vec(), 1), (b"b".to_vec(), 2), (b"d".to_vec(), 4)];
+    let b = vec![(b"b".to_vec(), 3), (b"c".to_vec(), 3), (b"d".to_vec(), 4), (vec![0, 255], 5)];
+    let mut out = Vec::new();
+    let counts = write_diff(a.into_iter().map(Ok), b.into_iter().map(Ok), &mut out).unwrap();
+    assert_eq!(counts, (1, 2, 1));
+    assert_eq!(String::from_utf8(out).unwrap(), "- a\n~ b\n+ c\n+ 00ff\n");
+}
+
+#[test]
+#[serial]
+fn verify_bench_report() {
+    delete_index();
+    let report = run_bench(2000, 50).unwrap();
+    assert_eq!(report.operations, 3500);
+    assert_eq!(report.lookups, 2000);
+    assert_eq!(report.lookup_allocations, 0);
+    assert_eq!(report.live_entries, 1500);
+    assert_eq!(report.live_bytes, 1500 * (22 + 50));
+    assert!(report.space_amplification() > 1.0);
+    assert!(report.write_amplification() > 1.0);
+    assert!(report.fill_factor > 0.0 && report.fill_factor <= 1.0);
+}
+
+#[test]
+#[serial]
+fn verify_backups_are_restored_and_checked() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..100u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let path = path::absolute("backup.test").unwrap();
+    snapshot::write(&path).unwrap();
+    let working_dir = std::env::current_dir().unwrap();
+    let mut out = Vec::new();
+    verify_backup(&path, &[], &mut out).unwrap();
+    assert_eq!(std::env::current_dir().unwrap(), working_dir);
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.contains("restored 100 entries, 0 orphan pages\n"));
+    assert!(report.ends_with("backup is restorable\n"));
+
+    // a page stored at another id makes the backup unrestorable.
+    let (_, mut pages) = snapshot::read(&path).unwrap();
+    assert!(snapshot::malformed_pages(&pages).is_empty());
+    let root = index.root().get();
+    pages[root] = pages[if root == 1 { 2 } else { 1 }];
+    assert_eq!(snapshot::malformed_pages(&pages), vec![index.root()]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_dry_runs_leave_the_database_alone() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..100u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    io::commit();
+    let mut out = Vec::new();
+    repair(true, &mut out).unwrap();
+    delete(true, &mut out).unwrap();
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.contains("would fix 0 parent pointers and 0 sibling links\n"));
+    assert!(report.contains("would free 0 orphan pages (0 bytes)\n"));
+    assert!(report.contains("index.000 ("));
+    assert!(report.contains("would delete "));
+    assert!(Path::new("index.000").exists());
+    assert_eq!(Index::open().unwrap().scan(..).unwrap().count(), 100);
+}
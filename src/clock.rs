@@ -0,0 +1,76 @@
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Clock is the time source of visibility timeouts and background throttling. Times are durations
+/// since the Unix epoch, or since any fixed origin on systems without a wall clock.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+
+    /// Blocks the calling thread until the clock advanced by the duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// SystemClock reads the wall clock and sleeps for real, it's the default.
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// VirtualClock only moves when it's advanced, so that tests and simulations fast-forward time
+/// instead of waiting for it. Sleeping advances the clock by the duration and returns at once.
+pub(crate) struct VirtualClock {
+    now: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    #[allow(dead_code)]
+    pub(crate) fn new(now: Duration) -> Self {
+        VirtualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+static CLOCK: Lazy<RwLock<Arc<dyn Clock>>> = Lazy::new(|| RwLock::new(Arc::new(SystemClock)));
+
+/// Replaces the clock of the process, e.g. with a `VirtualClock` for deterministic tests.
+pub(crate) fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap_or_else(|e| e.into_inner()) = clock;
+}
+
+pub(crate) fn clock() -> Arc<dyn Clock> {
+    CLOCK.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+pub(crate) fn now() -> Duration {
+    clock().now()
+}
+
+pub(crate) fn sleep(duration: Duration) {
+    clock().sleep(duration);
+}
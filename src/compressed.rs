@@ -0,0 +1,159 @@
+#[cfg(test)]
+use crate::btree::Index;
+#[cfg(test)]
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, PAGE_SIZE_USIZE};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// CompressedTier is the second tier of the page cache. Pages evicted from the page cache are kept
+/// here compressed, so that a budget of bytes holds several times the pages the page cache holds,
+/// at the cost of decompressing a page when it's read again. The page cache keeps the hot pages
+/// uncompressed. A page read from this tier moves back into the page cache, pages are evicted from
+/// this tier in the order they were admitted.
+struct CompressedTier {
+    pages: HashMap<Offset, (Vec<u8>, u64)>,
+    admitted: BTreeMap<u64, Offset>,
+    tick: u64,
+    bytes: usize,
+    // zero disables the tier.
+    capacity: usize,
+}
+
+static TIER: Lazy<Mutex<CompressedTier>> = Lazy::new(|| {
+    Mutex::new(CompressedTier {
+        pages: HashMap::new(),
+        admitted: BTreeMap::new(),
+        tick: 0,
+        bytes: 0,
+        capacity: 0,
+    })
+});
+
+impl CompressedTier {
+    fn remove(&mut self, page_id: Offset) -> Option<Vec<u8>> {
+        let (compressed, tick) = self.pages.remove(&page_id)?;
+        self.admitted.remove(&tick);
+        self.bytes -= compressed.len();
+        Some(compressed)
+    }
+
+    fn evict(&mut self, capacity: usize) {
+        while self.bytes > capacity {
+            let Some((_, page_id)) = self.admitted.pop_first() else {
+                break;
+            };
+            if let Some((compressed, _)) = self.pages.remove(&page_id) {
+                self.bytes -= compressed.len();
+            }
+        }
+    }
+}
+
+/// Sets the bytes of compressed pages kept in memory besides the page cache, zero disables the
+/// tier. Its budget is independent of the page cache capacity, which is counted in pages.
+pub(crate) fn set_capacity(bytes: usize) {
+    let mut tier = tier();
+    tier.capacity = bytes;
+    tier.evict(bytes);
+}
+
+pub(crate) fn enabled() -> bool {
+    tier().capacity > 0
+}
+
+/// Compresses the page evicted from the page cache into the tier.
+pub(crate) fn admit(page: &Page) {
+    let mut tier = tier();
+    if tier.capacity == 0 {
+        return;
+    }
+    let Ok(compressed) = snap::raw::Encoder::new().compress_vec(page.buffer()) else {
+        return;
+    };
+    let capacity = tier.capacity;
+    if compressed.len() > capacity {
+        return;
+    }
+    tier.remove(page.page_id());
+    tier.evict(capacity - compressed.len());
+    tier.tick += 1;
+    let tick = tier.tick;
+    tier.bytes += compressed.len();
+    tier.admitted.insert(tick, page.page_id());
+    tier.pages.insert(page.page_id(), (compressed, tick));
+}
+
+/// Takes the page out of the tier, None if it isn't held.
+pub(crate) fn take(page_id: Offset) -> Option<Page> {
+    let compressed = tier().remove(page_id)?;
+    let mut buffer = [0u8; PAGE_SIZE_USIZE];
+    match snap::raw::Decoder::new().decompress(&compressed, &mut buffer) {
+        Ok(PAGE_SIZE_USIZE) => Some(Page::new_from(buffer)),
+        _ => None,
+    }
+}
+
+/// Drops the page, after it was written or its write rolled back.
+pub(crate) fn remove(page_id: Offset) {
+    tier().remove(page_id);
+}
+
+pub(crate) fn clear() {
+    let mut tier = tier();
+    tier.pages.clear();
+    tier.admitted.clear();
+    tier.bytes = 0;
+}
+
+/// Returns the number of pages in the tier and the bytes they take.
+pub(crate) fn usage() -> (usize, usize) {
+    let tier = tier();
+    (tier.pages.len(), tier.bytes)
+}
+
+fn tier() -> std::sync::MutexGuard<'static, CompressedTier> {
+    TIER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+#[serial]
+fn verify_evicted_pages_are_kept_compressed() {
+    delete_index();
+    io::set_cache_capacity(2);
+    set_capacity(1 << 20);
+    let mut index = Index::open().unwrap();
+    for i in 0..200u32 {
+        let key = format!("compressed/key/{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let (pages, bytes) = usage();
+    assert!(pages > 2);
+    assert!(bytes * 4 < pages * PAGE_SIZE_USIZE);
+
+    // pages read from the tier move back into the page cache.
+    let value = |index: &Index, i: u32| {
+        let key = format!("compressed/key/{:03}", i);
+        index.get(Key::from(key.as_str())).unwrap().map(|payload| payload.to_bytes().to_vec())
+    };
+    for i in 0..200u32 {
+        assert_eq!(value(&index, i), Some(i.to_le_bytes().to_vec()));
+    }
+    index.insert(Key::from("compressed/key/000"), Payload::from_u32(1000)).unwrap();
+    assert_eq!(value(&index, 0), Some(1000u32.to_le_bytes().to_vec()));
+
+    // the budget is kept when it shrinks.
+    set_capacity(bytes / 2);
+    assert!(usage().1 <= bytes / 2);
+    set_capacity(0);
+    assert_eq!(usage(), (0, 0));
+    io::set_cache_capacity(0);
+}
@@ -1,48 +1,452 @@
-use crate::paging::S_PAGE_ID;
+use crate::errors::Error;
+use crate::io::{self, durability_mode, DurabilityMode};
+use crate::paging::{SlotLayout, S_PAGE_ID};
+use crate::sys;
 use crate::types::{FromLeBytes, Offset, ToLeBytes};
+use once_cell::sync::Lazy;
+use std::fs;
 use std::fs::OpenOptions;
+use std::path::Path;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
 
-const CONFIG_FILE: &str = "config";
+pub(crate) const CONFIG_FILE: &str = "config";
 const O_NEXT_PAGE_ID: u64 = 0;
-const TOTAL_CONFIG_SIZE: u64 = O_NEXT_PAGE_ID + size_of::<u64>() as u64;
+const O_ROOT_PAGE_ID: u64 = O_NEXT_PAGE_ID + size_of::<u64>() as u64;
+const O_DICTIONARY_PAGE_ID: u64 = O_ROOT_PAGE_ID + size_of::<u64>() as u64;
+const O_KEY_LAYOUT: u64 = O_DICTIONARY_PAGE_ID + size_of::<u64>() as u64;
+const O_HASH_DIRECTORY_PAGE_ID: u64 = O_KEY_LAYOUT + size_of::<u64>() as u64;
+const O_SEQUENCE_PAGE_ID: u64 = O_HASH_DIRECTORY_PAGE_ID + size_of::<u64>() as u64;
+// config writes since the last commit in shadow paging mode, in the order they were made.
+type ConfigWrite = (u64, Vec<u8>);
 
+static SHADOW_WRITES: Lazy<Mutex<Vec<ConfigWrite>>> = Lazy::new(|| Mutex::new(Vec::new()));
+const O_FREE_LIST_PAGE_ID: u64 = O_SEQUENCE_PAGE_ID + size_of::<u64>() as u64;
+const O_LAST_APPLIED_INDEX: u64 = O_FREE_LIST_PAGE_ID + size_of::<u64>() as u64;
+const O_TREE_STATS_PAGE_ID: u64 = O_LAST_APPLIED_INDEX + size_of::<u64>() as u64;
+// the heads of the free list shards past the first one, one page id after the other.
+const O_FREE_LIST_SHARDS: u64 = O_TREE_STATS_PAGE_ID + size_of::<u64>() as u64;
+const O_ARCHIVED: u64 = O_FREE_LIST_SHARDS + size_of::<u64>() as u64;
+const O_SLOT_LAYOUT: u64 = O_ARCHIVED + size_of::<u64>() as u64;
+const O_SHARD_CATALOG_PAGE_ID: u64 = O_SLOT_LAYOUT + size_of::<u64>() as u64;
+// reserved, files of version 13 on recorded the page size in it, which is fixed by the build.
+const O_PAGE_SIZE: u64 = O_SHARD_CATALOG_PAGE_ID + size_of::<u64>() as u64;
+const O_FREE_SPACE_MAP_PAGE_ID: u64 = O_PAGE_SIZE + size_of::<u64>() as u64;
+const O_PAGE_CHECKSUM_VERSION: u64 = O_FREE_SPACE_MAP_PAGE_ID + size_of::<u64>() as u64;
+const TOTAL_CONFIG_SIZE: u64 = O_PAGE_CHECKSUM_VERSION + size_of::<u64>() as u64;
+
+/// Number of free lists, see `freelist`. The heads of all but the first one share a field.
+pub(crate) const FREE_LIST_SHARDS: usize = 4;
+const _: () = assert!((FREE_LIST_SHARDS - 1) * S_PAGE_ID <= size_of::<u64>());
+
+/// Format version of the files written by this build. Every version appended a field to the
+/// config: 1 the root, 2 the key dictionary, 3 the key layout, 4 the hash directory, 5 the sequence
+/// catalog, 6 the free list, 7 the last applied log index, 8 the tree statistics, 9 the free list
+/// shards, 10 the archive flag, 11 the slot layout, 12 the shard catalog, 13 the page size (unused), 14 the
+/// free space map and 15 the page checksum version. Fields past the end of an older config read as
+/// zero, so older files are upgraded in place, see `upgrade`.
+pub(crate) const FORMAT_VERSION: u32 = 15;
+
+/// Format version of the first build writing pages with a checksum, which grew the page header
+/// and moved everything behind it. The pages of files of older versions are widened when they're
+/// opened, see `upgrade::widen_pages`.
+pub(crate) const CHECKSUM_FORMAT_VERSION: u32 = 13;
+
+/// Returns the id of the last page allocated by extending the file, zero if no page has been
+/// allocated yet. Despite the name, the next page extending the file takes the id following it,
+/// see `PageAllocator`.
 pub(crate) fn get_next_page_id() -> Offset {
     let mut buffer = [0u8; S_PAGE_ID];
     let page_id = read_from_disk(O_NEXT_PAGE_ID, &mut buffer);
-    Offset::from_bytes(page_id.to_vec().try_into().unwrap())
+    Offset::from_bytes(page_id)
 }
 
 pub(crate) fn update_next_page_id(next_page_id: Offset) {
     write_to_disk(O_NEXT_PAGE_ID, &next_page_id.to_bytes())
 }
 
-fn write_to_disk(offset: u64, data: &[u8]) {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(CONFIG_FILE)
-        .unwrap();
-    let _ = file.seek(SeekFrom::Start(offset));
-    let _ = file.write_all(data);
-    let _ = file.sync_all();
+/// Returns the page id of the index root, zero if no index has been created yet.
+pub(crate) fn get_root_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_ROOT_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
 }
 
-fn read_from_disk(offset: u64, buffer: &mut [u8]) -> &[u8] {
+pub(crate) fn update_root_page_id(root_page_id: Offset) {
+    write_to_disk(O_ROOT_PAGE_ID, &root_page_id.to_bytes())
+}
+
+/// Returns the first page of the key dictionary, zero if no key has been interned yet.
+pub(crate) fn get_dictionary_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_DICTIONARY_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_dictionary_page_id(dictionary_page_id: Offset) {
+    write_to_disk(O_DICTIONARY_PAGE_ID, &dictionary_page_id.to_bytes())
+}
+
+/// Returns the key layout the tree was created with, zero for variable-length keys.
+pub(crate) fn get_key_layout() -> u8 {
+    let mut buffer = [0u8; size_of::<u8>()];
+    read_from_disk(O_KEY_LAYOUT, &mut buffer)[0]
+}
+
+pub(crate) fn update_key_layout(key_layout: u8) {
+    write_to_disk(O_KEY_LAYOUT, &[key_layout])
+}
+
+/// Returns the directory page of the hash index, zero if no hash index has been created yet.
+pub(crate) fn get_hash_directory_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_HASH_DIRECTORY_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_hash_directory_page_id(directory_page_id: Offset) {
+    write_to_disk(O_HASH_DIRECTORY_PAGE_ID, &directory_page_id.to_bytes())
+}
+
+/// Returns the first page of the sequence catalog, zero if no sequence has been created yet.
+pub(crate) fn get_sequence_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_SEQUENCE_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_sequence_page_id(sequence_page_id: Offset) {
+    write_to_disk(O_SEQUENCE_PAGE_ID, &sequence_page_id.to_bytes())
+}
+
+/// Returns the head of the free list shard, zero if the shard is empty. Files written before the
+/// free list was sharded hold their free list in the first shard.
+pub(crate) fn get_free_list_page_id(shard: usize) -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(free_list_offset(shard), &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_free_list_page_id(shard: usize, free_list_page_id: Offset) {
+    write_to_disk(free_list_offset(shard), &free_list_page_id.to_bytes())
+}
+
+fn free_list_offset(shard: usize) -> u64 {
+    assert!(shard < FREE_LIST_SHARDS);
+    match shard {
+        0 => O_FREE_LIST_PAGE_ID,
+        _ => O_FREE_LIST_SHARDS + ((shard - 1) * S_PAGE_ID) as u64,
+    }
+}
+
+/// Returns the index of the last log entry applied with `raft::apply_log_entry`, zero if none.
+pub(crate) fn get_last_applied_index() -> u64 {
+    let mut buffer = [0u8; size_of::<u64>()];
+    let index = read_from_disk(O_LAST_APPLIED_INDEX, &mut buffer);
+    u64::from_le_bytes(index.try_into().unwrap())
+}
+
+pub(crate) fn update_last_applied_index(index: u64) {
+    write_to_disk(O_LAST_APPLIED_INDEX, &index.to_le_bytes())
+}
+
+/// Returns the page holding the tree statistics of the last checkpoint, zero if none was written.
+pub(crate) fn get_tree_stats_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_TREE_STATS_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_tree_stats_page_id(stats_page_id: Offset) {
+    write_to_disk(O_TREE_STATS_PAGE_ID, &stats_page_id.to_bytes())
+}
+
+/// Returns true if the index was frozen into an archive, see `archive::freeze`.
+pub(crate) fn get_archived() -> bool {
+    let mut buffer = [0u8; 1];
+    read_from_disk(O_ARCHIVED, &mut buffer)[0] != 0
+}
+
+pub(crate) fn update_archived(archived: bool) {
+    write_to_disk(O_ARCHIVED, &[u8::from(archived)])
+}
+
+/// Returns the slot layout of the pages created from now on, see `SlotLayout`.
+pub(crate) fn get_slot_layout() -> SlotLayout {
+    let mut buffer = [0u8; 1];
+    match read_from_disk(O_SLOT_LAYOUT, &mut buffer)[0] {
+        0 => SlotLayout::TableAtStart,
+        _ => SlotLayout::TableAtEnd,
+    }
+}
+
+pub(crate) fn update_slot_layout(layout: SlotLayout) {
+    write_to_disk(O_SLOT_LAYOUT, &[u8::from(layout == SlotLayout::TableAtEnd)])
+}
+
+/// Returns the first page of the shard catalog, zero if no shard has been created yet.
+pub(crate) fn get_shard_catalog_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_SHARD_CATALOG_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_shard_catalog_page_id(catalog_page_id: Offset) {
+    write_to_disk(O_SHARD_CATALOG_PAGE_ID, &catalog_page_id.to_bytes())
+}
+
+/// Returns the first page of the free space map, zero if it hasn't been written yet.
+pub(crate) fn get_free_space_map_page_id() -> Offset {
+    let mut buffer = [0u8; S_PAGE_ID];
+    let page_id = read_from_disk(O_FREE_SPACE_MAP_PAGE_ID, &mut buffer);
+    Offset::from_bytes(page_id)
+}
+
+pub(crate) fn update_free_space_map_page_id(map_page_id: Offset) {
+    write_to_disk(O_FREE_SPACE_MAP_PAGE_ID, &map_page_id.to_bytes())
+}
+
+/// Returns the version of the checksums of the pages, see `paging::PAGE_CHECKSUM_VERSION`, zero if
+/// it wasn't recorded, e.g. for files of versions before 15, whose pages carry the first version.
+pub(crate) fn get_page_checksum_version() -> u64 {
+    let mut buffer = [0u8; size_of::<u64>()];
+    u64::from_bytes(read_from_disk(O_PAGE_CHECKSUM_VERSION, &mut buffer))
+}
+
+pub(crate) fn update_page_checksum_version(version: u64) {
+    write_to_disk(O_PAGE_CHECKSUM_VERSION, &version.to_le_bytes())
+}
+
+/// Replaces the whole config with a copy taken by `snapshot`.
+pub(crate) fn restore(config: &[u8]) {
+    write_to_disk(0, config)
+}
+
+/// Returns the size of the config of the format version, the next page id followed by one field
+/// per version.
+pub(crate) fn size_of_version(version: u32) -> u64 {
+    (u64::from(version) + 1) * size_of::<u64>() as u64
+}
+
+/// Returns the size of the config file, fields which were never written may be missing.
+pub(crate) fn file_size() -> std::io::Result<u64> {
+    Ok(fs::metadata(CONFIG_FILE)?.len())
+}
+
+/// Returns the format version of the config file, derived from its size as every version appended
+/// a field. Fields are written with the bytes they hold, so a config may end short of a full field.
+/// None if the config is empty, i.e. the database is new. Pending shadow writes count, the file
+/// grows by them on the next commit.
+pub(crate) fn file_version() -> std::io::Result<Option<u32>> {
+    let size = match file_size() {
+        Ok(size) => size,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let shadow_writes = SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+    let size = shadow_writes
+        .iter()
+        .map(|(offset, data)| offset + data.len() as u64)
+        .fold(size, u64::max);
+    let field = size_of::<u64>() as u64;
+    Ok((size > 0).then(|| (size.div_ceil(field) - 1) as u32))
+}
+
+/// Returns the format version of the config file like `file_version`. A config of a newer
+/// version is refused with the version required to open it, the database may hold structures this
+/// build doesn't know of, e.g. pages it would neither reach nor free. Configs cut short are refused
+/// with CorruptConfig.
+pub(crate) fn checked_version() -> Result<Option<u32>, Error> {
+    let Some(version) = file_version()? else {
+        return Ok(None);
+    };
+    let size = file_size()?;
+    if !ends_with_field(size) {
+        return Err(Error::CorruptConfig { size });
+    }
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    Ok(Some(version))
+}
+
+// Fields are written with the bytes they hold, e.g. two for page ids, so configs written by older
+// builds end with the last byte of the field written last, configs written by `upgrade` and
+// `truncate_to_version` with a whole field. A config ending elsewhere was cut short.
+fn ends_with_field(size: u64) -> bool {
+    let (page_id, field) = (S_PAGE_ID as u64, size_of::<u64>() as u64);
+    let fields: [(u64, &[u64]); 16] = [
+        (O_NEXT_PAGE_ID, &[page_id]),
+        (O_ROOT_PAGE_ID, &[page_id]),
+        (O_DICTIONARY_PAGE_ID, &[page_id]),
+        (O_KEY_LAYOUT, &[1]),
+        (O_HASH_DIRECTORY_PAGE_ID, &[page_id]),
+        (O_SEQUENCE_PAGE_ID, &[page_id]),
+        (O_FREE_LIST_PAGE_ID, &[page_id]),
+        (O_LAST_APPLIED_INDEX, &[field]),
+        (O_TREE_STATS_PAGE_ID, &[page_id]),
+        // the head of each shard past the first one.
+        (O_FREE_LIST_SHARDS, &[page_id, 2 * page_id, 3 * page_id]),
+        (O_ARCHIVED, &[1]),
+        (O_SLOT_LAYOUT, &[1]),
+        (O_SHARD_CATALOG_PAGE_ID, &[page_id]),
+        (O_PAGE_SIZE, &[field]),
+        (O_FREE_SPACE_MAP_PAGE_ID, &[page_id]),
+        (O_PAGE_CHECKSUM_VERSION, &[field]),
+    ];
+    size.is_multiple_of(field)
+        || fields
+            .iter()
+            .any(|(offset, widths)| widths.iter().any(|width| offset + width == size))
+}
+
+/// Upgrades a config of an older format version by writing the fields added since as zeros,
+/// which is what they read as before, and returns the version it was upgraded from. Configs are
+/// checked by `checked_version` first.
+///
+/// The fields are written through to the file in shadow paging mode too: zeros don't change what
+/// the config reads as, so the upgrade needn't wait for a commit which may never come.
+pub(crate) fn upgrade() -> Result<Option<u32>, Error> {
+    let Some(version) = checked_version()? else {
+        return Ok(None);
+    };
+    let size = file_size()?;
+    if size >= TOTAL_CONFIG_SIZE {
+        return Ok(None);
+    }
+    let fields = vec![0u8; (TOTAL_CONFIG_SIZE - size) as usize];
+    io::with_retries(|| write_at(size, &fields))?;
+    Ok(Some(version))
+}
+
+/// Cuts the config file down to the fields of the format version, so that files of older versions
+/// can be written. Pending shadow writes must have been committed.
+pub(crate) fn truncate_to_version(version: u32) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).open(CONFIG_FILE)?;
+    file.set_len(size_of_version(version))?;
+    file.sync_all()
+}
+
+/// Returns a copy of the whole config.
+pub(crate) fn snapshot() -> Vec<u8> {
+    let mut buffer = vec![0u8; TOTAL_CONFIG_SIZE as usize];
+    read_from_disk(0, &mut buffer).to_vec()
+}
+
+/// Returns a copy of the whole config, of this format version, with the id of the last page
+/// allocated replaced, to be written along with the pages widened by `upgrade::widen_pages`.
+pub(crate) fn upgraded_image(next_page_id: Offset) -> Vec<u8> {
+    let mut image = snapshot();
+    image[O_NEXT_PAGE_ID as usize..][..S_PAGE_ID].copy_from_slice(&next_page_id.to_bytes());
+    image
+}
+
+/// Writes the config changed since the last commit into a copy of the config file, and renames the
+/// copy over the config file. The changes are kept until the rename went through.
+pub(crate) fn commit_shadow() -> std::io::Result<()> {
+    let mut shadow_writes = SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+    if shadow_writes.is_empty() {
+        return Ok(());
+    }
+    io::injected_error()?;
+    let shadow_file = format!("{}.shadow", CONFIG_FILE);
+    if fs::metadata(CONFIG_FILE).is_ok() {
+        fs::copy(CONFIG_FILE, &shadow_file)?;
+    }
     let mut file = OpenOptions::new()
-        .read(true)
         .write(true)
         .create(true)
-        .open(CONFIG_FILE)
-        .unwrap();
+        .truncate(false)
+        .open(&shadow_file)?;
+    for (offset, data) in shadow_writes.iter() {
+        file.seek(SeekFrom::Start(*offset))?;
+        file.write_all(data)?;
+    }
+    file.sync_all()?;
+    fs::rename(&shadow_file, CONFIG_FILE)?;
+    sys::sync_dir(Path::new("."))?;
+    shadow_writes.clear();
+    Ok(())
+}
+
+/// Returns the whole config with the writes pending in shadow paging mode applied, as large as the
+/// file grows by them, None if there are none. See `io::commit`.
+pub(crate) fn shadow_image() -> Option<Vec<u8>> {
+    let end = SHADOW_WRITES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(offset, data)| offset + data.len() as u64)
+        .max()?;
+    let size = file_size().unwrap_or(0).max(end);
+    let mut image = vec![0u8; size as usize];
+    read_from_disk(0, &mut image);
+    Some(image)
+}
+
+/// Writes the config image in place, see `shadow_image`.
+pub(crate) fn write_image(image: &[u8]) -> std::io::Result<()> {
+    write_at(0, image)
+}
 
-    let file_size = file.metadata().unwrap().len();
-    if file_size == 0 {
-        println!("Config file size mismatch. Setting defaults.");
-        write_to_disk(O_NEXT_PAGE_ID, Offset(0).to_bytes().as_slice());
+/// Returns the bytes held by the config writes pending in shadow paging mode.
+pub(crate) fn shadow_bytes() -> usize {
+    let shadow_writes = SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+    shadow_writes
+        .iter()
+        .map(|(_, data)| size_of::<ConfigWrite>() + data.len())
+        .sum()
+}
+
+pub(crate) fn discard_shadow() {
+    SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+// Config written after the database failed is kept in memory along with the shadow writes.
+fn write_to_disk(offset: u64, data: &[u8]) {
+    if durability_mode() == DurabilityMode::Shadow || io::failure().is_some() {
+        let mut shadow_writes = SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+        shadow_writes.push((offset, data.to_vec()));
+        return;
+    }
+    if let Err(e) = io::with_retries(|| write_at(offset, data)) {
+        io::fail(e);
+        write_to_disk(offset, data);
     }
+}
 
-    file.seek(SeekFrom::Start(offset)).unwrap();
-    file.read(buffer).unwrap();
+fn write_at(offset: u64, data: &[u8]) -> std::io::Result<()> {
+    io::injected_error()?;
+    let mut file = sys::open_or_create(Path::new(CONFIG_FILE))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+fn read_from_disk(offset: u64, buffer: &mut [u8]) -> &[u8] {
+    // fields which can't be read are left zeroed.
+    if let Err(e) = io::with_retries(|| read_at(offset, buffer)) {
+        io::fail(e);
+    }
+    let shadow_writes = SHADOW_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+    for (write_offset, data) in shadow_writes.iter() {
+        let start = offset.max(*write_offset);
+        let end = (offset + buffer.len() as u64).min(write_offset + data.len() as u64);
+        for position in start..end {
+            buffer[(position - offset) as usize] = data[(position - write_offset) as usize];
+        }
+    }
     buffer
 }
+
+fn read_at(offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    io::injected_error()?;
+    // fields past the end of the file read as zero, see `FORMAT_VERSION`.
+    let mut file = sys::open_or_create(Path::new(CONFIG_FILE))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let _ = file.read(buffer)?;
+    Ok(())
+}
@@ -0,0 +1,431 @@
+#[cfg(test)]
+use crate::btree::Index;
+#[cfg(test)]
+use crate::config;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+#[cfg(test)]
+use crate::snapshot;
+use crate::sys;
+#[cfg(test)]
+use crate::txn::{self, Transaction};
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use aes_gcm::aead::{Aead, KeyInit, Payload as AeadPayload};
+use aes_gcm::{Aes256Gcm, Key as AesKey, Nonce};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+pub(crate) const KEY_SIZE: usize = 32;
+const MAGIC: &[u8; 8] = b"TELECRYP";
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// plaintext bytes sealed at a time, so that large archives are streamed.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const S_SEGMENT_HEADER: usize = size_of::<u32>() + size_of::<u8>() + size_of::<u32>() + NONCE_SIZE;
+
+/// KeyProvider hands out the keys files are encrypted with. Every segment is tagged with the id of
+/// its key, so keys which were rotated out have to stay available as long as files sealed with
+/// them are to be read.
+pub(crate) trait KeyProvider: Send + Sync {
+    /// Returns the id of the key new segments are sealed with.
+    fn current_key_id(&self) -> u32;
+
+    /// Returns the key with the id, None if it's unknown.
+    fn key(&self, id: u32) -> Option<[u8; KEY_SIZE]>;
+}
+
+/// StaticKeys holds a fixed set of keys in memory, the last one added is the current key.
+pub(crate) struct StaticKeys {
+    keys: HashMap<u32, [u8; KEY_SIZE]>,
+    current: u32,
+}
+
+impl StaticKeys {
+    pub(crate) fn new(id: u32, key: [u8; KEY_SIZE]) -> Self {
+        StaticKeys {
+            keys: HashMap::from([(id, key)]),
+            current: id,
+        }
+    }
+
+    /// Adds a key and makes it the current one, the keys added before can still decrypt.
+    pub(crate) fn rotate(mut self, id: u32, key: [u8; KEY_SIZE]) -> Self {
+        self.keys.insert(id, key);
+        self.current = id;
+        self
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn key(&self, id: u32) -> Option<[u8; KEY_SIZE]> {
+        self.keys.get(&id).copied()
+    }
+}
+
+static PROVIDER: Lazy<RwLock<Option<Arc<dyn KeyProvider>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Sets the provider of the keys prepared transactions, snapshots and tree files are encrypted
+/// with, None writes them in plain text. Files are read whether they are encrypted or not.
+pub(crate) fn set_key_provider(provider: Option<Arc<dyn KeyProvider>>) {
+    *PROVIDER.write().unwrap_or_else(|e| e.into_inner()) = provider;
+}
+
+pub(crate) fn key_provider() -> Option<Arc<dyn KeyProvider>> {
+    PROVIDER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// UnknownKey is the error of a segment sealed with a key the provider doesn't know.
+#[derive(Debug)]
+pub(crate) struct UnknownKey(pub(crate) u32);
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown key {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKey {}
+
+/// Sink writes a file in plain text, or sealed in segments if a key provider is set:
+///  _________________________________________________________________________________
+/// | magic | key id | last | length | nonce | ciphertext and tag | key id | last | .. |
+///  ---------------------------------------------------------------------------------
+/// Segments are authenticated along with their position and whether they are the last one, so
+/// segments which were reordered, dropped or cut off fail to decrypt. The file must be finished
+/// with `finish`, which seals the last segment.
+pub(crate) enum Sink<W: Write> {
+    Plain(W),
+    Sealed(SealedWriter<W>),
+}
+
+impl<W: Write> Sink<W> {
+    pub(crate) fn new(mut inner: W) -> io::Result<Self> {
+        let Some(provider) = key_provider() else {
+            return Ok(Sink::Plain(inner));
+        };
+        inner.write_all(MAGIC)?;
+        Ok(Sink::Sealed(SealedWriter {
+            inner,
+            provider,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+            segment: 0,
+        }))
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Sink::Plain(inner) => Ok(inner),
+            Sink::Sealed(mut writer) => {
+                writer.seal(true)?;
+                Ok(writer.inner)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(inner) => inner.write(buf),
+            Sink::Sealed(writer) => {
+                let len = buf.len().min(SEGMENT_SIZE - writer.buffer.len());
+                writer.buffer.extend_from_slice(&buf[..len]);
+                if writer.buffer.len() == SEGMENT_SIZE {
+                    writer.seal(false)?;
+                }
+                Ok(len)
+            }
+        }
+    }
+
+    // sealed segments are only written once they are full, or by finish.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(inner) => inner.flush(),
+            Sink::Sealed(writer) => writer.inner.flush(),
+        }
+    }
+}
+
+pub(crate) struct SealedWriter<W: Write> {
+    inner: W,
+    provider: Arc<dyn KeyProvider>,
+    buffer: Vec<u8>,
+    segment: u64,
+}
+
+impl<W: Write> SealedWriter<W> {
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let key_id = self.provider.current_key_id();
+        let key = self.provider.key(key_id).ok_or_else(|| io::Error::other(UnknownKey(key_id)))?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = associated_data(key_id, self.segment, last);
+        let ciphertext = cipher(&key)
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                AeadPayload {
+                    msg: &self.buffer,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        self.inner.write_all(&key_id.to_le_bytes())?;
+        self.inner.write_all(&[u8::from(last)])?;
+        self.inner.write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.segment += 1;
+        Ok(())
+    }
+}
+
+/// Returns a reader of the plain text of a file written through a `Sink`, whether it was sealed
+/// or not. Sealed segments are decrypted with the keys of the current key provider.
+pub(crate) fn source<R: Read + 'static>(mut inner: R) -> io::Result<Box<dyn Read>> {
+    let mut magic = [0u8; MAGIC.len()];
+    let len = read_up_to(&mut inner, &mut magic)?;
+    if len < MAGIC.len() || &magic != MAGIC {
+        return Ok(Box::new(Cursor::new(magic[..len].to_vec()).chain(inner)));
+    }
+    Ok(Box::new(SealedReader {
+        inner,
+        provider: key_provider(),
+        plaintext: Cursor::new(Vec::new()),
+        segment: 0,
+        finished: false,
+    }))
+}
+
+struct SealedReader<R: Read> {
+    inner: R,
+    provider: Option<Arc<dyn KeyProvider>>,
+    plaintext: Cursor<Vec<u8>>,
+    segment: u64,
+    finished: bool,
+}
+
+impl<R: Read> SealedReader<R> {
+    fn open_segment(&mut self) -> io::Result<()> {
+        let mut header = [0u8; S_SEGMENT_HEADER];
+        if read_up_to(&mut self.inner, &mut header)? < S_SEGMENT_HEADER {
+            // the last segment is missing.
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let key_id = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let last = header[4] != 0;
+        let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+        if !(TAG_SIZE..=SEGMENT_SIZE + TAG_SIZE).contains(&len) {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let nonce = &header[9..];
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+        let key = self
+            .provider
+            .as_ref()
+            .and_then(|provider| provider.key(key_id))
+            .ok_or_else(|| io::Error::other(UnknownKey(key_id)))?;
+        let aad = associated_data(key_id, self.segment, last);
+        let plaintext = cipher(&key)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                AeadPayload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        self.plaintext = Cursor::new(plaintext);
+        self.segment += 1;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SealedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.plaintext.read(buf)?;
+            if len > 0 || buf.is_empty() || self.finished {
+                return Ok(len);
+            }
+            self.open_segment()?;
+        }
+    }
+}
+
+/// Returns the ids of the keys the segments of the file were sealed with, None for plain files.
+/// Only the segment headers are read, nothing is decrypted.
+pub(crate) fn key_ids(path: &Path) -> Result<Option<BTreeSet<u32>>, Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; MAGIC.len()];
+    if read_up_to(&mut file, &mut magic)? < MAGIC.len() || &magic != MAGIC {
+        return Ok(None);
+    }
+    let mut key_ids = BTreeSet::new();
+    let mut header = [0u8; S_SEGMENT_HEADER];
+    while read_up_to(&mut file, &mut header)? == S_SEGMENT_HEADER {
+        key_ids.insert(u32::from_le_bytes(header[..4].try_into().unwrap()));
+        let len = u32::from_le_bytes(header[5..9].try_into().unwrap());
+        file.seek_relative(i64::from(len))?;
+    }
+    Ok(Some(key_ids))
+}
+
+/// Seals the file again with the current key of the provider, which has to know the keys the file
+/// was sealed with. Plain files are sealed as well. The file is streamed into a temporary file
+/// which is then renamed, so an interrupted rekey leaves either version behind and can be run
+/// again. Returns false if the file was sealed with the current key only, and was left as it is.
+pub(crate) fn reseal(path: &Path) -> Result<bool, Error> {
+    let provider = key_provider().ok_or(Error::UnknownKey(0))?;
+    let current = BTreeSet::from([provider.current_key_id()]);
+    if key_ids(path)?.is_some_and(|key_ids| key_ids == current) {
+        return Ok(false);
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut source = source(File::open(path)?)?;
+    let mut sink = Sink::new(BufWriter::new(File::create(&temp_path)?))?;
+    io::copy(&mut source, &mut sink)?;
+    let file = sink.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sys::sync_dir(path.parent().unwrap_or(Path::new("")))?;
+    Ok(true)
+}
+
+fn cipher(key: &[u8; KEY_SIZE]) -> Aes256Gcm {
+    Aes256Gcm::new(AesKey::<Aes256Gcm>::from_slice(key))
+}
+
+fn associated_data(key_id: u32, segment: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(MAGIC.len() + 13);
+    aad.extend_from_slice(MAGIC);
+    aad.extend_from_slice(&key_id.to_le_bytes());
+    aad.extend_from_slice(&segment.to_le_bytes());
+    aad.push(u8::from(last));
+    aad
+}
+
+// Reads until the buffer is full or the end of the reader, and returns the bytes read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(len)
+}
+
+#[test]
+#[serial]
+fn verify_snapshots_are_sealed_with_rotated_keys() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("secret/{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let plain = Path::new("plain.test");
+    snapshot::write(plain).unwrap();
+    set_key_provider(Some(Arc::new(StaticKeys::new(1, [1u8; KEY_SIZE]))));
+    let sealed = Path::new("sealed.test");
+    snapshot::write(sealed).unwrap();
+    let bytes = fs::read(sealed).unwrap();
+    assert!(bytes.starts_with(MAGIC));
+    assert!(!bytes.windows(b"secret/".len()).any(|window| window == b"secret/"));
+
+    // the rotated key still decrypts the snapshot, and plain snapshots stay readable.
+    let rotated = StaticKeys::new(1, [1u8; KEY_SIZE]).rotate(2, [2u8; KEY_SIZE]);
+    set_key_provider(Some(Arc::new(rotated)));
+    let (config, pages) = snapshot::read(sealed).unwrap();
+    assert_eq!((config, pages.len()), (config::snapshot(), snapshot::read(plain).unwrap().1.len()));
+    set_key_provider(Some(Arc::new(StaticKeys::new(2, [2u8; KEY_SIZE]))));
+    assert!(matches!(snapshot::read(sealed), Err(Error::UnknownKey(1))));
+
+    // segments which were tampered with or cut off fail to decrypt.
+    set_key_provider(Some(Arc::new(StaticKeys::new(1, [1u8; KEY_SIZE]))));
+    let mut tampered = bytes.clone();
+    tampered[MAGIC.len() + S_SEGMENT_HEADER + 10] ^= 1;
+    fs::write(sealed, &tampered).unwrap();
+    assert!(matches!(
+        snapshot::read(sealed),
+        Err(Error::Io(io::ErrorKind::InvalidData))
+    ));
+    let segment = S_SEGMENT_HEADER + SEGMENT_SIZE + TAG_SIZE;
+    fs::write(sealed, &bytes[..MAGIC.len() + segment]).unwrap();
+    assert!(snapshot::read(sealed).is_err());
+
+    set_key_provider(None);
+    assert!(matches!(snapshot::read(sealed), Err(Error::UnknownKey(1))));
+    fs::remove_file(plain).unwrap();
+    fs::remove_file(sealed).unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_prepared_transactions_are_sealed() {
+    delete_index();
+    set_key_provider(Some(Arc::new(StaticKeys::new(7, [7u8; KEY_SIZE]))));
+    let mut txn = Transaction::begin().unwrap();
+    txn.insert(Key::from("secret/key"), Payload::from_u32(1)).unwrap();
+    let id = txn.prepare().unwrap();
+    let bytes = fs::read(format!("prepared.{}", id)).unwrap();
+    assert!(bytes.starts_with(MAGIC));
+    txn::recover_prepared().unwrap();
+    txn::commit_prepared(id).unwrap();
+    let payload = Index::open().unwrap().get(Key::from("secret/key")).unwrap().unwrap();
+    assert_eq!(*payload.to_bytes(), 1u32.to_le_bytes());
+    set_key_provider(None);
+}
+
+#[test]
+#[serial]
+fn verify_files_are_resealed_with_the_new_key() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let path = Path::new("resealed.test");
+    snapshot::write(path).unwrap();
+    let (config, pages) = snapshot::read(path).unwrap();
+    assert_eq!(key_ids(path).unwrap(), None);
+
+    set_key_provider(Some(Arc::new(StaticKeys::new(1, [1u8; KEY_SIZE]))));
+    assert!(reseal(path).unwrap());
+    assert_eq!(key_ids(path).unwrap(), Some(BTreeSet::from([1])));
+    assert!(!reseal(path).unwrap());
+
+    let rotated = StaticKeys::new(1, [1u8; KEY_SIZE]).rotate(2, [2u8; KEY_SIZE]);
+    set_key_provider(Some(Arc::new(rotated)));
+    assert!(reseal(path).unwrap());
+    assert_eq!(key_ids(path).unwrap(), Some(BTreeSet::from([2])));
+    set_key_provider(Some(Arc::new(StaticKeys::new(2, [2u8; KEY_SIZE]))));
+    let (resealed_config, resealed_pages) = snapshot::read(path).unwrap();
+    assert_eq!(resealed_config, config);
+    assert_eq!(resealed_pages.len(), pages.len());
+    assert!(!Path::new("resealed.test.tmp").exists());
+
+    set_key_provider(None);
+    fs::remove_file(path).unwrap();
+}
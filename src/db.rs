@@ -0,0 +1,1171 @@
+use crate::archive;
+use crate::blob;
+use crate::btree::{self, CompressionPolicy, Index, RepairReport};
+use crate::clock::{self, Clock};
+#[cfg(test)]
+use crate::clock::{SystemClock, VirtualClock};
+use crate::compressed;
+use crate::config;
+use crate::crypt::{self, KeyProvider};
+use crate::errors::Error;
+use crate::events::{self, EventListener, ListenerId};
+use crate::fixture;
+use crate::fsck::{self, FsckReport};
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::misses;
+use crate::pagemap;
+use crate::io::{DurabilityMode, RetryPolicy, SyncMode};
+use crate::pagetrace::{self, PageTrace};
+use crate::paging::{
+    self, Page, SlotLayout, DEFAULT_MAX_VALUE_SIZE, PAGE_CHECKSUM_VERSION, PAGE_SIZE_USIZE,
+};
+use crate::poison::{self, CorruptionReport};
+use crate::raft::{self, HistoryRetention, HistoryStats};
+use crate::ratelimit::RateLimiter;
+use crate::sequence::Sequence;
+use crate::shard::{self, ShardStats};
+use crate::snapshot;
+use crate::stats::{self, Stats};
+use crate::tier::{self, Tier};
+use crate::treefile;
+use crate::treestats::{self, TreeStats};
+use crate::txn::{self, IsolationLevel, Transaction, TransactionLimits};
+use crate::types::Offset;
+use crate::types::Key;
+use crate::upgrade;
+#[cfg(test)]
+use crate::types::Payload;
+#[cfg(test)]
+use crate::btree::load;
+#[cfg(test)]
+use crate::config::FORMAT_VERSION;
+#[cfg(test)]
+use crate::events::StallReason;
+#[cfg(test)]
+use crate::poison::Violation;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(test)]
+use std::time::Duration;
+
+/// Db is the entry point to a database, it holds the state shared by the structures stored in the
+/// database files.
+pub(crate) struct Db {
+    sequences: HashMap<String, Sequence>,
+    background_io: Arc<RateLimiter>,
+}
+
+/// MemoryUsage breaks down the memory held by a database in bytes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct MemoryUsage {
+    /// Pages held by the page cache.
+    pub(crate) buffer_pool: usize,
+    /// Pages held compressed after they were evicted from the page cache.
+    pub(crate) compressed_pool: usize,
+    /// Pages and config changes kept aside until the next commit in shadow paging mode. There is no
+    /// write-ahead log, these are all the writes buffered by the database.
+    pub(crate) write_buffer: usize,
+    /// Structures held by the database handle, e.g. the sequences.
+    pub(crate) auxiliary: usize,
+}
+
+impl MemoryUsage {
+    pub(crate) fn total(&self) -> usize {
+        self.buffer_pool + self.compressed_pool + self.write_buffer + self.auxiliary
+    }
+}
+
+/// DbOption is a knob of the database which can be changed with `Db::set_option`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DbOption {
+    /// Maximum number of cached pages, zero for an unbounded cache.
+    CacheSize(usize),
+    SyncMode(SyncMode),
+    BackgroundBytesPerSec(u64),
+    BackgroundIops(u64),
+    /// Number of pages read into the cache following a page read from the disk.
+    ReadaheadPages(usize),
+    /// The page size is fixed by the database files and can't be changed on a live database.
+    PageSize(usize),
+    /// Size of the largest value which can be stored, larger values are rejected.
+    MaxValueSize(usize),
+    RetryPolicy(RetryPolicy),
+    /// Number of absent keys remembered by the index lookups, zero to disable the cache.
+    NegativeCacheSize(usize),
+    /// Bytes of writes a transaction buffers in memory before spilling them into a temporary file.
+    TxnSpillThreshold(usize),
+    /// Limits on the age and the write set of transactions, checked from now on.
+    TxnLimits(TransactionLimits),
+    /// Bytes of compressed pages kept after they were evicted from the page cache, zero to
+    /// disable the compressed tier.
+    CompressedCacheSize(usize),
+    /// What the tree compresses, separately for inner page keys and leaf values.
+    Compression(CompressionPolicy),
+    /// Whether values are written along with a checksum verified by `Index::get_verified`.
+    ValueChecksums(bool),
+    /// How much history is kept by `Db::collect_history`.
+    HistoryRetention(HistoryRetention),
+    /// The layout of the pages created from now on, pages keep the layout they were created with.
+    SlotLayout(SlotLayout),
+}
+
+/// DbBuilder collects the options a database is opened with.
+pub(crate) struct DbBuilder {
+    background_bytes_per_sec: u64,
+    background_iops: u64,
+    cache_size: usize,
+    sync_mode: SyncMode,
+    readahead_pages: usize,
+    page_size: usize,
+    durability_mode: DurabilityMode,
+    max_value_size: usize,
+    retry_policy: RetryPolicy,
+    negative_cache_size: usize,
+    txn_spill_threshold: usize,
+    txn_limits: TransactionLimits,
+    compressed_cache_size: usize,
+    compression: CompressionPolicy,
+    value_checksums: bool,
+    history_retention: HistoryRetention,
+    cold_path: PathBuf,
+    clock: Arc<dyn Clock>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    // None keeps the layout stored in the config.
+    slot_layout: Option<SlotLayout>,
+}
+
+impl Default for DbBuilder {
+    fn default() -> Self {
+        DbBuilder {
+            background_bytes_per_sec: 0,
+            background_iops: 0,
+            cache_size: 0,
+            sync_mode: SyncMode::Flush,
+            readahead_pages: 0,
+            page_size: PAGE_SIZE_USIZE,
+            durability_mode: io::durability_mode(),
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            retry_policy: RetryPolicy::default(),
+            negative_cache_size: 0,
+            txn_spill_threshold: txn::DEFAULT_SPILL_THRESHOLD,
+            txn_limits: txn::limits(),
+            compressed_cache_size: 0,
+            compression: btree::compression_policy(),
+            value_checksums: btree::value_checksums(),
+            history_retention: raft::history_retention(),
+            cold_path: tier::cold_path(),
+            clock: clock::clock(),
+            key_provider: crypt::key_provider(),
+            slot_layout: None,
+        }
+    }
+}
+
+impl DbBuilder {
+    pub(crate) fn cache_size(mut self, pages: usize) -> Self {
+        self.cache_size = pages;
+        self
+    }
+
+    pub(crate) fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    pub(crate) fn readahead_pages(mut self, pages: usize) -> Self {
+        self.readahead_pages = pages;
+        self
+    }
+
+    pub(crate) fn durability_mode(mut self, durability_mode: DurabilityMode) -> Self {
+        self.durability_mode = durability_mode;
+        self
+    }
+
+    /// Limits the size of the stored values, inserting a larger value fails with `ValueTooLarge`.
+    pub(crate) fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// Bounds the retries of transient IO errors before the database fails, see `RetryPolicy`.
+    pub(crate) fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Remembers the misses of up to the given number of keys, for workloads probing the same absent
+    /// keys again and again, see `misses::known_absent`.
+    pub(crate) fn negative_cache_size(mut self, keys: usize) -> Self {
+        self.negative_cache_size = keys;
+        self
+    }
+
+    /// Bounds the memory of transactions, larger write sets are spilled into a temporary file and
+    /// streamed into the index at commit.
+    pub(crate) fn txn_spill_threshold(mut self, bytes: usize) -> Self {
+        self.txn_spill_threshold = bytes;
+        self
+    }
+
+    /// Aborts transactions running too long or writing too many keys, see `TransactionLimits`.
+    pub(crate) fn txn_limits(mut self, limits: TransactionLimits) -> Self {
+        self.txn_limits = limits;
+        self
+    }
+
+    /// Keeps up to the given bytes of pages evicted from the page cache compressed in memory, so
+    /// that re-reading them decompresses instead of reading from the disk.
+    pub(crate) fn compressed_cache_size(mut self, bytes: usize) -> Self {
+        self.compressed_cache_size = bytes;
+        self
+    }
+
+    /// Chooses whether the keys of inner pages and the values of leaves are compressed, see
+    /// `CompressionPolicy`.
+    pub(crate) fn compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = policy;
+        self
+    }
+
+    /// Stores a checksum along with every value written, see `btree::set_value_checksums`.
+    pub(crate) fn value_checksums(mut self, enabled: bool) -> Self {
+        self.value_checksums = enabled;
+        self
+    }
+
+    /// Sets how much of the history left behind by deletes is kept, see `HistoryRetention`.
+    pub(crate) fn history_retention(mut self, retention: HistoryRetention) -> Self {
+        self.history_retention = retention;
+        self
+    }
+
+    /// Sets the file the cold pages are stored in, e.g. on a cheaper disk, see `tier`.
+    pub(crate) fn cold_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cold_path = path.into();
+        self
+    }
+
+    /// Sets the time source of visibility timeouts and background throttling, e.g. a
+    /// `VirtualClock` for simulations or a clock of their own on systems without a wall clock.
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Encrypts prepared transactions, snapshots and detached trees with the keys of the provider.
+    /// Files written before, in plain text or with keys rotated out since, stay readable as long as
+    /// the provider knows their keys.
+    pub(crate) fn key_provider(mut self, provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(provider);
+        self
+    }
+
+    /// Sets the layout of the slotted pages created from now on, which is stored in the config,
+    /// see `SlotLayout`.
+    pub(crate) fn slot_layout(mut self, layout: SlotLayout) -> Self {
+        self.slot_layout = Some(layout);
+        self
+    }
+
+    /// The page size is fixed when the crate is built, other sizes are rejected.
+    pub(crate) fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Limits the bytes per second written by background tasks, zero for no limit.
+    pub(crate) fn background_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.background_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Limits the IO operations per second of background tasks, zero for no limit.
+    pub(crate) fn background_iops(mut self, iops: u64) -> Self {
+        self.background_iops = iops;
+        self
+    }
+
+    pub(crate) fn open(self) -> Result<Db, Error> {
+        if self.page_size != PAGE_SIZE_USIZE {
+            return Err(Error::ImmutableOption);
+        }
+        io::lock()?;
+        // a commit cut short is completed first, its journal may hold pages of the cold file.
+        tier::set_cold_path(self.cold_path);
+        io::replay_journal()?;
+        io::set_cache_capacity(self.cache_size);
+        io::set_sync_mode(self.sync_mode);
+        io::set_readahead_pages(self.readahead_pages);
+        io::set_durability_mode(self.durability_mode);
+        // the pages of files written before pages carried a checksum are widened first.
+        upgrade::widen_pages()?;
+        config::upgrade()?;
+        // new files, and files of versions before 15, carry checksums of the first version.
+        match config::get_page_checksum_version() {
+            0 => config::update_page_checksum_version(PAGE_CHECKSUM_VERSION),
+            PAGE_CHECKSUM_VERSION => {}
+            found => {
+                return Err(Error::UnsupportedChecksumVersion {
+                    found,
+                    supported: PAGE_CHECKSUM_VERSION,
+                });
+            }
+        }
+        if let Some(layout) = self.slot_layout {
+            config::update_slot_layout(layout);
+        }
+        paging::set_max_value_size(self.max_value_size);
+        io::set_retry_policy(self.retry_policy);
+        misses::set_capacity(self.negative_cache_size);
+        txn::set_spill_threshold(self.txn_spill_threshold);
+        txn::set_limits(self.txn_limits);
+        compressed::set_capacity(self.compressed_cache_size);
+        btree::set_compression_policy(self.compression);
+        btree::set_value_checksums(self.value_checksums);
+        raft::set_history_retention(self.history_retention);
+        clock::set_clock(self.clock);
+        crypt::set_key_provider(self.key_provider);
+        txn::recover_prepared()?;
+        Ok(Db {
+            sequences: HashMap::new(),
+            background_io: Arc::new(RateLimiter::new(
+                self.background_bytes_per_sec,
+                self.background_iops,
+            )),
+        })
+    }
+}
+
+impl Db {
+    pub(crate) fn builder() -> DbBuilder {
+        DbBuilder::default()
+    }
+
+    pub(crate) fn open() -> Result<Self, Error> {
+        Self::builder().open()
+    }
+
+    /// Returns the limiter shared by the background tasks of the database.
+    pub(crate) fn background_io(&self) -> Arc<RateLimiter> {
+        self.background_io.clone()
+    }
+
+    /// Changes the background IO limits, tasks already running pick them up with their next write.
+    pub(crate) fn set_background_io_limits(&self, bytes_per_sec: u64, iops: u64) {
+        self.background_io.set_limits(bytes_per_sec, iops);
+    }
+
+    /// Makes the changes since the last commit durable. Pages are written in place without shadow
+    /// paging, so there is nothing left to do then. Fails with Failed if the database failed.
+    pub(crate) fn commit(&self) -> Result<(), Error> {
+        io::commit();
+        io::check_writable()
+    }
+
+    /// Returns the kind of the IO error which failed the database, None if it didn't fail. A failed
+    /// database refuses writes until it's closed and opened again, unless it ran out of disk
+    /// space: writes are refused with DiskFull then, and accepted again once there is space.
+    pub(crate) fn failure(&self) -> Option<std::io::ErrorKind> {
+        io::failure()
+    }
+
+    /// Drops the changes since the last commit in shadow paging mode.
+    pub(crate) fn rollback(&self) {
+        io::rollback();
+    }
+
+    /// Writes a consistent copy of the database into a single file, see `snapshot::write`. Returns
+    /// the last applied log index the copy is consistent with.
+    pub(crate) fn snapshot_to_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<u64, Error> {
+        snapshot::write(path.as_ref())
+    }
+
+    /// Moves the index into a file of its own, see `treefile::detach`.
+    pub(crate) fn detach_tree(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        treefile::detach(path.as_ref())
+    }
+
+    /// Attaches a tree moved out of another database as the index of this one, see
+    /// `treefile::attach`.
+    pub(crate) fn attach_tree(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        treefile::attach(path.as_ref())
+    }
+
+    /// Freezes the index into a packed, checksummed and read-only archive, see `archive::freeze`.
+    pub(crate) fn freeze(&self, compress: bool) -> Result<(), Error> {
+        archive::freeze(compress)
+    }
+
+    /// Returns true if the index was frozen into an archive, writes are refused then.
+    pub(crate) fn is_archived(&self) -> bool {
+        archive::is_archived()
+    }
+
+    /// Removes the blob files no key of the index refers to, see `blob::collect_garbage`. Returns
+    /// the number of files removed, fails with Uncommitted until shadow pages are committed.
+    pub(crate) fn collect_blobs(&self) -> Result<usize, Error> {
+        blob::collect_garbage(&Index::open()?)
+    }
+
+    /// Moves the pages of the index into the tier, see `tier::migrate`. Returns the number of
+    /// pages moved.
+    pub(crate) fn migrate_tree(&self, tier: Tier) -> Result<usize, Error> {
+        tier::migrate(&treefile::page_ids()?, tier)
+    }
+
+    /// Moves the leaves holding the key range into the tier, see `tier::migrate`. Returns the
+    /// number of pages moved.
+    pub(crate) fn migrate_range<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+        tier: Tier,
+    ) -> Result<usize, Error> {
+        tier::migrate(&Index::open()?.range_pages(range)?, tier)
+    }
+
+    /// Moves the page into the slot of the free target page keeping its id, see
+    /// `pagemap::relocate`.
+    pub(crate) fn relocate_page(
+        &self,
+        page_id: Offset,
+        target: Offset,
+    ) -> Result<(), Error> {
+        pagemap::relocate(page_id, target)
+    }
+
+    /// Applies the writes of a Raft log entry exactly once, see `raft::apply_log_entry`.
+    pub(crate) fn apply_log_entry(&self, index: u64, entry: &[u8]) -> Result<bool, Error> {
+        raft::apply_log_entry(index, entry)
+    }
+
+    /// Removes the tombstones of deletes applied below the watermark, see `raft::purge_tombstones`.
+    pub(crate) fn purge_tombstones(&self, watermark: u64) -> Result<usize, Error> {
+        raft::purge_tombstones(watermark)
+    }
+
+    /// Removes the tombstones the history retention doesn't keep, see `raft::collect_history`.
+    pub(crate) fn collect_history(&self) -> Result<usize, Error> {
+        raft::collect_history()
+    }
+
+    /// Returns the number and bytes of the tombstones retained.
+    pub(crate) fn history_stats(&self) -> Result<HistoryStats, Error> {
+        raft::history_stats()
+    }
+
+    pub(crate) fn last_applied_index(&self) -> u64 {
+        config::get_last_applied_index()
+    }
+
+    /// Writes a snapshot for a Raft library, returning its last applied index.
+    pub(crate) fn export_snapshot(&self, path: impl AsRef<Path>) -> Result<u64, Error> {
+        raft::export_snapshot(path.as_ref())
+    }
+
+    /// Replaces the database with a snapshot received from the leader, see `raft::install_snapshot`.
+    /// The cached sequences are dropped, as their pages are replaced.
+    pub(crate) fn install_snapshot(&mut self, path: impl AsRef<Path>) -> Result<u64, Error> {
+        self.sequences.clear();
+        raft::install_snapshot(path.as_ref())
+    }
+
+    /// Rebuilds the parent and sibling links of the index pages, for files written by older
+    /// versions or damaged by bugs. Returns the pages which were fixed. Lifts the poison if the
+    /// index was walked without running into corruption.
+    pub(crate) fn repair_links(&self) -> Result<RepairReport, Error> {
+        let report = Index::open()?.repair_links()?;
+        poison::clear();
+        Ok(report)
+    }
+
+    /// Reports the pages leaked by crashes or bugs, and returns them to the free list if reclaim
+    /// is set. Lifts the poison if the database was walked without running into corruption.
+    pub(crate) fn fsck(&self, reclaim: bool) -> Result<FsckReport, Error> {
+        let report = fsck::check(reclaim)?;
+        poison::clear();
+        Ok(report)
+    }
+
+    /// Returns the traces of the pages allocated but neither linked into the database nor freed,
+    /// see `pagetrace::leaks`. Empty unless page tracing is enabled.
+    pub(crate) fn page_leaks(&self) -> Result<Vec<PageTrace>, Error> {
+        pagetrace::leaks()
+    }
+
+    /// Returns the report of the corruption which poisoned the database, None if it isn't
+    /// poisoned. A poisoned database refuses writes until fsck or repair_links succeeds.
+    pub(crate) fn poisoned(&self) -> Option<CorruptionReport> {
+        poison::report()
+    }
+
+    /// Registers the listener for the events of the storage engine.
+    pub(crate) fn add_event_listener(&self, listener: Arc<dyn EventListener>) -> ListenerId {
+        events::register(listener)
+    }
+
+    pub(crate) fn remove_event_listener(&self, id: ListenerId) {
+        events::unregister(id)
+    }
+
+    /// Changes an option of the live database. Options fixed by the database files are rejected.
+    pub(crate) fn set_option(&mut self, option: DbOption) -> Result<(), Error> {
+        let (bytes_per_sec, iops) = self.background_io.limits();
+        match option {
+            DbOption::CacheSize(pages) => io::set_cache_capacity(pages),
+            DbOption::SyncMode(sync_mode) => io::set_sync_mode(sync_mode),
+            DbOption::BackgroundBytesPerSec(rate) => self.set_background_io_limits(rate, iops),
+            DbOption::BackgroundIops(rate) => self.set_background_io_limits(bytes_per_sec, rate),
+            DbOption::ReadaheadPages(pages) => io::set_readahead_pages(pages),
+            DbOption::PageSize(page_size) if page_size == PAGE_SIZE_USIZE => {}
+            DbOption::PageSize(_) => return Err(Error::ImmutableOption),
+            DbOption::MaxValueSize(bytes) => paging::set_max_value_size(bytes),
+            DbOption::RetryPolicy(policy) => io::set_retry_policy(policy),
+            DbOption::NegativeCacheSize(keys) => misses::set_capacity(keys),
+            DbOption::TxnSpillThreshold(bytes) => txn::set_spill_threshold(bytes),
+            DbOption::TxnLimits(limits) => txn::set_limits(limits),
+            DbOption::CompressedCacheSize(bytes) => compressed::set_capacity(bytes),
+            DbOption::Compression(policy) => btree::set_compression_policy(policy),
+            DbOption::ValueChecksums(enabled) => btree::set_value_checksums(enabled),
+            DbOption::HistoryRetention(retention) => raft::set_history_retention(retention),
+            DbOption::SlotLayout(layout) => config::update_slot_layout(layout),
+        }
+        Ok(())
+    }
+
+    /// Reports the memory held by the database, so that embedders can budget memory across
+    /// databases. The figures are estimates, allocator overhead isn't accounted for.
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let page_bytes = size_of::<Page>() + size_of::<Offset>();
+        let sequences: usize = self
+            .sequences
+            .keys()
+            .map(|name| name.len() + size_of::<String>() + size_of::<Sequence>())
+            .sum();
+        MemoryUsage {
+            buffer_pool: io::cached_pages() * page_bytes,
+            compressed_pool: compressed::usage().1,
+            write_buffer: io::shadow_pages() * page_bytes + config::shadow_bytes(),
+            auxiliary: size_of::<Db>() + sequences + size_of::<RateLimiter>(),
+        }
+    }
+
+    /// Returns the pages touched and the bytes written by the index operations since the start of
+    /// the process.
+    pub(crate) fn stats(&self) -> Stats {
+        stats::snapshot()
+    }
+
+    /// Returns the entry count, the key and value bytes and the pages per level of the index. They
+    /// are kept up to date by the writes and read from the last checkpoint on open, the tree is
+    /// only walked for files written before the statistics were persisted.
+    pub(crate) fn tree_stats(&self) -> Result<TreeStats, Error> {
+        treestats::stats()
+    }
+
+    /// Returns the micro-shards along with their statistics, sorted by name, see `shard`.
+    pub(crate) fn shard_stats(&self) -> Result<Vec<(String, ShardStats)>, Error> {
+        let mut shards = Vec::new();
+        for name in shard::shards()? {
+            let stats = shard::stats(&name)?.unwrap_or_default();
+            shards.push((name, stats));
+        }
+        Ok(shards)
+    }
+
+    /// Tells the pager that the range is about to be scanned, so that its leaves are read into the
+    /// page cache up front, see `Index::advise_range`. Returns the number of leaves read.
+    pub(crate) fn advise_range<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+    ) -> Result<usize, Error> {
+        Index::open()?.advise_range(range)
+    }
+
+    /// Begins a transaction with snapshot isolation, see `Transaction` for its guarantees.
+    pub(crate) fn begin(&self) -> Result<Transaction, Error> {
+        Transaction::begin()
+    }
+
+    /// Begins a transaction with the isolation level, see `IsolationLevel`.
+    pub(crate) fn begin_with(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<Transaction, Error> {
+        Transaction::begin_with(isolation)
+    }
+
+    /// Commits a transaction prepared with `Transaction::prepare`, see `txn::commit_prepared`.
+    pub(crate) fn commit_prepared(&self, id: u64) -> Result<(), Error> {
+        txn::commit_prepared(id)
+    }
+
+    pub(crate) fn rollback_prepared(&self, id: u64) -> Result<(), Error> {
+        txn::rollback_prepared(id)
+    }
+
+    /// Returns the ids of the transactions prepared but neither committed nor rolled back.
+    pub(crate) fn prepared_transactions(&self) -> Result<Vec<u64>, Error> {
+        txn::prepared_ids()
+    }
+
+    /// Writes the fixture of the format version into the empty database, see `fixture::create`.
+    pub(crate) fn create_fixture(&self, version: u32) -> Result<(), Error> {
+        fixture::create(version)
+    }
+
+    /// Verifies that the database holds the fixture of the format version.
+    pub(crate) fn load_fixture(&self, version: u32) -> Result<(), Error> {
+        fixture::load(version)
+    }
+
+    /// Returns the named sequence, creating it if it doesn't exist yet. Repeated calls return the
+    /// same sequence, so that the ids reserved by it aren't handed out twice.
+    pub(crate) fn sequence(&mut self, name: &str) -> Result<&mut Sequence, Error> {
+        if !self.sequences.contains_key(name) {
+            let sequence = Sequence::load(name)?;
+            self.sequences.insert(name.to_string(), sequence);
+        }
+        Ok(self.sequences.get_mut(name).unwrap())
+    }
+}
+
+#[test]
+#[serial]
+fn verify_sequences_are_independent() {
+    delete_index();
+    let mut db = Db::open().unwrap();
+    assert_eq!(db.sequence("orders").unwrap().next_id().unwrap(), 0);
+    assert_eq!(db.sequence("orders").unwrap().next_id().unwrap(), 1);
+    assert_eq!(db.sequence("users").unwrap().next_id().unwrap(), 0);
+    assert_eq!(db.sequence("orders").unwrap().next_id().unwrap(), 2);
+}
+
+#[test]
+#[serial]
+fn verify_memory_usage() {
+    delete_index();
+    let mut db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    for i in 0..50u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let usage = db.memory_usage();
+    assert!(usage.buffer_pool >= io::cached_pages() * PAGE_SIZE_USIZE);
+    match io::durability_mode() {
+        DurabilityMode::WriteThrough => assert_eq!(usage.write_buffer, 0),
+        DurabilityMode::Shadow => {
+            assert!(io::shadow_pages() > 0);
+            assert!(usage.write_buffer >= io::shadow_pages() * PAGE_SIZE_USIZE);
+        }
+    }
+    db.commit().unwrap();
+    assert_eq!(db.memory_usage().write_buffer, 0);
+    db.sequence("orders").unwrap();
+    assert!(db.memory_usage().auxiliary > usage.auxiliary);
+    db.set_option(DbOption::CacheSize(2)).unwrap();
+    assert!(db.memory_usage().buffer_pool < usage.buffer_pool);
+    Db::open().unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_amplification_stats() {
+    delete_index();
+    let db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    let before = db.stats();
+    for i in 0..20u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    index.get(Key::from("007")).unwrap();
+    assert_eq!(index.scan(..).unwrap().count(), 20);
+    let after = db.stats();
+    let inserts = after.insert.operations - before.insert.operations;
+    assert_eq!(inserts, 20);
+    let written = after.insert.bytes_written - before.insert.bytes_written;
+    assert!(written >= inserts * PAGE_SIZE_USIZE as u64);
+    assert!(after.insert.write_amplification() > 1.0);
+    assert!(after.get.pages_read > before.get.pages_read);
+    // the scan is accounted for every leaf it walks.
+    assert!(after.scan.pages_read - before.scan.pages_read > 1);
+}
+
+#[test]
+#[serial]
+fn verify_background_io_limits() {
+    delete_index();
+    let db = Db::builder()
+        .background_bytes_per_sec(1 << 20)
+        .background_iops(100)
+        .open()
+        .unwrap();
+    assert_eq!(db.background_io().limits(), (1 << 20, 100));
+    db.set_background_io_limits(0, 50);
+    assert_eq!(db.background_io().limits(), (0, 50));
+}
+
+#[test]
+#[serial]
+fn verify_set_option_on_live_db() {
+    delete_index();
+    let mut db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    for i in 0..100u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    db.set_option(DbOption::CacheSize(8)).unwrap();
+    assert!(io::cached_pages() <= 8);
+    db.set_option(DbOption::BackgroundIops(10)).unwrap();
+    assert_eq!(db.background_io().limits(), (0, 10));
+    db.set_option(DbOption::SyncMode(SyncMode::Full)).unwrap();
+    assert!(index.get(Key::from("042")).unwrap().is_some());
+    assert!(matches!(
+        db.set_option(DbOption::PageSize(4096)),
+        Err(Error::ImmutableOption)
+    ));
+    assert!(matches!(
+        Db::builder().page_size(4096).open(),
+        Err(Error::ImmutableOption)
+    ));
+    io::close();
+}
+
+#[test]
+#[serial]
+fn verify_oversized_values_are_rejected() {
+    delete_index();
+    let mut db = Db::builder().max_value_size(100).open().unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("small"), Payload::from_str("x".repeat(100))).unwrap();
+    assert!(matches!(
+        index.insert(Key::from("small"), Payload::from_str("x".repeat(101))),
+        Err(Error::ValueTooLarge { max: 100, got: 101 })
+    ));
+    // the rejected update leaves the stored value in place.
+    assert_eq!(index.get(Key::from("small")).unwrap().unwrap().len(), 100);
+    db.set_option(DbOption::MaxValueSize(20_000)).unwrap();
+    index.insert(Key::from("large"), Payload::from_str("x".repeat(20_000))).unwrap();
+    assert_eq!(index.get(Key::from("large")).unwrap().unwrap().len(), 20_000);
+    Db::open().unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_shadow_paging_commit_and_rollback() {
+    delete_index();
+    let db = Db::builder()
+        .durability_mode(DurabilityMode::Shadow)
+        .open()
+        .unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    // nothing reaches the files before the commit.
+    assert!(std::fs::metadata("index.000").is_err());
+    db.commit().unwrap();
+    index.insert(Key::from("b"), Payload::from_u32(2)).unwrap();
+    db.rollback();
+
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(index.get(Key::from("b")).unwrap().is_none());
+    // the commit survives the database being reopened, the uncommitted writes don't.
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("c"), Payload::from_u32(3)).unwrap();
+    io::close();
+    let _db = Db::builder().durability_mode(DurabilityMode::Shadow).open().unwrap();
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(index.get(Key::from("c")).unwrap().is_none());
+    io::set_durability_mode(DurabilityMode::default());
+}
+
+#[test]
+#[serial]
+fn verify_shadow_paging_survives_a_crash_before_the_rename() {
+    delete_index();
+    let db = Db::builder().durability_mode(DurabilityMode::Shadow).open().unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    db.commit().unwrap();
+    index.insert(Key::from("b"), Payload::from_u32(2)).unwrap();
+    // a crash while the commit writes its journal leaves it behind half written.
+    std::fs::write("index.journal.tmp", [0xffu8; 100]).unwrap();
+    io::close();
+
+    let db = Db::builder().durability_mode(DurabilityMode::Shadow).open().unwrap();
+    let mut index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(index.get(Key::from("b")).unwrap().is_none());
+    // the next commit writes its journal over the leftover.
+    index.insert(Key::from("c"), Payload::from_u32(3)).unwrap();
+    db.commit().unwrap();
+    io::close();
+    let db = Db::builder().durability_mode(DurabilityMode::Shadow).open().unwrap();
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(index.get(Key::from("c")).unwrap().is_some());
+    assert!(db.fsck(false).unwrap().orphans.is_empty());
+    io::set_durability_mode(DurabilityMode::default());
+}
+
+#[test]
+#[serial]
+fn verify_evicted_shadow_pages_are_read_back() {
+    delete_index();
+    let db = Db::builder().durability_mode(DurabilityMode::Shadow).open().unwrap();
+    io::set_cache_capacity(2);
+    let mut index = Index::open().unwrap();
+    for i in 0..300u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    // the pages only exist as shadow pages, most of them are no longer cached.
+    assert!(std::fs::metadata("index.000").is_err());
+    assert!(io::shadow_pages() > io::cached_pages());
+    for i in 0..300u32 {
+        let payload = index.get(Key::from(format!("{:03}", i).as_str())).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), i.to_le_bytes());
+    }
+    assert_eq!(index.scan(..).unwrap().count(), 300);
+    db.rollback();
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("000")).unwrap().is_none());
+    io::set_cache_capacity(0);
+    io::set_durability_mode(DurabilityMode::default());
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct StallRecorder(Mutex<Vec<StallReason>>);
+
+#[cfg(test)]
+impl EventListener for StallRecorder {
+    fn on_write_stall(&self, reason: StallReason, _duration: Duration) {
+        self.0.lock().unwrap().push(reason);
+    }
+}
+
+#[test]
+#[serial]
+fn verify_write_stalls_are_reported() {
+    delete_index();
+    let db = Db::builder()
+        .background_iops(100)
+        .durability_mode(DurabilityMode::Shadow)
+        .open()
+        .unwrap();
+    let recorder = Arc::new(StallRecorder::default());
+    let id = db.add_event_listener(recorder.clone());
+    let before = db.stats();
+    let page = Page::new_data(&io::page_allocator()).unwrap();
+    // the burst is one second worth of operations, the writes beyond it wait.
+    for _ in 0..102 {
+        io::write_background(&page, &db.background_io());
+    }
+    db.commit().unwrap();
+    db.remove_event_listener(id);
+    let after = db.stats();
+    assert!(after.stall_count >= before.stall_count + 3);
+    assert!(after.stall_ms >= before.stall_ms + 10);
+    let reasons = recorder.0.lock().unwrap();
+    assert!(reasons.contains(&StallReason::Throttled));
+    assert_eq!(reasons.last(), Some(&StallReason::Checkpoint));
+    io::set_durability_mode(DurabilityMode::default());
+}
+
+#[test]
+#[serial]
+fn verify_throttling_fast_forwards_a_virtual_clock() {
+    delete_index();
+    let virtual_clock = Arc::new(VirtualClock::new(Duration::ZERO));
+    let db = Db::builder()
+        .background_iops(10)
+        .clock(virtual_clock.clone())
+        .open()
+        .unwrap();
+    let page = Page::new_data(&io::page_allocator()).unwrap();
+    let started = std::time::Instant::now();
+    // ten seconds worth of writes beyond the burst.
+    for _ in 0..110 {
+        io::write_background(&page, &db.background_io());
+    }
+    assert!(virtual_clock.now() >= Duration::from_secs(10));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    clock::set_clock(Arc::new(SystemClock));
+}
+
+#[test]
+#[serial]
+fn verify_io_errors_are_retried_then_fail_the_database() {
+    delete_index();
+    let db = Db::builder()
+        .retry_policy(RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::from_micros(10),
+        })
+        .open()
+        .unwrap();
+    let recorder = Arc::new(FailureRecorder::default());
+    let listener = db.add_event_listener(recorder.clone());
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    // transient errors within the policy are retried.
+    io::inject_errors(vec![ErrorKind::Interrupted, ErrorKind::WouldBlock]);
+    index.insert(Key::from("b"), Payload::from_u32(2)).unwrap();
+    assert_eq!(db.failure(), None);
+    // in shadow mode "b" only reaches the files with the commit.
+    db.commit().unwrap();
+    assert!(recorder.0.lock().unwrap().is_empty());
+    io::inject_errors(vec![ErrorKind::Interrupted; 3]);
+    assert!(matches!(
+        index.insert(Key::from("c"), Payload::from_u32(3)),
+        Err(Error::Failed(ErrorKind::Interrupted))
+    ));
+    assert_eq!(db.failure(), Some(ErrorKind::Interrupted));
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["failed"]);
+    db.remove_event_listener(listener);
+    // reads go on, writes and commits are refused.
+    assert!(index.get(Key::from("b")).unwrap().is_some());
+    assert!(matches!(index.delete(Key::from("a")), Err(Error::Failed(_))));
+    assert!(matches!(db.commit(), Err(Error::Failed(_))));
+    // the failed write never reached the files.
+    io::close();
+    let db = Db::open().unwrap();
+    assert_eq!(db.failure(), None);
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("b")).unwrap().is_some());
+    assert!(index.get(Key::from("c")).unwrap().is_none());
+    io::set_retry_policy(RetryPolicy::default());
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FailureRecorder(Mutex<Vec<&'static str>>);
+
+#[cfg(test)]
+impl EventListener for FailureRecorder {
+    fn on_failed(&self, _error: &std::io::Error) {
+        self.0.lock().unwrap().push("failed");
+    }
+
+    fn on_disk_space_recovered(&self) {
+        self.0.lock().unwrap().push("recovered");
+    }
+}
+
+#[test]
+#[serial]
+fn verify_full_disks_degrade_the_database_until_there_is_space() {
+    delete_index();
+    let db = Db::open().unwrap();
+    let recorder = Arc::new(FailureRecorder::default());
+    let listener = db.add_event_listener(recorder.clone());
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    // the insert runs into the full disk, and so does the attempt to recover at its end.
+    io::inject_errors(vec![ErrorKind::StorageFull; 3]);
+    assert!(matches!(
+        index.insert(Key::from("b"), Payload::from_u32(2)),
+        Err(Error::DiskFull)
+    ));
+    assert_eq!(db.failure(), Some(ErrorKind::StorageFull));
+    // reads go on, writes are refused while the disk is full.
+    assert!(index.get(Key::from("b")).unwrap().is_some());
+    assert!(matches!(index.delete(Key::from("a")), Err(Error::DiskFull)));
+    // the next write finds space and writes the pages kept in memory first.
+    index.insert(Key::from("c"), Payload::from_u32(3)).unwrap();
+    assert_eq!(db.failure(), None);
+    db.remove_event_listener(listener);
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["failed", "recovered"]);
+    db.commit().unwrap();
+    io::close();
+    let index = Index::open().unwrap();
+    for key in ["a", "b", "c"] {
+        assert!(index.get(Key::from(key)).unwrap().is_some());
+    }
+}
+
+#[test]
+#[serial]
+fn verify_older_formats_are_upgraded_on_open() {
+    for mode in [DurabilityMode::WriteThrough, DurabilityMode::Shadow] {
+        delete_index();
+        fixture::create(5).unwrap();
+        io::close();
+        assert_eq!(config::file_version().unwrap(), Some(5));
+        let db = Db::builder().durability_mode(mode).open().unwrap();
+        assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+        assert_eq!(config::get_page_checksum_version(), PAGE_CHECKSUM_VERSION);
+        assert_eq!(db.tree_stats().unwrap().entries, 40);
+        // the upgrade doesn't wait for a commit.
+        io::close();
+        assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+    }
+    io::set_durability_mode(DurabilityMode::default());
+
+    // files of a newer version are refused.
+    let size = config::file_size().unwrap();
+    let file = std::fs::OpenOptions::new().write(true).open("config").unwrap();
+    file.set_len(size + size_of::<u64>() as u64).unwrap();
+    assert!(matches!(
+        Db::open(),
+        Err(Error::UnsupportedFormatVersion { found, supported })
+            if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+    ));
+    io::close();
+
+    // a config cut short within a field is corrupt, its missing fields aren't reset to zero.
+    file.set_len(size - 3).unwrap();
+    let Err(error) = Db::open() else {
+        panic!("a config cut short was opened");
+    };
+    assert!(matches!(
+        error,
+        Error::CorruptConfig { size: cut } if cut == size - 3
+    ));
+    assert!(error.is_corruption() && error.is_fatal());
+    io::close();
+
+    // configs written by older builds end with the bytes of the field written last, e.g. with the
+    // two bytes of the dictionary page id.
+    file.set_len(config::size_of_version(1) + 2).unwrap();
+    Db::open().unwrap();
+    assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+    io::close();
+}
+
+#[test]
+#[serial]
+fn verify_files_without_page_checksums_are_widened() {
+    delete_index();
+    // written by the last build whose pages carried no checksum.
+    fixture::install(12).unwrap();
+    assert_eq!(config::file_version().unwrap(), Some(12));
+    Db::open().unwrap();
+    assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+    assert_eq!(config::get_page_checksum_version(), PAGE_CHECKSUM_VERSION);
+    fixture::check(12).unwrap();
+    io::close();
+
+    // files recording checksums of a later version are refused.
+    delete_index();
+    let db = Db::open().unwrap();
+    config::update_page_checksum_version(PAGE_CHECKSUM_VERSION + 1);
+    db.commit().unwrap();
+    io::close();
+    assert!(matches!(
+        Db::open(),
+        Err(Error::UnsupportedChecksumVersion { found, .. })
+            if found == PAGE_CHECKSUM_VERSION + 1
+    ));
+    io::close();
+}
+
+#[test]
+#[serial]
+fn verify_corruption_poisons_the_database() {
+    delete_index();
+    let db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    let orphan = Page::new_data(&io::page_allocator()).unwrap().page_id();
+    io::write(&Page::new_page(99, orphan));
+    assert!(load(orphan).is_err());
+    let report = db.poisoned().unwrap();
+    assert_eq!(report.page_id, orphan);
+    assert_eq!(report.violation, Violation::UnknownPageType(99));
+    assert!(report.to_string().starts_with(&format!("page {}: unknown page type 99", orphan)));
+    // reads go on, writes are refused until the database is checked.
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(matches!(
+        index.insert(Key::from("b"), Payload::from_u32(2)),
+        Err(Error::Poisoned)
+    ));
+    // the corrupt page isn't reachable, so the check goes through.
+    db.fsck(false).unwrap();
+    assert!(db.poisoned().is_none());
+    index.insert(Key::from("b"), Payload::from_u32(2)).unwrap();
+}
+
+#[cfg(test)]
+fn flip_last_byte(page_id: Offset) {
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open("index.000").unwrap();
+    let offset = ((page_id.get() + 1) * PAGE_SIZE_USIZE - 1) as u64;
+    let mut byte = [0u8];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[byte[0] ^ 1]).unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_corrupt_pages_fail_their_checksum() {
+    delete_index();
+    let db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    let root = index.root();
+    io::commit();
+    io::close();
+
+    // a bit flipped on the disk is noticed when the page is read.
+    flip_last_byte(root);
+    let index = Index::open().unwrap();
+    let error = index.get(Key::from("a")).unwrap_err();
+    assert!(matches!(error, Error::ChecksumMismatch { .. }));
+    assert!(error.is_corruption() && !error.is_retryable() && !error.is_fatal());
+    assert_eq!(error.page_id(), Some(root));
+    let report = db.poisoned().unwrap();
+    assert_eq!(report.page_id, root);
+    assert!(matches!(
+        report.violation,
+        Violation::ChecksumMismatch { stored, computed } if stored != computed
+    ));
+    // the database isn't failed, the page fails each time it's read.
+    assert!(io::failure().is_none());
+    assert!(load(root).is_err());
+    // by any reader, e.g. a snapshot, which poisons the database as well.
+    poison::clear();
+    assert!(matches!(
+        db.snapshot_to_file("snapshot.test"),
+        Err(Error::ChecksumMismatch { page_id, .. }) if page_id == root
+    ));
+    assert_eq!(db.poisoned().unwrap().page_id, root);
+    let _ = std::fs::remove_file("snapshot.test.tmp");
+}
+
+#[test]
+#[serial]
+fn verify_corrupt_overflow_pages_fail_their_checksum() {
+    delete_index();
+    let db = Db::open().unwrap();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("large"), Payload::from_str("l".repeat(20_000))).unwrap();
+    let overflow = load(index.root()).unwrap().overflow_page_ids(0).unwrap()[1];
+    io::commit();
+    io::close();
+
+    // the value isn't cut short at the corrupt page, its read fails and poisons the database.
+    flip_last_byte(overflow);
+    let index = Index::open().unwrap();
+    assert!(matches!(
+        index.get(Key::from("large")),
+        Err(Error::ChecksumMismatch { page_id, .. }) if page_id == overflow
+    ));
+    assert_eq!(db.poisoned().unwrap().page_id, overflow);
+}
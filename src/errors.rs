@@ -1,4 +1,117 @@
+use crate::crypt::UnknownKey;
+use crate::events::TransactionLimit;
+use crate::io::CorruptPage;
+use crate::types::Offset;
+use std::io::ErrorKind;
+
+// the fields are only read through Debug.
+#[allow(dead_code)]
 #[derive(Debug)]
-pub enum InvalidPageOffsetError {
+pub(crate) enum Error {
     OutOfRange,
-}
\ No newline at end of file
+    UnknownPayloadType(u8),
+    MalformedPayload,
+    ValueChecksumMismatch,
+    ChecksumMismatch { page_id: Offset, lsn: u64 },
+    KeyLayoutMismatch,
+    ImmutableOption,
+    ValueTooLarge { max: usize, got: usize },
+    IndexNotEmpty,
+    UnsortedInput,
+    UnknownFormatVersion(u32),
+    UnsupportedFormatVersion { found: u32, supported: u32 },
+    CorruptConfig { size: u64 },
+    UnsupportedChecksumVersion { found: u64, supported: u64 },
+    UnsupportedPageVersion { page_id: Offset, found: u8, supported: u8 },
+    Locked,
+    Failed(std::io::ErrorKind),
+    DiskFull,
+    Poisoned,
+    UnknownTransaction(u64),
+    UnknownKey(u32),
+    Io(std::io::ErrorKind),
+    Archived,
+    NoSpace { needed: usize, available: usize },
+    Cancelled,
+    CorruptPage { page_id: Offset, lsn: u64 },
+    TransactionAborted(TransactionLimit),
+    Uncommitted,
+}
+
+impl Error {
+    /// Returns true if the operation may succeed when it's tried again later, once the lock is
+    /// released, the disk freed or the interrupted call repeated.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            Error::Locked | Error::DiskFull => true,
+            Error::Io(kind) => matches!(
+                kind,
+                ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is caused by data damaged on the disk rather than by the call.
+    /// Reading a corrupt page poisons the database, see `poison`.
+    pub(crate) fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            Error::ChecksumMismatch { .. }
+                | Error::CorruptPage { .. }
+                | Error::CorruptConfig { .. }
+                | Error::ValueChecksumMismatch
+                | Error::Poisoned
+        )
+    }
+
+    /// Returns true if the database can't be used by this build until it's reopened, checked or
+    /// migrated; retrying the call won't help.
+    pub(crate) fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::Failed(_)
+                | Error::Poisoned
+                | Error::UnknownFormatVersion(_)
+                | Error::UnsupportedFormatVersion { .. }
+                | Error::CorruptConfig { .. }
+                | Error::UnsupportedChecksumVersion { .. }
+                | Error::UnsupportedPageVersion { .. }
+        )
+    }
+
+    /// Returns the page the error was raised for, None if it isn't about a page.
+    pub(crate) fn page_id(&self) -> Option<Offset> {
+        match self {
+            Error::ChecksumMismatch { page_id, .. }
+            | Error::CorruptPage { page_id, .. }
+            | Error::UnsupportedPageVersion { page_id, .. } => Some(*page_id),
+            _ => None,
+        }
+    }
+
+    /// Returns the last log index applied to the database when the corrupt page was read, see
+    /// `raft`. A replica restored from a snapshot catches up by the entries from the snapshot's
+    /// index on. None for other errors.
+    pub(crate) fn lsn(&self) -> Option<u64> {
+        match self {
+            Error::ChecksumMismatch { lsn, .. } | Error::CorruptPage { lsn, .. } => Some(*lsn),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        if let Some(UnknownKey(id)) = error.get_ref().and_then(|inner| inner.downcast_ref()) {
+            return Error::UnknownKey(*id);
+        }
+        if let Some(corrupt) = CorruptPage::of(&error) {
+            return corrupt.violation.error(corrupt.page_id);
+        }
+        match error.kind() {
+            ErrorKind::StorageFull => Error::DiskFull,
+            kind => Error::Io(kind),
+        }
+    }
+}
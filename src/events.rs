@@ -0,0 +1,132 @@
+use crate::errors::Error;
+use crate::poison::CorruptionReport;
+use crate::types::Offset;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// StallReason tells why writers were held up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum StallReason {
+    /// A background writer waited for the IO rate limiter.
+    Throttled,
+    /// Writers waited for a checkpoint to move the shadow pages into the index file.
+    Checkpoint,
+}
+
+/// BulkOperation is a long running operation reporting its progress, see `on_progress`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BulkOperation {
+    BulkLoad,
+    /// The purge of expired tombstones.
+    Purge,
+    /// The export of a snapshot or a tree file.
+    Export,
+    /// The install of a snapshot or the attach of a tree file.
+    Import,
+    Rekey,
+}
+
+/// TransactionLimit is the limit a transaction ran into, see `txn::TransactionLimits`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TransactionLimit {
+    Age,
+    /// The number of keys written.
+    WriteSet,
+}
+
+/// Progress counts the items a bulk operation processed: entries for bulk loads, leaves for
+/// purges, pages for exports and imports, and files for rekeys. The total is an estimate, None if
+/// it isn't known up front.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Progress {
+    pub(crate) done: u64,
+    pub(crate) total: Option<u64>,
+}
+
+/// EventListener receives notifications about the internals of the storage engine, so that they
+/// can be forwarded to the observability stack of the embedder. The callbacks run synchronously on
+/// the thread doing the work and should return quickly. All callbacks default to no-ops.
+pub(crate) trait EventListener: Send + Sync {
+    /// A full page was split, the upper half of its entries moved to the new page.
+    fn on_page_split(&self, _page_id: Offset, _new_page_id: Offset) {}
+
+    /// The entries of a page were merged into the target page, the page is marked deleted.
+    fn on_page_merge(&self, _page_id: Offset, _target_page_id: Offset) {}
+
+    /// A page was evicted from the page cache.
+    fn on_page_evicted(&self, _page_id: Offset) {}
+
+    fn on_checkpoint_start(&self) {}
+
+    fn on_checkpoint_end(&self) {}
+
+    /// The database files were recovered after an unclean shutdown.
+    #[allow(dead_code)]
+    fn on_recovery(&self) {}
+
+    /// A page failed a consistency check while it was read.
+    fn on_corruption(&self, _page_id: Offset, _error: &Error) {}
+
+    /// The database was poisoned by corruption, writes are refused until fsck or repair runs.
+    fn on_poisoned(&self, _report: &CorruptionReport) {}
+
+    /// An IO error which retries didn't resolve failed the database, writes are refused until
+    /// it's reopened. A full disk is reported too, writes resume once it has room again.
+    fn on_failed(&self, _error: &std::io::Error) {}
+
+    /// The disk ran full earlier and has room again. The writes kept in memory since then are on
+    /// the disk, and writes are accepted again.
+    fn on_disk_space_recovered(&self) {}
+
+    /// Writes were held up on purpose for the duration.
+    fn on_write_stall(&self, _reason: StallReason, _duration: Duration) {}
+
+    /// A bulk operation made progress, e.g. to drive a progress bar. Reported after each page or
+    /// file processed.
+    fn on_progress(&self, _operation: BulkOperation, _progress: Progress) {}
+
+    /// A running transaction passed half of the limit, it's aborted once it reaches the limit.
+    /// Reported once per transaction and limit.
+    fn on_long_transaction(&self, _id: u64, _limit: TransactionLimit) {}
+
+    /// A running transaction reached the limit and was aborted, its writes are dropped.
+    fn on_transaction_aborted(&self, _id: u64, _limit: TransactionLimit) {}
+}
+
+/// ListenerId identifies a registered listener, so that it can be removed again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct ListenerId(usize);
+
+type Listeners = Vec<(ListenerId, Arc<dyn EventListener>)>;
+
+static LISTENERS: Lazy<RwLock<Listeners>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+pub(crate) fn register(listener: Arc<dyn EventListener>) -> ListenerId {
+    let mut listeners = LISTENERS.write().unwrap_or_else(|e| e.into_inner());
+    let id = ListenerId(listeners.last().map_or(0, |(id, _)| id.0 + 1));
+    listeners.push((id, listener));
+    id
+}
+
+pub(crate) fn unregister(id: ListenerId) {
+    let mut listeners = LISTENERS.write().unwrap_or_else(|e| e.into_inner());
+    listeners.retain(|(current, _)| *current != id);
+}
+
+/// Calls the event on all registered listeners.
+pub(crate) fn emit(event: impl Fn(&dyn EventListener)) {
+    let listeners = LISTENERS.read().unwrap_or_else(|e| e.into_inner());
+    for (_, listener) in listeners.iter() {
+        event(listener.as_ref());
+    }
+}
+
+/// Reports the progress of the bulk operation to all registered listeners.
+pub(crate) fn progress(operation: BulkOperation, done: usize, total: Option<usize>) {
+    let progress = Progress {
+        done: done as u64,
+        total: total.map(|total| total as u64),
+    };
+    emit(|listener| listener.on_progress(operation, progress));
+}
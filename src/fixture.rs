@@ -0,0 +1,303 @@
+use crate::btree::Index;
+#[cfg(test)]
+use crate::config::CHECKSUM_FORMAT_VERSION;
+use crate::config::{
+    self, get_dictionary_page_id, get_last_applied_index, get_next_page_id, get_root_page_id,
+    get_tree_stats_page_id, FORMAT_VERSION,
+};
+#[cfg(test)]
+use crate::db::Db;
+use crate::errors::Error;
+use crate::freelist;
+use crate::fsck;
+use crate::fsm;
+use crate::hash::HashIndex;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, SlotLayout, PAGE_CHECKSUM_VERSION, ZERO};
+use crate::raft;
+use crate::sequence::Sequence;
+use crate::shard::{self, ShardStats};
+use crate::treestats;
+#[cfg(test)]
+use crate::types::Offset;
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::path::Path;
+
+// Enough keys to split the root, so that fixtures hold inner pages.
+const FIXTURE_KEYS: u32 = 40;
+const FIXTURE_HASH_KEYS: u32 = 20;
+const FIXTURE_SEQUENCE: &str = "fixture";
+const FIXTURE_IDS: u64 = 3;
+const FIXTURE_LOG_INDEX: u64 = 5;
+const FIXTURE_SHARD: usize = 1;
+const FIXTURE_MICRO_SHARD: &str = "fixture";
+// the fixtures written by the builds of earlier versions, a directory per version.
+#[cfg(test)]
+const WRITTEN_FIXTURES_DIR: &str = "tests/fixtures";
+
+fn check_version(version: u32) -> Result<(), Error> {
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(Error::UnknownFormatVersion(version));
+    }
+    Ok(())
+}
+
+// Version 1 predates the key dictionary, its keys are too short to be interned.
+fn fixture_key(version: u32, i: u32) -> String {
+    if version == 1 {
+        format!("{:03}", i)
+    } else {
+        format!("fixture/key/{:03}", i)
+    }
+}
+
+fn hash_key(i: u32) -> String {
+    format!("hash/{:02}", i)
+}
+
+/// Writes the fixture of the format version into the empty database in the working directory.
+/// Fixtures are small databases in the format of a given version, holding the structures which
+/// existed at that version: an index for all versions, separators sharing long prefixes so that
+/// the key dictionary is used from version 2 on, a hash index from 4, a sequence from 5, a free
+/// page from 6, an applied log entry from 7, the tree stats page from 8, a free page in another
+/// free list shard from 9, pages with the slot table at their end from 11, an emptied shard in
+/// the shard catalog from 12 and the free space map from 14. Versions 3, 10, 13 and 15 only added
+/// the key layout, the archive flag, the unused page size and the page checksum version to the
+/// config.
+/// Fixtures created by the current engine only show that it writes and reads the older formats
+/// alike; the fixtures written by the builds of earlier versions are checked in, see `install`,
+/// so that a change breaking their files fails the tests rather than the users upgrading.
+pub(crate) fn create(version: u32) -> Result<(), Error> {
+    check_version(version)?;
+    if get_root_page_id() != ZERO || get_next_page_id() != ZERO {
+        return Err(Error::IndexNotEmpty);
+    }
+    if version >= 11 {
+        config::update_slot_layout(SlotLayout::TableAtEnd);
+    }
+    if version >= 15 {
+        config::update_page_checksum_version(PAGE_CHECKSUM_VERSION);
+    }
+    let mut index = Index::open()?;
+    for i in 0..FIXTURE_KEYS {
+        index.insert(Key::from(fixture_key(version, i).as_str()), Payload::from_u32(i))?;
+    }
+    if version >= 4 {
+        let mut hash_index = HashIndex::open()?;
+        for i in 0..FIXTURE_HASH_KEYS {
+            hash_index.insert(Key::from(hash_key(i).as_str()), Payload::from_u32(i))?;
+        }
+    }
+    if version >= 5 {
+        let mut sequence = Sequence::load(FIXTURE_SEQUENCE)?;
+        for _ in 0..FIXTURE_IDS {
+            sequence.next_id()?;
+        }
+    }
+    if version >= 12 {
+        let key = Key::from("key");
+        shard::insert(&mut index, FIXTURE_MICRO_SHARD, key, Payload::from_u32(0))?;
+        shard::delete(&mut index, FIXTURE_MICRO_SHARD, key)?;
+    }
+    // the stats page and the free space map are written before the free page is pushed, which they
+    // would take otherwise.
+    if version >= 8 {
+        treestats::persist()?;
+    } else {
+        treestats::reset();
+    }
+    if version >= 14 {
+        fsm::persist()?;
+    }
+    if version >= 6 {
+        // the free pages are allocated before they are pushed, which they would be taken from.
+        // Files of versions before 9 only hold the head of the first shard.
+        let allocator = io::page_allocator();
+        let free_page = Page::new_data(&allocator)?.page_id();
+        let shard_page = match version >= 9 {
+            true => Some(Page::new_data(&allocator)?.page_id()),
+            false => None,
+        };
+        freelist::push_to(0, free_page)?;
+        if let Some(page_id) = shard_page {
+            freelist::push_to(FIXTURE_SHARD, page_id)?;
+        }
+    }
+    // files of older versions have no free space map, the commit of the log entry and the one
+    // below would write it.
+    if version < 14 {
+        fsm::reset();
+    }
+    if version >= 7 {
+        raft::apply_log_entry(FIXTURE_LOG_INDEX, &[])?;
+    }
+    if version < 14 {
+        fsm::reset();
+    }
+    io::commit();
+    config::truncate_to_version(version)?;
+    Ok(())
+}
+
+/// Reads the database in the working directory as the fixture of the format version, and fails
+/// with MalformedPayload unless its config is of the version and it holds the fixture's contents.
+pub(crate) fn load(version: u32) -> Result<(), Error> {
+    check_version(version)?;
+    expect(config::file_size()? <= config::size_of_version(version))?;
+    let checksum_version = if version >= 15 { PAGE_CHECKSUM_VERSION } else { 0 };
+    expect(config::get_page_checksum_version() == checksum_version)?;
+    check(version)
+}
+
+/// Fails with MalformedPayload unless the database in the working directory holds the contents of
+/// the fixture of the format version, whatever the version of its config, e.g. once it's upgraded.
+pub(crate) fn check(version: u32) -> Result<(), Error> {
+    check_version(version)?;
+    let index = Index::open()?;
+    let mut keys = 0;
+    for (i, entry) in (0..).zip(index.scan(..)?) {
+        let (key, payload) = entry?;
+        expect(key == fixture_key(version, i).as_bytes())?;
+        expect(*payload.to_bytes() == i.to_le_bytes())?;
+        keys += 1;
+    }
+    expect(keys == FIXTURE_KEYS)?;
+    expect((get_dictionary_page_id() != ZERO) == (version >= 2))?;
+    if version >= 4 {
+        let hash_index = HashIndex::open()?;
+        for i in 0..FIXTURE_HASH_KEYS {
+            let payload = hash_index.get(Key::from(hash_key(i).as_str()))?;
+            expect(payload.is_some_and(|payload| *payload.to_bytes() == i.to_le_bytes()))?;
+        }
+    }
+    if version >= 5 {
+        expect(Sequence::load(FIXTURE_SEQUENCE)?.peek_id() >= FIXTURE_IDS)?;
+    }
+    expect(freelist::pages()?.1.len() == usize::from(version >= 6) + usize::from(version >= 9))?;
+    expect((config::get_free_list_page_id(FIXTURE_SHARD) != ZERO) == (version >= 9))?;
+    let log_index = if version >= 7 { FIXTURE_LOG_INDEX } else { 0 };
+    expect(get_last_applied_index() == log_index)?;
+    expect((get_tree_stats_page_id() != ZERO) == (version >= 8))?;
+    expect(!config::get_archived())?;
+    expect((config::get_slot_layout() == SlotLayout::TableAtEnd) == (version >= 11))?;
+    expect((config::get_shard_catalog_page_id() != ZERO) == (version >= 12))?;
+    expect((config::get_free_space_map_page_id() != ZERO) == (version >= 14))?;
+    let micro_shard = (version >= 12).then(ShardStats::default);
+    expect(shard::stats(FIXTURE_MICRO_SHARD)? == micro_shard)?;
+    expect(treestats::stats()?.entries == u64::from(FIXTURE_KEYS))?;
+    expect(fsck::check(false)?.orphans.is_empty())
+}
+
+fn expect(holds: bool) -> Result<(), Error> {
+    if holds {
+        Ok(())
+    } else {
+        Err(Error::MalformedPayload)
+    }
+}
+
+/// Copies the fixture of the format version written by an earlier build into the empty working
+/// directory, see tests/fixtures/README.md.
+#[cfg(test)]
+pub(crate) fn install(version: u32) -> std::io::Result<()> {
+    for file in fs::read_dir(Path::new(WRITTEN_FIXTURES_DIR).join(format!("v{}", version)))? {
+        let file = file?;
+        fs::copy(file.path(), file.file_name())?;
+    }
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn verify_fixtures_written_by_earlier_builds() {
+    for version in 1..FORMAT_VERSION {
+        delete_index();
+        install(version).unwrap();
+        // the configs of versions 1 to 5 end with the bytes of their last field.
+        assert_eq!(config::file_version().unwrap(), Some(version));
+        // the pages of files of older versions can't be read before they're widened.
+        if version >= CHECKSUM_FORMAT_VERSION {
+            load(version).unwrap();
+        }
+        // opening the database upgrades it, its contents are left as they are.
+        let db = Db::open().unwrap();
+        assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+        assert_eq!(db.tree_stats().unwrap().entries, u64::from(FIXTURE_KEYS));
+        let index = Index::open().unwrap();
+        for i in 0..FIXTURE_KEYS {
+            let payload = index.get(Key::from(fixture_key(version, i).as_str())).unwrap();
+            assert_eq!(*payload.unwrap().to_bytes(), i.to_le_bytes());
+        }
+        check(version).unwrap();
+        // reopened, the upgraded files are read as they were written.
+        io::close();
+        Db::open().unwrap();
+        check(version).unwrap();
+    }
+    io::close();
+}
+
+#[test]
+#[serial]
+fn verify_slot_tables_at_the_end_survive_the_upgrade() {
+    delete_index();
+    // written by the build of version 11, whose pages carried no checksum.
+    install(11).unwrap();
+    Db::open().unwrap();
+    let slotted = |page: &Page| page.is_slotted() && page.num_of_slots() != ZERO;
+    let layouts = || {
+        Offset(1)
+            .through(get_next_page_id())
+            .map(|page_id| (page_id, Page::new_from(io::read_stored(page_id).unwrap())))
+            // pages which were never written, e.g. the free pages, read as zeroes.
+            .filter(|(page_id, page)| page.page_id() == *page_id && slotted(page))
+            .map(|(_, page)| page.slot_layout())
+            .collect::<Vec<_>>()
+    };
+    let widened = layouts();
+    assert!(!widened.is_empty());
+    assert!(widened.iter().all(|layout| *layout == SlotLayout::TableAtEnd));
+    // the widened pages take new keys, which split them into pages of the same layout.
+    let mut index = Index::open().unwrap();
+    for i in FIXTURE_KEYS..3 * FIXTURE_KEYS {
+        index.insert(Key::from(fixture_key(11, i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    io::commit();
+    io::close();
+    Db::open().unwrap();
+    assert!(layouts().len() > widened.len());
+    assert!(layouts().iter().all(|layout| *layout == SlotLayout::TableAtEnd));
+    let index = Index::open().unwrap();
+    for i in 0..3 * FIXTURE_KEYS {
+        let payload = index.get(Key::from(fixture_key(11, i).as_str())).unwrap();
+        assert_eq!(*payload.unwrap().to_bytes(), i.to_le_bytes());
+    }
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+    io::close();
+}
+
+#[test]
+#[serial]
+fn verify_fixtures_of_every_version() {
+    for version in 1..=FORMAT_VERSION {
+        delete_index();
+        create(version).unwrap();
+        io::close();
+        load(version).unwrap();
+        assert!(config::file_size().unwrap() <= config::size_of_version(version));
+    }
+    // the fixture of the latest version isn't readable as an older one.
+    assert!(matches!(load(1), Err(Error::MalformedPayload)));
+    assert!(matches!(
+        load(FORMAT_VERSION + 1),
+        Err(Error::UnknownFormatVersion(_))
+    ));
+    assert!(matches!(create(1), Err(Error::IndexNotEmpty)));
+}
@@ -0,0 +1,206 @@
+use crate::btree::load;
+use crate::config::{get_free_list_page_id, update_free_list_page_id, FREE_LIST_SHARDS};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::config::get_next_page_id;
+#[cfg(test)]
+use crate::fsck;
+use crate::fsm;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::pagetrace;
+use crate::pins;
+use crate::paging::{Page, ZERO};
+use crate::types::Offset;
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use std::collections::HashSet;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+static SHARDS: [Mutex<()>; FREE_LIST_SHARDS] = [const { Mutex::new(()) }; FREE_LIST_SHARDS];
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // threads are spread over the shards in the order they first touch the free list.
+    static HOME_SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % FREE_LIST_SHARDS;
+}
+
+fn lock(shard: usize) -> MutexGuard<'static, ()> {
+    SHARDS[shard].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn home_shard() -> usize {
+    HOME_SHARD.with(|shard| *shard)
+}
+
+/// The free list holds the ids of pages which can be allocated again. It's sharded, so that
+/// threads splitting pages at the same time don't wait for each other: each shard is a chain of
+/// free list pages linked through their right siblings, starting with the head recorded in the
+/// config, and is guarded by a lock of its own. Threads push to and pop from the shard they are
+/// assigned to, and pop from the other shards once theirs is empty. An emptied head page is
+/// allocated itself, so the free list never holds empty pages. Pages pinned by open cursors are
+/// pushed once the cursors unpin them, see `pins`.
+pub(crate) fn push(page_id: Offset) -> Result<(), Error> {
+    push_to(home_shard(), page_id)
+}
+
+/// Pushes the pages, e.g. the overflow pages of a slot which was deleted or replaced.
+pub(crate) fn push_all(page_ids: &[Offset]) -> Result<(), Error> {
+    page_ids.iter().try_for_each(|page_id| push(*page_id))
+}
+
+/// Pushes the page to the shard rather than the one of the thread.
+pub(crate) fn push_to(shard: usize, page_id: Offset) -> Result<(), Error> {
+    pagetrace::freed(page_id);
+    let (pinned, unpinned) = pins::pin_freed(page_id);
+    release(&unpinned)?;
+    if pinned {
+        return Ok(());
+    }
+    push_unpinned(shard, page_id)
+}
+
+/// Pushes the pages unpinned by the cursors.
+pub(crate) fn release(page_ids: &[Offset]) -> Result<(), Error> {
+    let shard = home_shard();
+    page_ids.iter().try_for_each(|page_id| push_unpinned(shard, *page_id))
+}
+
+fn push_unpinned(shard: usize, page_id: Offset) -> Result<(), Error> {
+    fsm::clear(page_id);
+    let _guard = lock(shard);
+    let head_id = get_free_list_page_id(shard);
+    if head_id != ZERO {
+        let mut head = load(head_id)?;
+        if !head.is_full()? {
+            head.push_free_page(page_id)?;
+            io::write(&head);
+            return Ok(());
+        }
+    }
+    let mut new_head = Page::new_free_list_head(&io::page_allocator())?;
+    new_head.set_right_sibling(get_free_list_page_id(shard));
+    update_free_list_page_id(shard, new_head.page_id());
+    new_head.push_free_page(page_id)?;
+    io::write(&new_head);
+    Ok(())
+}
+
+/// Takes a page from the free list, None if it is empty.
+pub(crate) fn pop() -> Result<Option<Offset>, Error> {
+    let home = home_shard();
+    // only one shard is locked at a time, so that threads falling back to other shards can't
+    // deadlock.
+    for shard in (home..FREE_LIST_SHARDS).chain(0..home) {
+        if let Some(page_id) = pop_from(shard)? {
+            return Ok(Some(page_id));
+        }
+    }
+    Ok(None)
+}
+
+fn pop_from(shard: usize) -> Result<Option<Offset>, Error> {
+    let _guard = lock(shard);
+    let head_id = get_free_list_page_id(shard);
+    if head_id == ZERO {
+        return Ok(None);
+    }
+    let mut head = load(head_id)?;
+    match head.pop_free_page() {
+        Some(page_id) => {
+            io::write(&head);
+            Ok(Some(page_id))
+        }
+        None => {
+            update_free_list_page_id(shard, head.right_sibling());
+            Ok(Some(head_id))
+        }
+    }
+}
+
+/// Returns the ids of the free list pages and the ids of the free pages of all shards. A chain
+/// running in a loop is followed until it returns to a page seen before, see `looping_shards`.
+pub(crate) fn pages() -> Result<(Vec<Offset>, Vec<Offset>), Error> {
+    let (mut list_pages, mut free_pages) = (Vec::new(), Vec::new());
+    for shard in 0..FREE_LIST_SHARDS {
+        let (shard_list_pages, shard_free_pages, _) = walk(shard)?;
+        list_pages.extend(shard_list_pages);
+        free_pages.extend(shard_free_pages);
+    }
+    Ok((list_pages, free_pages))
+}
+
+/// Returns the shards whose chain of free list pages runs in a loop instead of ending, e.g. after
+/// a page was pushed to the free list while it was on it already.
+pub(crate) fn looping_shards() -> Result<Vec<usize>, Error> {
+    let mut shards = Vec::new();
+    for shard in 0..FREE_LIST_SHARDS {
+        if walk(shard)?.2 {
+            shards.push(shard);
+        }
+    }
+    Ok(shards)
+}
+
+// Follows the chain of the shard until it ends or returns to a page seen before. Returns the free
+// list pages, the free pages and whether the chain loops.
+fn walk(shard: usize) -> Result<(Vec<Offset>, Vec<Offset>, bool), Error> {
+    let (mut list_pages, mut free_pages) = (Vec::new(), Vec::new());
+    let mut seen = BTreeSet::new();
+    let mut next = get_free_list_page_id(shard);
+    while next != ZERO {
+        if !seen.insert(next) {
+            return Ok((list_pages, free_pages, true));
+        }
+        let page = load(next)?;
+        list_pages.push(next);
+        free_pages.extend((0..page.num_of_slots().get()).map(|i| page.free_page_at(i)));
+        next = page.right_sibling();
+    }
+    Ok((list_pages, free_pages, false))
+}
+
+#[test]
+#[serial]
+fn verify_shards_are_allocated_from_concurrently() {
+    delete_index();
+    // pages allocated and freed again by threads spread over the shards.
+    let churn = || {
+        let allocator = io::page_allocator();
+        let allocate = || Page::new_data(&allocator).unwrap().page_id();
+        let pages: Vec<Offset> = (0..40).map(|_| allocate()).collect();
+        for page_id in &pages {
+            push(*page_id).unwrap();
+        }
+        let pages: Vec<Offset> = (0..40).map(|_| allocate()).collect();
+        pages
+    };
+    let threads: Vec<_> = (0..FREE_LIST_SHARDS).map(|_| std::thread::spawn(churn)).collect();
+    let allocated: Vec<Offset> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+    // no page was handed out twice.
+    let unique: HashSet<Offset> = allocated.iter().copied().collect();
+    assert_eq!(unique.len(), allocated.len());
+    for page_id in allocated {
+        push(page_id).unwrap();
+    }
+    // every page allocated is back on the free list, as a free page or a free list page.
+    let (list_pages, free_pages) = pages().unwrap();
+    assert_eq!(list_pages.len() + free_pages.len(), get_next_page_id().get());
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    // a thread whose shard is empty pops from the others.
+    delete_index();
+    let page_id = Page::new_data(&io::page_allocator()).unwrap().page_id();
+    let shard = (home_shard() + 1) % FREE_LIST_SHARDS;
+    push_to(shard, page_id).unwrap();
+    let head = get_free_list_page_id(shard);
+    assert_ne!(head, ZERO);
+    assert_eq!(pop().unwrap(), Some(page_id));
+    assert_eq!(pop().unwrap(), Some(head));
+    assert_eq!(get_free_list_page_id(shard), ZERO);
+    assert_eq!(pop().unwrap(), None);
+}
@@ -0,0 +1,259 @@
+use crate::btree::{children, load, misbounded_pages};
+use crate::cancel::CancellationToken;
+use crate::config::{
+    get_dictionary_page_id, get_hash_directory_page_id, get_next_page_id, get_root_page_id,
+    get_sequence_page_id, get_shard_catalog_page_id, get_tree_stats_page_id,
+};
+use crate::errors::Error;
+use crate::freelist;
+use crate::fsm;
+#[cfg(test)]
+use crate::btree::Index;
+#[cfg(test)]
+use crate::config::get_free_list_page_id;
+#[cfg(test)]
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, ZERO};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::BTreeSet;
+
+/// FsckReport lists the pages which are neither reachable from any root in the config nor on the
+/// free list, leaked by crashes or bugs, and the pages of the tree whose high key contradicts the
+/// separators of their parents. The free list is checked as well, as a broken free list goes
+/// unnoticed until a page in use is allocated again and overwritten: the pages on the free list
+/// which are reachable from a root too, the shards whose chain of free list pages loops, and the
+/// pages referenced past the last page allocated, the next page id in the config.
+#[derive(Debug, Default)]
+pub(crate) struct FsckReport {
+    pub(crate) orphans: Vec<Offset>,
+    #[allow(dead_code)]
+    pub(crate) misbounded: Vec<Offset>,
+    pub(crate) free_but_used: Vec<Offset>,
+    pub(crate) looping_free_lists: Vec<usize>,
+    pub(crate) unallocated: Vec<Offset>,
+    pub(crate) reclaimed: bool,
+}
+
+impl FsckReport {
+    /// Returns true if the free list can be trusted to hand out unused pages only. Orphans are
+    /// leaked pages, which waste room without damaging anything.
+    pub(crate) fn free_list_is_sound(&self) -> bool {
+        self.free_but_used.is_empty()
+            && self.looping_free_lists.is_empty()
+            && self.unallocated.is_empty()
+    }
+}
+
+/// Walks all structures of the database and reports the orphan pages, which are returned to the
+/// free list if reclaim is set, and the misbounded pages and the damage to the free list, which
+/// are left as they are. Orphans aren't reclaimed while the free list is damaged.
+pub(crate) fn check(reclaim: bool) -> Result<FsckReport, Error> {
+    check_cancellable(reclaim, &CancellationToken::new())
+}
+
+/// Checks the database like `check` until the token is cancelled, which is checked before each
+/// page is walked. Nothing is reclaimed unless the walk completed.
+pub(crate) fn check_cancellable(
+    reclaim: bool,
+    cancel: &CancellationToken,
+) -> Result<FsckReport, Error> {
+    let last = get_next_page_id();
+    let used = walk_roots(last, cancel)?;
+    let free = free_pages()?;
+    let reachable: BTreeSet<Offset> = used.union(&free).copied().collect();
+    // page ids are allocated from one on.
+    let orphans: Vec<Offset> = Offset(1)
+        .through(last)
+        .filter(|page_id| !reachable.contains(page_id))
+        .collect();
+    let mut report = FsckReport {
+        orphans,
+        misbounded: misbounded_pages(get_root_page_id())?,
+        free_but_used: used.intersection(&free).copied().collect(),
+        looping_free_lists: freelist::looping_shards()?,
+        unallocated: reachable.iter().filter(|page_id| **page_id > last).copied().collect(),
+        reclaimed: false,
+    };
+    if reclaim && report.free_list_is_sound() {
+        for page_id in &report.orphans {
+            freelist::push(*page_id)?;
+        }
+        report.reclaimed = true;
+    }
+    Ok(report)
+}
+
+/// Returns the pages reachable from the roots in the config, including the free list.
+pub(crate) fn reachable() -> Result<BTreeSet<Offset>, Error> {
+    let mut reachable = walk_roots(get_next_page_id(), &CancellationToken::new())?;
+    reachable.extend(free_pages()?);
+    Ok(reachable)
+}
+
+// The free list pages and the free pages on them.
+fn free_pages() -> Result<BTreeSet<Offset>, Error> {
+    let (list_pages, free_pages) = freelist::pages()?;
+    Ok(list_pages.into_iter().chain(free_pages).collect())
+}
+
+// Returns the pages reachable from the roots in the config, the free list aside. Pages past the
+// last one allocated are marked without being read.
+fn walk_roots(
+    last: Offset,
+    cancel: &CancellationToken,
+) -> Result<BTreeSet<Offset>, Error> {
+    let mut reachable = BTreeSet::new();
+    mark_tree(get_root_page_id(), last, &mut reachable, cancel)?;
+    let heads = [
+        get_dictionary_page_id(),
+        get_sequence_page_id(),
+        get_tree_stats_page_id(),
+        get_shard_catalog_page_id(),
+    ];
+    for head in heads {
+        mark_chain(head, last, &mut reachable, cancel)?;
+    }
+    let directory_id = get_hash_directory_page_id();
+    if directory_id != ZERO {
+        reachable.insert(directory_id);
+        let directory = load(directory_id)?;
+        for i in 0..directory.num_of_slots().get() {
+            mark_chain(directory.directory_entry_at(i).0, last, &mut reachable, cancel)?;
+        }
+    }
+    reachable.extend(fsm::pages()?);
+    Ok(reachable)
+}
+
+fn mark_tree(
+    root: Offset,
+    last: Offset,
+    reachable: &mut BTreeSet<Offset>,
+    cancel: &CancellationToken,
+) -> Result<(), Error> {
+    let mut pending = vec![root];
+    while let Some(page_id) = pending.pop() {
+        if page_id == ZERO || !reachable.insert(page_id) || page_id > last {
+            continue;
+        }
+        cancel.check()?;
+        let page = load(page_id)?;
+        if page.is_leaf() {
+            mark_overflow_pages(&page, reachable)?;
+        } else {
+            pending.extend(children(&page)?);
+        }
+    }
+    Ok(())
+}
+
+// Chains of data pages linked through their right siblings.
+fn mark_chain(
+    head: Offset,
+    last: Offset,
+    reachable: &mut BTreeSet<Offset>,
+    cancel: &CancellationToken,
+) -> Result<(), Error> {
+    let mut next = head;
+    while next != ZERO && reachable.insert(next) && next <= last {
+        cancel.check()?;
+        let page = load(next)?;
+        mark_overflow_pages(&page, reachable)?;
+        next = page.right_sibling();
+    }
+    Ok(())
+}
+
+fn mark_overflow_pages(page: &Page, reachable: &mut BTreeSet<Offset>) -> Result<(), Error> {
+    for i in 0..page.num_of_slots().get() {
+        reachable.extend(page.overflow_page_ids(i)?);
+    }
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn verify_orphans_are_reclaimed() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    index
+        .insert(Key::from("large"), Payload::from_str("x".repeat(20_000)))
+        .unwrap();
+    assert!(check(false).unwrap().orphans.is_empty());
+
+    let leaked = Page::new_data(&io::page_allocator()).unwrap();
+    io::write(&leaked);
+    let report = check(true).unwrap();
+    assert_eq!(report.orphans, vec![leaked.page_id()]);
+    assert!(check(false).unwrap().orphans.is_empty());
+    // the reclaimed page is allocated again.
+    assert_eq!(Page::new_data(&io::page_allocator()).unwrap().page_id(), leaked.page_id());
+}
+
+#[test]
+#[serial]
+fn verify_high_keys_are_checked_against_separators() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..200u32 {
+        let key = format!("bounded/key/{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert!(check(false).unwrap().misbounded.is_empty());
+
+    let mut leaf = load(index.root()).unwrap();
+    while !leaf.is_leaf() {
+        leaf = load(leaf.left_most_page_id()).unwrap();
+    }
+    leaf.set_high_key(Some(b"bounded/key/999")).unwrap();
+    io::write(&leaf);
+    assert_eq!(check(false).unwrap().misbounded, vec![leaf.page_id()]);
+}
+
+#[test]
+#[serial]
+fn verify_the_free_list_is_checked() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    freelist::push_to(0, Page::new_data(&io::page_allocator()).unwrap().page_id()).unwrap();
+    assert!(check(false).unwrap().free_list_is_sound());
+
+    // a page of the tree pushed to the free list, orphans aren't reclaimed then.
+    let leaked = Page::new_data(&io::page_allocator()).unwrap();
+    io::write(&leaked);
+    freelist::push_to(0, index.root()).unwrap();
+    let report = check(true).unwrap();
+    assert_eq!(report.free_but_used, vec![index.root()]);
+    assert_eq!(report.orphans, vec![leaked.page_id()]);
+    assert!(!report.reclaimed);
+
+    // a chain of free list pages looping back to its head.
+    delete_index();
+    freelist::push_to(1, Page::new_data(&io::page_allocator()).unwrap().page_id()).unwrap();
+    let mut head = load(get_free_list_page_id(1)).unwrap();
+    head.set_right_sibling(head.page_id());
+    io::write(&head);
+    assert_eq!(check(false).unwrap().looping_free_lists, vec![1]);
+
+    // a page past the last one allocated.
+    delete_index();
+    let unallocated = get_next_page_id().checked_add(5).unwrap();
+    freelist::push_to(0, unallocated).unwrap();
+    let report = check(false).unwrap();
+    assert_eq!(report.unallocated, vec![unallocated]);
+    assert!(report.free_but_used.is_empty() && report.looping_free_lists.is_empty());
+}
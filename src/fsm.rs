@@ -0,0 +1,211 @@
+use crate::btree::load;
+#[cfg(test)]
+use crate::btree::Index;
+use crate::config::{get_free_space_map_page_id, update_free_space_map_page_id};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, FREE_SPACE_MAP_CAPACITY, FREE_SPACE_UNIT, ZERO};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::sync::Mutex;
+
+enum State {
+    // not read since the database was opened, or dropped by a rollback. Writes aren't recorded.
+    Unloaded,
+    // kept up to date by the writes, dirty until it's written at the next checkpoint.
+    Loaded { map: Vec<u8>, dirty: bool },
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::Unloaded));
+
+fn lock() -> std::sync::MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The free space map records the free bytes of each page, rounded down to a `FREE_SPACE_UNIT`,
+/// so that inserts and compaction can pick pages with room without reading them. It's held in
+/// memory and updated by every page written, and written into a chain of free space map pages
+/// linked through their right siblings at each checkpoint, starting with the page recorded in the
+/// config. The n-th page of the chain holds the pages from n * `FREE_SPACE_MAP_CAPACITY` on.
+/// Pages written while the map isn't loaded aren't recorded, the map is approximate.
+pub(crate) fn open() -> Result<(), Error> {
+    let mut state = lock();
+    if matches!(*state, State::Unloaded) {
+        *state = State::Loaded {
+            map: read()?,
+            dirty: false,
+        };
+    }
+    Ok(())
+}
+
+fn read() -> Result<Vec<u8>, Error> {
+    let mut map = Vec::new();
+    let mut next = get_free_space_map_page_id();
+    while next != ZERO {
+        let page = load(next)?;
+        map.extend((0..FREE_SPACE_MAP_CAPACITY).map(|index| page.free_space_at(index)));
+        next = page.right_sibling();
+    }
+    Ok(map)
+}
+
+/// Records the free space of the page written. Only pages with a slot table have room for keys,
+/// the others are recorded as full, and so are pages whose slot table ran into their slots. The
+/// pages of the map itself aren't recorded.
+pub(crate) fn record(page: &Page) {
+    if page.is_free_space_map() {
+        return;
+    }
+    let free_size = page.free_size().map_or(0, |size| size.get());
+    let free_bytes = if page.is_slotted() { free_size } else { 0 };
+    set(page.page_id(), free_bytes);
+}
+
+/// Records the freed page as full, so that it isn't picked until it's allocated and written.
+pub(crate) fn clear(page_id: Offset) {
+    set(page_id, 0);
+}
+
+fn set(page_id: Offset, free_bytes: usize) {
+    let mut state = lock();
+    let State::Loaded { map, dirty } = &mut *state else {
+        return;
+    };
+    let units = (free_bytes / FREE_SPACE_UNIT) as u8;
+    let index = page_id.get();
+    if index >= map.len() {
+        if units == 0 {
+            return;
+        }
+        map.resize(index + 1, 0);
+    }
+    if map[index] != units {
+        map[index] = units;
+        *dirty = true;
+    }
+}
+
+/// Returns the free bytes of the page as recorded, None if the map isn't loaded.
+#[allow(dead_code)]
+pub(crate) fn free_space(page_id: Offset) -> Option<usize> {
+    match &*lock() {
+        State::Loaded { map, .. } => {
+            let units = map.get(page_id.get()).copied().unwrap_or(0);
+            Some(usize::from(units) * FREE_SPACE_UNIT)
+        }
+        State::Unloaded => None,
+    }
+}
+
+/// Returns the first page with at least the given free bytes as recorded.
+#[allow(dead_code)]
+pub(crate) fn find(free_bytes: usize) -> Option<Offset> {
+    let State::Loaded { map, .. } = &*lock() else {
+        return None;
+    };
+    let units = free_bytes.div_ceil(FREE_SPACE_UNIT);
+    map.iter()
+        .position(|recorded| usize::from(*recorded) >= units.max(1))
+        .map(Offset::from_usize)
+}
+
+/// Returns the free bytes of all pages as recorded, None if the map isn't loaded.
+#[allow(dead_code)]
+pub(crate) fn total() -> Option<usize> {
+    match &*lock() {
+        State::Loaded { map, .. } => {
+            Some(map.iter().map(|units| usize::from(*units) * FREE_SPACE_UNIT).sum())
+        }
+        State::Unloaded => None,
+    }
+}
+
+/// Writes the map into its pages if it changed since the last checkpoint, extending the chain
+/// as the map grows. Called by the checkpoint. The map is copied first, the pages allocated for it
+/// write the free list, which is recorded.
+pub(crate) fn persist() -> Result<(), Error> {
+    let map = match &mut *lock() {
+        State::Loaded { map, dirty } if *dirty => {
+            *dirty = false;
+            map.clone()
+        }
+        _ => return Ok(()),
+    };
+    // each page is written once the page following it is linked.
+    let mut previous: Option<Page> = None;
+    let mut next = get_free_space_map_page_id();
+    for chunk in map.chunks(FREE_SPACE_MAP_CAPACITY) {
+        let mut page = match next {
+            ZERO => {
+                let page = Page::new_free_space_map(&io::page_allocator())?;
+                match previous.as_mut() {
+                    Some(previous) => previous.set_right_sibling(page.page_id()),
+                    None => update_free_space_map_page_id(page.page_id()),
+                }
+                page
+            }
+            page_id => load(page_id)?,
+        };
+        if let Some(previous) = previous.take() {
+            io::write(&previous);
+        }
+        for (index, units) in chunk.iter().enumerate() {
+            page.set_free_space(index, *units);
+        }
+        next = page.right_sibling();
+        previous = Some(page);
+    }
+    if let Some(previous) = previous {
+        io::write(&previous);
+    }
+    Ok(())
+}
+
+/// Returns the pages of the map.
+pub(crate) fn pages() -> Result<Vec<Offset>, Error> {
+    let mut pages = Vec::new();
+    let mut next = get_free_space_map_page_id();
+    while next != ZERO {
+        pages.push(next);
+        next = load(next)?.right_sibling();
+    }
+    Ok(pages)
+}
+
+/// Drops the map held in memory, it's read again from the last checkpoint when it's opened.
+pub(crate) fn reset() {
+    *lock() = State::Unloaded;
+}
+
+#[test]
+#[serial]
+fn verify_free_space_is_recorded_by_writes() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let root = index.root();
+    let empty = free_space(root).unwrap();
+    assert!(empty > 0);
+    index.insert(Key::from("a"), Payload::from_str("x".repeat(1000))).unwrap();
+    assert!(free_space(root).unwrap() <= empty - 1000 + FREE_SPACE_UNIT);
+    assert_eq!(find(empty + 1), None);
+    assert_eq!(find(100), Some(root));
+    let recorded = free_space(root).unwrap();
+
+    // the map is written at the checkpoint and read back.
+    io::commit();
+    assert_eq!(pages().unwrap().len(), 1);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+    io::close();
+    Index::open().unwrap();
+    assert_eq!(free_space(root), Some(recorded));
+    assert!(total().unwrap() >= recorded);
+}
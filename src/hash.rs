@@ -0,0 +1,245 @@
+use crate::allocator::PageAllocator;
+use crate::btree::load;
+use crate::config::{get_hash_directory_page_id, update_hash_directory_page_id};
+use crate::errors::Error;
+use crate::freelist;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, DIRECTORY_CAPACITY, MAX_KEY_SIZE, ZERO};
+use crate::types::{Key, Offset, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::sync::Arc;
+
+// The directory must fit into a single page.
+const MAX_GLOBAL_DEPTH: u32 = DIRECTORY_CAPACITY.ilog2();
+
+/// HashIndex is an extendible hashing index for workloads which only look up single keys and don't
+/// need them in order. The directory maps the lowest global depth bits of the key hash to bucket
+/// pages, which are slotted data pages like the leaves of the tree. A full bucket is split on its
+/// next hash bit, doubling the directory first if the bucket is referenced by a single entry. Once
+/// the directory can't grow anymore, full buckets are chained through their right siblings.
+pub(crate) struct HashIndex {
+    directory: Page,
+    // allocates the buckets, the one of the database the index was opened in.
+    allocator: Arc<PageAllocator>,
+}
+
+impl HashIndex {
+    /// Opens the hash index persisted in the database files, or creates one with a single bucket.
+    pub(crate) fn open() -> Result<Self, Error> {
+        let directory_id = get_hash_directory_page_id();
+        let allocator = io::page_allocator();
+        if directory_id != ZERO {
+            return Ok(HashIndex {
+                directory: load(directory_id)?,
+                allocator,
+            });
+        }
+        let bucket = Page::new_data(&allocator)?;
+        io::write(&bucket);
+        let mut directory = Page::new_hash_directory(&allocator)?;
+        directory.set_directory_entry(0, bucket.page_id(), 0)?;
+        io::write(&directory);
+        update_hash_directory_page_id(directory.page_id());
+        Ok(HashIndex { directory, allocator })
+    }
+
+    pub(crate) fn global_depth(&self) -> u32 {
+        self.directory.num_of_slots().get().trailing_zeros()
+    }
+
+    pub(crate) fn get(&self, key: Key) -> Result<Option<Payload>, Error> {
+        let mut next = self.bucket_for(key).0;
+        while next != ZERO {
+            let bucket = load(next)?;
+            if let Some(index) = bucket.find_slot(key)? {
+                return Ok(Some(bucket.value_at(index)?));
+            }
+            next = bucket.right_sibling();
+        }
+        Ok(None)
+    }
+
+    /// Inserts the key-payload pair, replacing the payload if the key exists.
+    pub(crate) fn insert(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let result = self.insert_into_bucket(key, payload);
+        io::check_writable()?;
+        result
+    }
+
+    fn insert_into_bucket(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::OutOfRange);
+        }
+        self.delete_from_bucket(key)?;
+        loop {
+            let (bucket_id, local_depth) = self.bucket_for(key);
+            let mut bucket = load(bucket_id)?;
+            if !bucket.is_full()? {
+                bucket.add(key, payload)?;
+                return Ok(());
+            }
+            if u32::from(local_depth) == self.global_depth() {
+                if self.global_depth() == MAX_GLOBAL_DEPTH {
+                    return append_to_chain(bucket, key, payload, &self.allocator);
+                }
+                self.grow()?;
+            }
+            self.split(&bucket, local_depth)?;
+        }
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn delete(&mut self, key: Key) -> Result<bool, Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let result = self.delete_from_bucket(key);
+        io::check_writable()?;
+        result
+    }
+
+    fn delete_from_bucket(&mut self, key: Key) -> Result<bool, Error> {
+        let mut next = self.bucket_for(key).0;
+        while next != ZERO {
+            let mut bucket = load(next)?;
+            if let Some(index) = bucket.find_slot(key)? {
+                let overflow_page_ids = bucket.overflow_page_ids(index)?;
+                bucket.delete_slot(index)?;
+                io::write(&bucket);
+                freelist::push_all(&overflow_page_ids)?;
+                return Ok(true);
+            }
+            next = bucket.right_sibling();
+        }
+        Ok(false)
+    }
+
+    fn bucket_for(&self, key: Key) -> (Offset, u8) {
+        let mask = self.directory.num_of_slots().get() - 1;
+        self.directory
+            .directory_entry_at(hash(key.as_bytes()) as usize & mask)
+    }
+
+    // Doubles the directory, the new upper half references the same buckets as the lower half.
+    fn grow(&mut self) -> Result<(), Error> {
+        let len = self.directory.num_of_slots().get();
+        for i in 0..len {
+            let (bucket, local_depth) = self.directory.directory_entry_at(i);
+            self.directory.set_directory_entry(i + len, bucket, local_depth)?;
+        }
+        io::write(&self.directory);
+        Ok(())
+    }
+
+    /// Splits the bucket on the hash bit following its local depth. The bucket keeps its page id for
+    /// the keys with the bit cleared, the directory entries with the bit set move to a new bucket.
+    fn split(&mut self, bucket: &Page, local_depth: u8) -> Result<(), Error> {
+        let bit = 1usize << local_depth;
+        let mut low = Page::new_page(bucket.page_type(), bucket.page_id());
+        low.set_slot_layout(bucket.slot_layout())?;
+        let mut high = Page::new_data(&self.allocator)?;
+        for i in 0..bucket.num_of_slots().get() {
+            let target = if hash(&bucket.key_at(i)?) as usize & bit == 0 {
+                &mut low
+            } else {
+                &mut high
+            };
+            target.push_slot(&bucket.slot_at(i)?)?;
+        }
+        io::write(&low);
+        io::write(&high);
+        for i in 0..self.directory.num_of_slots().get() {
+            if self.directory.directory_entry_at(i).0 == bucket.page_id() {
+                let target = if i & bit == 0 { low.page_id() } else { high.page_id() };
+                self.directory.set_directory_entry(i, target, local_depth + 1)?;
+            }
+        }
+        io::write(&self.directory);
+        Ok(())
+    }
+}
+
+fn append_to_chain(
+    mut bucket: Page,
+    key: Key,
+    payload: Payload,
+    allocator: &PageAllocator,
+) -> Result<(), Error> {
+    while bucket.is_full()? {
+        if bucket.right_sibling() == ZERO {
+            let mut next = Page::new_data(allocator)?;
+            next.set_left_sibling(bucket.page_id());
+            bucket.set_right_sibling(next.page_id());
+            io::write(&bucket);
+            bucket = next;
+        } else {
+            bucket = load(bucket.right_sibling())?;
+        }
+    }
+    bucket.add(key, payload)?;
+    Ok(())
+}
+
+// FNV-1a, the bucket of a key must not change across builds, unlike with the std hashers.
+pub(crate) fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+#[serial]
+fn verify_hash_insert_get_and_delete() {
+    delete_index();
+    let mut index = HashIndex::open().unwrap();
+    for i in 0..300u32 {
+        let key = format!("user-{}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert!(index.global_depth() > 0);
+    index.insert(Key::from("user-7"), Payload::from_u32(700)).unwrap();
+    assert!(index.delete(Key::from("user-8")).unwrap());
+    assert!(!index.delete(Key::from("user-8")).unwrap());
+
+    let index = HashIndex::open().unwrap();
+    for i in 0..300u32 {
+        let key = format!("user-{}", i);
+        let payload = index.get(Key::from(key.as_str())).unwrap();
+        match i {
+            7 => assert_eq!(payload.unwrap().to_bytes(), &700u32.to_le_bytes().to_vec()),
+            8 => assert!(payload.is_none()),
+            _ => assert_eq!(payload.unwrap().to_bytes(), &i.to_le_bytes().to_vec()),
+        }
+    }
+    assert!(index.get(Key::from("missing")).unwrap().is_none());
+
+    // the overflow pages of replaced and deleted values are freed.
+    let mut index = HashIndex::open().unwrap();
+    index.insert(Key::from("large"), Payload::from_str("x".repeat(20_000))).unwrap();
+    index.insert(Key::from("large"), Payload::from_str("y".repeat(20_000))).unwrap();
+    assert!(index.delete(Key::from("large")).unwrap());
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+}
+
+#[test]
+#[serial]
+fn verify_full_bucket_is_chained_at_max_depth() {
+    delete_index();
+    let allocator = io::page_allocator();
+    let bucket = Page::new_data(&allocator).unwrap();
+    io::write(&bucket);
+    let head = bucket.page_id();
+    for i in 0..12u32 {
+        let (key, payload) = (format!("{:02}", i), Payload::from_u32(i));
+        append_to_chain(load(head).unwrap(), Key::from(key.as_str()), payload, &allocator).unwrap();
+    }
+    let second = load(load(head).unwrap().right_sibling()).unwrap();
+    assert!(second.is_full().unwrap());
+    assert_ne!(second.right_sibling(), ZERO);
+}
@@ -0,0 +1,193 @@
+use crate::btree::load;
+use crate::config::{get_dictionary_page_id, update_dictionary_page_id};
+use crate::errors::Error;
+use crate::io;
+use crate::paging::{Page, ZERO};
+use crate::types::{Key, Offset, Payload, PayloadType};
+use std::collections::HashMap;
+
+// Shorter prefixes aren't worth a dictionary lookup.
+const MIN_INTERN_LEN: usize = 8;
+const S_PREFIX_ID: usize = size_of::<u16>();
+
+/// Interner is the key dictionary of the tree. Separator keys in inner pages often repeat long
+/// prefixes, such separators are stored as a reference into the dictionary followed by their
+/// suffix:
+///  _____________________
+/// | prefix id | suffix |
+///  ---------------------
+/// The dictionary is persisted in a chain of data pages, each slot maps a prefix id to its bytes.
+/// Entries are never removed, as separators of any page may still reference them.
+pub(crate) struct Interner {
+    ids: HashMap<Vec<u8>, u16>,
+    prefixes: Vec<Vec<u8>>,
+    tail: Offset,
+}
+
+impl Interner {
+    /// Loads the dictionary from its page chain.
+    pub(crate) fn load() -> Result<Self, Error> {
+        let mut interner = Interner {
+            ids: HashMap::new(),
+            prefixes: Vec::new(),
+            tail: ZERO,
+        };
+        let mut next = get_dictionary_page_id();
+        while next != ZERO {
+            let page = load(next)?;
+            for i in 0..page.num_of_slots().get() {
+                let id = u16::from_le_bytes(
+                    page.key_at(i)?
+                        .try_into()
+                        .map_err(|_| Error::MalformedPayload)?,
+                );
+                let prefix = page.value_at(i)?.into_vec();
+                if usize::from(id) != interner.prefixes.len() {
+                    return Err(Error::MalformedPayload);
+                }
+                interner.ids.insert(prefix.clone(), id);
+                interner.prefixes.push(prefix);
+            }
+            interner.tail = next;
+            next = page.right_sibling();
+        }
+        Ok(interner)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    /// Returns the full key of an interned separator.
+    pub(crate) fn resolve(&self, interned: &[u8]) -> Result<Vec<u8>, Error> {
+        let (prefix, suffix) = self.split(interned)?;
+        let mut key = prefix.to_vec();
+        key.extend_from_slice(suffix);
+        Ok(key)
+    }
+
+    /// Returns the prefix an interned separator refers to and its suffix, without copying them.
+    pub(crate) fn split<'a>(
+        &'a self,
+        interned: &'a [u8],
+    ) -> Result<(&'a [u8], &'a [u8]), Error> {
+        if interned.len() < S_PREFIX_ID {
+            return Err(Error::MalformedPayload);
+        }
+        let id = u16::from_le_bytes([interned[0], interned[1]]);
+        let prefix = self
+            .prefixes
+            .get(usize::from(id))
+            .ok_or(Error::MalformedPayload)?;
+        Ok((prefix, &interned[S_PREFIX_ID..]))
+    }
+
+    /// Encodes the separator as a dictionary reference if it starts with a known prefix, or shares
+    /// a long enough prefix with one of its neighbours in the page, which is then added to the
+    /// dictionary. Returns None if the separator should be stored as it is.
+    pub(crate) fn encode(
+        &mut self,
+        separator: &[u8],
+        neighbours: &[Vec<u8>],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (id, prefix_len) = match self.longest_known_prefix(separator) {
+            Some(known) => known,
+            None => {
+                let shared = neighbours
+                    .iter()
+                    .map(|neighbour| common_prefix_len(separator, neighbour))
+                    .max()
+                    .unwrap_or(0);
+                if shared < MIN_INTERN_LEN {
+                    return Ok(None);
+                }
+                match self.intern(&separator[..shared])? {
+                    Some(id) => (id, shared),
+                    None => return Ok(None),
+                }
+            }
+        };
+        let mut interned = Vec::with_capacity(S_PREFIX_ID + separator.len() - prefix_len);
+        interned.extend_from_slice(&id.to_le_bytes());
+        interned.extend_from_slice(&separator[prefix_len..]);
+        Ok(Some(interned))
+    }
+
+    fn longest_known_prefix(&self, key: &[u8]) -> Option<(u16, usize)> {
+        (MIN_INTERN_LEN..=key.len())
+            .rev()
+            .find_map(|len| self.ids.get(&key[..len]).map(|id| (*id, len)))
+    }
+
+    // Appends the prefix to the dictionary, None if the id space is exhausted.
+    fn intern(&mut self, prefix: &[u8]) -> Result<Option<u16>, Error> {
+        let id: u16 = match self.prefixes.len().try_into() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let mut tail = if self.tail == ZERO {
+            let page = Page::new_data(&io::page_allocator())?;
+            update_dictionary_page_id(page.page_id());
+            page
+        } else {
+            load(self.tail)?
+        };
+        if tail.is_full()? {
+            let mut next = Page::new_data(&io::page_allocator())?;
+            next.set_left_sibling(tail.page_id());
+            tail.set_right_sibling(next.page_id());
+            io::write(&tail);
+            tail = next;
+        }
+        tail.add(
+            Key::from(&id.to_le_bytes()),
+            Payload::from_buffer(prefix, PayloadType::Bytes),
+        )?;
+        self.tail = tail.page_id();
+        self.ids.insert(prefix.to_vec(), id);
+        self.prefixes.push(prefix.to_vec());
+        Ok(Some(id))
+    }
+}
+
+/// Reads the key at the slot index, resolving interned separators. Leaf pages never hold interned
+/// keys, so the interner is optional.
+pub(crate) fn resolved_key_at(
+    page: &Page,
+    index: usize,
+    interner: Option<&Interner>,
+) -> Result<Vec<u8>, Error> {
+    let key = page.key_at(index)?;
+    match (page.key_type_at(index)?, interner) {
+        (PayloadType::Interned, Some(interner)) => interner.resolve(&key),
+        (PayloadType::Interned, None) => Err(Error::MalformedPayload),
+        _ => Ok(key),
+    }
+}
+
+/// Returns the key at the slot index like `resolved_key_at`, split into the prefix from the
+/// dictionary and the rest stored in the page rather than copied into one key. Keys which aren't
+/// interned have the prefix of the page, empty unless the page is compressed.
+pub(crate) fn key_parts_at<'a>(
+    page: &'a Page,
+    index: usize,
+    interner: &'a Interner,
+) -> Result<(&'a [u8], &'a [u8]), Error> {
+    let key = page.key_slice_at(index)?;
+    match page.key_type_at(index)? {
+        PayloadType::Interned => interner.split(key),
+        _ => Ok((page.prefix(), key)),
+    }
+}
+
+pub(crate) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[test]
+fn verify_common_prefix_len() {
+    assert_eq!(common_prefix_len(b"user:0001", b"user:0002"), 8);
+    assert_eq!(common_prefix_len(b"abc", b"xyz"), 0);
+    assert_eq!(common_prefix_len(b"abc", b"abcdef"), 3);
+}
@@ -1,56 +1,1023 @@
+use crate::allocator::PageAllocator;
+use crate::blob;
+#[cfg(test)]
+use crate::btree::Index;
+use crate::compressed;
+use crate::config;
+use crate::errors::Error;
+use crate::events::{self, StallReason};
+use crate::fsm;
+use crate::latch;
+use crate::misses;
+use crate::pagemap;
+use crate::pagetrace;
+use crate::pins;
 use crate::paging::{Page, PAGE_SIZE, PAGE_SIZE_USIZE};
+use crate::poison::{self, Violation};
+use crate::ratelimit::RateLimiter;
+use crate::shard;
+use crate::stats;
+use crate::sync::{Arc, Mutex};
+use crate::sys;
+use crate::tier::{self, Tier};
+use crate::treestats;
 use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
 use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // in-memory cache which holds page ids to Page objects.
-static CACHE: Lazy<Mutex<HashMap<Offset, Arc<Mutex<Page>>>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+static CACHE: Lazy<PageCache> = Lazy::new(PageCache::new);
+// Settings which can be changed on a live database.
+static FULL_SYNC: AtomicBool = AtomicBool::new(false);
+static READAHEAD_PAGES: AtomicUsize = AtomicUsize::new(0);
+static SHADOW_PAGING: AtomicBool = AtomicBool::new(cfg!(feature = "shadow-paging"));
+static MAX_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_MAX_RETRIES);
+static RETRY_BACKOFF_MICROS: AtomicU64 = AtomicU64::new(DEFAULT_RETRY_BACKOFF_MICROS);
+// the error which failed the database, writes are refused until it's closed.
+static FAILURE: Lazy<std::sync::Mutex<Option<ErrorKind>>> =
+    Lazy::new(|| std::sync::Mutex::new(None));
+#[cfg(test)]
+static INJECTED_ERRORS: Lazy<std::sync::Mutex<Vec<ErrorKind>>> =
+    Lazy::new(|| std::sync::Mutex::new(Vec::new()));
+// pages of the index file with disk space reserved for them.
+static ALLOCATED_PAGES: AtomicUsize = AtomicUsize::new(0);
+// the allocator of the open database, replaced once it's closed.
+static PAGE_ALLOCATOR: Lazy<std::sync::Mutex<std::sync::Arc<PageAllocator>>> =
+    Lazy::new(|| std::sync::Mutex::new(std::sync::Arc::default()));
+// the lock file of the open database, held until it's closed.
+static DB_LOCK: Lazy<std::sync::Mutex<Option<File>>> = Lazy::new(|| std::sync::Mutex::new(None));
+// pages written since the last commit in shadow paging mode.
+static SHADOW_PAGES: Lazy<std::sync::Mutex<HashMap<Offset, Page>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// held shared by the write operations under way and exclusively by exports, see `write_operation`.
+static WRITE_GATE: RwLock<()> = RwLock::new(());
+thread_local! {
+    // write operations and exports the thread is in, only the outermost one takes the gate.
+    static GATE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// A cached page along with its version.
+type CachedPage = (Arc<Mutex<Page>>, u64);
+
+/// PageCache maps page ids to the latches of the cached pages. Its synchronization is built on the
+/// primitives in `sync`, so it can be model-checked with loom.
+///
+/// Each cached page carries a version which changes whenever the page is replaced, so that readers
+/// can copy a page without waiting for its latch and validate the copy afterwards. Versions are
+/// drawn from a single clock, a page evicted and read again never gets an earlier version back.
+pub(crate) struct PageCache {
+    pages: Mutex<Pages>,
+    // zero for an unbounded cache.
+    capacity: crate::sync::AtomicUsize,
+    clock: crate::sync::AtomicU64,
+}
+
+// The cached pages linked in the order of their last use, from the least recently used one, which
+// is evicted first. Moving a page to the end of the list doesn't allocate, so cache hits don't.
+#[derive(Default)]
+struct Pages {
+    entries: HashMap<Offset, CacheEntry>,
+    least_recent: Option<Offset>,
+    most_recent: Option<Offset>,
+}
+
+struct CacheEntry {
+    page: CachedPage,
+    previous: Option<Offset>,
+    next: Option<Offset>,
+}
+
+impl Pages {
+    // Returns the page, which counts as its use.
+    fn touch(&mut self, page_id: Offset) -> Option<&CachedPage> {
+        if !self.entries.contains_key(&page_id) {
+            return None;
+        }
+        self.unlink(page_id);
+        self.link(page_id);
+        self.entries.get(&page_id).map(|entry| &entry.page)
+    }
+
+    fn insert(&mut self, page_id: Offset, page: CachedPage) {
+        self.remove(page_id);
+        let entry = CacheEntry { page, previous: None, next: None };
+        self.entries.insert(page_id, entry);
+        self.link(page_id);
+    }
+
+    fn remove(&mut self, page_id: Offset) -> Option<CachedPage> {
+        if !self.entries.contains_key(&page_id) {
+            return None;
+        }
+        self.unlink(page_id);
+        self.entries.remove(&page_id).map(|entry| entry.page)
+    }
+
+    fn pop_least_recent(&mut self) -> Option<(Offset, CachedPage)> {
+        let page_id = self.least_recent?;
+        self.remove(page_id).map(|page| (page_id, page))
+    }
+
+    // Links the cached page in as the most recently used one.
+    fn link(&mut self, page_id: Offset) {
+        let previous = self.most_recent.replace(page_id);
+        match previous {
+            Some(previous) => self.entries.get_mut(&previous).unwrap().next = Some(page_id),
+            None => self.least_recent = Some(page_id),
+        }
+        let entry = self.entries.get_mut(&page_id).unwrap();
+        (entry.previous, entry.next) = (previous, None);
+    }
+
+    fn unlink(&mut self, page_id: Offset) {
+        let entry = &self.entries[&page_id];
+        let (previous, next) = (entry.previous, entry.next);
+        match previous {
+            Some(previous) => self.entries.get_mut(&previous).unwrap().next = next,
+            None => self.least_recent = next,
+        }
+        match next {
+            Some(next) => self.entries.get_mut(&next).unwrap().previous = previous,
+            None => self.most_recent = previous,
+        }
+    }
+}
+
+impl PageCache {
+    pub(crate) fn new() -> Self {
+        PageCache {
+            pages: Mutex::new(Pages::default()),
+            capacity: crate::sync::AtomicUsize::new(0),
+            clock: crate::sync::AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, page_id: Offset) -> Option<Arc<Mutex<Page>>> {
+        self.get_versioned(page_id).map(|(page, _)| page)
+    }
+
+    pub(crate) fn get_versioned(&self, page_id: Offset) -> Option<CachedPage> {
+        let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        pages.touch(page_id).cloned()
+    }
+
+    /// Returns the version of the cached page, None if the page isn't cached.
+    pub(crate) fn version(&self, page_id: Offset) -> Option<u64> {
+        let pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        pages.entries.get(&page_id).map(|entry| entry.page.1)
+    }
+
+    pub(crate) fn contains(&self, page_id: Offset) -> bool {
+        let pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        pages.entries.contains_key(&page_id)
+    }
+
+    /// Caches the page, evicting the least recently used pages if the cache is full.
+    pub(crate) fn insert(&self, page_id: Offset, page: Arc<Mutex<Page>>) {
+        let capacity = self.capacity.load(crate::sync::Ordering::Relaxed);
+        let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        if capacity > 0 && !pages.entries.contains_key(&page_id) {
+            evict(&mut pages, capacity - 1);
+        }
+        let version = self.clock.fetch_add(1, crate::sync::Ordering::Relaxed) + 1;
+        pages.insert(page_id, (page, version));
+    }
+
+    pub(crate) fn remove(&self, page_id: Offset) {
+        let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        pages.remove(page_id);
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.pages.lock().unwrap_or_else(|e| e.into_inner()) = Pages::default();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pages.lock().unwrap_or_else(|e| e.into_inner()).entries.len()
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, crate::sync::Ordering::Relaxed);
+        if capacity > 0 {
+            let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+            evict(&mut pages, capacity);
+        }
+    }
+}
+
+/// DurabilityMode selects how page writes reach the database files. The shadow-paging feature makes
+/// shadow paging the default.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DurabilityMode {
+    /// Pages are written in place as they change.
+    WriteThrough,
+    /// Changed pages are kept aside until `commit`, which writes them into a copy of the index file
+    /// and renames the copy over the index file. The files switch from one committed state to the
+    /// next, at the cost of rewriting the index file on each commit.
+    Shadow,
+}
+
+impl Default for DurabilityMode {
+    fn default() -> Self {
+        if cfg!(feature = "shadow-paging") {
+            DurabilityMode::Shadow
+        } else {
+            DurabilityMode::WriteThrough
+        }
+    }
+}
+
+/// SyncMode controls how far a page write is pushed before it returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SyncMode {
+    /// Pages are handed over to the operating system.
+    Flush,
+    /// Pages are synced to the disk.
+    Full,
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_MICROS: u64 = 1000;
+
+/// RetryPolicy bounds the retries of transient IO errors, i.e. interrupted calls, calls which would
+/// block and short writes. The backoff doubles with every retry. Errors which persist, and all
+/// other errors, fail the database.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    /// The wait before the first retry.
+    pub(crate) backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: Duration::from_micros(DEFAULT_RETRY_BACKOFF_MICROS),
+        }
+    }
+}
+
+pub(crate) fn set_retry_policy(policy: RetryPolicy) {
+    MAX_RETRIES.store(policy.max_retries, Ordering::Relaxed);
+    RETRY_BACKOFF_MICROS.store(policy.backoff.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: MAX_RETRIES.load(Ordering::Relaxed),
+        backoff: Duration::from_micros(RETRY_BACKOFF_MICROS.load(Ordering::Relaxed)),
+    }
+}
+
+fn is_transient(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::WriteZero
+    )
+}
+
+/// Runs the IO operation, retrying transient errors as the retry policy allows.
+pub(crate) fn with_retries<T>(
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let policy = retry_policy();
+    let mut backoff = policy.backoff;
+    let mut retries = 0;
+    loop {
+        match operation() {
+            Err(e) if is_transient(e.kind()) && retries < policy.max_retries => {
+                retries += 1;
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Puts the database into the failed state after an IO error which retries didn't resolve. Pages
+/// and config written from then on are only kept in memory, like uncommitted shadow pages, so that
+/// the operation running into the error completes without leaving a half written structure on the
+/// disk. Writes are refused with Failed until the database is closed, reads go on.
+///
+/// A full disk only degrades the database: writes are refused with DiskFull, and each write tries
+/// to write the pages kept in memory again, see `recover`.
+pub(crate) fn fail(error: std::io::Error) {
+    let mut failure = FAILURE.lock().unwrap_or_else(|e| e.into_inner());
+    if failure.is_none() {
+        *failure = Some(error.kind());
+        drop(failure);
+        events::emit(|listener| listener.on_failed(&error));
+    }
+}
+
+/// Returns the kind of the error which failed the database, None if it didn't fail.
+pub(crate) fn failure() -> Option<ErrorKind> {
+    *FAILURE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// WriteGate keeps exports out while a write operation is under way, or write operations out while
+/// an export copies the database, until it's dropped.
+pub(crate) struct WriteGate {
+    _shared: Option<RwLockReadGuard<'static, ()>>,
+    _exclusive: Option<RwLockWriteGuard<'static, ()>>,
+}
+
+impl Drop for WriteGate {
+    fn drop(&mut self) {
+        GATE_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Starts a write operation, which exports wait for, so that they either see all of its writes or
+/// none of them. Operations nested into another one, e.g. the inserts applying a log entry, and
+/// operations of the thread running the export are covered by the outermost one.
+pub(crate) fn write_operation() -> WriteGate {
+    let outermost = GATE_DEPTH.with(|depth| depth.replace(depth.get() + 1)) == 0;
+    WriteGate {
+        _shared: outermost.then(|| WRITE_GATE.read().unwrap_or_else(|e| e.into_inner())),
+        _exclusive: None,
+    }
+}
+
+/// Waits for the write operations under way and holds new ones off until the gate is dropped, so
+/// that the database is copied at a point where no operation is half done.
+pub(crate) fn quiesce() -> WriteGate {
+    let outermost = GATE_DEPTH.with(|depth| depth.replace(depth.get() + 1)) == 0;
+    WriteGate {
+        _shared: None,
+        _exclusive: outermost.then(|| WRITE_GATE.write().unwrap_or_else(|e| e.into_inner())),
+    }
+}
+
+/// Returns Failed if the database failed, DiskFull if the disk is still full, Poisoned if
+/// corruption was detected, and Archived if the index was frozen into an archive. Write operations
+/// check it when they start and when they end, so that the one running into the failure reports it
+/// as well.
+pub(crate) fn check_writable() -> Result<(), Error> {
+    match failure() {
+        Some(ErrorKind::StorageFull) if !recover() => return Err(Error::DiskFull),
+        Some(ErrorKind::StorageFull) | None => {}
+        Some(kind) => return Err(Error::Failed(kind)),
+    }
+    if poison::report().is_some() {
+        return Err(Error::Poisoned);
+    }
+    if config::get_archived() {
+        return Err(Error::Archived);
+    }
+    Ok(())
+}
+
+/// Writes the pages and the config kept in memory since the disk ran full, and lifts the failure
+/// once they are all on the disk. The write which ran into the full disk completed in memory, it's
+/// written along with them although it was reported as DiskFull. Returns false if the database is
+/// still failed, other failures than a full disk persist until it's reopened.
+pub(crate) fn recover() -> bool {
+    match failure() {
+        None => return true,
+        Some(ErrorKind::StorageFull) => {}
+        Some(_) => return false,
+    }
+    if write_pending().is_err() {
+        return false;
+    }
+    FAILURE.lock().unwrap_or_else(|e| e.into_inner()).take();
+    events::emit(|listener| listener.on_disk_space_recovered());
+    true
+}
+
+// Shadow pages are checkpointed, in write through mode they are written in place.
+fn write_pending() -> std::io::Result<()> {
+    let mut shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    if SHADOW_PAGING.load(Ordering::Relaxed) {
+        if !shadow_pages.is_empty() {
+            with_retries(|| checkpoint(&shadow_pages))?;
+        }
+    } else {
+        for page in shadow_pages.values() {
+            with_retries(|| write_to_disk(page))?;
+        }
+    }
+    shadow_pages.clear();
+    drop(shadow_pages);
+    with_retries(config::commit_shadow)
+}
+
+// Fails the next IO operations with the errors, last one first.
+#[cfg(test)]
+pub(crate) fn inject_errors(errors: Vec<ErrorKind>) {
+    *INJECTED_ERRORS.lock().unwrap_or_else(|e| e.into_inner()) = errors;
+}
+
+#[cfg(test)]
+pub(crate) fn injected_error() -> std::io::Result<()> {
+    match INJECTED_ERRORS.lock().unwrap_or_else(|e| e.into_inner()).pop() {
+        Some(kind) => Err(kind.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(test))]
+pub(crate) fn injected_error() -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Sets the maximum number of cached pages, zero for an unbounded cache.
+pub(crate) fn set_cache_capacity(pages: usize) {
+    CACHE.set_capacity(pages);
+}
+
+pub(crate) fn set_sync_mode(sync_mode: SyncMode) {
+    FULL_SYNC.store(sync_mode == SyncMode::Full, Ordering::Relaxed);
+}
+
+/// Sets the number of pages following a page read from the disk which are read into the cache
+/// along with it.
+pub(crate) fn set_readahead_pages(pages: usize) {
+    READAHEAD_PAGES.store(pages, Ordering::Relaxed);
+}
+
+pub(crate) fn durability_mode() -> DurabilityMode {
+    if SHADOW_PAGING.load(Ordering::Relaxed) {
+        DurabilityMode::Shadow
+    } else {
+        DurabilityMode::WriteThrough
+    }
+}
+
+/// Changes the durability mode, pending shadow pages are committed first.
+pub(crate) fn set_durability_mode(mode: DurabilityMode) {
+    commit();
+    SHADOW_PAGING.store(mode == DurabilityMode::Shadow, Ordering::Relaxed);
+}
+
+/// Atomically writes the pages and the config written since the last commit, in the index file and
+/// the cold file alike. They are written into a journal first, and then in place, see `checkpoint`.
+/// Writers wait for the checkpoint, which is reported as a write stall. Nothing is committed once
+/// the database failed.
+pub(crate) fn commit() {
+    if failure().is_some() {
+        return;
+    }
+    // the free space map is approximate, one which can't be written is read back when reopened.
+    // It's written first, the pages written below are recorded for the next checkpoint.
+    if fsm::persist().is_err() {
+        fsm::reset();
+    }
+    // stats which can't be written are recomputed rather than left behind the tree.
+    if treestats::persist().is_err() {
+        treestats::invalidate();
+    }
+    // statistics of shards which can't be written stay dirty for the next checkpoint.
+    let _ = shard::persist();
+    // listeners are called without the shadow pages locked, they may read pages.
+    if shadow_pages() > 0 {
+        let started = Instant::now();
+        events::emit(|listener| listener.on_checkpoint_start());
+        let mut shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = with_retries(|| checkpoint(&shadow_pages)) {
+            drop(shadow_pages);
+            fail(e);
+            return;
+        }
+        shadow_pages.clear();
+        drop(shadow_pages);
+        events::emit(|listener| listener.on_checkpoint_end());
+        stats::record_stall(StallReason::Checkpoint, started.elapsed());
+    }
+    if let Err(e) = with_retries(config::commit_shadow) {
+        fail(e);
+    }
+}
+
+// Writes the shadow pages and the config into the journal, and then in place. Only the pages
+// written since the last commit are written, the hot and the cold ones alike, each of them twice.
+fn checkpoint(shadow_pages: &HashMap<Offset, Page>) -> std::io::Result<()> {
+    injected_error()?;
+    let journal = write_journal(shadow_pages, config::shadow_image().as_deref())?;
+    apply_journal(&journal)?;
+    config::discard_shadow();
+    fs::remove_file(JOURNAL_FILE)?;
+    sys::sync_dir(Path::new("."))
+}
+
+/// Writes the pages and the config image through the journal like a checkpoint, so that either
+/// all or none of them are written. The pages bypass the cache and the shadow pages, e.g. pages
+/// rewritten before the database is opened, see `upgrade`.
+pub(crate) fn write_atomically(
+    pages: &HashMap<Offset, Page>,
+    config: &[u8],
+) -> std::io::Result<()> {
+    let journal = write_journal(pages, Some(config))?;
+    apply_journal(&journal)?;
+    fs::remove_file(JOURNAL_FILE)?;
+    sys::sync_dir(Path::new("."))
+}
+
+// Writes the journal into a temporary file, which is renamed into place once it's synced. The
+// rename commits the checkpoint, from then on it's completed by `replay_journal` if it's cut short.
+fn write_journal(
+    shadow_pages: &HashMap<Offset, Page>,
+    config: Option<&[u8]>,
+) -> std::io::Result<Vec<u8>> {
+    let mut journal = Vec::with_capacity((shadow_pages.len() + 1) * PAGE_SIZE_USIZE);
+    journal.extend_from_slice(JOURNAL_MAGIC);
+    for (page_id, page) in shadow_pages {
+        let (kind, position) = match tier::tier(page_id.get()) {
+            Tier::Hot => (J_HOT_PAGE, pagemap::slot(page_id.get())),
+            Tier::Cold => (J_COLD_PAGE, page_id.get()),
+        };
+        append_record(&mut journal, kind, position as u64, &page.sealed());
+    }
+    if let Some(image) = config {
+        append_record(&mut journal, J_CONFIG, 0, image);
+    }
+    let temp_path = format!("{}.tmp", JOURNAL_FILE);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&journal)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, JOURNAL_FILE)?;
+    sys::sync_dir(Path::new("."))?;
+    Ok(journal)
+}
+
+fn append_record(journal: &mut Vec<u8>, kind: u8, position: u64, data: &[u8]) {
+    journal.push(kind);
+    journal.extend_from_slice(&position.to_le_bytes());
+    journal.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    journal.extend_from_slice(data);
+}
+
+// Writes the records of the journal in place and syncs the files they went to. Records are
+// written over whatever an earlier attempt left behind, so the journal can be applied again.
+fn apply_journal(journal: &[u8]) -> std::io::Result<()> {
+    let malformed = || std::io::Error::from(ErrorKind::InvalidData);
+    let mut records = journal.strip_prefix(JOURNAL_MAGIC.as_slice()).ok_or_else(malformed)?;
+    let (mut index_file, mut cold_file) = (None, None);
+    while !records.is_empty() {
+        let (header, rest) = records.split_at_checked(RECORD_HEADER_SIZE).ok_or_else(malformed)?;
+        let position = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let length = u32::from_le_bytes(header[9..].try_into().unwrap()) as usize;
+        let (data, rest) = rest.split_at_checked(length).ok_or_else(malformed)?;
+        match header[0] {
+            J_HOT_PAGE => write_record(&mut index_file, Path::new(INDEX_FILE), position, data)?,
+            J_COLD_PAGE => write_record(&mut cold_file, &tier::cold_path(), position, data)?,
+            J_CONFIG => config::write_image(data)?,
+            _ => return Err(malformed()),
+        }
+        records = rest;
+    }
+    for file in [index_file, cold_file].into_iter().flatten() {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Writes the page at the slot or page id, the file is opened by the first record written to it.
+fn write_record(
+    file: &mut Option<File>,
+    path: &Path,
+    position: u64,
+    page: &[u8],
+) -> std::io::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => file.insert(sys::open_or_create(path)?),
+    };
+    file.seek(SeekFrom::Start(position * PAGE_SIZE_USIZE as u64))?;
+    file.write_all(page)
+}
+
+/// Completes the checkpoint which was cut short after its journal was renamed into place, see
+/// `commit`. Called when the database is opened, before anything is read from its files. A journal
+/// which wasn't renamed into place belongs to a commit which never happened, it's left behind to
+/// be written over.
+pub(crate) fn replay_journal() -> Result<(), Error> {
+    let journal = match fs::read(JOURNAL_FILE) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    apply_journal(&journal)?;
+    fs::remove_file(JOURNAL_FILE)?;
+    sys::sync_dir(Path::new("."))?;
+    Ok(())
+}
+
+/// Drops the pages and the config written since the last commit.
+pub(crate) fn rollback() {
+    let mut shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    for page_id in shadow_pages.keys() {
+        CACHE.remove(*page_id);
+        compressed::remove(*page_id);
+    }
+    // the tree stats counted the dropped writes.
+    if !shadow_pages.is_empty() {
+        treestats::reset();
+        shard::reset();
+        fsm::reset();
+    }
+    shadow_pages.clear();
+    config::discard_shadow();
+    pins::clear();
+}
+
+/// Returns the allocator of the open database, which the pages created are passed, see `Page::new`.
+pub(crate) fn page_allocator() -> std::sync::Arc<PageAllocator> {
+    PAGE_ALLOCATOR.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+pub(crate) fn cached_pages() -> usize {
+    CACHE.len()
+}
+
+/// Returns the number of pages written since the last commit in shadow paging mode.
+pub(crate) fn shadow_pages() -> usize {
+    SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner()).len()
+}
 
 const INDEX_FILE: &str = "index.000";
+const LOCK_FILE: &str = "index.lock";
+const JOURNAL_FILE: &str = "index.journal";
+const JOURNAL_MAGIC: &[u8; 8] = b"TELEJRNL";
+// the records of the journal, a kind, the slot or page id the data is written at, and its length.
+const J_HOT_PAGE: u8 = 0;
+const J_COLD_PAGE: u8 = 1;
+const J_CONFIG: u8 = 2;
+const RECORD_HEADER_SIZE: usize = 1 + size_of::<u64>() + size_of::<u32>();
+// disk space is reserved for this many pages at a time as the index file grows.
+const PREALLOCATION_PAGES: usize = 64;
+
+/// Takes the lock of the database in the working directory, so that no other process opens it
+/// while it's open. Fails with Locked if another process holds it.
+pub(crate) fn lock() -> Result<(), Error> {
+    let mut held = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if held.is_some() {
+        return Ok(());
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_FILE)?;
+    match sys::try_lock(&file) {
+        Ok(()) => {
+            *held = Some(file);
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Err(Error::Locked),
+        Err(e) => Err(e.into()),
+    }
+}
 
 pub(crate) fn write(page: &Page) {
-    let page_id: usize = page.page_id().try_into().unwrap();
+    stats::record_write(PAGE_SIZE_USIZE);
+    fsm::record(page);
+    compressed::remove(page.page_id());
+    if !SHADOW_PAGING.load(Ordering::Relaxed) && failure().is_none() {
+        match with_retries(|| write_to_disk(page)) {
+            Ok(()) => {
+                CACHE.insert(page.page_id(), Arc::new(Mutex::new(*page)));
+                return;
+            }
+            Err(e) => fail(e),
+        }
+    }
+    // the cache is filled without the shadow pages locked, evictions call the listeners.
+    SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner()).insert(page.page_id(), *page);
+    CACHE.insert(page.page_id(), Arc::new(Mutex::new(*page)));
+}
+
+fn write_to_disk(page: &Page) -> std::io::Result<()> {
+    write_to_tier(tier::tier(page.page_id().get()), page)
+}
+
+fn write_to_tier(tier: Tier, page: &Page) -> std::io::Result<()> {
+    match tier {
+        Tier::Hot => write_to_slot(pagemap::slot(page.page_id().get()), page),
+        Tier::Cold => write_to_cold_file(page),
+    }
+}
+
+/// Writes the page into the file of the tier, bypassing the tier the page is in and the cache, see
+/// `tier::migrate`.
+pub(crate) fn write_tier(tier: Tier, page: &Page) -> Result<(), Error> {
+    if let Err(e) = with_retries(|| write_to_tier(tier, page)) {
+        fail(e);
+    }
+    check_writable()
+}
+
+// Cold pages are stored at their page id in the cold file, which is left sparse.
+fn write_to_cold_file(page: &Page) -> std::io::Result<()> {
+    injected_error()?;
+    let mut file = sys::open_or_create(&tier::cold_path())?;
+    file.seek(SeekFrom::Start((page.page_id().get() * PAGE_SIZE_USIZE) as u64))?;
+    file.write_all(&page.sealed())?;
+    file.flush()?;
+    if FULL_SYNC.load(Ordering::Relaxed) {
+        sys::sync_data(&file)?;
+    }
+    Ok(())
+}
+
+/// Writes the page into the slot of the index file, bypassing the page map and the cache, see
+/// `pagemap::relocate`.
+pub(crate) fn write_slot(slot: Offset, page: &Page) -> Result<(), Error> {
+    if let Err(e) = with_retries(|| write_to_slot(slot.get(), page)) {
+        fail(e);
+    }
+    check_writable()
+}
+
+fn write_to_slot(page_id: usize, page: &Page) -> std::io::Result<()> {
+    injected_error()?;
     let page_size: usize = PAGE_SIZE.try_into().unwrap();
     let file_offset: usize = page_id * page_size;
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(INDEX_FILE)
-        .unwrap();
-    let _ = file.seek(SeekFrom::Start(file_offset.try_into().unwrap()));
-    let _ = file.write_all(page.buffer());
-    file.flush().unwrap();
-    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
-    cache.insert(page.page_id(), Arc::new(Mutex::new(page.clone())));
+    let mut file = sys::open_or_create(Path::new(INDEX_FILE))?;
+    file.seek(SeekFrom::Start(file_offset.try_into().unwrap()))?;
+    file.write_all(&page.sealed())?;
+    file.flush()?;
+    if page_id >= ALLOCATED_PAGES.load(Ordering::Relaxed) {
+        let allocated = (page_id + 1).next_multiple_of(PREALLOCATION_PAGES);
+        // the page is written already, running out of space is left to the next writes.
+        if sys::preallocate(&file, (allocated * page_size) as u64).is_ok() {
+            ALLOCATED_PAGES.fetch_max(allocated, Ordering::Relaxed);
+        }
+    }
+    if FULL_SYNC.load(Ordering::Relaxed) {
+        sys::sync_data(&file)?;
+    }
+    Ok(())
+}
+
+// The cache is written through, so any page can be evicted, the least recently used ones go
+// first. Evicted pages move into the compressed tier if it's enabled, unless they are latched.
+fn evict(pages: &mut Pages, capacity: usize) {
+    let compress = pages.entries.len() > capacity && compressed::enabled();
+    while pages.entries.len() > capacity {
+        let Some((victim, (page, _))) = pages.pop_least_recent() else {
+            break;
+        };
+        if compress && let Ok(page) = page.try_lock() {
+            compressed::admit(&page);
+        }
+        events::emit(|listener| listener.on_page_evicted(victim));
+    }
+}
+
+/// Writes a page on behalf of a background task, throttled by the limiter.
+#[allow(dead_code)]
+pub(crate) fn write_background(page: &Page, limiter: &RateLimiter) {
+    limiter.acquire(PAGE_SIZE_USIZE as u64);
+    write(page);
 }
 
 pub(crate) fn read(page_id: usize) -> Option<Arc<Mutex<Page>>> {
-    let id = Offset(page_id as u16);
-    let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
-    cache.get(&id).cloned().or_else(|| read_from_disk(page_id))
+    read_checked(page_id).ok()
 }
 
-fn read_from_disk(page_id: usize) -> Option<Arc<Mutex<Page>>> {
-    let file_offset = page_id * PAGE_SIZE_USIZE;
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(INDEX_FILE)
-        .unwrap();
-    file.seek(SeekFrom::Start(file_offset as u64)).unwrap();
+/// Reads the page like `read_checked`. A corrupt page poisons the database, see `poison`, other
+/// errors are returned as they are.
+pub(crate) fn read_verified(page_id: Offset) -> Result<Arc<Mutex<Page>>, Error> {
+    read_checked(page_id.get()).map_err(|e| match CorruptPage::of(&e) {
+        Some(corrupt) => poison::corrupted(&corrupt.page, page_id, corrupt.violation.clone()),
+        None => e.into(),
+    })
+}
+
+/// Reads the page like `read`, failing with the error of the read. Pages which fail the checks of
+/// `Page::verify` fail with a CorruptPage error, which doesn't fail the database, see `load`.
+pub(crate) fn read_checked(page_id: usize) -> std::io::Result<Arc<Mutex<Page>>> {
+    stats::record_read();
+    let id = Offset::try_from(page_id).map_err(|_| std::io::Error::from(ErrorKind::InvalidInput))?;
+    if let Some(page) = CACHE.get(id) {
+        return Ok(page);
+    }
+    if let Some(page) = compressed::take(id) {
+        let page = Arc::new(Mutex::new(page));
+        CACHE.insert(id, page.clone());
+        return Ok(page);
+    }
+    // shadow pages evicted from the cache aren't on the disk yet.
+    let shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(page) = shadow_pages.get(&id) {
+        return Ok(Arc::new(Mutex::new(*page)));
+    }
+    drop(shadow_pages);
+    match with_retries(|| read_from_disk(id)) {
+        Err(e) if CorruptPage::of(&e).is_none() => {
+            let kind = e.kind();
+            fail(e);
+            Err(kind.into())
+        }
+        result => result,
+    }
+}
+
+/// Reads the page into the cache ahead of its use, pages read from the disk otherwise aren't
+/// cached until they are written. Uncommitted shadow pages evicted from the cache are cached again
+/// from their shadow copy.
+pub(crate) fn prefetch(page_id: usize) {
+    let Ok(id) = Offset::try_from(page_id) else {
+        return;
+    };
+    if CACHE.contains(id) {
+        return;
+    }
+    let shadow_page = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner()).get(&id).copied();
+    if let Some(page) = shadow_page {
+        CACHE.insert(id, Arc::new(Mutex::new(page)));
+        return;
+    }
+    if let Some(page) = read(page_id) {
+        CACHE.insert(id, page);
+    }
+}
+
+/// Reads the page along with its version. Pages which aren't kept in the cache, e.g. uncommitted
+/// shadow pages evicted from it, have no version.
+pub(crate) fn read_versioned(page_id: usize) -> Option<(Arc<Mutex<Page>>, Option<u64>)> {
+    let id = Offset::try_from(page_id).ok()?;
+    if let Some((page, version)) = CACHE.get_versioned(id) {
+        stats::record_read();
+        return Some((page, Some(version)));
+    }
+    let page = read(page_id)?;
+    Some((page, CACHE.version(id)))
+}
+
+/// Returns the version of the cached page, None if the page isn't cached.
+pub(crate) fn page_version(page_id: Offset) -> Option<u64> {
+    CACHE.version(page_id)
+}
+
+/// Reads the page as it's stored, bypassing the cache and the checks of `Page::verify`, e.g. a page
+/// written before pages carried a checksum, see `upgrade`.
+pub(crate) fn read_stored(page_id: Offset) -> std::io::Result<[u8; PAGE_SIZE_USIZE]> {
+    let (_, mut file, _) = open_stored(page_id)?;
+    let mut buffer = [0u8; PAGE_SIZE_USIZE];
+    let _ = file.read(&mut buffer)?;
+    Ok(buffer)
+}
+
+// Opens the file of the tier the page is stored in, positioned at the page, along with the tier
+// and the slot of the page in the file.
+fn open_stored(page_id: Offset) -> std::io::Result<(Tier, File, usize)> {
+    injected_error()?;
+    let tier = tier::tier(page_id.get());
+    let (path, slot) = match tier {
+        Tier::Hot => (PathBuf::from(INDEX_FILE), pagemap::slot(page_id.get())),
+        Tier::Cold => (tier::cold_path(), page_id.get()),
+    };
+    let mut file = sys::open_or_create(&path)?;
+    file.seek(SeekFrom::Start((slot * PAGE_SIZE_USIZE) as u64))?;
+    Ok((tier, file, slot))
+}
+
+fn read_from_disk(page_id: Offset) -> std::io::Result<Arc<Mutex<Page>>> {
+    let (tier, mut file, slot) = open_stored(page_id)?;
     let mut buffer = [0u8; PAGE_SIZE_USIZE];
-    file.read(&mut buffer).unwrap();
-    let new_page = Page::new_from(buffer);
-    Some(Arc::new(Mutex::new(new_page)))
+    // pages which were never written read as zeroes.
+    let _ = file.read(&mut buffer)?;
+    // pages read ahead are read again when they are needed, so errors are ignored. Cold pages
+    // aren't read ahead, they are read rarely.
+    if tier == Tier::Hot {
+        let _ = read_ahead(&mut file, slot);
+    }
+    let page = Page::new_from(buffer);
+    if let Err(violation) = page.verify() {
+        let page = Box::new(page);
+        let corrupt = CorruptPage { page_id, violation, page };
+        return Err(std::io::Error::new(ErrorKind::InvalidData, corrupt));
+    }
+    Ok(Arc::new(Mutex::new(page)))
+}
+
+/// CorruptPage is the error of a page read from the disk which failed the checks of
+/// `Page::verify`, e.g. after a torn write or bit rot. It carries the page as it was read.
+pub(crate) struct CorruptPage {
+    pub(crate) page_id: Offset,
+    pub(crate) violation: Violation,
+    pub(crate) page: Box<Page>,
+}
+
+impl CorruptPage {
+    /// Returns the corrupt page the error was raised for, None for other errors.
+    pub(crate) fn of(error: &std::io::Error) -> Option<&CorruptPage> {
+        error.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Debug for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CorruptPage")
+            .field("page_id", &self.page_id)
+            .field("violation", &self.violation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: {}", self.page_id, self.violation)
+    }
+}
+
+impl std::error::Error for CorruptPage {}
+
+// The slots following the slot read are read ahead, which hold the pages following it unless pages
+// were relocated, see `pagemap`.
+fn read_ahead(file: &mut File, slot: usize) -> std::io::Result<()> {
+    // only pages which are on the disk in full are read ahead.
+    let file_size = file.metadata()?.len() as usize;
+    for next_slot in slot + 1..=slot + READAHEAD_PAGES.load(Ordering::Relaxed) {
+        let next_offset: Offset = match pagemap::page_id(next_slot).try_into() {
+            Ok(next_offset) => next_offset,
+            Err(_) => break,
+        };
+        if (next_slot + 1) * PAGE_SIZE_USIZE > file_size {
+            break;
+        }
+        // the slots of cold pages hold stale copies, and so do the slots of shadow pages.
+        if CACHE.contains(next_offset) || tier::tier(next_offset.get()) == Tier::Cold {
+            continue;
+        }
+        let shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(page) = shadow_pages.get(&next_offset).copied() {
+            drop(shadow_pages);
+            CACHE.insert(next_offset, Arc::new(Mutex::new(page)));
+            continue;
+        }
+        drop(shadow_pages);
+        file.seek(SeekFrom::Start((next_slot * PAGE_SIZE_USIZE) as u64))?;
+        let mut buffer = [0u8; PAGE_SIZE_USIZE];
+        file.read_exact(&mut buffer)?;
+        // corrupt pages are left to fail when they are read.
+        let page = Page::new_from(buffer);
+        if page.verify().is_ok() {
+            CACHE.insert(next_offset, Arc::new(Mutex::new(page)));
+        }
+    }
+    Ok(())
+}
+
+/// Drops the cached pages and the uncommitted changes, and releases the lock, so that the files of
+/// another database can be opened. A failed or poisoned database can be opened again afterwards.
+pub(crate) fn close() {
+    CACHE.clear();
+    *PAGE_ALLOCATOR.lock().unwrap_or_else(|e| e.into_inner()) = std::sync::Arc::default();
+    compressed::clear();
+    ALLOCATED_PAGES.store(0, Ordering::Relaxed);
+    FAILURE.lock().unwrap_or_else(|e| e.into_inner()).take();
+    poison::clear();
+    DB_LOCK.lock().unwrap_or_else(|e| e.into_inner()).take();
+    SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    config::discard_shadow();
+    latch::reset();
+    misses::clear();
+    treestats::reset();
+    shard::reset();
+    fsm::reset();
+    pagetrace::clear();
+    pins::clear();
+    pagemap::reset();
+    tier::reset();
+}
+
+/// Returns the paths of the files of the database in the working directory, the ones
+/// `delete_index` removes. The blobs are stored in a directory of their own.
+pub(crate) fn database_files() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from(INDEX_FILE),
+        PathBuf::from(config::CONFIG_FILE),
+        PathBuf::from(LOCK_FILE),
+        PathBuf::from(JOURNAL_FILE),
+        PathBuf::from(pagemap::PAGE_MAP_FILE),
+        PathBuf::from(tier::TIERS_FILE),
+        tier::cold_path(),
+        PathBuf::from(blob::BLOB_DIR),
+    ]
 }
 
 pub(crate) fn delete_index() {
+    close();
     match fs::remove_file("index.000") {
         Ok(_) => println!("index.000 deleted."),
         Err(_) => println!("index.000 not found."),
@@ -60,4 +1027,82 @@ pub(crate) fn delete_index() {
         Ok(_) => println!("config deleted."),
         Err(_) => println!("config not found."),
     }
+    let _ = fs::remove_file(LOCK_FILE);
+    let _ = fs::remove_file(JOURNAL_FILE);
+    pagemap::delete();
+    tier::delete();
+    blob::delete();
+}
+
+#[test]
+#[serial]
+fn verify_checkpoints_cut_short_are_completed_from_the_journal() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..300u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let range = index.range_pages(Key::from("100")..Key::from("200")).unwrap();
+    tier::migrate(&range, Tier::Cold).unwrap();
+    set_durability_mode(DurabilityMode::Shadow);
+    // the first update goes to the cold file, the second one to the index file.
+    index.insert(Key::from("150"), Payload::from_u32(1150)).unwrap();
+    index.insert(Key::from("250"), Payload::from_u32(1250)).unwrap();
+    // a crash right after the journal was renamed into place leaves the files as they were.
+    let shadow_pages = SHADOW_PAGES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    write_journal(&shadow_pages, config::shadow_image().as_deref()).unwrap();
+    close();
+    let payload = Index::open().unwrap().get(Key::from("150")).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &150u32.to_le_bytes());
+    close();
+    replay_journal().unwrap();
+    assert!(fs::metadata(JOURNAL_FILE).is_err());
+    let index = Index::open().unwrap();
+    for (key, value) in [("150", 1150u32), ("250", 1250), ("100", 100)] {
+        let payload = index.get(Key::from(key)).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), &value.to_le_bytes());
+    }
+    set_durability_mode(DurabilityMode::default());
+}
+
+#[test]
+fn verify_the_least_recently_used_pages_are_evicted() {
+    let cache = PageCache::new();
+    cache.set_capacity(2);
+    let insert = |page_id: u16| {
+        let page = Page::new_page(0, Offset(page_id));
+        cache.insert(Offset(page_id), Arc::new(Mutex::new(page)));
+    };
+    insert(1);
+    insert(2);
+    // the page read is kept, the one left unused since is evicted.
+    assert!(cache.get(Offset(1)).is_some());
+    insert(3);
+    assert!(cache.contains(Offset(1)) && cache.contains(Offset(3)));
+    assert!(!cache.contains(Offset(2)));
+    cache.set_capacity(1);
+    assert!(cache.contains(Offset(3)) && !cache.contains(Offset(1)));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_concurrent_inserts_respect_capacity() {
+    loom::model(|| {
+        let cache = Arc::new(PageCache::new());
+        cache.set_capacity(1);
+        let handles: Vec<_> = [1u16, 2]
+            .into_iter()
+            .map(|page_id| {
+                let cache = cache.clone();
+                loom::thread::spawn(move || {
+                    let page = Page::new_page(0, Offset(page_id));
+                    cache.insert(Offset(page_id), Arc::new(Mutex::new(page)));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.len(), 1);
+    });
 }
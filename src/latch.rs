@@ -0,0 +1,169 @@
+#[cfg(test)]
+use crate::paging::PAGE_SIZE_USIZE;
+use crate::paging::Page;
+use crate::sync::{Mutex, MutexGuard};
+use crate::types::Offset;
+#[cfg(all(debug_assertions, not(loom)))]
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(all(debug_assertions, not(loom)))]
+use std::cell::RefCell;
+#[cfg(all(debug_assertions, not(loom)))]
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+
+// Page latches held by the current thread, in the order they were taken.
+#[cfg(all(debug_assertions, not(loom)))]
+thread_local! {
+    static HELD: RefCell<Vec<Offset>> = const { RefCell::new(Vec::new()) };
+}
+
+// Lock order observed so far, an edge from a page to the pages latched while holding it.
+#[cfg(all(debug_assertions, not(loom)))]
+static LOCK_ORDER: Lazy<Mutex<HashMap<Offset, HashSet<Offset>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// LatchGuard holds the latch of a cached page. In debug builds the latches are tracked per
+/// thread, taking a latch twice or against the lock order observed so far panics, catching
+/// deadlocks in the split and merge paths before they happen.
+pub(crate) struct LatchGuard<'a> {
+    guard: MutexGuard<'a, Page>,
+    page_id: Offset,
+}
+
+/// Takes the latch of the page.
+pub(crate) fn lock(page_id: Offset, page: &Mutex<Page>) -> LatchGuard<'_> {
+    #[cfg(all(debug_assertions, not(loom)))]
+    track(page_id);
+    LatchGuard {
+        guard: page.lock().unwrap_or_else(|e| e.into_inner()),
+        page_id,
+    }
+}
+
+/// Copies the page if its latch is free. The latch is never waited for, so the copy doesn't take
+/// part in the lock order.
+pub(crate) fn try_copy(page: &Mutex<Page>) -> Option<Page> {
+    page.try_lock().ok().map(|guard| *guard)
+}
+
+/// Forgets the lock order, page ids are reused once the database is dropped.
+pub(crate) fn reset() {
+    #[cfg(all(debug_assertions, not(loom)))]
+    LOCK_ORDER.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+#[cfg(all(debug_assertions, not(loom)))]
+fn track(page_id: Offset) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        assert!(
+            !held.contains(&page_id),
+            "page {:?} latched twice by the same thread",
+            page_id
+        );
+        let mut lock_order = LOCK_ORDER.lock().unwrap_or_else(|e| e.into_inner());
+        for holder in held.iter() {
+            assert!(
+                !reaches(&lock_order, page_id, *holder),
+                "lock order violation, page {:?} latched while holding page {:?}",
+                page_id,
+                holder
+            );
+            lock_order.entry(*holder).or_default().insert(page_id);
+        }
+        held.push(page_id);
+    });
+}
+
+#[cfg(all(debug_assertions, not(loom)))]
+fn reaches(lock_order: &HashMap<Offset, HashSet<Offset>>, from: Offset, to: Offset) -> bool {
+    let mut pending = vec![from];
+    let mut visited = HashSet::new();
+    while let Some(page_id) = pending.pop() {
+        if page_id == to {
+            return true;
+        }
+        if visited.insert(page_id)
+            && let Some(next) = lock_order.get(&page_id)
+        {
+            pending.extend(next.iter().copied());
+        }
+    }
+    false
+}
+
+impl Deref for LatchGuard<'_> {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        &self.guard
+    }
+}
+
+impl DerefMut for LatchGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Page {
+        &mut self.guard
+    }
+}
+
+impl Drop for LatchGuard<'_> {
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, not(loom)))]
+        HELD.with(|held| held.borrow_mut().retain(|page_id| *page_id != self.page_id));
+    }
+}
+
+#[test]
+#[serial]
+fn verify_consistent_lock_order_is_accepted() {
+    let (a, b) = (
+        Mutex::new(Page::new_from([0u8; PAGE_SIZE_USIZE])),
+        Mutex::new(Page::new_from([0u8; PAGE_SIZE_USIZE])),
+    );
+    for _ in 0..2 {
+        let _first = lock(Offset(9001), &a);
+        let _second = lock(Offset(9002), &b);
+    }
+}
+
+#[test]
+#[serial]
+#[cfg(all(debug_assertions, not(loom)))]
+#[should_panic(expected = "lock order violation")]
+fn verify_lock_order_violation_panics() {
+    let (a, b) = (
+        Mutex::new(Page::new_from([0u8; PAGE_SIZE_USIZE])),
+        Mutex::new(Page::new_from([0u8; PAGE_SIZE_USIZE])),
+    );
+    {
+        let _first = lock(Offset(9101), &a);
+        let _second = lock(Offset(9102), &b);
+    }
+    let _first = lock(Offset(9102), &b);
+    let _second = lock(Offset(9101), &a);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_latched_writes_are_not_lost() {
+    use crate::sync::Arc;
+    loom::model(|| {
+        let page = Arc::new(Mutex::new(Page::new_page(0, Offset(1))));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let page = page.clone();
+                loom::thread::spawn(move || {
+                    let mut guard = lock(Offset(1), &page);
+                    let sibling = guard.right_sibling().get();
+                    guard.set_right_sibling(Offset(sibling as u16 + 1));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(lock(Offset(1), &page).right_sibling().get(), 2);
+    });
+}
@@ -1,14 +1,73 @@
 extern crate alloc;
 extern crate core;
 
+// The modules allowing dead code are embedding APIs, which the command line tool doesn't use yet.
 mod paging;
 mod types;
 mod errors;
 mod btree;
 mod io;
 mod config;
+#[allow(dead_code)]
+mod typed;
+mod intern;
+mod hash;
+#[allow(dead_code)]
+mod queue;
+#[allow(dead_code)]
+mod db;
+mod sequence;
+#[allow(dead_code)]
+mod spatial;
+#[allow(dead_code)]
+mod multimap;
+mod ratelimit;
+mod events;
+mod snapshot;
+mod freelist;
+mod fsck;
+mod latch;
+mod sync;
+mod sys;
+mod stats;
+mod poison;
+#[allow(dead_code)]
+mod txn;
+mod cli;
+mod fixture;
+#[allow(dead_code)]
+mod aio;
+mod treefile;
+#[allow(dead_code)]
+mod cached;
+mod misses;
+mod raft;
+mod treestats;
+mod pagetrace;
+mod clock;
+mod crypt;
+mod compressed;
+mod pins;
+mod checksum;
+mod pagemap;
+mod archive;
+mod tier;
+mod blob;
+mod shard;
+mod allocs;
+mod cancel;
+mod fsm;
+mod allocator;
+mod upgrade;
 
 fn main() {
-    println!("Hello, world!");
-    let _ = paging::Page::new_inner();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = cli::parse(&args) else {
+        eprintln!("{}", cli::USAGE);
+        std::process::exit(2);
+    };
+    if let Err(e) = cli::run(command, &mut std::io::stdout()) {
+        eprintln!("error: {}", cli::describe(&e));
+        std::process::exit(cli::exit_code(&e));
+    }
 }
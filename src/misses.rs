@@ -0,0 +1,115 @@
+#[cfg(test)]
+use crate::btree::Index;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// A key known to be absent from the tree with the given root, as of the version of the leaf the
+/// lookup ended in.
+struct Miss {
+    root: Offset,
+    leaf: Offset,
+    version: u64,
+}
+
+// zero disables the cache.
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static MISSES: Lazy<Mutex<HashMap<Vec<u8>, Miss>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Sets the number of keys whose misses are remembered, so that lookups probing the same absent
+/// keys again don't descend the tree. Zero, the default, disables the cache.
+pub(crate) fn set_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    evict(&mut MISSES.lock().unwrap_or_else(|e| e.into_inner()), capacity);
+}
+
+/// Returns true if the key is known to be absent from the tree. Pages get a new version whenever
+/// they are written, so a miss holds as long as its leaf keeps the version it had at the lookup:
+/// an insert into the leaf's key range, a split or a delete of a neighbour invalidate it. So does
+/// the eviction of the leaf from the page cache, as the version is lost along with the page.
+pub(crate) fn known_absent(root: Offset, key: &[u8]) -> bool {
+    let mut misses = MISSES.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(miss) = misses.get(key) else {
+        return false;
+    };
+    if miss.root == root && io::page_version(miss.leaf) == Some(miss.version) {
+        return true;
+    }
+    misses.remove(key);
+    false
+}
+
+/// Remembers that the lookup of the key in the tree ended in the leaf without finding it.
+pub(crate) fn record(root: Offset, key: &[u8], leaf: Offset) {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if capacity == 0 {
+        return;
+    }
+    let Some(version) = io::page_version(leaf) else {
+        return;
+    };
+    let mut misses = MISSES.lock().unwrap_or_else(|e| e.into_inner());
+    if !misses.contains_key(key) {
+        evict(&mut misses, capacity - 1);
+    }
+    misses.insert(key.to_vec(), Miss { root, leaf, version });
+}
+
+pub(crate) fn clear() {
+    MISSES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+#[cfg(test)]
+pub(crate) fn len() -> usize {
+    MISSES.lock().unwrap_or_else(|e| e.into_inner()).len()
+}
+
+fn evict(misses: &mut HashMap<Vec<u8>, Miss>, capacity: usize) {
+    while misses.len() > capacity {
+        let victim = misses.keys().next().unwrap().clone();
+        misses.remove(&victim);
+    }
+}
+
+#[test]
+#[serial]
+fn verify_misses_are_invalidated_by_writes() {
+    delete_index();
+    set_capacity(2);
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("{:03}", i * 2);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert!(index.get(Key::from("011")).unwrap().is_none());
+    assert!(known_absent(index.root(), b"011"));
+    assert!(index.get(Key::from("011")).unwrap().is_none());
+
+    // the insert writes the leaf the key was missed in.
+    index.insert(Key::from("011"), Payload::from_u32(11)).unwrap();
+    assert!(!known_absent(index.root(), b"011"));
+    assert!(index.get(Key::from("011")).unwrap().is_some());
+
+    // a write into another leaf keeps the miss.
+    assert!(index.get(Key::from("051")).unwrap().is_none());
+    index.insert(Key::from("001"), Payload::from_u32(1)).unwrap();
+    assert!(known_absent(index.root(), b"051"));
+
+    for key in ["101", "103", "105"] {
+        assert!(index.get(Key::from(key)).unwrap().is_none());
+    }
+    assert_eq!(len(), 2);
+    set_capacity(0);
+    assert_eq!(len(), 0);
+    assert!(index.get(Key::from("101")).unwrap().is_none());
+    assert_eq!(len(), 0);
+}
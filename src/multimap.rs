@@ -0,0 +1,202 @@
+use crate::btree::Index;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+
+/// MultiMapIndex maps a term to the ids of the documents containing it. Each term is a single index
+/// entry whose payload is the posting list, the ids are kept sorted and stored as varint-encoded
+/// deltas to their predecessor, so dense posting lists take about a byte per document.
+pub(crate) struct MultiMapIndex {
+    index: Index,
+}
+
+impl MultiMapIndex {
+    pub(crate) fn new(index: Index) -> Self {
+        MultiMapIndex { index }
+    }
+
+    pub(crate) fn into_inner(self) -> Index {
+        self.index
+    }
+
+    /// Returns the sorted ids of the documents containing the term.
+    pub(crate) fn get(&self, term: &str) -> Result<Vec<u64>, Error> {
+        match self.index.get(Key::from(term))? {
+            Some(payload) => decode_postings(payload.to_bytes()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Adds the document to the posting list of the term, returns false if it is listed already.
+    pub(crate) fn insert(
+        &mut self,
+        term: &str,
+        doc_id: u64,
+    ) -> Result<bool, Error> {
+        let mut postings = self.get(term)?;
+        match postings.binary_search(&doc_id) {
+            Ok(_) => Ok(false),
+            Err(position) => {
+                postings.insert(position, doc_id);
+                self.put(term, &postings)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Removes the document from the posting list of the term, the term is dropped along with its
+    /// last document.
+    pub(crate) fn remove(
+        &mut self,
+        term: &str,
+        doc_id: u64,
+    ) -> Result<bool, Error> {
+        let mut postings = self.get(term)?;
+        match postings.binary_search(&doc_id) {
+            Ok(position) => {
+                postings.remove(position);
+                if postings.is_empty() {
+                    self.index.delete(Key::from(term))?;
+                } else {
+                    self.put(term, &postings)?;
+                }
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Adds the document to the posting lists of all terms in the text.
+    pub(crate) fn index_document(
+        &mut self,
+        doc_id: u64,
+        text: &str,
+    ) -> Result<(), Error> {
+        for term in tokenize(text) {
+            self.insert(&term, doc_id)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the documents containing all terms of the query.
+    pub(crate) fn search(&self, query: &str) -> Result<Vec<u64>, Error> {
+        let mut result: Option<Vec<u64>> = None;
+        for term in tokenize(query) {
+            let postings = self.get(&term)?;
+            result = Some(match result {
+                Some(result) => intersect(&result, &postings),
+                None => postings,
+            });
+        }
+        Ok(result.unwrap_or_default())
+    }
+
+    fn put(&mut self, term: &str, postings: &[u64]) -> Result<(), Error> {
+        let payload = Payload::from_vec(encode_postings(postings), PayloadType::Bytes);
+        self.index.insert(Key::from(term), payload)
+    }
+}
+
+/// Splits the text into lower-case alphanumeric terms, each term is returned once.
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let mut terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+fn intersect(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let (mut i, mut j) = (0, 0);
+    let mut result = Vec::new();
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    result
+}
+
+fn encode_postings(postings: &[u64]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(postings.len());
+    let mut previous = 0;
+    for doc_id in postings {
+        let mut delta = doc_id - previous;
+        while delta >= 0x80 {
+            buffer.push((delta as u8) | 0x80);
+            delta >>= 7;
+        }
+        buffer.push(delta as u8);
+        previous = *doc_id;
+    }
+    buffer
+}
+
+fn decode_postings(buffer: &[u8]) -> Result<Vec<u64>, Error> {
+    let mut postings = Vec::new();
+    let (mut previous, mut delta, mut shift) = (0u64, 0u64, 0u32);
+    for byte in buffer {
+        if shift >= u64::BITS {
+            return Err(Error::MalformedPayload);
+        }
+        delta |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            previous = previous
+                .checked_add(delta)
+                .ok_or(Error::MalformedPayload)?;
+            postings.push(previous);
+            (delta, shift) = (0, 0);
+        } else {
+            shift += 7;
+        }
+    }
+    if shift != 0 {
+        return Err(Error::MalformedPayload);
+    }
+    Ok(postings)
+}
+
+#[test]
+fn verify_postings_round_trip() {
+    let postings = vec![0, 1, 127, 128, 300, 1 << 40, u64::MAX];
+    let encoded = encode_postings(&postings);
+    assert_eq!(decode_postings(&encoded).unwrap(), postings);
+    assert_eq!(encode_postings(&[1, 2, 3]).len(), 3);
+    assert!(matches!(
+        decode_postings(&[0x80]),
+        Err(Error::MalformedPayload)
+    ));
+}
+
+#[test]
+#[serial]
+fn verify_keyword_search() {
+    delete_index();
+    let mut index = MultiMapIndex::new(Index::open().unwrap());
+    index.index_document(1, "The quick brown fox").unwrap();
+    index
+        .index_document(2, "the lazy dog, the quick cat")
+        .unwrap();
+    index.index_document(3, "Brown dog").unwrap();
+    assert_eq!(index.get("the").unwrap(), vec![1, 2]);
+    assert_eq!(index.search("quick THE").unwrap(), vec![1, 2]);
+    assert_eq!(index.search("brown dog").unwrap(), vec![3]);
+    assert!(index.search("missing").unwrap().is_empty());
+    assert!(!index.insert("dog", 2).unwrap());
+    assert!(index.remove("dog", 2).unwrap());
+    assert!(index.remove("dog", 3).unwrap());
+    assert!(index.get("dog").unwrap().is_empty());
+    assert!(index.into_inner().get(Key::from("dog")).unwrap().is_none());
+}
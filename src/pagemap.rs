@@ -0,0 +1,206 @@
+#[cfg(test)]
+use crate::btree::{load, Index};
+use crate::errors::Error;
+use crate::freelist;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+#[cfg(test)]
+use crate::paging::Page;
+use crate::sys;
+use crate::tier::{self, Tier};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+pub(crate) const PAGE_MAP_FILE: &str = "pagemap";
+const MAGIC: &[u8; 8] = b"TELEPMAP";
+
+/// PageMap maps the page ids the structures refer to onto the slots of the index file holding the
+/// pages. Pages are stored in the slot of their id until they are relocated, only relocated pages
+/// have entries. The map is a permutation of the slots, relocating a page swaps its slot with the
+/// slot of a free page, so no slot is ever held by two pages.
+#[derive(Default)]
+struct PageMap {
+    slots: HashMap<Offset, Offset>,
+    // the inverse, slot → page id.
+    page_ids: HashMap<Offset, Offset>,
+}
+
+impl PageMap {
+    fn slot(&self, page_id: Offset) -> Offset {
+        self.slots.get(&page_id).copied().unwrap_or(page_id)
+    }
+
+    fn page_id(&self, slot: Offset) -> Offset {
+        self.page_ids.get(&slot).copied().unwrap_or(slot)
+    }
+
+    fn assign(&mut self, page_id: Offset, slot: Offset) {
+        if page_id == slot {
+            self.slots.remove(&page_id);
+            self.page_ids.remove(&slot);
+        } else {
+            self.slots.insert(page_id, slot);
+            self.page_ids.insert(slot, page_id);
+        }
+    }
+}
+
+// None until the map was read from its file.
+static MAP: Lazy<RwLock<Option<PageMap>>> = Lazy::new(|| RwLock::new(None));
+
+fn with_map<T>(f: impl FnOnce(&PageMap) -> T) -> T {
+    if let Some(map) = MAP.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return f(map);
+    }
+    let mut map = MAP.write().unwrap_or_else(|e| e.into_inner());
+    // a map which can't be read leaves the pages in the slots of their ids, which fails the
+    // reads of relocated pages with page id mismatches.
+    f(map.get_or_insert_with(|| read().unwrap_or_default()))
+}
+
+/// Returns the slot of the index file holding the page.
+pub(crate) fn slot(page_id: usize) -> usize {
+    match Offset::try_from(page_id) {
+        Ok(page_id) => with_map(|map| map.slot(page_id)).get(),
+        Err(_) => page_id,
+    }
+}
+
+/// Returns the id of the page held by the slot of the index file.
+pub(crate) fn page_id(slot: usize) -> usize {
+    match Offset::try_from(slot) {
+        Ok(slot) => with_map(|map| map.page_id(slot)).get(),
+        Err(_) => slot,
+    }
+}
+
+/// Drops the map read from the file, so that the map of another database is read.
+pub(crate) fn reset() {
+    *MAP.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub(crate) fn delete() {
+    reset();
+    let _ = fs::remove_file(PAGE_MAP_FILE);
+}
+
+/// Moves the page into the slot of the free target page, which takes the slot of the page in
+/// turn, e.g. so that compaction moves the pages at the end of the index file to the front. The
+/// page keeps its id, so no reference to it is rewritten. Changes are committed first. The page is
+/// copied into the target slot before the map is written, and the free page is copied into the
+/// freed slot afterwards, so a crash leaves at most a stale copy in the slot of the free page.
+pub(crate) fn relocate(page_id: Offset, target: Offset) -> Result<(), Error> {
+    io::check_writable()?;
+    // committing may allocate pages, e.g. for the tree stats, so the target is checked after.
+    io::commit();
+    // cold pages aren't stored in the slots of the index file.
+    let cold = |page_id: Offset| tier::tier(page_id.get()) == Tier::Cold;
+    if page_id == target || cold(page_id) || cold(target) {
+        return Err(Error::OutOfRange);
+    }
+    if !freelist::pages()?.1.contains(&target) {
+        return Err(Error::OutOfRange);
+    }
+    let read_page = |page_id: Offset| {
+        let page = io::read_verified(page_id)?;
+        let page = *page.lock().unwrap_or_else(|e| e.into_inner());
+        Ok::<_, Error>(page)
+    };
+    let (page, free_page) = (read_page(page_id)?, read_page(target)?);
+    let mut map = MAP.write().unwrap_or_else(|e| e.into_inner());
+    let map = map.get_or_insert_with(|| read().unwrap_or_default());
+    let (slot, target_slot) = (map.slot(page_id), map.slot(target));
+    io::write_slot(target_slot, &page)?;
+    map.assign(page_id, target_slot);
+    map.assign(target, slot);
+    write(map)?;
+    io::write_slot(slot, &free_page)?;
+    io::check_writable()
+}
+
+fn read() -> std::io::Result<PageMap> {
+    let mut bytes = Vec::new();
+    match File::open(PAGE_MAP_FILE) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PageMap::default()),
+        Err(e) => return Err(e),
+    };
+    let malformed = || std::io::Error::from(ErrorKind::InvalidData);
+    let entries = bytes.strip_prefix(MAGIC.as_slice()).ok_or_else(malformed)?;
+    let mut map = PageMap::default();
+    for entry in entries.chunks(2 * size_of::<u16>()) {
+        let [a, b, c, d] = entry else {
+            return Err(malformed());
+        };
+        let page_id = Offset(u16::from_le_bytes([*a, *b]));
+        map.assign(page_id, Offset(u16::from_le_bytes([*c, *d])));
+    }
+    Ok(map)
+}
+
+// The map is written into a temporary file which is then renamed, so the file either holds the
+// previous map or the new one.
+fn write(map: &PageMap) -> std::io::Result<()> {
+    let temp_path = format!("{}.tmp", PAGE_MAP_FILE);
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    file.write_all(MAGIC)?;
+    for (page_id, slot) in &map.slots {
+        file.write_all(&page_id.0.to_le_bytes())?;
+        file.write_all(&slot.0.to_le_bytes())?;
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, PAGE_MAP_FILE)?;
+    sys::sync_dir(Path::new("."))
+}
+
+#[test]
+#[serial]
+fn verify_relocated_pages_keep_their_ids() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..60u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    io::commit();
+    let free_page = Page::new_data(&io::page_allocator()).unwrap();
+    io::write(&free_page);
+    freelist::push(free_page.page_id()).unwrap();
+    let leaf = index.root();
+    let (leaf_slot, free_slot) = (slot(leaf.get()), slot(free_page.page_id().get()));
+    assert_eq!(leaf_slot, leaf.get());
+
+    relocate(leaf, free_page.page_id()).unwrap();
+    assert_eq!(slot(leaf.get()), free_slot);
+    assert_eq!(slot(free_page.page_id().get()), leaf_slot);
+    assert_eq!(page_id(free_slot), leaf.get());
+    // the map is read from its file once the database is opened again.
+    io::close();
+    let index = Index::open().unwrap();
+    assert_eq!(slot(leaf.get()), free_slot);
+    assert_eq!(load(leaf).unwrap().page_id(), leaf);
+    for i in 0..60u32 {
+        let payload = index.get(Key::from(format!("{:03}", i).as_str())).unwrap().unwrap();
+        assert_eq!(payload.to_bytes(), &i.to_le_bytes());
+    }
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    // the free page is allocated into the slot the leaf left.
+    assert_eq!(Page::new_data(&io::page_allocator()).unwrap().page_id(), free_page.page_id());
+    assert!(matches!(
+        relocate(leaf, free_page.page_id()),
+        Err(Error::OutOfRange)
+    ));
+}
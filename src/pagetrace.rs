@@ -0,0 +1,136 @@
+#[cfg(test)]
+use crate::btree::Index;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::freelist;
+use crate::fsck;
+#[cfg(test)]
+use crate::io::{self, delete_index};
+#[cfg(test)]
+use crate::paging::Page;
+use crate::stats::{self, Operation};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+static TRACING: AtomicBool = AtomicBool::new(cfg!(feature = "page-tracing"));
+// the last allocation or free of each page.
+static TRACES: Lazy<Mutex<HashMap<Offset, PageTrace>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum PageEvent {
+    Allocated,
+    Freed,
+}
+
+/// PageTrace records where a page was allocated or freed: the index operation running at the time,
+/// if any, and the backtrace of the call.
+#[derive(Clone, Debug)]
+pub(crate) struct PageTrace {
+    pub(crate) page_id: Offset,
+    pub(crate) event: PageEvent,
+    #[allow(dead_code)]
+    pub(crate) operation: Option<(Operation, u64)>,
+    #[allow(dead_code)]
+    pub(crate) backtrace: Arc<Backtrace>,
+}
+
+/// Turns the tracing of page allocations and frees on or off. It's on by default in builds with
+/// the page-tracing feature. Capturing a backtrace per allocation is slow, it's meant for tests
+/// and debugging sessions hunting page leaks.
+#[allow(dead_code)]
+pub(crate) fn set_enabled(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        clear();
+    }
+}
+
+pub(crate) fn enabled() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+pub(crate) fn allocated(page_id: Offset) {
+    record(page_id, PageEvent::Allocated);
+}
+
+pub(crate) fn freed(page_id: Offset) {
+    record(page_id, PageEvent::Freed);
+}
+
+fn record(page_id: Offset, event: PageEvent) {
+    if !enabled() {
+        return;
+    }
+    let trace = PageTrace {
+        page_id,
+        event,
+        operation: stats::current_operation(),
+        backtrace: Arc::new(Backtrace::force_capture()),
+    };
+    traces().insert(page_id, trace);
+}
+
+/// Returns the traces of the pages allocated since tracing was enabled which are neither linked
+/// into any structure of the database nor freed, in page id order. Pages allocated by an operation
+/// which is still running, e.g. the right half of a split before the separator is inserted, are
+/// reported as well.
+pub(crate) fn leaks() -> Result<Vec<PageTrace>, Error> {
+    let reachable = fsck::reachable()?;
+    let mut leaks: Vec<PageTrace> = traces()
+        .values()
+        .filter(|trace| trace.event == PageEvent::Allocated && !reachable.contains(&trace.page_id))
+        .cloned()
+        .collect();
+    leaks.sort_by_key(|trace| trace.page_id);
+    Ok(leaks)
+}
+
+pub(crate) fn clear() {
+    traces().clear();
+}
+
+fn traces() -> std::sync::MutexGuard<'static, HashMap<Offset, PageTrace>> {
+    TRACES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+#[serial]
+fn verify_leaked_pages_are_traced() {
+    delete_index();
+    set_enabled(true);
+    let mut index = Index::open().unwrap();
+    for i in 0..100u32 {
+        let key = format!("traced/key/{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert!(leaks().unwrap().is_empty());
+
+    let leaked = Page::new_data(&io::page_allocator()).unwrap().page_id();
+    let freed = Page::new_data(&io::page_allocator()).unwrap().page_id();
+    freelist::push(freed).unwrap();
+    let reported = leaks().unwrap();
+    assert_eq!(reported.len(), 1);
+    assert_eq!(reported[0].page_id, leaked);
+    assert_eq!(reported[0].event, PageEvent::Allocated);
+    assert!(reported[0].operation.is_none());
+    assert!(reported[0].backtrace.to_string().contains("verify_leaked_pages_are_traced"));
+
+    // splits allocate their right pages within the inserts.
+    let operations: Vec<Operation> = traces()
+        .values()
+        .filter_map(|trace| trace.operation.map(|(operation, _)| operation))
+        .collect();
+    assert!(!operations.is_empty());
+    assert!(operations.iter().all(|operation| *operation == Operation::Insert));
+
+    set_enabled(false);
+    assert!(leaks().unwrap().is_empty());
+}
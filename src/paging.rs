@@ -1,24 +1,48 @@
-use crate::config::{get_next_page_id, update_next_page_id};
-use crate::errors::InvalidPageOffsetError;
+#[cfg(test)]
+use crate::btree::{load, Index};
+use crate::checksum::{crc32, crc32_append};
+use crate::allocator::PageAllocator;
+use crate::config;
+use crate::errors::Error;
+use crate::events;
+use crate::freelist;
+use crate::intern::common_prefix_len;
+#[cfg(test)]
+use crate::fsck;
+#[cfg(test)]
+use crate::treefile;
 use crate::io;
+#[cfg(test)]
 use crate::io::delete_index;
-use crate::types::PayloadType::Str;
-use crate::types::{FromLeBytes, Key, Offset, Payload, PayloadType, ToLeBytes};
+use crate::latch;
+use crate::pagetrace;
+use crate::poison::Violation;
+use crate::types::PayloadType::Bytes;
+use crate::types::{
+    read_at, FromLeBytes, Key, Offset, Offset32, PagePayload, Payload, PayloadType, ToLeBytes,
+};
 use alloc::vec::Vec;
+#[cfg(test)]
 use rand::Rng;
+#[cfg(test)]
 use serial_test::serial;
 use std::cmp::min;
 use std::convert::TryInto;
 use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-const ZERO: Offset = Offset(0);
+pub(crate) const ZERO: Offset = Offset(0);
 pub(crate) const PAGE_SIZE: Offset = Offset(8172);
 pub(crate) const PAGE_SIZE_USIZE: usize = PAGE_SIZE.0 as usize;
 
 // min-max ranges.
 const MIN_FAN_OUT: usize = 5;
-const MAX_FAN_OUT: usize = 10;
-const MAX_KEY_SIZE: usize = 1024;
+pub(crate) const MAX_FAN_OUT: usize = 10;
+pub(crate) const MAX_KEY_SIZE: usize = 1024;
+pub(crate) const DEFAULT_MAX_VALUE_SIZE: usize = 1 << 20;
+
+// Values larger than the limit are rejected instead of being spread over overflow pages.
+static MAX_VALUE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_VALUE_SIZE);
 
 // Reference size constants.
 const S_NUM_OF_SLOTS: usize = size_of::<Offset>();
@@ -31,6 +55,9 @@ const S_RIGHT_SIBLING: usize = size_of::<Offset>();
 const S_PARENT_PAGE_ID: usize = size_of::<Offset>();
 const S_FREE_START: usize = size_of::<Offset>();
 const S_FREE_END: usize = size_of::<Offset>();
+const S_CHECKSUM: usize = size_of::<u32>();
+const S_MAGIC: usize = size_of::<u16>();
+const S_FORMAT_VERSION: usize = size_of::<u8>();
 const S_SLOT_TABLE_ITEM: usize = size_of::<Offset>();
 const S_DATA_TYPE: usize = size_of::<u8>();
 // Size of offset reference.
@@ -47,7 +74,10 @@ pub const TOTAL_HEADER_SIZE: usize = S_FLAGS
     + S_PAGE_TYPE
     + S_NUM_OF_SLOTS
     + S_FREE_START
-    + S_FREE_END;
+    + S_FREE_END
+    + S_CHECKSUM
+    + S_MAGIC
+    + S_FORMAT_VERSION;
 
 /// Slot structure as follows:
 ///                   ___________________________________________________________________________________
@@ -55,7 +85,7 @@ pub const TOTAL_HEADER_SIZE: usize = S_FLAGS
 ///                   ----------------------------------------------------------------------------------
 pub const SINGLE_RECORD_METADATA_SPACE_REQUIREMENT: usize =
     SINGLE_SLOT_HEADER_SIZE + S_SLOT_TABLE_ITEM;
-pub const SINGLE_SLOT_HEADER_SIZE: usize = 1 * S_PAGE_ID + 2 * S_DATA_LENGTH + 2 * S_DATA_TYPE;
+pub const SINGLE_SLOT_HEADER_SIZE: usize = S_PAGE_ID + 2 * S_DATA_LENGTH + 2 * S_DATA_TYPE;
 
 /// Offsets in a page header.
 const OFFSET_NUM_OF_SLOTS: usize = 0;
@@ -68,45 +98,183 @@ const OFFSET_RIGHT_SIBLING: usize = OFFSET_LEFT_SIBLING + S_LEFT_SIBLING;
 const OFFSET_PARENT_PAGE_ID: usize = OFFSET_RIGHT_SIBLING + S_RIGHT_SIBLING;
 const OFFSET_FREE_START: usize = OFFSET_PARENT_PAGE_ID + S_PARENT_PAGE_ID;
 const OFFSET_FREE_END: usize = OFFSET_FREE_START + S_FREE_START;
+const OFFSET_CHECKSUM: usize = OFFSET_FREE_END + S_FREE_END;
+const OFFSET_MAGIC: usize = OFFSET_CHECKSUM + S_CHECKSUM;
+const OFFSET_FORMAT_VERSION: usize = OFFSET_MAGIC + S_MAGIC;
+
+/// Stamped into the header of every page written, so that pages of other files, or written before
+/// pages carried it, are told apart from pages failing their checksum.
+pub(crate) const PAGE_MAGIC: u16 = 0x5450;
+/// Format version of the page layout written by this build, raised whenever the layout changes.
+/// Pages of a newer version are refused rather than misread. Version 2 gave overflow pages a type
+/// of their own, version 3 added the prefix shared by the keys of a page.
+pub(crate) const PAGE_FORMAT_VERSION: u8 = 3;
+/// Version of the checksum in the page header, recorded in the config. Version 1 is the CRC32 of
+/// the page with the checksum field left out.
+pub(crate) const PAGE_CHECKSUM_VERSION: u64 = 1;
 
 const F_DELETED: u8 = 9u8;
+const F_HIGH_KEY: u8 = 0x10u8;
+const F_SLOT_TABLE_AT_END: u8 = 0x20u8;
+const F_SORTED_SLOTS: u8 = 0x40u8;
+// Set on pages whose keys are stored without the prefix they share, see `Page::prefix`.
+const F_PREFIX: u8 = 0x80u8;
+// Set in the payload type of slots whose payload spilled into overflow pages, their inline bytes
+// start with the total length of the payload. Slots spilled before the length was stored, or with
+// no room left for it, don't carry it.
+const T_SPILLED_WITH_LENGTH: u8 = 0x80u8;
+const S_TOTAL_LENGTH: usize = size_of::<Offset32>();
 /// Error constants
+#[allow(dead_code)]
 const READ_ERR: &str = "Failed to read page.";
 const O_ERR: &str = "Value exceeds offset type's size.";
 
+/// SlotRef locates the slot of a key in a page: its index in the slot table, and the offset and
+/// length of its bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct SlotRef {
+    pub(crate) index: usize,
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+}
+
 #[derive(Clone, Copy)]
 pub struct Page {
     buffer: [u8; PAGE_SIZE_USIZE],
 }
 
+/// SlotLayout is the layout of the slots of a slotted page. By default the slot table follows the
+/// page header and grows towards the end of the page, while the slots grow from the end towards
+/// the header. With the slot table at the end, the slots follow the header and the slot table
+/// grows from the end towards them, so that searches read the table from a single cache line
+/// range next to the high key, while inserts append slots behind the header. The layout of new
+/// pages is a format option of the database, see `config::get_slot_layout`, each page is flagged
+/// with the layout it was created with, so pages of both layouts are read.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum SlotLayout {
+    #[default]
+    TableAtStart,
+    TableAtEnd,
+}
+
 const DATA_PAGE: u8 = 0;
 const INNER_PAGE: u8 = 1;
+const DENSE_INNER_PAGE: u8 = 2;
+
+/// Dense inner pages of trees with fixed-width u64 keys have neither a slot table nor per-entry
+/// lengths, the keys and the child page ids are stored in two fixed-stride arrays:
+///  _____________________________________________________________
+/// | Page Header | key[0] | key[1] | .. | child[0] | child[1] | .. |
+///  -------------------------------------------------------------
+/// The number of slots in the header holds the number of keys.
+const S_DENSE_KEY: usize = size_of::<u64>();
+pub(crate) const DENSE_CAPACITY: usize =
+    (PAGE_SIZE_USIZE - TOTAL_HEADER_SIZE) / (S_DENSE_KEY + S_PAGE_ID);
+const OFFSET_DENSE_KEYS: usize = TOTAL_HEADER_SIZE;
+const OFFSET_DENSE_CHILDREN: usize = OFFSET_DENSE_KEYS + DENSE_CAPACITY * S_DENSE_KEY;
+
+/// Slotted pages created by a split carry a high key, the exclusive upper bound of the keys the page
+/// and its subtree hold. It's stored at the end of the page, the slots grow backward in front of it:
+///  _______________________________________________________________________
+/// | Page Header | slot table | .. free space .. | slots | high key | size |
+///  -----------------------------------------------------------------------
+/// Pages without the high key flag, e.g. the right most pages of a level, are unbounded.
+const S_HIGH_KEY_LENGTH: usize = size_of::<Offset>();
+/// The prefix shared by the keys of a page is stored in front of the high key, followed by its
+/// length: | slots | prefix | prefix length | high key | high key length |.
+const S_PREFIX_LENGTH: usize = size_of::<Offset>();
+
+const HASH_DIRECTORY_PAGE: u8 = 3;
+
+/// Hash directory pages hold a fixed-stride array of bucket page ids along with the local depth of
+/// the bucket, the number of slots in the header holds the number of entries:
+///  _______________________________________________________________
+/// | Page Header | bucket[0] | depth[0] | bucket[1] | depth[1] | .. |
+///  ---------------------------------------------------------------
+const S_LOCAL_DEPTH: usize = size_of::<u8>();
+const S_DIRECTORY_ENTRY: usize = S_PAGE_ID + S_LOCAL_DEPTH;
+pub(crate) const DIRECTORY_CAPACITY: usize =
+    (PAGE_SIZE_USIZE - TOTAL_HEADER_SIZE) / S_DIRECTORY_ENTRY;
+
+const FREE_LIST_PAGE: u8 = 4;
+
+/// Free list pages hold an array of page ids which can be allocated again, the number of slots in
+/// the header holds the number of page ids.
+pub(crate) const FREE_LIST_CAPACITY: usize = (PAGE_SIZE_USIZE - TOTAL_HEADER_SIZE) / S_PAGE_ID;
+
+const FREE_SPACE_MAP_PAGE: u8 = 5;
+
+/// Free space map pages hold one byte per page, the free bytes of the page in units of
+/// `FREE_SPACE_UNIT`, see `fsm`. The number of slots in the header is left at zero.
+pub(crate) const FREE_SPACE_MAP_CAPACITY: usize = PAGE_SIZE_USIZE - TOTAL_HEADER_SIZE;
+pub(crate) const FREE_SPACE_UNIT: usize = PAGE_SIZE_USIZE / u8::MAX as usize + 1;
+
+/// Overflow pages hold the rest of a payload which didn't fit into its slot, chained through the
+/// id of the next overflow page, see `add_overflow_data`. Overflow pages of version 1 were data
+/// pages.
+const OVERFLOW_PAGE: u8 = 6;
+/// The bytes of a payload an overflow page holds, behind its slot table item, the id of the next
+/// overflow page and the length of the bytes.
+pub(crate) const OVERFLOW_CAPACITY: usize =
+    PAGE_SIZE_USIZE - TOTAL_HEADER_SIZE - S_SLOT_TABLE_ITEM - S_PAGE_ID - S_DATA_LENGTH;
+
+/// Returns the key of a dense page, which holds fixed width keys only.
+pub(crate) fn dense_key(key: &[u8]) -> Result<u64, Error> {
+    key.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| Error::KeyLayoutMismatch)
+}
+
+pub(crate) fn max_value_size() -> usize {
+    MAX_VALUE_SIZE.load(Ordering::Relaxed)
+}
 
-fn next_page() -> Offset {
-    let mut next = get_next_page_id();
-    next = next + 1;
-    update_next_page_id(next);
-    next
+/// Sets the size of the largest value which can be stored.
+pub(crate) fn set_max_value_size(max: usize) {
+    MAX_VALUE_SIZE.store(max, Ordering::Relaxed);
+}
+
+/// Rejects values which exceed the maximum value size.
+pub(crate) fn check_value_size(len: usize) -> Result<(), Error> {
+    let max = max_value_size();
+    if len > max {
+        return Err(Error::ValueTooLarge { max, got: len });
+    }
+    Ok(())
+}
+
+// Pages on the free list are allocated first, the allocator extends the file once it ran dry.
+pub(crate) fn next_page(allocator: &PageAllocator) -> Result<Offset, Error> {
+    if let Ok(Some(page_id)) = freelist::pop() {
+        pagetrace::allocated(page_id);
+        return Ok(page_id);
+    }
+    allocator.allocate()
 }
 
 impl Page {
-    fn new(page_type: u8) -> Self {
-        Self::new_page(page_type, next_page())
+    fn new(page_type: u8, allocator: &PageAllocator) -> Result<Self, Error> {
+        let mut page = Self::new_page(page_type, next_page(allocator)?);
+        // overflow pages, which are created with new_page, are read with the table at the start.
+        if matches!(page_type, DATA_PAGE | INNER_PAGE) {
+            page.set_slot_layout(config::get_slot_layout())?;
+        }
+        Ok(page)
     }
 
-    fn new_page(page_type: u8, page_id: Offset) -> Self {
+    pub(crate) fn new_page(page_type: u8, page_id: Offset) -> Self {
         let mut new_instance = Self {
             buffer: [0u8; PAGE_SIZE_USIZE],
         };
 
-        new_instance.set_flags(0);
+        new_instance.set_flags(F_SORTED_SLOTS);
         new_instance.set_left_most_page_id(ZERO);
         new_instance.set_right_sibling(ZERO);
         new_instance.set_left_sibling(ZERO);
         new_instance.set_parent(ZERO);
         new_instance.set_num_of_slots(ZERO);
         new_instance.set_free_start(TOTAL_HEADER_SIZE.try_into().expect(O_ERR));
-        new_instance.set_free_end(PAGE_SIZE.try_into().expect(O_ERR));
+        new_instance.set_free_end(PAGE_SIZE);
         new_instance.set_page_type(page_type);
         new_instance.set_page_id(page_id);
         new_instance
@@ -116,21 +284,22 @@ impl Page {
         Page { buffer }
     }
 
-    pub fn new_leaf(key: Key, payload: Payload) -> Result<Offset, InvalidPageOffsetError> {
-        let mut head_page = Self::new(DATA_PAGE);
+    #[allow(dead_code)]
+    pub fn new_leaf(key: Key, payload: Payload) -> Result<Offset, Error> {
+        let mut head_page = Self::new(DATA_PAGE, &io::page_allocator())?;
         head_page.add(key, payload)
     }
 
-    pub fn add(&mut self, key: Key, payload: Payload) -> Result<Offset, InvalidPageOffsetError> {
+    pub fn add(&mut self, key: Key, payload: Payload) -> Result<Offset, Error> {
         let head_page = self;
         let current_page_id = head_page.page_id();
-        let mut current_page = head_page;
+        let current_page = head_page;
         let payload_and_page_id = current_page.add_key_data(key, payload)?;
         let mut residual = payload_and_page_id.0;
         let mut page_id = payload_and_page_id.1;
-        io::write(&current_page);
+        io::write(current_page);
         while residual.len() > 0 {
-            let mut current_page = Self::new_page(DATA_PAGE, page_id);
+            let mut current_page = Self::new_overflow(page_id);
             let overflow = current_page.add_overflow_data(residual)?;
             io::write(&current_page);
             residual = overflow.0;
@@ -139,33 +308,276 @@ impl Page {
         Ok(current_page_id)
     }
 
-    pub fn new_inner() -> Self {
-        Self::new(INNER_PAGE)
+    pub fn new_inner(allocator: &PageAllocator) -> Result<Self, Error> {
+        Self::new(INNER_PAGE, allocator)
+    }
+
+    pub fn new_data(allocator: &PageAllocator) -> Result<Self, Error> {
+        Self::new(DATA_PAGE, allocator)
+    }
+
+    pub fn new_dense_inner(allocator: &PageAllocator) -> Result<Self, Error> {
+        Self::new(DENSE_INNER_PAGE, allocator)
+    }
+
+    pub fn new_hash_directory(allocator: &PageAllocator) -> Result<Self, Error> {
+        Self::new(HASH_DIRECTORY_PAGE, allocator)
+    }
+
+    /// Creates a free list page on a page the file is extended by, so that the free list isn't
+    /// popped from while it's pushed to.
+    pub(crate) fn new_free_list_head(
+        allocator: &PageAllocator,
+    ) -> Result<Self, Error> {
+        Ok(Self::new_page(FREE_LIST_PAGE, allocator.allocate()?))
+    }
+
+    pub(crate) fn new_free_space_map(
+        allocator: &PageAllocator,
+    ) -> Result<Self, Error> {
+        Self::new(FREE_SPACE_MAP_PAGE, allocator)
+    }
+
+    /// Creates an overflow page on the page id allocated by the slot or the overflow page
+    /// referring to it.
+    pub(crate) fn new_overflow(page_id: Offset) -> Self {
+        Self::new_page(OVERFLOW_PAGE, page_id)
+    }
+
+    /// Creates an overflow page holding the bytes of a payload, followed by the next overflow page.
+    /// Fails with OutOfRange if the bytes exceed `OVERFLOW_CAPACITY`.
+    pub(crate) fn new_overflow_with(
+        page_id: Offset,
+        next_page_id: Offset,
+        data: &[u8],
+    ) -> Result<Self, Error> {
+        if data.len() > OVERFLOW_CAPACITY {
+            return Err(Error::OutOfRange);
+        }
+        let mut page = Self::new_overflow(page_id);
+        page.add_overflow_slot(next_page_id, data)?;
+        Ok(page)
+    }
+
+    pub(crate) fn slot_layout(&self) -> SlotLayout {
+        if !self.is_marked_deleted() && self.flags() & F_SLOT_TABLE_AT_END != 0 {
+            SlotLayout::TableAtEnd
+        } else {
+            SlotLayout::TableAtStart
+        }
+    }
+
+    /// Sets the slot layout of an empty page, e.g. of a page rebuilt in place by a split, which
+    /// keeps the layout of the page. Fails if the page holds slots, a high key or a prefix.
+    pub(crate) fn set_slot_layout(
+        &mut self,
+        layout: SlotLayout,
+    ) -> Result<(), Error> {
+        if self.num_of_slots() != ZERO || self.trailer_size() != 0 {
+            return Err(Error::OutOfRange);
+        }
+        match layout {
+            SlotLayout::TableAtStart => self.set_flags(self.flags() & !F_SLOT_TABLE_AT_END),
+            SlotLayout::TableAtEnd => self.set_flags(self.flags() | F_SLOT_TABLE_AT_END),
+        }
+        Ok(())
+    }
+
+    // Returns the offset of the slot table item at the index.
+    fn slot_table_item(&self, index: usize) -> usize {
+        match self.slot_layout() {
+            SlotLayout::TableAtStart => TOTAL_HEADER_SIZE + index * S_SLOT_TABLE_ITEM,
+            SlotLayout::TableAtEnd => {
+                PAGE_SIZE_USIZE - self.trailer_size() - (index + 1) * S_SLOT_TABLE_ITEM
+            }
+        }
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.page_type() == DATA_PAGE
+    }
+
+    pub(crate) fn has_known_page_type(&self) -> bool {
+        matches!(
+            self.page_type(),
+            DATA_PAGE
+                | INNER_PAGE
+                | DENSE_INNER_PAGE
+                | HASH_DIRECTORY_PAGE
+                | FREE_LIST_PAGE
+                | FREE_SPACE_MAP_PAGE
+                | OVERFLOW_PAGE
+        )
+    }
+
+    /// Returns true for the pages keys are inserted into, the ones with a slot table.
+    pub(crate) fn is_slotted(&self) -> bool {
+        !self.is_marked_deleted() && matches!(self.page_type(), DATA_PAGE | INNER_PAGE)
+    }
+
+    pub(crate) fn is_free_space_map(&self) -> bool {
+        self.page_type() == FREE_SPACE_MAP_PAGE
+    }
+
+    /// Returns the name of the page type, for diagnostics.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self.page_type() {
+            DATA_PAGE => "data",
+            INNER_PAGE => "inner",
+            DENSE_INNER_PAGE => "dense inner",
+            HASH_DIRECTORY_PAGE => "hash directory",
+            FREE_LIST_PAGE => "free list",
+            FREE_SPACE_MAP_PAGE => "free space map",
+            OVERFLOW_PAGE => "overflow",
+            _ => "unknown",
+        }
+    }
+
+    pub(crate) fn is_dense(&self) -> bool {
+        self.page_type() == DENSE_INNER_PAGE
+    }
+
+    /// A page is full once all of its slots are taken, the remaining free space is reserved for
+    /// them.
+    pub(crate) fn is_full(&self) -> Result<bool, Error> {
+        if self.is_dense() {
+            return Ok(self.num_of_slots().get() == DENSE_CAPACITY);
+        }
+        Ok(self.slots_available()? == 0)
+    }
+
+    pub(crate) fn dense_key_at(&self, index: usize) -> u64 {
+        let offset = OFFSET_DENSE_KEYS + index * S_DENSE_KEY;
+        read_at::<u64>(&self.buffer, offset)
+    }
+
+    pub(crate) fn dense_child_at(&self, index: usize) -> Offset {
+        let offset = OFFSET_DENSE_CHILDREN + index * S_PAGE_ID;
+        read_at::<Offset>(&self.buffer, offset)
+    }
+
+    /// Returns the number of keys less or equal to the given key. The keys are scanned as one
+    /// contiguous array without early exit, so the comparison loop can be vectorized.
+    pub(crate) fn dense_rank(&self, key: u64) -> usize {
+        let len = self.num_of_slots().get();
+        self.buffer[OFFSET_DENSE_KEYS..OFFSET_DENSE_KEYS + len * S_DENSE_KEY]
+            .chunks_exact(S_DENSE_KEY)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .filter(|current| *current <= key)
+            .count()
+    }
+
+    /// Inserts the key and its child in key order, shifting the greater entries by one stride.
+    pub(crate) fn dense_insert(&mut self, key: u64, child: Offset) -> Result<(), Error> {
+        let len = self.num_of_slots().get();
+        if len == DENSE_CAPACITY {
+            return Err(Error::OutOfRange);
+        }
+        let index = self.dense_rank(key);
+        let key_offset = OFFSET_DENSE_KEYS + index * S_DENSE_KEY;
+        let child_offset = OFFSET_DENSE_CHILDREN + index * S_PAGE_ID;
+        self.buffer.copy_within(
+            key_offset..OFFSET_DENSE_KEYS + len * S_DENSE_KEY,
+            key_offset + S_DENSE_KEY,
+        );
+        self.buffer.copy_within(
+            child_offset..OFFSET_DENSE_CHILDREN + len * S_PAGE_ID,
+            child_offset + S_PAGE_ID,
+        );
+        Self::write_le::<u64, S_DENSE_KEY>(&mut self.buffer, key_offset, key, |value| {
+            value.to_le_bytes().to_vec()
+        });
+        Self::write_le::<Offset, S_PAGE_ID>(&mut self.buffer, child_offset, child, |value| {
+            value.to_bytes()
+        });
+        self.set_num_of_slots(self.num_of_slots().checked_add(1)?);
+        Ok(())
     }
 
     pub fn add_left_most(&mut self, left_most_page_id: Offset) {
         self.set_left_most_page_id(left_most_page_id);
     }
 
-    fn add_key_ref(&mut self, key: Key, payload: Payload) -> Result<(), InvalidPageOffsetError> {
-        match self.add_key_data(key, payload) {
+    pub(crate) fn add_key_ref(
+        &mut self,
+        key: Key,
+        payload: impl PagePayload,
+    ) -> Result<(), Error> {
+        match self.add_key_data(key, payload.to_payload()) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
         }
     }
 
-    fn delete_key(&mut self, key: Key) -> Result<(), InvalidPageOffsetError> {
-        let num_of_slots = self.num_of_slots().try_into()?;
-        for i in 0..num_of_slots {
-            if let Ok(current_key) = self.key_at(i)
-                && key.to_str() == current_key
-            {
-                let _ = self.delete_slot(i);
-                io::write(&self);
-                break;
+    /// Removes the slot of the key and writes the page, the counterpart of `add`. Returns false if
+    /// the page doesn't hold the key. The overflow pages of the slot are freed once the page is
+    /// written, so that a crash in between leaks them rather than leaving them referenced.
+    #[allow(dead_code)]
+    pub(crate) fn delete(&mut self, key: Key) -> Result<bool, Error> {
+        let Some(index) = self.find_slot(key)? else {
+            return Ok(false);
+        };
+        let overflow_page_ids = self.overflow_page_ids(index)?;
+        self.delete_slot(index)?;
+        io::write(self);
+        freelist::push_all(&overflow_page_ids)?;
+        Ok(true)
+    }
+
+    /// Replaces the payload of the key and writes the page, returns false if the page doesn't hold
+    /// the key. The slot is overwritten in place if the payload fits into it, and moved into the
+    /// free space otherwise, compacting the page first if needed. Payloads aren't spilled into
+    /// overflow pages, the update fails with NoSpace, leaving the page as it was, if the page has
+    /// no room for the payload next to the headroom kept for its minimum fan-out. The overflow
+    /// pages the previous payload spilled into are freed once the page is written.
+    #[allow(dead_code)]
+    pub(crate) fn update(
+        &mut self,
+        key: Key,
+        payload: Payload,
+    ) -> Result<bool, Error> {
+        let Some(index) = self.find_slot(key)? else {
+            return Ok(false);
+        };
+        check_value_size(payload.len())?;
+        let overflow_page_ids = self.overflow_page_ids(index)?;
+        let key_buf = &key.as_bytes()[self.prefix().len()..];
+        let key_type = self.key_type_at(index)?;
+        let payload_type = payload.payload_type;
+        let slot = Self::encode_slot(key_buf, key_type, payload_type, payload.to_bytes(), ZERO)?;
+        let (start, end) = self.get_slot_boundaries(index)?;
+        if slot.len() <= end - start {
+            self.buffer[start..start + slot.len()].copy_from_slice(&slot);
+            self.buffer[start + slot.len()..end].fill(0);
+        } else {
+            let page = *self;
+            // the payload is cut off the slot, so that compacting the page reclaims its bytes.
+            let key_slot = Self::encode_slot(key_buf, key_type, payload_type, &[], ZERO)?;
+            self.buffer[start..start + key_slot.len()].copy_from_slice(&key_slot);
+            self.buffer[start + key_slot.len()..end].fill(0);
+            if self.room_for_update()? < slot.len() {
+                self.compact()?;
             }
+            let available = self.room_for_update()?;
+            if available < slot.len() {
+                *self = page;
+                return Err(Error::NoSpace { needed: slot.len(), available });
+            }
+            let (start, end) = self.get_slot_boundaries(index)?;
+            let new_start = self.add_slot(&slot)?;
+            self.buffer[start..end].fill(0);
+            self.update_slot_table_item(index, new_start);
         }
-        Ok(())
+        io::write(self);
+        freelist::push_all(&overflow_page_ids)?;
+        Ok(true)
+    }
+
+    // the free space less the headroom reserved for the slots up to the minimum fan-out.
+    fn room_for_update(&self) -> Result<usize, Error> {
+        let free_space: usize = self.free_size()?.try_into()?;
+        let single_record_reservation = SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE;
+        Ok(free_space.saturating_sub(self.slots_available()? * single_record_reservation))
     }
 
     // Adds data into a leaf node.
@@ -174,68 +586,281 @@ impl Page {
     fn add_key_data(
         &mut self,
         key: Key,
+        payload: Payload,
+    ) -> Result<(Payload, Offset), Error> {
+        self.add_typed_key_data(key, Bytes, payload)
+    }
+
+    /// Adds a separator whose key is encoded as a reference into the key dictionary of the tree.
+    pub(crate) fn add_interned_key_ref(
+        &mut self,
+        key: Key,
+        payload: impl PagePayload,
+    ) -> Result<(), Error> {
+        self.add_typed_key_data(key, PayloadType::Interned, payload.to_payload())
+            .map(|_| ())
+    }
+
+    fn add_typed_key_data(
+        &mut self,
+        key: Key,
+        key_buf_type: PayloadType,
         mut payload: Payload,
-    ) -> Result<(Payload, Offset), InvalidPageOffsetError> {
+    ) -> Result<(Payload, Offset), Error> {
+        // a page whose slots are taken is refused unchanged, so that the caller splits it.
+        if self.slots_available()? == 0 {
+            let needed = SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + key.len() + payload.len();
+            return Err(Error::NoSpace { needed, available: 0 });
+        }
+        // keys not sharing the prefix of the page shorten it, interned keys are stored whole.
+        let interned = key_buf_type == PayloadType::Interned;
+        if !key.as_bytes().starts_with(self.prefix()) || interned && !self.prefix().is_empty() {
+            let prefix = self.prefix().to_vec();
+            let len = if interned { 0 } else { common_prefix_len(&prefix, key.as_bytes()) };
+            self.reprefix(&prefix[..len])?;
+        }
         // determine the payload and key size.
         let payload_ref = &payload;
-        let key_buf = key.to_bytes();
+        let key_buf = &key.as_bytes()[self.prefix().len()..];
         let key_buf_size = key_buf.len();
         let payload_size = payload.len();
+        check_value_size(payload_size)?;
         let payload_type = payload_ref.payload_type;
-        let key_buf_type: PayloadType = Str;
-        let slots_available = self.slots_available()?;
-        if slots_available == 0 {
-            panic!("No slot left!");
+        // bytes left between the slots are reclaimed before the payload spills into overflow pages.
+        if !self
+            .available_space_for_payload(key_buf_size)
+            .is_ok_and(|space| space >= payload_size)
+        {
+            self.compact()?;
         }
 
-        let available_net_free_space_for_payload = self.available_space_for_payload(key_buf_size);
+        let space = self.available_space_for_payload(key_buf_size);
+        // not even the key fits next to the headroom kept for the minimum fan-out.
+        let Ok(available_net_free_space_for_payload) = space else {
+            let needed = SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + key_buf_size;
+            let available = self.free_size()?.try_into()?;
+            return Err(Error::NoSpace { needed, available });
+        };
+        // payloads spilling into overflow pages lead with their total length if there is room for
+        // it, so that their length is known without reading the overflow pages.
+        let with_length = payload_size > available_net_free_space_for_payload
+            && available_net_free_space_for_payload >= S_TOTAL_LENGTH;
+        let mut payload_buf = Vec::new();
+        if with_length {
+            let total_length = Offset32::try_from(payload_size)?;
+            payload_buf.extend_from_slice(&total_length.to_bytes());
+        }
         // consume the payload for available net space or payload size if it is smaller than available net space.
-        let mut payload_buf = vec![0; min(available_net_free_space_for_payload?, payload_size)];
-        let _ = payload.read(&mut payload_buf);
-        let mut slot: Vec<u8> =
-            Vec::with_capacity(Self::slot_size(key_buf_size, payload_size).try_into()?);
-        let payload_size_in_offset: Offset = payload_buf.len().try_into()?;
-        let key_buf_size_in_offset: Offset = key_buf_size.try_into()?;
+        let mut inline_buf =
+            vec![0; min(available_net_free_space_for_payload - payload_buf.len(), payload_size)];
+        let _ = payload.read(&mut inline_buf);
+        payload_buf.extend_from_slice(&inline_buf);
         let overflow_page_id = if payload.len() > 0 {
-            next_page()
+            next_page(&io::page_allocator())?
         } else {
             Offset(0)
         };
+        let mut slot =
+            Self::encode_slot(key_buf, key_buf_type, payload_type, &payload_buf, overflow_page_id)?;
+        if with_length {
+            slot[S_DATA_LENGTH] |= T_SPILLED_WITH_LENGTH;
+        }
+
+        let new_free_end = self.add_slot(&slot)?;
+        // advance the free start and slot table with the new free end.
+        self.add_to_slot_table(new_free_end)?;
+        self.sort_last_slot()?;
+        Ok((payload, overflow_page_id))
+    }
+
+    /// Returns the bucket page id and its local depth at the directory index.
+    pub(crate) fn directory_entry_at(&self, index: usize) -> (Offset, u8) {
+        let offset = TOTAL_HEADER_SIZE + index * S_DIRECTORY_ENTRY;
+        let bucket = read_at::<Offset>(&self.buffer, offset);
+        let local_depth =
+            read_at::<u8>(&self.buffer, offset + S_PAGE_ID);
+        (bucket, local_depth)
+    }
+
+    /// Sets the directory entry at the index, the directory grows up to the index if needed.
+    pub(crate) fn set_directory_entry(
+        &mut self,
+        index: usize,
+        bucket: Offset,
+        local_depth: u8,
+    ) -> Result<(), Error> {
+        if index >= DIRECTORY_CAPACITY {
+            return Err(Error::OutOfRange);
+        }
+        let offset = TOTAL_HEADER_SIZE + index * S_DIRECTORY_ENTRY;
+        Self::write_le::<Offset, S_PAGE_ID>(&mut self.buffer, offset, bucket, |value| {
+            value.to_bytes()
+        });
+        Self::write_le::<u8, S_LOCAL_DEPTH>(
+            &mut self.buffer,
+            offset + S_PAGE_ID,
+            local_depth,
+            |value| value.to_le_bytes().to_vec(),
+        );
+        if index >= self.num_of_slots().get() {
+            self.set_num_of_slots(Offset::from_usize(index + 1));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn free_page_at(&self, index: usize) -> Offset {
+        let offset = TOTAL_HEADER_SIZE + index * S_PAGE_ID;
+        read_at::<Offset>(&self.buffer, offset)
+    }
+
+    /// Appends the page id to the free list page.
+    pub(crate) fn push_free_page(&mut self, page_id: Offset) -> Result<(), Error> {
+        let len = self.num_of_slots().get();
+        if len == FREE_LIST_CAPACITY {
+            return Err(Error::OutOfRange);
+        }
+        let offset = TOTAL_HEADER_SIZE + len * S_PAGE_ID;
+        Self::write_le::<Offset, S_PAGE_ID>(&mut self.buffer, offset, page_id, |value| {
+            value.to_bytes()
+        });
+        self.set_num_of_slots(self.num_of_slots().checked_add(1)?);
+        Ok(())
+    }
+
+    /// Removes the last page id of the free list page.
+    pub(crate) fn pop_free_page(&mut self) -> Option<Offset> {
+        let len = self.num_of_slots().get();
+        if len == 0 {
+            return None;
+        }
+        self.set_num_of_slots(self.num_of_slots().saturating_sub(1));
+        Some(self.free_page_at(len - 1))
+    }
+
+    /// Returns the free space of the page at the index of the free space map page, in units.
+    pub(crate) fn free_space_at(&self, index: usize) -> u8 {
+        self.buffer[TOTAL_HEADER_SIZE + index]
+    }
+
+    pub(crate) fn set_free_space(&mut self, index: usize, units: u8) {
+        self.buffer[TOTAL_HEADER_SIZE + index] = units;
+    }
+
+    /// Returns the ids of the overflow pages holding the rest of the payload at the slot index.
+    pub(crate) fn overflow_page_ids(&self, index: usize) -> Result<Vec<Offset>, Error> {
+        let slot_offset =
+            read_at::<Offset>(&self.buffer, self.slot_table_item(index));
+        let overflow_page_ref_offset =
+            slot_offset.get() + S_DATA_LENGTH + S_DATA_TYPE + S_DATA_LENGTH + S_DATA_TYPE;
+        let mut next = read_at::<Offset>(&self.buffer, overflow_page_ref_offset);
+        let mut page_ids = Vec::new();
+        while next != ZERO {
+            page_ids.push(next);
+            let overflow_page = io::read_verified(next)?;
+            let overflow_page = latch::lock(next, &overflow_page);
+            next = overflow_page.get_overflow_data()?.1;
+        }
+        Ok(page_ids)
+    }
+
+    /// Rewrites the page ids stored in the page through the map: its own id, the header links, the
+    /// children of inner pages and the overflow references of data pages. Overflow pages hold
+    /// another slot layout and are remapped with `remap_overflow`.
+    pub(crate) fn remap(&mut self, map: impl Fn(Offset) -> Offset) -> Result<(), Error> {
+        self.remap_header(&map);
+        for i in 0..self.num_of_slots().get() {
+            let offset = match self.page_type() {
+                DENSE_INNER_PAGE => OFFSET_DENSE_CHILDREN + i * S_PAGE_ID,
+                INNER_PAGE => {
+                    let slot_offset = self.slot_offset(i);
+                    let key_len = read_at::<Offset>(
+                        &self.buffer,
+                        slot_offset + S_DATA_LENGTH + S_DATA_TYPE,
+                    );
+                    slot_offset + SINGLE_SLOT_HEADER_SIZE + key_len.get()
+                }
+                DATA_PAGE => self.slot_offset(i) + 2 * (S_DATA_LENGTH + S_DATA_TYPE),
+                _ => return Err(Error::MalformedPayload),
+            };
+            self.remap_at(offset, &map);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the id of the overflow page and the reference to the next one through the map.
+    pub(crate) fn remap_overflow(&mut self, map: impl Fn(Offset) -> Offset) {
+        self.remap_header(&map);
+        let offset = self.slot_offset(0);
+        self.remap_at(offset, &map);
+    }
+
+    fn remap_header(&mut self, map: &impl Fn(Offset) -> Offset) {
+        for offset in [
+            OFFSET_PAGE_ID,
+            OFFSET_LEFT_MOST,
+            OFFSET_LEFT_SIBLING,
+            OFFSET_RIGHT_SIBLING,
+            OFFSET_PARENT_PAGE_ID,
+        ] {
+            self.remap_at(offset, map);
+        }
+    }
+
+    // zero stands for no page and is kept.
+    fn remap_at(&mut self, offset: usize, map: &impl Fn(Offset) -> Offset) {
+        let page_id = read_at::<Offset>(&self.buffer, offset);
+        if page_id != ZERO {
+            Self::write_le::<Offset, S_PAGE_ID>(&mut self.buffer, offset, map(page_id), |value| {
+                value.to_bytes()
+            });
+        }
+    }
+
+    fn encode_slot(
+        key_buf: &[u8],
+        key_buf_type: PayloadType,
+        payload_type: PayloadType,
+        payload_buf: &[u8],
+        overflow_page_id: Offset,
+    ) -> Result<Vec<u8>, Error> {
+        let mut slot: Vec<u8> =
+            Vec::with_capacity(Self::slot_size(key_buf.len(), payload_buf.len())?.try_into()?);
+        let payload_size_in_offset: Offset = payload_buf.len().try_into()?;
+        let key_buf_size_in_offset: Offset = key_buf.len().try_into()?;
         slot.extend_from_slice(&payload_size_in_offset.to_bytes());
         slot.extend_from_slice(&[payload_type as u8]);
         slot.extend_from_slice(&key_buf_size_in_offset.to_bytes());
         slot.extend_from_slice(&[key_buf_type as u8]);
         slot.extend_from_slice(&overflow_page_id.to_bytes());
-        slot.extend_from_slice(key_buf.as_slice());
-        slot.extend_from_slice(&payload_buf);
+        slot.extend_from_slice(key_buf);
+        slot.extend_from_slice(payload_buf);
+        Ok(slot)
+    }
 
-        let new_free_end = self.add_slot(&mut slot)?;
-        // advance the free start and slot table with the new free end.
-        self.add_to_slot_table(new_free_end)?;
-        Ok((payload, overflow_page_id))
+    fn slot_offset(&self, index: usize) -> usize {
+        read_at::<Offset>(&self.buffer, self.slot_table_item(index)).get()
     }
 
     // reserve minimum required space for residual slots.
     fn available_space_for_payload(
-        &mut self,
+        &self,
         key_buf_size: usize,
-    ) -> Result<usize, InvalidPageOffsetError> {
+    ) -> Result<usize, Error> {
         let slots_available = self.slots_available()?;
         if slots_available == 0 {
             return Ok(0);
         }
-        let free_space: usize = self.free_size().try_into()?;
+        let free_space: usize = self.free_size()?.try_into()?;
         let single_record_reservation = SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE;
 
-        Ok(
-            free_space
-            - SINGLE_RECORD_METADATA_SPACE_REQUIREMENT // headroom for the current key-payload.
-            - key_buf_size // current key.
-            - ((slots_available - 1) * single_record_reservation), // reserved headroom to satisfy min. requirements.
-        )
+        free_space
+            .checked_sub(SINGLE_RECORD_METADATA_SPACE_REQUIREMENT) // headroom for the current key-payload.
+            .and_then(|space| space.checked_sub(key_buf_size)) // current key.
+            .and_then(|space| space.checked_sub((slots_available - 1) * single_record_reservation)) // reserved headroom to satisfy min. requirements.
+            .ok_or(Error::OutOfRange)
     }
 
-    fn slots_available(&mut self) -> Result<usize, InvalidPageOffsetError> {
+    fn slots_available(&self) -> Result<usize, Error> {
         let num_of_slots: usize = self.num_of_slots().try_into()?;
         let slots_available: usize = if num_of_slots == MAX_FAN_OUT {
             0
@@ -246,26 +871,14 @@ impl Page {
     }
 
     // Read offset payload as a vector of bytes.
-    fn get_overflow_data(&self) -> Result<(Vec<u8>, Offset), InvalidPageOffsetError> {
+    fn get_overflow_data(&self) -> Result<(Vec<u8>, Offset), Error> {
         let offset_index = TOTAL_HEADER_SIZE;
-        let slot_offset = Self::read_le::<Offset, S_SLOT_TABLE_ITEM>(
-            &self.buffer,
-            offset_index,
-            Offset::from_bytes,
-        );
+        let slot_offset = read_at::<Offset>(&self.buffer, offset_index);
 
-        let next_overflow = Self::read_le::<Offset, S_SLOT_TABLE_ITEM>(
-            &self.buffer,
-            slot_offset.try_into()?,
-            Offset::from_bytes,
-        );
+        let next_overflow = read_at::<Offset>(&self.buffer, slot_offset.try_into()?);
 
         let payload_size_offset = slot_offset.get() + S_OFFSET;
-        let payload_len = Self::read_le::<Offset, S_OFFSET>(
-            &self.buffer,
-            payload_size_offset,
-            Offset::from_bytes,
-        );
+        let payload_len = read_at::<Offset>(&self.buffer, payload_size_offset);
 
         let payload_offset = payload_size_offset + S_OFFSET;
         let payload: Vec<u8> = Self::read_le_into_buffer::<Vec<u8>>(
@@ -283,46 +896,59 @@ impl Page {
     fn add_overflow_data(
         &mut self,
         mut payload: Payload,
-    ) -> Result<(Payload, Offset), InvalidPageOffsetError> {
-        let max_available_payload_size: usize = self
-            .max_available_payload_size_in_overflow_page()
-            .try_into()
-            .expect(O_ERR);
+    ) -> Result<(Payload, Offset), Error> {
+        let max_available_payload_size = self.max_available_payload_size_in_overflow_page()?;
         let copy_size = min(payload.len(), max_available_payload_size);
         let mut payload_in_bytes: Vec<u8> = vec![0; copy_size];
         let _ = payload.read(&mut payload_in_bytes);
-        let payload_size: Offset = copy_size.try_into().expect(O_ERR);
-        let mut slot: Vec<u8> = Vec::with_capacity(copy_size);
         let next_page_id = if payload.len() > 0 {
-            next_page()
+            next_page(&io::page_allocator())?
         } else {
             Offset(0)
         };
+        self.add_overflow_slot(next_page_id, &payload_in_bytes)?;
+        Ok((payload, next_page_id))
+    }
+
+    fn add_overflow_slot(&mut self, next_page_id: Offset, data: &[u8]) -> Result<(), Error> {
+        let payload_size: Offset = data.len().try_into()?;
+        let mut slot: Vec<u8> = Vec::with_capacity(S_PAGE_ID + S_DATA_LENGTH + data.len());
         slot.extend_from_slice(&next_page_id.to_bytes());
         slot.extend_from_slice(&payload_size.to_bytes());
-        slot.extend_from_slice(&payload_in_bytes);
-        let new_free_end = self.add_slot(&mut slot)?;
+        slot.extend_from_slice(data);
+        let new_free_end = self.add_slot(&slot)?;
         // advance the free start and slot table with the new free end.
-        self.add_to_slot_table(new_free_end)?;
-        Ok((payload, next_page_id))
+        self.add_to_slot_table(new_free_end)
     }
 
     /// slot offset[0] → next_page_id | payload_size | payload
-    fn max_available_payload_size_in_overflow_page(&self) -> Offset {
-        self.free_size() - S_SLOT_TABLE_ITEM - S_DATA_LENGTH - S_PAGE_ID
-    }
-
-    fn slot_size(key_len: usize, payload_len: usize) -> Offset {
-        (SINGLE_SLOT_HEADER_SIZE + key_len + payload_len)
+    fn max_available_payload_size_in_overflow_page(&self) -> Result<usize, Error> {
+        let free_size: usize = self.free_size()?.try_into()?;
+        free_size
+            .checked_sub(S_SLOT_TABLE_ITEM + S_DATA_LENGTH + S_PAGE_ID)
+            .ok_or(Error::OutOfRange)
+    }
+
+    fn slot_size(key_len: usize, payload_len: usize) -> Result<Offset, Error> {
+        SINGLE_SLOT_HEADER_SIZE
+            .checked_add(key_len)
+            .and_then(|size| size.checked_add(payload_len))
+            .ok_or(Error::OutOfRange)?
             .try_into()
-            .expect(O_ERR)
     }
 
     pub(crate) fn is_marked_deleted(&self) -> bool {
         self.flags() == F_DELETED
     }
 
-    fn delete_slot(&mut self, index: usize) -> Result<(), InvalidPageOffsetError> {
+    /// Removes the slot at the index: the slots stored left of it are moved right by its length and
+    /// the slot table is shifted left by one item, so that both the number of slots and the free
+    /// space between the slot table and the slots are updated. With the slot table at the end, the
+    /// slots stored right of it are moved left and the table is shifted right instead.
+    pub(crate) fn delete_slot(&mut self, index: usize) -> Result<(), Error> {
+        if self.slot_layout() == SlotLayout::TableAtEnd {
+            return self.delete_slot_before_table(index);
+        }
         let (start, end) = self.get_slot_boundaries(index)?;
         let slot_len = end - start;
         let free_end: usize = self.free_end().try_into()?;
@@ -333,10 +959,17 @@ impl Page {
             let new_free_end = free_end + slot_len;
             self.buffer.copy_within(free_end..start, new_free_end);
             //TODO overflow handling.
-            self.set_free_end(Offset::from_usize(new_free_end));
-            for i in index + 1..num_of_slots {
-                self.shift_right_offset_value_in_slot_table_item(i, Offset::from_usize(slot_len));
+            self.set_free_end(new_free_end.try_into()?);
+            // the slots stored left of it, which aren't the slots after it in the table once
+            // updates moved slots.
+            for i in (0..num_of_slots).filter(|i| *i != index) {
+                if self.slot_offset(i) < start {
+                    self.shift_right_offset_value_in_slot_table_item(i, slot_len.try_into()?);
+                }
             }
+        } else {
+            // the slot is the first one, its bytes are left to the free space.
+            self.set_free_end((free_end + slot_len).try_into()?);
         }
 
         {
@@ -358,13 +991,81 @@ impl Page {
         }
 
         let num_of_slots = self.num_of_slots();
-        self.set_num_of_slots(num_of_slots - 1);
-        self.set_free_start(Offset::from_usize(end_of_table - S_SLOT_TABLE_ITEM));
+        self.set_num_of_slots(num_of_slots.checked_sub(1)?);
+        self.set_free_start((end_of_table - S_SLOT_TABLE_ITEM).try_into()?);
         Ok(())
     }
 
+    fn delete_slot_before_table(&mut self, index: usize) -> Result<(), Error> {
+        let (start, end) = self.get_slot_boundaries(index)?;
+        let slot_len = end - start;
+        let free_start: usize = self.free_start().try_into()?;
+        let num_of_slots: usize = self.num_of_slots().try_into()?;
+        self.buffer.copy_within(end..free_start, start);
+        self.buffer[free_start - slot_len..free_start].fill(0);
+        for i in (0..num_of_slots).filter(|i| *i != index) {
+            let slot_offset = self.slot_offset(i);
+            if slot_offset > start {
+                self.update_slot_table_item(i, (slot_offset - slot_len).try_into()?);
+            }
+        }
+        self.set_free_start((free_start - slot_len).try_into()?);
+
+        // the items of the slots after it are stored in front of its item.
+        let free_end: usize = self.free_end().try_into()?;
+        let slot_item_start = self.slot_table_item(index);
+        self.buffer.copy_within(free_end..slot_item_start, free_end + S_SLOT_TABLE_ITEM);
+        self.buffer[free_end..free_end + S_SLOT_TABLE_ITEM].fill(0);
+        self.set_num_of_slots(self.num_of_slots().checked_sub(1)?);
+        self.set_free_end((free_end + S_SLOT_TABLE_ITEM).try_into()?);
+        Ok(())
+    }
+
+    /// Moves the slots of a slotted page next to each other in front of the high key, keeping their
+    /// order, and updates the slot table, so that bytes left between the slots, e.g. by pages
+    /// written before deletes reclaimed the space of the first slot, are added to the free space.
+    /// Returns the number of bytes reclaimed.
+    pub(crate) fn compact(&mut self) -> Result<usize, Error> {
+        if self.is_dense() {
+            return Ok(0);
+        }
+        let mut slots = Vec::new();
+        for i in 0..self.num_of_slots().get() {
+            let (start, end) = self.get_slot_boundaries(i)?;
+            slots.push((start, end, i));
+        }
+        if self.slot_layout() == SlotLayout::TableAtEnd {
+            // the slots are moved next to the header, the ones closest to it first.
+            slots.sort_unstable_by_key(|(start, _, _)| *start);
+            let mut free_start = TOTAL_HEADER_SIZE;
+            for (start, end, i) in slots {
+                self.buffer.copy_within(start..end, free_start);
+                self.update_slot_table_item(i, free_start.try_into()?);
+                free_start += end - start;
+            }
+            let old_free_start: usize = self.free_start().try_into()?;
+            self.buffer[free_start..old_free_start].fill(0);
+            self.set_free_start(free_start.try_into()?);
+            return Ok(old_free_start - free_start);
+        }
+        // the slots closest to the end of the page are moved first, so that no slot is overwritten
+        // before it's moved.
+        slots.sort_unstable_by_key(|(start, _, _)| std::cmp::Reverse(*start));
+        let mut free_end = PAGE_SIZE_USIZE - self.trailer_size();
+        for (start, end, i) in slots {
+            let new_start = free_end - (end - start);
+            self.buffer.copy_within(start..end, new_start);
+            self.update_slot_table_item(i, new_start.try_into()?);
+            free_end = new_start;
+        }
+        let old_free_end: usize = self.free_end().try_into()?;
+        self.buffer[old_free_end..free_end].fill(0);
+        self.set_free_end(free_end.try_into()?);
+        Ok(free_end - old_free_end)
+    }
+
     fn update_slot_table_item(&mut self, index: usize, offset: Offset) {
-        let slot_item_offset = TOTAL_HEADER_SIZE + index * S_SLOT_TABLE_ITEM;
+        let slot_item_offset = self.slot_table_item(index);
         let start: usize = slot_item_offset;
         let end: usize = start + S_SLOT_TABLE_ITEM;
         let new_offset_value = &offset.to_bytes();
@@ -372,12 +1073,8 @@ impl Page {
     }
 
     fn shift_right_offset_value_in_slot_table_item(&mut self, index: usize, amount: Offset) {
-        let slot_item_offset = TOTAL_HEADER_SIZE + index * S_SLOT_TABLE_ITEM;
-        let slot_offset = Self::read_le::<Offset, S_OFFSET>(
-            &self.buffer,
-            slot_item_offset,
-            Offset::from_bytes,
-        );
+        let slot_item_offset = self.slot_table_item(index);
+        let slot_offset = read_at::<Offset>(&self.buffer, slot_item_offset);
         let new_offset_value = slot_offset + amount;
         let start: usize = slot_item_offset;
         let end: usize = start + S_SLOT_TABLE_ITEM;
@@ -386,30 +1083,18 @@ impl Page {
     }
 
 
-    fn get_slot_boundaries(&self, index: usize) -> Result<(usize, usize), InvalidPageOffsetError> {
-        let slot_offset_in_table = TOTAL_HEADER_SIZE + index * S_SLOT_TABLE_ITEM;
-        let slot_offset = Self::read_le::<Offset, S_OFFSET>(
-            &self.buffer,
-            slot_offset_in_table,
-            Offset::from_bytes,
-        );
+    fn get_slot_boundaries(&self, index: usize) -> Result<(usize, usize), Error> {
+        let slot_offset_in_table = self.slot_table_item(index);
+        let slot_offset = read_at::<Offset>(&self.buffer, slot_offset_in_table);
 
-        let payload_len = Self::read_le::<Offset, S_DATA_LENGTH>(
-            &self.buffer,
-            slot_offset.try_into()?,
-            Offset::from_bytes,
-        );
+        let payload_len = read_at::<Offset>(&self.buffer, slot_offset.try_into()?);
 
         let slot_offset_usize: usize = slot_offset.try_into()?;
         let payload_type_offset = slot_offset_usize + S_DATA_LENGTH;
         let key_len_offset = payload_type_offset + S_DATA_TYPE;
-        let key_len = Self::read_le::<Offset, S_DATA_LENGTH>(
-            &self.buffer,
-            key_len_offset,
-            Offset::from_bytes,
-        );
+        let key_len = read_at::<Offset>(&self.buffer, key_len_offset);
 
-        let total_slot_size = Self::slot_size(key_len.try_into()?, payload_len.try_into()?);
+        let total_slot_size = Self::slot_size(key_len.try_into()?, payload_len.try_into()?)?;
         let end = slot_offset + total_slot_size;
         Ok((slot_offset_usize, end.try_into()?))
     }
@@ -417,7 +1102,18 @@ impl Page {
     // new_free_end is the new position of the free_end after inserting a new slot at the end of the
     // page. The slots make the page grow backward:
     // | Page Header | slot table | ... free space ... | new slot | prev slot | .. |
-    fn add_to_slot_table(&mut self, new_free_end: Offset) -> Result<(), InvalidPageOffsetError> {
+    // With the slot table at the end, the item of the slot is prepended to the table instead:
+    // | Page Header | .. | prev slot | new slot | ... free space ... | new item | slot table |
+    fn add_to_slot_table(&mut self, new_free_end: Offset) -> Result<(), Error> {
+        if self.slot_layout() == SlotLayout::TableAtEnd {
+            let free_end: usize = self.free_end().try_into()?;
+            let start = free_end - S_SLOT_TABLE_ITEM;
+            self.buffer[start..free_end].copy_from_slice(&new_free_end.to_bytes());
+            self.set_free_end(start.try_into()?);
+            self.set_num_of_slots(self.num_of_slots().checked_add(1)?);
+            debug_assert!(self.free_start() <= self.free_end());
+            return Ok(());
+        }
         let free_start = self.free_start();
         let new_free_end_offset = &new_free_end.to_bytes();
         let start: usize = free_start.try_into()?;
@@ -425,16 +1121,154 @@ impl Page {
         self.buffer[start..end].copy_from_slice(new_free_end_offset);
         let size_of_slot_table_item: Offset = S_SLOT_TABLE_ITEM.try_into()?;
         self.set_free_start(free_start + size_of_slot_table_item);
-        self.set_num_of_slots(self.num_of_slots() + 1);
+        self.set_num_of_slots(self.num_of_slots().checked_add(1)?);
         debug_assert!(self.free_start() <= self.free_end());
         Ok(())
     }
 
-    fn get_for_key(&self, key: Key) -> Result<Option<String>, InvalidPageOffsetError> {
+    /// Returns the index of the slot holding the given key.
+    pub(crate) fn find_slot(&self, key: Key) -> Result<Option<usize>, Error> {
+        Ok(self.find(key)?.map(|slot| slot.index))
+    }
+
+    /// Returns the slot holding the given key. The slot table of a sorted page is binary searched,
+    /// the one of a page in insertion order is scanned.
+    pub(crate) fn find(&self, key: Key) -> Result<Option<SlotRef>, Error> {
+        let num_of_slots = self.num_of_slots().get();
+        let Some(key) = key.as_bytes().strip_prefix(self.prefix()) else {
+            return Ok(None);
+        };
+        let index = if self.has_sorted_slots() {
+            let index = self.partition_point(num_of_slots, key)?;
+            (index > 0 && self.key_slice_at(index - 1)? == key).then(|| index - 1)
+        } else {
+            let mut found = None;
+            for i in 0..num_of_slots {
+                if key == self.key_slice_at(i)? {
+                    found = Some(i);
+                    break;
+                }
+            }
+            found
+        };
+        let Some(index) = index else {
+            return Ok(None);
+        };
+        let (start, end) = self.get_slot_boundaries(index)?;
+        Ok(Some(SlotRef {
+            index,
+            offset: start,
+            len: end - start,
+        }))
+    }
+
+    /// Returns the payload of the key in a leaf as it's stored, with the parts spilled into
+    /// overflow pages read back. None if the page doesn't hold the key or holds its tombstone.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Payload>, Error> {
+        match self.find_slot(Key::from(key))? {
+            Some(index) if !self.is_tombstone_at(index)? => Ok(Some(self.value_at(index)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the child of an inner page covering the key, the one referenced by the greatest
+    /// separator less or equal to the key. None if the separators aren't in key order, interned
+    /// separators are compared through the dictionary of the index.
+    pub(crate) fn child(&self, key: &[u8]) -> Result<Option<Offset>, Error> {
+        let rank = if self.is_dense() {
+            self.dense_rank(dense_key(key)?)
+        } else if self.has_sorted_slots() {
+            self.rank(key)?
+        } else {
+            return Ok(None);
+        };
+        match rank {
+            0 => Ok(Some(self.left_most_page_id())),
+            rank if self.is_dense() => Ok(Some(self.dense_child_at(rank - 1))),
+            rank => self.payload_as(rank - 1).map(Some),
+        }
+    }
+
+    /// Returns true if the slot table is in key order. Pages are created sorted and stay sorted
+    /// until a key is interned into them, interned keys don't sort by their bytes. Pages written
+    /// before slots were sorted are in insertion order.
+    pub(crate) fn has_sorted_slots(&self) -> bool {
+        !self.is_marked_deleted() && self.flags() & F_SORTED_SLOTS != 0
+    }
+
+    /// Returns the number of slots whose keys are less or equal to the key, by binary search. Only
+    /// meaningful for pages with sorted slots, see `has_sorted_slots`.
+    pub(crate) fn rank(&self, key: &[u8]) -> Result<usize, Error> {
+        let num_of_slots = self.num_of_slots().get();
+        let prefix = self.prefix();
+        match key.strip_prefix(prefix) {
+            Some(key) => self.partition_point(num_of_slots, key),
+            // keys not sharing the prefix are less or greater than all keys of the page.
+            None if key < prefix => Ok(0),
+            None => Ok(num_of_slots),
+        }
+    }
+
+    // Returns the number of slots among the first ones whose keys are less or equal to the key,
+    // which is compared as it's stored, without the prefix of the page.
+    fn partition_point(&self, slots: usize, key: &[u8]) -> Result<usize, Error> {
+        let (mut low, mut high) = (0, slots);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.key_slice_at(middle)? <= key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        Ok(low)
+    }
+
+    // Moves the item of the slot added last into its place in key order, shifting the items after
+    // it by one.
+    fn sort_last_slot(&mut self) -> Result<(), Error> {
+        if !self.has_sorted_slots() {
+            return Ok(());
+        }
+        let last = self.num_of_slots().get() - 1;
+        if self.key_type_at(last)? == PayloadType::Interned {
+            self.set_flags(self.flags() & !F_SORTED_SLOTS);
+            return Ok(());
+        }
+        let index = self.partition_point(last, self.key_slice_at(last)?)?;
+        let offset = self.slot_offset(last);
+        for i in (index..last).rev() {
+            self.update_slot_table_item(i + 1, Offset::from_usize(self.slot_offset(i)));
+        }
+        self.update_slot_table_item(index, Offset::from_usize(offset));
+        Ok(())
+    }
+
+    /// Returns a copy of the raw slot at the given index, including the slot header, so that it can
+    /// be moved into another page with `push_slot` without touching its overflow pages. The key is
+    /// stored without the prefix of the page, the page pushed to needs the same prefix.
+    pub(crate) fn slot_at(&self, index: usize) -> Result<Vec<u8>, Error> {
+        let (start, end) = self.get_slot_boundaries(index)?;
+        Ok(self.buffer[start..end].to_vec())
+    }
+
+    /// Appends a raw slot copied with `slot_at`.
+    pub(crate) fn push_slot(&mut self, slot: &[u8]) -> Result<(), Error> {
+        let free_size: usize = self.free_size()?.try_into()?;
+        if free_size < slot.len() + S_SLOT_TABLE_ITEM {
+            return Err(Error::OutOfRange);
+        }
+        let new_free_end = self.add_slot(slot)?;
+        self.add_to_slot_table(new_free_end)?;
+        self.sort_last_slot()
+    }
+
+    #[allow(dead_code)]
+    fn get_for_key(&self, key: Key) -> Result<Option<String>, Error> {
         let num_of_slots = self.num_of_slots().try_into()?;
         for i in 0..num_of_slots {
             if let Ok(current_key) = self.key_at(i)
-                && key.to_str() == current_key
+                && key.as_bytes() == current_key.as_slice()
             {
                 let found = self.payload_at(i);
                 return match found {
@@ -446,32 +1280,34 @@ impl Page {
         Ok(None)
     }
 
-    fn payload_at(&self, index: usize) -> Result<String, InvalidPageOffsetError> {
-        let index_usize: usize = index;
-        let offset_index = TOTAL_HEADER_SIZE + (index_usize * S_SLOT_TABLE_ITEM);
+    fn payload_at(&self, index: usize) -> Result<String, Error> {
+        self.value_at(index).map(|value| value.to_str())
+    }
+
+    /// Decodes the payload of the slot at the given index.
+    pub(crate) fn payload_as<T: PagePayload>(&self, index: usize) -> Result<T, Error> {
+        match self.inline_value_at(index)? {
+            Some(bytes) => T::from_stored(bytes, self.payload_type_at(index)?),
+            None => T::from_payload(&self.value_at(index)?),
+        }
+    }
+
+    /// Reads the payload of the slot at the given index, following its overflow chain if the
+    /// payload did not fit into the page.
+    pub(crate) fn value_at(&self, index: usize) -> Result<Payload, Error> {
+        let offset_index = self.slot_table_item(index);
         let slot_offset =
-            Self::read_le::<Offset, S_OFFSET>(&self.buffer, offset_index, Offset::from_bytes);
-        let payload_len = Self::read_le::<Offset, S_DATA_LENGTH>(
-            &self.buffer,
-            slot_offset.try_into()?,
-            Offset::from_bytes,
-        );
+            read_at::<Offset>(&self.buffer, offset_index);
+        let payload_len = read_at::<Offset>(&self.buffer, slot_offset.try_into()?);
         let slot_offset_usize: usize = slot_offset.try_into()?;
         let payload_type_offset = slot_offset_usize + S_DATA_LENGTH;
-        let _ = Self::read_le::<u8, S_DATA_TYPE>(&self.buffer, payload_type_offset, u8::from_bytes);
+        let raw_payload_type = read_at::<u8>(&self.buffer, payload_type_offset);
+        let payload_type: PayloadType = (raw_payload_type & !T_SPILLED_WITH_LENGTH).try_into()?;
         let key_len_offset = payload_type_offset + S_DATA_TYPE;
-        let key_len = Self::read_le::<Offset, S_DATA_LENGTH>(
-            &self.buffer,
-            key_len_offset,
-            Offset::from_bytes,
-        );
+        let key_len = read_at::<Offset>(&self.buffer, key_len_offset);
         let key_type_offset = key_len_offset + S_DATA_LENGTH;
         let overflow_page_ref_offset = key_type_offset + S_DATA_TYPE;
-        let overflow_page_ref = Self::read_le::<Offset, S_PAGE_ID>(
-            &self.buffer,
-            overflow_page_ref_offset,
-            Offset::from_bytes,
-        );
+        let overflow_page_ref = read_at::<Offset>(&self.buffer, overflow_page_ref_offset);
         let key_offset = overflow_page_ref_offset + S_PAGE_ID;
         let key_len_usize: usize = key_len.try_into()?;
         let page_size: usize = PAGE_SIZE.try_into()?;
@@ -486,58 +1322,103 @@ impl Page {
         );
         let mut current_right_sibling = overflow_page_ref;
         if current_right_sibling == ZERO {
-            return Ok(Self::stringify(payload));
+            return Ok(Payload::from_vec(payload, payload_type));
         }
-
-        loop {
-            if current_right_sibling == ZERO {
-                break;
-            }
-
-            let current_right_sibling_id: usize = current_right_sibling.try_into()?;
-            current_right_sibling = match io::read(current_right_sibling_id) {
-                Some(overflow_page) => {
-                    let mutex = overflow_page.lock().unwrap();
-                    if let Ok((overflow_data, next_overflow)) = mutex.get_overflow_data() {
-                        payload.extend_from_slice(&overflow_data);
-                        next_overflow
-                    } else {
-                        ZERO
-                    }
-                }
-                None => {
-                    panic!("Page ID out of bounds");
-                }
-            };
+        let total_length = match raw_payload_type & T_SPILLED_WITH_LENGTH {
+            0 => None,
+            _ => Some(Self::total_length(&payload)?),
+        };
+        if total_length.is_some() {
+            payload.drain(..S_TOTAL_LENGTH);
         }
 
-        Ok(Self::stringify(payload))
+        // a corrupt overflow page poisons the database like any other page read.
+        while current_right_sibling != ZERO {
+            let overflow_page = io::read_verified(current_right_sibling)?;
+            let mutex = latch::lock(current_right_sibling, &overflow_page);
+            let (overflow_data, next_overflow) = mutex.get_overflow_data()?;
+            payload.extend_from_slice(&overflow_data);
+            current_right_sibling = next_overflow;
+        }
+        // a chain cut short, e.g. by an overflow page overwritten, is noticed by its length.
+        if total_length.is_some_and(|total_length| total_length != payload.len()) {
+            return Err(Error::MalformedPayload);
+        }
+        Ok(Payload::from_vec(payload, payload_type))
     }
 
-    fn stringify(data: Vec<u8>) -> String {
-        String::from_utf8_lossy(data.as_slice()).to_string()
+    /// Returns the length of the payload at the slot index. Overflow pages are only read for
+    /// payloads spilled without their total length.
+    pub(crate) fn value_len_at(&self, index: usize) -> Result<usize, Error> {
+        if let Some(value) = self.inline_value_at(index)? {
+            return Ok(value.len());
+        }
+        let slot_offset = self.slot_offset(index);
+        if read_at::<u8>(&self.buffer, slot_offset + S_DATA_LENGTH) & T_SPILLED_WITH_LENGTH == 0 {
+            return Ok(self.value_at(index)?.len());
+        }
+        let key_len = read_at::<Offset>(&self.buffer, slot_offset + S_DATA_LENGTH + S_DATA_TYPE);
+        let payload_offset = slot_offset + SINGLE_SLOT_HEADER_SIZE + key_len.get();
+        let inline = self
+            .buffer
+            .get(payload_offset..payload_offset + S_TOTAL_LENGTH)
+            .ok_or(Error::MalformedPayload)?;
+        Self::total_length(inline)
+    }
+
+    fn total_length(inline: &[u8]) -> Result<usize, Error> {
+        let bytes = inline.get(..S_TOTAL_LENGTH).ok_or(Error::MalformedPayload)?;
+        usize::try_from(Offset32::from_bytes(bytes))
+    }
+
+    /// Returns the payload at the slot index as it's stored in the page, without copying it. None if
+    /// a part of the payload was spilled into overflow pages.
+    pub(crate) fn inline_value_at(&self, index: usize) -> Result<Option<&[u8]>, Error> {
+        let slot_offset = self.slot_offset(index);
+        let payload_len = read_at::<Offset>(&self.buffer, slot_offset).get();
+        let key_len = read_at::<Offset>(&self.buffer, slot_offset + S_DATA_LENGTH + S_DATA_TYPE);
+        let overflow_page_ref_offset = slot_offset + 2 * (S_DATA_LENGTH + S_DATA_TYPE);
+        if read_at::<Offset>(&self.buffer, overflow_page_ref_offset) != ZERO {
+            return Ok(None);
+        }
+        let payload_offset = slot_offset + SINGLE_SLOT_HEADER_SIZE + key_len.get();
+        self.buffer
+            .get(payload_offset..payload_offset + payload_len)
+            .map(Some)
+            .ok_or(Error::MalformedPayload)
     }
 
-    fn add_slot(&mut self, slot: &Vec<u8>) -> Result<Offset, InvalidPageOffsetError> {
-        let free_end = self.free_end();
-        let new_free_end = free_end - slot.len();
+    // Slots which don't fit into the free space are rejected rather than overwriting the slot table.
+    // Returns the offset of the slot.
+    fn add_slot(&mut self, slot: &[u8]) -> Result<Offset, Error> {
+        let free_start: usize = self.free_start().try_into()?;
+        let free_end: usize = self.free_end().try_into()?;
+        if self.slot_layout() == SlotLayout::TableAtEnd {
+            let new_free_start = free_start
+                .checked_add(slot.len())
+                .filter(|new_free_start| *new_free_start <= free_end)
+                .ok_or(Error::OutOfRange)?;
+            self.buffer[free_start..new_free_start].copy_from_slice(slot);
+            self.set_free_start(new_free_start.try_into()?);
+            return free_start.try_into();
+        }
+        let new_free_end = free_end
+            .checked_sub(slot.len())
+            .filter(|new_free_end| *new_free_end >= free_start)
+            .ok_or(Error::OutOfRange)?;
         // update the buffer with key-payload.
-        self.buffer[new_free_end.try_into().expect(O_ERR)..free_end.try_into()?]
-            .copy_from_slice(&slot);
+        self.buffer[new_free_end..free_end].copy_from_slice(slot);
+        let new_free_end: Offset = new_free_end.try_into()?;
         self.set_free_end(new_free_end);
         debug_assert!(self.free_start() <= self.free_end());
         // As we reverse traverse the slot blocks, the old free_end becomes the start of the slot.
         Ok(new_free_end)
     }
 
-    pub(crate) fn free_size(&self) -> Offset {
-        self.free_end() - self.free_start()
-    }
-
-    fn read_le<T, const N: usize>(buf: &[u8], offset: usize, f: fn(Vec<u8>) -> T) -> T {
-        let slice = &buf[offset..offset + N];
-        let arr: [u8; N] = slice.try_into().expect("slice length mismatch");
-        f(arr.to_vec())
+    /// Returns the free space between the slot table and the slots, a page whose slot table runs
+    /// into its slots fails with OutOfRange.
+    pub(crate) fn free_size(&self) -> Result<Offset, Error> {
+        self.free_end().checked_sub(self.free_start().get())
     }
 
     fn read_le_into_buffer<T>(buf: &[u8], offset: usize, length: usize, f: fn(Vec<u8>) -> T) -> T {
@@ -551,12 +1432,8 @@ impl Page {
     }
 
     /// Returns the number of slots from the first two bytes in the page.
-    fn num_of_slots(&self) -> Offset {
-        Self::read_le::<Offset, S_NUM_OF_SLOTS>(
-            &self.buffer,
-            OFFSET_NUM_OF_SLOTS,
-            Offset::from_bytes,
-        )
+    pub(crate) fn num_of_slots(&self) -> Offset {
+        read_at::<Offset>(&self.buffer, OFFSET_NUM_OF_SLOTS)
     }
 
     fn set_num_of_slots(&mut self, num: Offset) {
@@ -569,7 +1446,7 @@ impl Page {
     }
 
     pub(crate) fn page_id(&self) -> Offset {
-        Self::read_le::<Offset, S_PAGE_ID>(&self.buffer, OFFSET_PAGE_ID, |v| Offset::from_bytes(v))
+        read_at::<Offset>(&self.buffer, OFFSET_PAGE_ID)
     }
 
     fn set_page_id(&mut self, num: Offset) {
@@ -582,10 +1459,50 @@ impl Page {
         &self.buffer
     }
 
+    /// Returns the page as it's written to the disk, stamped with the magic and the format version
+    /// of pages and with the CRC32 of the page in the header. The checksum isn't kept up to date in
+    /// memory, it's computed on each write.
+    pub(crate) fn sealed(&self) -> [u8; PAGE_SIZE_USIZE] {
+        let mut sealed = *self;
+        sealed.buffer[OFFSET_MAGIC..OFFSET_MAGIC + S_MAGIC]
+            .copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        sealed.buffer[OFFSET_FORMAT_VERSION] = PAGE_FORMAT_VERSION;
+        let checksum = sealed.checksum();
+        sealed.buffer[OFFSET_CHECKSUM..OFFSET_CHECKSUM + S_CHECKSUM]
+            .copy_from_slice(&checksum.to_le_bytes());
+        sealed.buffer
+    }
+
+    /// Checks the page as it was read from the disk, failing with the violation unless it carries
+    /// the magic of pages, a format version this build reads and a matching checksum. Pages which
+    /// were never written are all zeroes and pass.
+    pub(crate) fn verify(&self) -> Result<(), Violation> {
+        if self.buffer.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        let magic = u16::from_le_bytes([self.buffer[OFFSET_MAGIC], self.buffer[OFFSET_MAGIC + 1]]);
+        if magic != PAGE_MAGIC {
+            return Err(Violation::UnknownMagic(magic));
+        }
+        let version = self.buffer[OFFSET_FORMAT_VERSION];
+        if version > PAGE_FORMAT_VERSION {
+            return Err(Violation::UnsupportedPageVersion(version));
+        }
+        let (stored, computed) = (read_at::<u32>(&self.buffer, OFFSET_CHECKSUM), self.checksum());
+        if stored != computed {
+            return Err(Violation::ChecksumMismatch { stored, computed });
+        }
+        Ok(())
+    }
+
+    // the CRC32 of the page with the checksum field left out.
+    fn checksum(&self) -> u32 {
+        let crc = crc32(&self.buffer[..OFFSET_CHECKSUM]);
+        crc32_append(crc, &self.buffer[OFFSET_CHECKSUM + S_CHECKSUM..])
+    }
+
     pub(crate) fn page_type(&self) -> u8 {
-        Self::read_le::<u8, S_PAGE_TYPE>(&self.buffer, OFFSET_PAGE_TYPE, |value| {
-            u8::from_bytes(value)
-        })
+        read_at::<u8>(&self.buffer, OFFSET_PAGE_TYPE)
     }
 
     fn set_page_type(&mut self, num: u8) {
@@ -595,7 +1512,7 @@ impl Page {
     }
 
     fn flags(&self) -> u8 {
-        Self::read_le::<u8, S_FLAGS>(&self.buffer, OFFSET_FLAGS, u8::from_bytes)
+        read_at::<u8>(&self.buffer, OFFSET_FLAGS)
     }
 
     fn set_flags(&mut self, num: u8) {
@@ -604,25 +1521,21 @@ impl Page {
         });
     }
 
-    fn left_most_page_id(&self) -> Offset {
-        Self::read_le::<Offset, S_LEFT_MOST>(&self.buffer, OFFSET_LEFT_MOST, Offset::from_bytes)
+    pub(crate) fn left_most_page_id(&self) -> Offset {
+        read_at::<Offset>(&self.buffer, OFFSET_LEFT_MOST)
     }
 
-    fn set_left_most_page_id(&mut self, num: Offset) {
+    pub(crate) fn set_left_most_page_id(&mut self, num: Offset) {
         Self::write_le::<Offset, S_LEFT_MOST>(&mut self.buffer, OFFSET_LEFT_MOST, num, |value| {
             value.to_bytes()
         });
     }
 
-    fn left_sibling(&self) -> Offset {
-        Self::read_le::<Offset, S_LEFT_SIBLING>(
-            &self.buffer,
-            OFFSET_LEFT_SIBLING,
-            Offset::from_bytes,
-        )
+    pub(crate) fn left_sibling(&self) -> Offset {
+        read_at::<Offset>(&self.buffer, OFFSET_LEFT_SIBLING)
     }
 
-    fn set_left_sibling(&mut self, num: Offset) {
+    pub(crate) fn set_left_sibling(&mut self, num: Offset) {
         Self::write_le::<Offset, S_LEFT_SIBLING>(
             &mut self.buffer,
             OFFSET_LEFT_SIBLING,
@@ -631,15 +1544,11 @@ impl Page {
         );
     }
 
-    fn right_sibling(&self) -> Offset {
-        Self::read_le::<Offset, S_RIGHT_SIBLING>(
-            &self.buffer,
-            OFFSET_RIGHT_SIBLING,
-            Offset::from_bytes,
-        )
+    pub(crate) fn right_sibling(&self) -> Offset {
+        read_at::<Offset>(&self.buffer, OFFSET_RIGHT_SIBLING)
     }
 
-    fn set_right_sibling(&mut self, num: Offset) {
+    pub(crate) fn set_right_sibling(&mut self, num: Offset) {
         Self::write_le::<Offset, S_RIGHT_SIBLING>(
             &mut self.buffer,
             OFFSET_RIGHT_SIBLING,
@@ -648,15 +1557,11 @@ impl Page {
         );
     }
 
-    fn parent(&self) -> Offset {
-        Self::read_le::<Offset, S_PARENT_PAGE_ID>(
-            &self.buffer,
-            OFFSET_PARENT_PAGE_ID,
-            Offset::from_bytes,
-        )
+    pub(crate) fn parent(&self) -> Offset {
+        read_at::<Offset>(&self.buffer, OFFSET_PARENT_PAGE_ID)
     }
 
-    fn set_parent(&mut self, num: Offset) {
+    pub(crate) fn set_parent(&mut self, num: Offset) {
         Self::write_le::<Offset, S_PARENT_PAGE_ID>(
             &mut self.buffer,
             OFFSET_PARENT_PAGE_ID,
@@ -666,7 +1571,7 @@ impl Page {
     }
 
     pub(crate) fn free_start(&self) -> Offset {
-        Self::read_le::<Offset, S_FREE_START>(&self.buffer, OFFSET_FREE_START, Offset::from_bytes)
+        read_at::<Offset>(&self.buffer, OFFSET_FREE_START)
     }
 
     fn set_free_start(&mut self, num: Offset) {
@@ -676,7 +1581,7 @@ impl Page {
     }
 
     fn free_end(&self) -> Offset {
-        Self::read_le::<Offset, S_FREE_END>(&self.buffer, OFFSET_FREE_END, Offset::from_bytes)
+        read_at::<Offset>(&self.buffer, OFFSET_FREE_END)
     }
 
     fn set_free_end(&mut self, num: Offset) {
@@ -685,86 +1590,606 @@ impl Page {
         });
     }
 
-    fn key_at(&self, index: usize) -> Result<String, InvalidPageOffsetError> {
-        let slot_offset = Self::read_le::<Offset, S_SLOT_TABLE_ITEM>(
-            &self.buffer,
-            TOTAL_HEADER_SIZE + (index * S_SLOT_TABLE_ITEM),
-            Offset::from_bytes,
-        );
+    pub(crate) fn key_at(&self, index: usize) -> Result<Vec<u8>, Error> {
+        Ok([self.prefix(), self.key_slice_at(index)?].concat())
+    }
+
+    /// Returns the key at the slot index as it's stored in the page, without copying it. The
+    /// prefix of the page isn't part of it, see `prefix`.
+    pub(crate) fn key_slice_at(&self, index: usize) -> Result<&[u8], Error> {
+        let slot_offset =
+            read_at::<Offset>(&self.buffer, self.slot_table_item(index));
 
         let slot_offset_usize: usize = slot_offset.try_into()?;
         // we don't need to read the payload length which is stored in the first register.
         // let's skip it to resolve the payload type.
         let payload_type_offset = slot_offset_usize + S_OFFSET;
         //TODO according to payload type we should use deserialization helper.
-        // let payload_type = Self::read_le::<u8, SIZE_FLAGS>(&self.buffer, payload_type_offset, u8::from_bytes);
+        // let payload_type = read_at::<u8>(&self.buffer, payload_type_offset);
         let key_len_offset = payload_type_offset + S_PAGE_TYPE;
-        let key_len = Self::read_le::<Offset, S_SLOT_TABLE_ITEM>(
-            &self.buffer,
-            key_len_offset,
-            Offset::from_bytes,
-        );
+        let key_len = read_at::<Offset>(&self.buffer, key_len_offset);
 
         let key_type_offset = key_len_offset + S_OFFSET;
         let overflow_page_ref_offset = key_type_offset + S_FLAGS;
         let key_offset = overflow_page_ref_offset + S_PAGE_ID;
         let key_len_usize: usize = key_len.try_into()?;
-        let key_value =
-            Self::read_le_into_buffer::<Vec<u8>>(&self.buffer, key_offset, key_len_usize, |b| b);
+        self.buffer
+            .get(key_offset..key_offset + key_len_usize)
+            .ok_or(Error::MalformedPayload)
+    }
+
+    /// Walks the slots in slot order and yields their keys and payloads as they're stored in the
+    /// page, without copying them, the keys without the prefix of the page. Payloads spilled into
+    /// overflow pages are None, `value_at` reads them. Dense pages have no keyed slots to walk.
+    #[allow(dead_code)]
+    pub(crate) fn iter(
+        &self,
+    ) -> impl Iterator<Item = Result<(&[u8], Option<&[u8]>), Error>> + '_ {
+        let len = if self.is_dense() { 0 } else { self.num_of_slots().get() };
+        (0..len).map(|index| Ok((self.key_slice_at(index)?, self.inline_value_at(index)?)))
+    }
 
-        Ok(Self::stringify(key_value))
+    pub(crate) fn payload_type_at(&self, index: usize) -> Result<PayloadType, Error> {
+        let payload_type_offset = self.slot_offset(index) + S_DATA_LENGTH;
+        (read_at::<u8>(&self.buffer, payload_type_offset) & !T_SPILLED_WITH_LENGTH).try_into()
+    }
+
+    /// Returns true if the slot at the index holds the tombstone of a logically deleted key.
+    pub(crate) fn is_tombstone_at(&self, index: usize) -> Result<bool, Error> {
+        Ok(self.payload_type_at(index)? == PayloadType::Tombstone)
+    }
+
+    pub(crate) fn key_type_at(&self, index: usize) -> Result<PayloadType, Error> {
+        let slot_offset =
+            read_at::<Offset>(&self.buffer, self.slot_table_item(index));
+        let key_type_offset = slot_offset.get() + S_DATA_LENGTH + S_DATA_TYPE + S_DATA_LENGTH;
+        read_at::<u8>(&self.buffer, key_type_offset).try_into()
+    }
+
+    /// Returns the exclusive upper bound of the keys in the page, None if the page is unbounded.
+    pub(crate) fn high_key(&self) -> Option<&[u8]> {
+        let size = self.high_key_size();
+        if size == 0 {
+            return None;
+        }
+        let start = PAGE_SIZE_USIZE.checked_sub(size)?;
+        self.buffer.get(start..PAGE_SIZE_USIZE - S_HIGH_KEY_LENGTH)
+    }
+
+    /// Sets or removes the high key of a slotted page, moving the slots, or the slot table if it's
+    /// at the end, in front of it. Fails if the page has no room for it.
+    pub(crate) fn set_high_key(&mut self, key: Option<&[u8]>) -> Result<(), Error> {
+        if !self.is_leaf() && self.page_type() != INNER_PAGE {
+            return Err(Error::OutOfRange);
+        }
+        if key.is_some_and(|key| key.len() > MAX_KEY_SIZE) {
+            return Err(Error::OutOfRange);
+        }
+        let new_size = key.map_or(0, |key| key.len() + S_HIGH_KEY_LENGTH);
+        let high_key_start = self.resize_trailer(PAGE_SIZE_USIZE, self.high_key_size(), new_size)?;
+        match key {
+            Some(key) => {
+                let key_len: Offset = key.len().try_into()?;
+                self.buffer[high_key_start..high_key_start + key.len()].copy_from_slice(key);
+                self.buffer[PAGE_SIZE_USIZE - S_HIGH_KEY_LENGTH..]
+                    .copy_from_slice(&key_len.to_bytes());
+                self.set_flags(self.flags() | F_HIGH_KEY);
+            }
+            None => self.set_flags(self.flags() & !F_HIGH_KEY),
+        }
+        Ok(())
+    }
+
+    // Resizes the part of the trailer of the page ending at end, the high key or the prefix, from
+    // old_size to new_size bytes. The bytes between the free space and the part are moved along:
+    // the slots, or the slot table if it's at the end, and the prefix if the high key is resized.
+    // Returns the start of the part, which is zeroed. Fails if the page has no room for it.
+    fn resize_trailer(
+        &mut self,
+        end: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<usize, Error> {
+        let free_start: usize = self.free_start().try_into()?;
+        let free_end: usize = self.free_end().try_into()?;
+        let new_free_end = (free_end + old_size)
+            .checked_sub(new_size)
+            .filter(|new_free_end| *new_free_end >= free_start)
+            .ok_or(Error::OutOfRange)?;
+        self.buffer.copy_within(free_end..end - old_size, new_free_end);
+        if new_free_end > free_end {
+            self.buffer[free_end..new_free_end].fill(0);
+        }
+        // the slots move along, or the slot table if it's at the end, which is read from in front
+        // of the new trailer once its size is set.
+        if self.slot_layout() == SlotLayout::TableAtStart {
+            for i in 0..self.num_of_slots().get() {
+                let slot_offset = self.slot_offset(i) - free_end + new_free_end;
+                self.update_slot_table_item(i, slot_offset.try_into()?);
+            }
+        }
+        self.set_free_end(new_free_end.try_into()?);
+        let start = end - new_size;
+        self.buffer[start..end].fill(0);
+        Ok(start)
+    }
+
+    // The bytes the high key takes at the end of the page, zero if there is none.
+    fn high_key_size(&self) -> usize {
+        if self.is_marked_deleted() || self.flags() & F_HIGH_KEY == 0 {
+            return 0;
+        }
+        read_at::<Offset>(&self.buffer, PAGE_SIZE_USIZE - S_HIGH_KEY_LENGTH).get() + S_HIGH_KEY_LENGTH
+    }
+
+    /// Returns the prefix shared by the keys of the page, which is stripped off the keys as they're
+    /// stored. Empty unless the prefix flag is set, see `compress_prefix`.
+    pub(crate) fn prefix(&self) -> &[u8] {
+        let size = self.prefix_size();
+        let end = PAGE_SIZE_USIZE - self.high_key_size() - S_PREFIX_LENGTH;
+        match size {
+            0 => &[],
+            size => &self.buffer[end + S_PREFIX_LENGTH - size..end],
+        }
+    }
+
+    // The bytes the prefix takes in front of the high key, zero if there is none.
+    fn prefix_size(&self) -> usize {
+        if self.is_marked_deleted() || self.flags() & F_PREFIX == 0 {
+            return 0;
+        }
+        let end = PAGE_SIZE_USIZE - self.high_key_size();
+        read_at::<Offset>(&self.buffer, end - S_PREFIX_LENGTH).get() + S_PREFIX_LENGTH
+    }
+
+    // The bytes the high key and the prefix take at the end of the page.
+    fn trailer_size(&self) -> usize {
+        self.high_key_size() + self.prefix_size()
+    }
+
+    /// Sets the prefix of an empty page, e.g. of the halves of a split page, which are pushed the
+    /// slots of the page as they're stored. Fails if the page holds slots.
+    pub(crate) fn set_prefix(&mut self, prefix: &[u8]) -> Result<(), Error> {
+        if self.num_of_slots() != ZERO {
+            return Err(Error::OutOfRange);
+        }
+        self.write_prefix(prefix)
+    }
+
+    fn write_prefix(&mut self, prefix: &[u8]) -> Result<(), Error> {
+        if prefix.len() > MAX_KEY_SIZE {
+            return Err(Error::OutOfRange);
+        }
+        let end = PAGE_SIZE_USIZE - self.high_key_size();
+        let new_size = if prefix.is_empty() { 0 } else { prefix.len() + S_PREFIX_LENGTH };
+        let start = self.resize_trailer(end, self.prefix_size(), new_size)?;
+        if prefix.is_empty() {
+            self.set_flags(self.flags() & !F_PREFIX);
+            return Ok(());
+        }
+        let prefix_len: Offset = prefix.len().try_into()?;
+        self.buffer[start..start + prefix.len()].copy_from_slice(prefix);
+        self.buffer[end - S_PREFIX_LENGTH..end].copy_from_slice(&prefix_len.to_bytes());
+        self.set_flags(self.flags() | F_PREFIX);
+        Ok(())
+    }
+
+    /// Strips the longest prefix the keys of a leaf share off the keys as they're stored, so that
+    /// the slots take less room. The prefix of the page, if any, is extended. Returns the bytes
+    /// saved, nothing is changed unless the prefix saves more than it takes. Only pages with
+    /// sorted slots are compressed, their first and last keys share the prefix of all keys.
+    pub(crate) fn compress_prefix(&mut self) -> Result<usize, Error> {
+        let num_of_slots = self.num_of_slots().get();
+        if !self.is_leaf() || !self.has_sorted_slots() || num_of_slots < 2 {
+            return Ok(0);
+        }
+        let first = self.key_slice_at(0)?;
+        let len = common_prefix_len(first, self.key_slice_at(num_of_slots - 1)?);
+        let added = if self.prefix().is_empty() { len + S_PREFIX_LENGTH } else { len };
+        let saved = (len * num_of_slots).saturating_sub(added);
+        if saved == 0 {
+            return Ok(0);
+        }
+        let prefix = [self.prefix(), &first[..len]].concat();
+        self.reprefix(&prefix)?;
+        Ok(saved)
+    }
+
+    /// Returns whether the key can be added to a page which isn't full. Keys not sharing the prefix
+    /// of the page shorten it when they're added, which takes room for the bytes given back to the
+    /// keys stored. The headroom kept for the slots up to the minimum fan-out is left as it is.
+    pub(crate) fn has_room_for(&self, key: &[u8]) -> Result<bool, Error> {
+        let prefix = self.prefix();
+        if key.starts_with(prefix) {
+            return Ok(true);
+        }
+        let len = common_prefix_len(prefix, key);
+        let given_back = (prefix.len() - len) * self.num_of_slots().get();
+        let returned = if len == 0 { prefix.len() + S_PREFIX_LENGTH } else { prefix.len() - len };
+        let single_record_reservation = SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE;
+        let free_space: usize = self.free_size()?.try_into()?;
+        Ok(free_space + returned
+            >= given_back + self.slots_available()? * single_record_reservation)
+    }
+
+    // Stores the keys of the page with the given prefix stripped off instead of the prefix of the
+    // page, the slots are rebuilt in slot order. Fails, leaving the page as it was, if a key
+    // doesn't start with the prefix or the page has no room for the keys.
+    fn reprefix(&mut self, prefix: &[u8]) -> Result<(), Error> {
+        let old_prefix = self.prefix().to_vec();
+        let mut slots = Vec::with_capacity(self.num_of_slots().get());
+        for index in 0..self.num_of_slots().get() {
+            let key = [old_prefix.as_slice(), self.key_slice_at(index)?].concat();
+            let key = key.strip_prefix(prefix).ok_or(Error::OutOfRange)?;
+            slots.push(Self::with_key(&self.slot_at(index)?, key)?);
+        }
+        let new_size = if prefix.is_empty() { 0 } else { prefix.len() + S_PREFIX_LENGTH };
+        let slots_size: usize = slots.iter().map(|slot| slot.len() + S_SLOT_TABLE_ITEM).sum();
+        if TOTAL_HEADER_SIZE + slots_size + self.high_key_size() + new_size > PAGE_SIZE_USIZE {
+            return Err(Error::OutOfRange);
+        }
+        let free_end = PAGE_SIZE_USIZE - self.trailer_size();
+        self.buffer[TOTAL_HEADER_SIZE..free_end].fill(0);
+        self.set_num_of_slots(ZERO);
+        self.set_free_start(TOTAL_HEADER_SIZE.try_into()?);
+        self.set_free_end(free_end.try_into()?);
+        self.write_prefix(prefix)?;
+        for slot in slots {
+            self.push_slot(&slot)?;
+        }
+        Ok(())
+    }
+
+    // Returns a copy of the raw slot with the key replaced.
+    fn with_key(slot: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+        let key_len_offset = S_DATA_LENGTH + S_DATA_TYPE;
+        let key_len = read_at::<Offset>(slot, key_len_offset).get();
+        let key_end = SINGLE_SLOT_HEADER_SIZE + key_len;
+        let new_key_len: Offset = key.len().try_into()?;
+        let mut new_slot = slot[..SINGLE_SLOT_HEADER_SIZE].to_vec();
+        new_slot[key_len_offset..key_len_offset + S_DATA_LENGTH]
+            .copy_from_slice(&new_key_len.to_bytes());
+        let rest = slot.get(key_end..).ok_or(Error::MalformedPayload)?;
+        new_slot.extend_from_slice(key);
+        new_slot.extend_from_slice(rest);
+        Ok(new_slot)
     }
 
     pub(crate) fn mark_deleted(&mut self) {
         self.set_flags(F_DELETED)
     }
 
-    pub(crate) fn merge_into(&mut self, target_page: &mut Page) -> Result<(), InvalidPageOffsetError> {
+    #[allow(dead_code)]
+    pub(crate) fn merge_into(&mut self, target_page: &mut Page) -> Result<(), Error> {
         let num_of_slots: usize = self.num_of_slots().get();
         for i in 0..num_of_slots {
             let key = self.key_at(i)?;
             let payload = self.payload_at(i)?;
-            let _ = target_page.add(Key::from_str(key), Payload::from_str(payload));
+            let _ = target_page.add(Key::from(key.as_slice()), Payload::from_str(payload));
             self.mark_deleted();
             io::write(self)
         }
+        events::emit(|listener| listener.on_page_merge(self.page_id(), target_page.page_id()));
+        Ok(())
+    }
+}
+
+/// Pages written before pages carried a checksum end their header in front of the checksum, the
+/// slot table, the arrays of dense, directory and free list pages and the slots of pages with the
+/// slot table at the end start right behind it.
+const LEGACY_HEADER_SIZE: usize = OFFSET_CHECKSUM;
+const HEADER_GROWTH: usize = TOTAL_HEADER_SIZE - LEGACY_HEADER_SIZE;
+const LEGACY_DENSE_CAPACITY: usize =
+    (PAGE_SIZE_USIZE - LEGACY_HEADER_SIZE) / (S_DENSE_KEY + S_PAGE_ID);
+const LEGACY_OFFSET_DENSE_CHILDREN: usize =
+    LEGACY_HEADER_SIZE + LEGACY_DENSE_CAPACITY * S_DENSE_KEY;
+const OFFSET_OVERFLOW_REF: usize = 2 * (S_DATA_LENGTH + S_DATA_TYPE);
+
+/// LegacyPage is a page as it was written before pages carried a checksum, read to be rewritten
+/// with the wider header by `widen`, see `upgrade`. The fields of the header kept their offsets,
+/// the contents behind the header are read at their legacy offsets. Offsets pointing past the page
+/// fail with MalformedPayload.
+#[derive(Clone, Copy)]
+pub(crate) struct LegacyPage {
+    page: Page,
+}
+
+impl LegacyPage {
+    pub(crate) fn new_from(buffer: [u8; PAGE_SIZE_USIZE]) -> Self {
+        LegacyPage { page: Page::new_from(buffer) }
+    }
+
+    /// Returns the page for reading the fields of its header.
+    pub(crate) fn header(&self) -> &Page {
+        &self.page
+    }
+
+    /// Returns the children of a legacy inner or dense page, the left most one first.
+    pub(crate) fn children(&self) -> Result<Vec<Offset>, Error> {
+        let mut children = vec![self.page.left_most_page_id()];
+        for index in 0..self.page.num_of_slots().get() {
+            let offset = match self.page.page_type() {
+                DENSE_INNER_PAGE => LEGACY_OFFSET_DENSE_CHILDREN + index * S_PAGE_ID,
+                _ => {
+                    let start = self.slot(index)?.0;
+                    let key_len = self.read(start + S_DATA_LENGTH + S_DATA_TYPE)?.get();
+                    start + SINGLE_SLOT_HEADER_SIZE + key_len
+                }
+            };
+            children.push(self.read(offset)?);
+        }
+        Ok(children)
+    }
+
+    /// Returns the first overflow page of each payload of a legacy data page which spilled.
+    pub(crate) fn overflow_refs(&self) -> Result<Vec<Offset>, Error> {
+        let mut refs = Vec::new();
+        for index in 0..self.page.num_of_slots().get() {
+            let overflow_ref = self.read(self.slot(index)?.0 + OFFSET_OVERFLOW_REF)?;
+            if overflow_ref != ZERO {
+                refs.push(overflow_ref);
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Returns the bytes of a payload held by a legacy overflow page, and the next overflow page.
+    pub(crate) fn overflow_data(&self) -> Result<(&[u8], Offset), Error> {
+        let start = self.read(LEGACY_HEADER_SIZE)?.get();
+        let next_page_id = self.read(start)?;
+        let len = self.read(start + S_PAGE_ID)?.get();
+        let offset = start + S_PAGE_ID + S_DATA_LENGTH;
+        let data = self.page.buffer.get(offset..offset + len).ok_or(Error::MalformedPayload)?;
+        Ok((data, next_page_id))
+    }
+
+    /// Returns the bucket pages of a legacy hash directory page.
+    pub(crate) fn directory_buckets(&self) -> Result<Vec<Offset>, Error> {
+        (0..self.page.num_of_slots().get())
+            .map(|index| self.read(LEGACY_HEADER_SIZE + index * S_DIRECTORY_ENTRY))
+            .collect()
+    }
+
+    /// Returns the page with the wider header in front of the contents of the legacy page. Slotted
+    /// pages need the bytes the header grew by between their slot table and their slots, dense and
+    /// directory pages need them behind their arrays, pages short of them fail with NoSpace, see
+    /// `cut_spilled_slot`. Free list pages drop the page ids which don't fit any more, fsck
+    /// reclaims them as orphans. The checksum is stamped when the page is written.
+    pub(crate) fn widen(&self) -> Result<Page, Error> {
+        if self.page.is_marked_deleted() {
+            return Ok(self.widen_truncated());
+        }
+        let old = &self.page.buffer;
+        let mut page = Page::new_from([0u8; PAGE_SIZE_USIZE]);
+        page.buffer[..LEGACY_HEADER_SIZE].copy_from_slice(&old[..LEGACY_HEADER_SIZE]);
+        let len = self.page.num_of_slots().get();
+        match self.page.page_type() {
+            DENSE_INNER_PAGE => {
+                Self::check_capacity(len, DENSE_CAPACITY, S_DENSE_KEY + S_PAGE_ID)?;
+                page.buffer[OFFSET_DENSE_KEYS..][..len * S_DENSE_KEY]
+                    .copy_from_slice(&old[LEGACY_HEADER_SIZE..][..len * S_DENSE_KEY]);
+                page.buffer[OFFSET_DENSE_CHILDREN..][..len * S_PAGE_ID]
+                    .copy_from_slice(&old[LEGACY_OFFSET_DENSE_CHILDREN..][..len * S_PAGE_ID]);
+            }
+            HASH_DIRECTORY_PAGE => {
+                Self::check_capacity(len, DIRECTORY_CAPACITY, S_DIRECTORY_ENTRY)?;
+                page.buffer[TOTAL_HEADER_SIZE..][..len * S_DIRECTORY_ENTRY]
+                    .copy_from_slice(&old[LEGACY_HEADER_SIZE..][..len * S_DIRECTORY_ENTRY]);
+            }
+            FREE_LIST_PAGE => {
+                let len = len.min(FREE_LIST_CAPACITY);
+                page.buffer[TOTAL_HEADER_SIZE..][..len * S_PAGE_ID]
+                    .copy_from_slice(&old[LEGACY_HEADER_SIZE..][..len * S_PAGE_ID]);
+                page.set_num_of_slots(len.try_into()?);
+            }
+            DATA_PAGE | INNER_PAGE | OVERFLOW_PAGE => {
+                let free_start = self.page.free_start().get();
+                let free_end = self.page.free_end().get();
+                if free_start < LEGACY_HEADER_SIZE || free_end > PAGE_SIZE_USIZE {
+                    return Err(Error::MalformedPayload);
+                }
+                let available = free_end.checked_sub(free_start).ok_or(Error::MalformedPayload)?;
+                if available < HEADER_GROWTH {
+                    return Err(Error::NoSpace { needed: HEADER_GROWTH, available });
+                }
+                // the slot table, or the slots of pages with the slot table at the end, move
+                // behind the wider header, everything behind the free space stays in place.
+                page.buffer[TOTAL_HEADER_SIZE..free_start + HEADER_GROWTH]
+                    .copy_from_slice(&old[LEGACY_HEADER_SIZE..free_start]);
+                page.buffer[free_end..].copy_from_slice(&old[free_end..]);
+                page.set_free_start((free_start + HEADER_GROWTH).try_into()?);
+                if page.slot_layout() == SlotLayout::TableAtEnd {
+                    for index in 0..len {
+                        let offset = page.slot_offset(index) + HEADER_GROWTH;
+                        page.update_slot_table_item(index, offset.try_into()?);
+                    }
+                }
+            }
+            _ => return Err(Error::MalformedPayload),
+        }
+        Ok(page)
+    }
+
+    /// Returns the page with the wider header and the header fields of the legacy page, but none of
+    /// its contents, for pages which aren't read, e.g. free pages.
+    pub(crate) fn widen_truncated(&self) -> Page {
+        let mut page = Page::new_from([0u8; PAGE_SIZE_USIZE]);
+        page.buffer[..LEGACY_HEADER_SIZE].copy_from_slice(&self.page.buffer[..LEGACY_HEADER_SIZE]);
+        page.set_num_of_slots(ZERO);
+        page.set_free_start(TOTAL_HEADER_SIZE.try_into().expect(O_ERR));
+        page.set_free_end(PAGE_SIZE);
+        if !page.is_marked_deleted() {
+            page.set_flags(page.flags() & !(F_HIGH_KEY | F_PREFIX));
+        }
+        page
+    }
+
+    /// Makes room for the wider header in a legacy data page by cutting the last bytes the header
+    /// grew by off the inline part of a payload which spilled into overflow pages. Returns the
+    /// first overflow page of the payload and the bytes cut, which belong in front of the bytes of
+    /// its overflow pages. None if no payload spilled with that many bytes inline.
+    pub(crate) fn cut_spilled_slot(&mut self) -> Result<Option<(Offset, Vec<u8>)>, Error> {
+        for index in 0..self.page.num_of_slots().get() {
+            let (start, end) = self.slot(index)?;
+            let overflow_ref = self.read(start + OFFSET_OVERFLOW_REF)?;
+            let payload_len = self.read(start)?.get();
+            let kept = match self.page.buffer[start + S_DATA_LENGTH] & T_SPILLED_WITH_LENGTH {
+                0 => 0,
+                _ => S_TOTAL_LENGTH,
+            };
+            if overflow_ref == ZERO || payload_len < kept + HEADER_GROWTH {
+                continue;
+            }
+            let cut = self.page.buffer[end - HEADER_GROWTH..end].to_vec();
+            Page::write_le::<Offset, S_DATA_LENGTH>(
+                &mut self.page.buffer,
+                start,
+                (payload_len - HEADER_GROWTH).try_into()?,
+                |value| value.to_bytes(),
+            );
+            let num_of_slots = self.page.num_of_slots().get();
+            match self.page.slot_layout() {
+                // the slots stored left of it move right by the bytes cut.
+                SlotLayout::TableAtStart => {
+                    let free_end = self.page.free_end().get();
+                    self.page
+                        .buffer
+                        .copy_within(free_end..end - HEADER_GROWTH, free_end + HEADER_GROWTH);
+                    self.page.buffer[free_end..free_end + HEADER_GROWTH].fill(0);
+                    for i in 0..num_of_slots {
+                        let offset = self.read(self.slot_table_item(i))?.get();
+                        if offset <= start {
+                            self.set_slot_offset(i, offset + HEADER_GROWTH)?;
+                        }
+                    }
+                    self.page.set_free_end((free_end + HEADER_GROWTH).try_into()?);
+                }
+                // the slots stored right of it move left by the bytes cut.
+                SlotLayout::TableAtEnd => {
+                    let free_start = self.page.free_start().get();
+                    self.page.buffer.copy_within(end..free_start, end - HEADER_GROWTH);
+                    self.page.buffer[free_start - HEADER_GROWTH..free_start].fill(0);
+                    for i in 0..num_of_slots {
+                        let offset = self.read(self.slot_table_item(i))?.get();
+                        if offset > start {
+                            self.set_slot_offset(i, offset - HEADER_GROWTH)?;
+                        }
+                    }
+                    self.page.set_free_start((free_start - HEADER_GROWTH).try_into()?);
+                }
+            }
+            return Ok(Some((overflow_ref, cut)));
+        }
+        Ok(None)
+    }
+
+    fn check_capacity(len: usize, capacity: usize, stride: usize) -> Result<(), Error> {
+        if len > capacity {
+            return Err(Error::NoSpace { needed: len * stride, available: capacity * stride });
+        }
+        Ok(())
+    }
+
+    // Returns the offset of the slot table item at the index.
+    fn slot_table_item(&self, index: usize) -> usize {
+        match self.page.slot_layout() {
+            SlotLayout::TableAtStart => LEGACY_HEADER_SIZE + index * S_SLOT_TABLE_ITEM,
+            SlotLayout::TableAtEnd => self.page.slot_table_item(index),
+        }
+    }
+
+    // Returns the start and the end of the slot at the index.
+    fn slot(&self, index: usize) -> Result<(usize, usize), Error> {
+        let start = self.read(self.slot_table_item(index))?.get();
+        let payload_len = self.read(start)?.get();
+        let key_len = self.read(start + S_DATA_LENGTH + S_DATA_TYPE)?.get();
+        let end = start + SINGLE_SLOT_HEADER_SIZE + key_len + payload_len;
+        if start < LEGACY_HEADER_SIZE || end > PAGE_SIZE_USIZE {
+            return Err(Error::MalformedPayload);
+        }
+        Ok((start, end))
+    }
+
+    fn set_slot_offset(&mut self, index: usize, offset: usize) -> Result<(), Error> {
+        let item = self.slot_table_item(index);
+        let offset: Offset = offset.try_into()?;
+        self.page.buffer[item..item + S_SLOT_TABLE_ITEM].copy_from_slice(&offset.to_bytes());
         Ok(())
     }
+
+    fn read(&self, offset: usize) -> Result<Offset, Error> {
+        self.page
+            .buffer
+            .get(offset..offset + S_OFFSET)
+            .map(Offset::from_bytes)
+            .ok_or(Error::MalformedPayload)
+    }
+
+    /// Returns a legacy data page holding the slots, the first one at the end of the page.
+    #[cfg(test)]
+    pub(crate) fn data_page(page_id: Offset, slots: &[Vec<u8>]) -> [u8; PAGE_SIZE_USIZE] {
+        let mut page = Page::new_page(DATA_PAGE, page_id);
+        page.set_flags(0);
+        let mut free_end = PAGE_SIZE_USIZE;
+        for (index, slot) in slots.iter().enumerate() {
+            free_end -= slot.len();
+            page.buffer[free_end..free_end + slot.len()].copy_from_slice(slot);
+            let item = LEGACY_HEADER_SIZE + index * S_SLOT_TABLE_ITEM;
+            page.buffer[item..item + S_SLOT_TABLE_ITEM]
+                .copy_from_slice(&Offset::from_usize(free_end).to_bytes());
+        }
+        page.set_num_of_slots(Offset::from_usize(slots.len()));
+        let free_start = LEGACY_HEADER_SIZE + slots.len() * S_SLOT_TABLE_ITEM;
+        page.set_free_start(Offset::from_usize(free_start));
+        page.set_free_end(Offset::from_usize(free_end));
+        page.buffer
+    }
+
+    /// Returns the slot of a legacy data page holding the key as a string.
+    #[cfg(test)]
+    pub(crate) fn encode_slot(
+        key: &[u8],
+        payload_type: PayloadType,
+        payload: &[u8],
+        overflow_page_id: Offset,
+    ) -> Vec<u8> {
+        Page::encode_slot(key, PayloadType::Str, payload_type, payload, overflow_page_id).unwrap()
+    }
 }
 
 #[test]
 #[serial]
 fn test_add_slot_results_in_correct_num_of_slots() {
-    let mut new_inner = Page::new_inner();
+    let mut new_inner = Page::new_inner(&io::page_allocator()).unwrap();
     let key1 = Payload::from_u16(123);
     let key2 = Payload::from_u16(789);
-    let _ = new_inner.add_key_ref(Key::from_str("abc".to_string()), key1);
-    let _ = new_inner.add_key_ref(Key::from_str("xyz".to_string()), key2);
+    let _ = new_inner.add_key_ref(Key::from("abc"), key1);
+    let _ = new_inner.add_key_ref(Key::from("xyz"), key2);
     assert_eq!(new_inner.num_of_slots(), Offset(2));
 }
 
 #[test]
 #[serial]
-fn verify_available_space_empty_page() -> Result<(), InvalidPageOffsetError> {
-    let new_inner = Page::new_inner();
-    let available_space = new_inner.free_size();
-    let total_empty_size = PAGE_SIZE - TOTAL_HEADER_SIZE;
+fn verify_available_space_empty_page() -> Result<(), Error> {
+    let new_inner = Page::new_inner(&io::page_allocator()).unwrap();
+    let available_space = new_inner.free_size()?;
+    let total_empty_size = PAGE_SIZE.checked_sub(TOTAL_HEADER_SIZE)?;
     assert_eq!(available_space, total_empty_size);
     Ok(())
 }
 
 #[test]
 #[serial]
-fn verify_available_space_after_insertion() -> Result<(), InvalidPageOffsetError> {
-    let key1 = Key::from_str("foo".to_string());
-    let key2 = Key::from_str("foo".to_string());
+fn verify_available_space_after_insertion() -> Result<(), Error> {
+    let key1 = Key::from("foo");
+    let key2 = Key::from("foo");
     let payload = Payload::from_str("123".to_string());
     let payload_len = payload.len();
-    let mut new_inner = Page::new_inner();
-    let _ = new_inner.add_key_ref(key1.clone(), payload.clone());
+    let mut new_inner = Page::new_inner(&io::page_allocator()).unwrap();
+    let _ = new_inner.add_key_ref(key1, payload.clone());
     let _ = new_inner.add_key_ref(key2, payload);
-    let available_space: usize = new_inner.free_size().try_into()?;
-    let slot_size: usize = Page::slot_size(key1.len(), payload_len).try_into()?;
+    let available_space: usize = new_inner.free_size()?.try_into()?;
+    let slot_size: usize = Page::slot_size(key1.len(), payload_len)?.try_into()?;
     let page_size: usize = PAGE_SIZE.try_into()?;
     let total_empty_size: usize =
         page_size - (TOTAL_HEADER_SIZE + (2 * S_SLOT_TABLE_ITEM) + (2 * slot_size));
@@ -775,39 +2200,39 @@ fn verify_available_space_after_insertion() -> Result<(), InvalidPageOffsetError
 #[test]
 #[serial]
 fn verify_read_the_inserted() {
-    let mut new_inner = Page::new_inner();
+    let mut new_inner = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
-    let _ = new_inner.add_key_ref(Key::from_str("abcdefh".to_string()), payload1);
-    let _ = new_inner.add_key_ref(Key::from_str("xyz".to_string()), payload2);
+    let _ = new_inner.add_key_ref(Key::from("abcdefh"), payload1);
+    let _ = new_inner.add_key_ref(Key::from("xyz"), payload2);
     match new_inner.payload_at(0) {
         Ok(payload) => {
             assert_eq!(payload, "123");
         }
-        Err(_) => assert!(false),
+        Err(e) => panic!("{:?}", e),
     }
 
     match new_inner.payload_at(1) {
         Ok(payload) => {
             assert_eq!(payload, "234");
         }
-        Err(_) => assert!(false),
+        Err(e) => panic!("{:?}", e),
     }
 }
 
 #[test]
 #[serial]
-fn verify_add_data_node_less_than_page_size() -> Result<(), InvalidPageOffsetError> {
+fn verify_add_data_node_less_than_page_size() -> Result<(), Error> {
     let page_size: usize = PAGE_SIZE.try_into()?;
     let string = random_string(100);
     assert!(string.len() < page_size);
-    let data_node = Page::new_leaf(Key::from_str("foo".to_string()), Payload::from_str(string))?;
+    let data_node = Page::new_leaf(Key::from("foo"), Payload::from_str(string))?;
     let page = io::read(data_node.0 as usize);
     if let Some(leading_page) = page {
         let mutex = leading_page.lock().unwrap();
         assert!(mutex.free_end() > mutex.free_start());
     } else {
-        assert!(false);
+        panic!("{}", READ_ERR);
     }
 
     Ok(())
@@ -815,16 +2240,17 @@ fn verify_add_data_node_less_than_page_size() -> Result<(), InvalidPageOffsetErr
 
 #[test]
 #[serial]
-fn verify_add_data_node_full_page() -> Result<(), InvalidPageOffsetError> {
-    let key = Key::from_str("foo".to_string());
+fn verify_add_data_node_full_page() -> Result<(), Error> {
+    let key = Key::from("foo");
     let max_page_size: usize = PAGE_SIZE.try_into()?;
     // available bytes consists of available space excluding the page header, one slot header
     // requirements, and the rest reserved for remaining slots, and key length.
-    let available_bytes = PAGE_SIZE
-        - (TOTAL_HEADER_SIZE
+    let available_bytes = PAGE_SIZE.checked_sub(
+        TOTAL_HEADER_SIZE
             + SINGLE_RECORD_METADATA_SPACE_REQUIREMENT
             + key.len()
-            + ((MIN_FAN_OUT - 1) * (SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE)));
+            + ((MIN_FAN_OUT - 1) * (SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE)),
+    )?;
     let available_space = available_bytes.try_into()?;
     let payload_string = random_string(available_space);
     assert!(payload_string.len() < max_page_size);
@@ -832,27 +2258,27 @@ fn verify_add_data_node_full_page() -> Result<(), InvalidPageOffsetError> {
     let page = io::read(data_node.0 as usize);
     if let Some(leading_page) = page {
         let mutex = leading_page.lock().unwrap();
-        let free_space: usize = mutex.free_size().try_into()?;
+        let free_space: usize = mutex.free_size()?.try_into()?;
         assert_eq!(
             free_space,
             (MIN_FAN_OUT - 1) * (SINGLE_RECORD_METADATA_SPACE_REQUIREMENT + MAX_KEY_SIZE)
         );
     } else {
-        assert!(false);
+        panic!("{}", READ_ERR);
     }
     Ok(())
 }
 
 #[test]
 #[serial]
-fn verify_add_second_payload_larger_than_available_size() -> Result<(), InvalidPageOffsetError> {
+fn verify_add_second_payload_larger_than_available_size() -> Result<(), Error> {
     delete_index();
     let page_size: usize = PAGE_SIZE.try_into()?;
     // one head page and two overflow pages expected.
     let input_value = random_string(page_size * 2);
     assert!(input_value.len() > page_size);
     let data_node = Page::new_leaf(
-        Key::from_str("foo".to_string()),
+        Key::from("foo"),
         Payload::from_str(input_value.clone()),
     )?;
     let page_id: usize = data_node.try_into()?;
@@ -863,7 +2289,7 @@ fn verify_add_second_payload_larger_than_available_size() -> Result<(), InvalidP
         let guard = leading_page.lock().unwrap();
         let num_of_slots: usize = guard.num_of_slots().try_into()?;
         assert_eq!(num_of_slots, 2);
-        let bar_value = guard.get_for_key(Key::from_str("bar".to_string()));
+        let bar_value = guard.get_for_key(Key::from("bar"));
         if let Ok(a) = bar_value {
             assert_eq!(Some(second_input.clone()), a);
         }
@@ -871,26 +2297,27 @@ fn verify_add_second_payload_larger_than_available_size() -> Result<(), InvalidP
     Ok(())
 }
 
+#[cfg(test)]
 fn add_to_page(page_id: usize, key: String, second_input: String) {
     let leading_page = io::read(page_id).expect(READ_ERR);
     {
         let mut mutex = leading_page.lock().unwrap();
         let _ = mutex
-            .add(Key::from_str(key), Payload::from_str(second_input))
+            .add(Key::from(key.as_str()), Payload::from_str(second_input))
             .unwrap();
     };
 }
 
 #[test]
 #[serial]
-fn verify_add_payload_larger_than_available_size() -> Result<(), InvalidPageOffsetError> {
+fn verify_add_payload_larger_than_available_size() -> Result<(), Error> {
     delete_index();
     let page_size: usize = PAGE_SIZE.try_into()?;
     // one head page and two overflow pages expected.
     let input_value = random_string(page_size * 2);
     assert!(input_value.len() > page_size);
     let data_node = Page::new_leaf(
-        Key::from_str("foo".to_string()),
+        Key::from("foo"),
         Payload::from_str(input_value.clone()),
     )?;
 
@@ -903,7 +2330,7 @@ fn verify_add_payload_larger_than_available_size() -> Result<(), InvalidPageOffs
             assert_eq!(input_value, payload)
         }
     } else {
-        assert!(false);
+        panic!("{}", READ_ERR);
     }
 
     Ok(())
@@ -911,37 +2338,31 @@ fn verify_add_payload_larger_than_available_size() -> Result<(), InvalidPageOffs
 
 #[test]
 #[serial]
-#[should_panic(expected = "No slot left!")]
 fn verify_max_fan_out() {
     delete_index();
-    let input_value = "".to_string();
-    let data_node_id = Page::new_leaf(
-        Key::from_str("foo".to_string()),
-        Payload::from_str(input_value.clone()),
-    )
-    .unwrap()
-    .get();
-
-    // Fill the page slots up with overflowing payloads.
-    for i in 0..MAX_FAN_OUT + 1 {
-        let random_key = random_string(3);
-        add_to_page(data_node_id, random_key, input_value.clone());
-    }
-
+    let data_node_id = Page::new_leaf(Key::from("foo"), Payload::from_str(String::new()))
+        .unwrap()
+        .get();
     let page = io::read(data_node_id).expect(READ_ERR);
-    {
-        let mutex = page.lock().unwrap();
-        let free_size: usize = mutex.free_size().try_into().expect(O_ERR);
-        assert_eq!(free_size, 0)
-    }
+    let mut page = page.lock().unwrap();
+    while !page.is_full().unwrap() {
+        page.add(Key::from(random_string(3).as_str()), Payload::from_str(String::new())).unwrap();
+    }
+    // a full page refuses the key without being changed, so that the caller can split it.
+    let num_of_slots = page.num_of_slots();
+    assert!(matches!(
+        page.add(Key::from("bar"), Payload::from_str(String::new())),
+        Err(Error::NoSpace { available: 0, .. })
+    ));
+    assert_eq!(page.num_of_slots(), num_of_slots);
 }
 
 #[test]
 #[serial]
 fn verify_next_page_id() {
     delete_index();
-    assert_eq!(next_page(), Offset(1));
-    assert_eq!(next_page(), Offset(2));
+    assert_eq!(next_page(&io::page_allocator()).unwrap(), Offset(1));
+    assert_eq!(next_page(&io::page_allocator()).unwrap(), Offset(2));
 }
 
 // This test ensures minimum fan-out in case all payloads exceeds the page capacity.
@@ -953,14 +2374,14 @@ fn verify_no_space_left_in_head_after_inserting_overflowed_pages() {
     let input_value = random_string(page_size * 2);
     assert!(input_value.len() > page_size);
     let data_node_id = Page::new_leaf(
-        Key::from_str("foo".to_string()),
+        Key::from("foo"),
         Payload::from_str(input_value.clone()),
     )
     .unwrap()
     .get();
 
     // Fill the page slots up with overflowing payloads.
-    for i in 0..MIN_FAN_OUT - 1 {
+    for _ in 0..MIN_FAN_OUT - 1 {
         let random_key = random_string(9);
         add_to_page(data_node_id, random_key, input_value.clone());
     }
@@ -968,7 +2389,7 @@ fn verify_no_space_left_in_head_after_inserting_overflowed_pages() {
     let page = io::read(data_node_id).expect(READ_ERR);
     {
         let mutex = page.lock().unwrap();
-        let free_size: usize = mutex.free_size().try_into().expect(O_ERR);
+        let free_size: usize = mutex.free_size().unwrap().get();
         assert_eq!(free_size, 0)
     }
 }
@@ -977,33 +2398,33 @@ fn verify_no_space_left_in_head_after_inserting_overflowed_pages() {
 #[serial]
 fn verify_slot_boundaries() {
     delete_index();
-    let mut page = Page::new_inner();
+    let mut page = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
-    let key1 = Key::from_str("a".to_string());
-    let key2 = Key::from_str("b".to_string());
-    let _ = page.add_key_ref(key1.clone(), payload1.clone());
-    let _ = page.add_key_ref(key2.clone(), payload2.clone());
+    let key1 = Key::from("a");
+    let key2 = Key::from("b");
+    let _ = page.add_key_ref(key1, payload1.clone());
+    let _ = page.add_key_ref(key2, payload2.clone());
     assert_eq!(Offset(2), page.num_of_slots());
-    match page.get_for_key(Key::from_str("a".to_string())) {
+    match page.get_for_key(Key::from("a")) {
         Ok(key_value) => { assert_eq!(Some("123".to_string()), key_value); },
-        Err(_) => { assert!(false) }
+        Err(e) => { panic!("{:?}", e) }
     }
     {
         let (start, end) = match page.get_slot_boundaries(0) {
             Ok(slot_boundaries) => (slot_boundaries.0, slot_boundaries.1),
-            Err(_) => { assert!(false); return }
+            Err(e) => { panic!("{:?}", e) }
         };
-        assert_eq!(Offset::from_usize(start), PAGE_SIZE - Page::slot_size(key1.len(), payload1.len()));
+        assert_eq!(Offset::from_usize(start), PAGE_SIZE - Page::slot_size(key1.len(), payload1.len()).unwrap());
         assert_eq!(Offset::from_usize(end), PAGE_SIZE);
     }
     {
         let (start, end) = match page.get_slot_boundaries(1) {
             Ok(slot_boundaries) => (slot_boundaries.0, slot_boundaries.1),
-            Err(_) => { assert!(false); return }
+            Err(e) => { panic!("{:?}", e) }
         };
         assert_eq!(Offset::from_usize(start), page.free_end());
-        assert_eq!(Offset::from_usize(end), page.free_end() + Page::slot_size(key2.len(), payload2.len()));
+        assert_eq!(Offset::from_usize(end), page.free_end() + Page::slot_size(key2.len(), payload2.len()).unwrap());
     }
 }
 
@@ -1011,27 +2432,27 @@ fn verify_slot_boundaries() {
 #[serial]
 fn verify_tail_deletion() {
     delete_index();
-    let mut page = Page::new_inner();
+    let mut page = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
     let payload3 = Payload::from_str("456".to_string());
-    let _ = page.add_key_ref(Key::from_str("a".to_string()), payload1.clone());
-    let _ = page.add_key_ref(Key::from_str("b".to_string()), payload2.clone());
-    let _ = page.add_key_ref(Key::from_str("c".to_string()), payload3.clone());
+    let _ = page.add_key_ref(Key::from("a"), payload1.clone());
+    let _ = page.add_key_ref(Key::from("b"), payload2.clone());
+    let _ = page.add_key_ref(Key::from("c"), payload3.clone());
     assert_eq!(Offset(3), page.num_of_slots());
-    let result_payload_1 = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_1 = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(Some("123".to_string()), result_payload_1);
 
     let available_space_before_deletion = page.free_end() - page.free_start();
-    let _ = page.delete_key(Key::from_str("c".to_string()));
+    assert!(page.delete(Key::from("c")).unwrap());
     let available_space_after_deletion = page.free_end() - page.free_start();
     assert!(available_space_before_deletion < available_space_after_deletion);
     assert_eq!(Offset(2), page.num_of_slots());
-    let result_payload_for_a = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_for_a = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(Some("123".to_string()), result_payload_for_a);
-    let result_payload_for_b = page.get_for_key(Key::from_str("b".to_string())).unwrap();
+    let result_payload_for_b = page.get_for_key(Key::from("b")).unwrap();
     assert_eq!(Some("234".to_string()), result_payload_for_b);
-    let result_payload_for_c = page.get_for_key(Key::from_str("c".to_string())).unwrap();
+    let result_payload_for_c = page.get_for_key(Key::from("c")).unwrap();
     assert_eq!(None, result_payload_for_c);
 }
 
@@ -1039,86 +2460,127 @@ fn verify_tail_deletion() {
 #[serial]
 fn verify_intermediary_deletion() {
     delete_index();
-    let mut page = Page::new_inner();
+    let mut page = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
     let payload3 = Payload::from_str("456".to_string());
-    let _ = page.add_key_ref(Key::from_str("a".to_string()), payload1.clone());
-    let _ = page.add_key_ref(Key::from_str("b".to_string()), payload2.clone());
-    let _ = page.add_key_ref(Key::from_str("c".to_string()), payload3.clone());
+    let _ = page.add_key_ref(Key::from("a"), payload1.clone());
+    let _ = page.add_key_ref(Key::from("b"), payload2.clone());
+    let _ = page.add_key_ref(Key::from("c"), payload3.clone());
     assert_eq!(Offset(3), page.num_of_slots());
-    let result_payload_1 = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_1 = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(Some("123".to_string()), result_payload_1);
 
     let available_space_before_deletion = page.free_end() - page.free_start();
-    let _ = page.delete_key(Key::from_str("b".to_string()));
+    assert!(page.delete(Key::from("b")).unwrap());
     let available_space_after_deletion = page.free_end() - page.free_start();
     assert!(available_space_before_deletion < available_space_after_deletion);
     assert_eq!(Offset(2), page.num_of_slots());
-    let result_payload_for_a = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_for_a = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(Some("123".to_string()), result_payload_for_a);
-    let result_payload_for_b = page.get_for_key(Key::from_str("b".to_string())).unwrap();
+    let result_payload_for_b = page.get_for_key(Key::from("b")).unwrap();
     assert_eq!(None, result_payload_for_b);
-    let result_payload_for_c = page.get_for_key(Key::from_str("c".to_string())).unwrap();
+    let result_payload_for_c = page.get_for_key(Key::from("c")).unwrap();
     assert_eq!(Some("456".to_string()), result_payload_for_c);
+    assert!(!page.delete(Key::from("b")).unwrap());
+    assert_eq!(Offset(2), page.num_of_slots());
 }
 
 #[test]
 #[serial]
 fn verify_head_deletion() {
     delete_index();
-    let mut page = Page::new_inner();
+    let mut page = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
     let payload3 = Payload::from_str("456".to_string());
-    let _ = page.add_key_ref(Key::from_str("a".to_string()), payload1.clone());
-    let _ = page.add_key_ref(Key::from_str("b".to_string()), payload2.clone());
-    let _ = page.add_key_ref(Key::from_str("c".to_string()), payload3.clone());
+    let _ = page.add_key_ref(Key::from("a"), payload1.clone());
+    let _ = page.add_key_ref(Key::from("b"), payload2.clone());
+    let _ = page.add_key_ref(Key::from("c"), payload3.clone());
     assert_eq!(Offset(3), page.num_of_slots());
-    let result_payload_1 = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_1 = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(Some("123".to_string()), result_payload_1);
 
     let available_space_before_deletion = page.free_end() - page.free_start();
-    let _ = page.delete_key(Key::from_str("a".to_string()));
+    assert!(page.delete(Key::from("a")).unwrap());
     let available_space_after_deletion = page.free_end() - page.free_start();
     assert!(available_space_before_deletion < available_space_after_deletion);
     assert_eq!(Offset(2), page.num_of_slots());
-    let result_payload_for_a = page.get_for_key(Key::from_str("a".to_string())).unwrap();
+    let result_payload_for_a = page.get_for_key(Key::from("a")).unwrap();
     assert_eq!(None, result_payload_for_a);
-    let result_payload_for_b = page.get_for_key(Key::from_str("b".to_string())).unwrap();
+    let result_payload_for_b = page.get_for_key(Key::from("b")).unwrap();
     assert_eq!(Some("234".to_string()), result_payload_for_b);
-    let result_payload_for_c = page.get_for_key(Key::from_str("c".to_string())).unwrap();
+    let result_payload_for_c = page.get_for_key(Key::from("c")).unwrap();
     assert_eq!(Some("456".to_string()), result_payload_for_c);
 }
 
+#[test]
+#[serial]
+fn verify_binary_keys() {
+    delete_index();
+    let mut page = Page::new_inner(&io::page_allocator()).unwrap();
+    let uuid_key = [0x9fu8, 0x00, 0xff, 0x10, 0x80, 0x00, 0x00, 0x01];
+    let encoded_key = 42u64.to_be_bytes();
+    let _ = page.add_key_ref(Key::from(&uuid_key), Payload::from_str("uuid".to_string()));
+    let _ = page.add_key_ref(Key::from(&encoded_key), Payload::from_str("int".to_string()));
+    // the slots are in key order.
+    assert_eq!(page.key_at(0).unwrap(), encoded_key.to_vec());
+    assert_eq!(page.key_at(1).unwrap(), uuid_key.to_vec());
+    let uuid_value = page.get_for_key(Key::from(&uuid_key)).unwrap();
+    assert_eq!(Some("uuid".to_string()), uuid_value);
+    let int_value = page.get_for_key(Key::from(encoded_key.as_slice())).unwrap();
+    assert_eq!(Some("int".to_string()), int_value);
+    let missing = page.get_for_key(Key::from(&[0x9fu8, 0x00])).unwrap();
+    assert_eq!(None, missing);
+}
+
 #[test]
 #[serial]
 fn merge_two_space_with_enough_space() {
     delete_index();
-    let mut page1 = Page::new_inner();
-    let mut page2 = Page::new_inner();
+    let mut page1 = Page::new_inner(&io::page_allocator()).unwrap();
+    let mut page2 = Page::new_inner(&io::page_allocator()).unwrap();
     let payload1 = Payload::from_str("123".to_string());
     let payload2 = Payload::from_str("234".to_string());
     let payload3 = Payload::from_str("456".to_string());
-    let key1 = Key::from_str("a".to_string());
-    let key2 = Key::from_str("b".to_string());
-    let key3 = Key::from_str("c".to_string());
-    let _ = page1.add_key_ref(key1.clone(), payload1.clone());
-    let _ = page1.add_key_ref(key2.clone(), payload2.clone());
-    let _ = page2.add_key_ref(key3.clone(), payload3.clone());
+    let key1 = Key::from("a");
+    let key2 = Key::from("b");
+    let key3 = Key::from("c");
+    let _ = page1.add_key_ref(key1, payload1.clone());
+    let _ = page1.add_key_ref(key2, payload2.clone());
+    let _ = page2.add_key_ref(key3, payload3.clone());
     assert_eq!(Offset(2), page1.num_of_slots());
     assert_eq!(Offset(1), page2.num_of_slots());
     page1.merge_into(&mut page2).unwrap();
-    let result1 = page2.get_for_key(key1.clone()).unwrap();
-    let result2 = page2.get_for_key(key2.clone()).unwrap();
-    let result3 = page2.get_for_key(key3.clone()).unwrap();
+    let result1 = page2.get_for_key(key1).unwrap();
+    let result2 = page2.get_for_key(key2).unwrap();
+    let result3 = page2.get_for_key(key3).unwrap();
     assert_eq!(Some("123".to_string()), result1);
     assert_eq!(Some("234".to_string()), result2);
     assert_eq!(Some("456".to_string()), result3);
-    assert_eq!(page1.is_marked_deleted(), true);
-    assert_eq!(page2.is_marked_deleted(), false);
+    assert!(page1.is_marked_deleted());
+    assert!(!page2.is_marked_deleted());
+}
+
+#[test]
+#[serial]
+fn verify_dense_insert_keeps_key_order() {
+    delete_index();
+    let mut page = Page::new_dense_inner(&io::page_allocator()).unwrap();
+    page.set_left_most_page_id(Offset(1));
+    for (key, child) in [(30u64, Offset(4)), (10, Offset(2)), (20, Offset(3))] {
+        page.dense_insert(key, child).unwrap();
+    }
+    assert_eq!(page.num_of_slots(), Offset(3));
+    let keys: Vec<u64> = (0..3).map(|i| page.dense_key_at(i)).collect();
+    assert_eq!(keys, vec![10, 20, 30]);
+    assert_eq!(page.dense_child_at(1), Offset(3));
+    assert_eq!(page.dense_rank(5), 0);
+    assert_eq!(page.dense_rank(20), 2);
+    assert_eq!(page.dense_rank(u64::MAX), 3);
 }
 
+#[cfg(test)]
 fn random_string(len: usize) -> String {
     const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
     let mut rng = rand::thread_rng();
@@ -1130,3 +2592,377 @@ fn random_string(len: usize) -> String {
         .collect();
     s
 }
+
+#[test]
+fn verify_oversized_slots_are_rejected() {
+    assert!(matches!(
+        Page::slot_size(usize::MAX, 1),
+        Err(Error::OutOfRange)
+    ));
+    assert!(matches!(
+        Page::slot_size(u16::MAX as usize, 0),
+        Err(Error::OutOfRange)
+    ));
+    let mut page = Page::new_page(DATA_PAGE, Offset(1));
+    assert!(matches!(
+        page.add_slot(&vec![0u8; PAGE_SIZE_USIZE]),
+        Err(Error::OutOfRange)
+    ));
+    assert_eq!(page.free_end(), PAGE_SIZE);
+}
+
+#[test]
+#[serial]
+fn verify_compaction_reclaims_the_bytes_between_slots() {
+    delete_index();
+    let mut page = Page::new_data(&io::page_allocator()).unwrap();
+    page.add_key_data(Key::from("a"), Payload::from_u32(1)).unwrap();
+    page.add_key_data(Key::from("b"), Payload::from_u32(2)).unwrap();
+    page.set_high_key(Some(b"c")).unwrap();
+    let free_size = page.free_size().unwrap();
+    // the first slot leaves its bytes to the free space.
+    page.delete_slot(1).unwrap();
+    page.add_key_data(Key::from("b"), Payload::from_u32(2)).unwrap();
+    assert_eq!(page.free_size().unwrap(), free_size);
+    assert_eq!(page.compact().unwrap(), 0);
+
+    // bytes left in front of the slots by a page written before.
+    page.set_free_end(page.free_end() - Offset(100));
+    assert_eq!(page.compact().unwrap(), 100);
+    assert_eq!(page.free_size().unwrap(), free_size);
+    assert_eq!(page.key_at(1).unwrap(), b"b");
+    assert_eq!(page.value_at(0).unwrap().to_bytes(), &1u32.to_le_bytes());
+    assert_eq!(page.high_key(), Some(b"c".as_slice()));
+
+    // inserts compact the page rather than spilling the payload into overflow pages.
+    page.set_free_end(page.free_start() + Offset(50));
+    let value = "x".repeat(1000);
+    let payload = Payload::from_str(value.clone());
+    let (residual, _) = page.add_key_data(Key::from("big"), payload).unwrap();
+    assert_eq!(residual.len(), 0);
+    assert_eq!(page.value_at(2).unwrap().to_bytes(), value.as_bytes());
+    assert_eq!(page.key_at(0).unwrap(), b"a");
+}
+
+#[test]
+#[serial]
+fn verify_updates_overwrite_or_move_the_slot() {
+    delete_index();
+    let mut page = Page::new_data(&io::page_allocator()).unwrap();
+    page.add_key_data(Key::from("a"), Payload::from_str("a".repeat(100))).unwrap();
+    page.add_key_data(Key::from("b"), Payload::from_u32(2)).unwrap();
+    let free_size = page.free_size().unwrap();
+    assert!(!page.update(Key::from("c"), Payload::from_u32(3)).unwrap());
+
+    // a smaller payload is written into the slot.
+    assert!(page.update(Key::from("a"), Payload::from_u32(1)).unwrap());
+    assert_eq!(page.free_size().unwrap(), free_size);
+    assert_eq!(page.value_at(0).unwrap().to_bytes(), &1u32.to_le_bytes());
+    assert_eq!(page.payload_type_at(0).unwrap(), PayloadType::U32);
+
+    // a larger one moves the slot, keeping its place in the slot table.
+    let value = "b".repeat(200);
+    assert!(page.update(Key::from("b"), Payload::from_str(value.clone())).unwrap());
+    assert_eq!(page.key_at(1).unwrap(), b"b");
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), value.as_bytes());
+    assert_eq!(page.value_at(0).unwrap().to_bytes(), &1u32.to_le_bytes());
+    let stored = *io::read(page.page_id().get()).unwrap().lock().unwrap();
+    assert_eq!(stored.value_at(1).unwrap().to_bytes(), value.as_bytes());
+    // deletes move the slots stored left of the deleted one, whatever their place in the table.
+    page.add_key_data(Key::from("c"), Payload::from_u32(3)).unwrap();
+    page.delete_slot(0).unwrap();
+    assert_eq!(page.value_at(0).unwrap().to_bytes(), value.as_bytes());
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), &3u32.to_le_bytes());
+
+    // the bytes the slots left behind are reclaimed once the page runs out of free space.
+    let value = "c".repeat(3000);
+    assert!(page.update(Key::from("c"), Payload::from_str(value.clone())).unwrap());
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), value.as_bytes());
+    let free_size = page.free_size().unwrap();
+    assert!(matches!(
+        page.update(Key::from("c"), Payload::from_str("c".repeat(5000))),
+        Err(Error::NoSpace { .. })
+    ));
+    assert_eq!(page.free_size().unwrap(), free_size);
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), value.as_bytes());
+
+    // the overflow pages of a spilled payload are freed once it's replaced or deleted.
+    for replace in [true, false] {
+        let mut page = Page::new_data(&io::page_allocator()).unwrap();
+        page.add(Key::from("large"), Payload::from_str("l".repeat(20_000))).unwrap();
+        let overflow_page_ids = page.overflow_page_ids(0).unwrap();
+        assert!(!overflow_page_ids.is_empty());
+        if replace {
+            assert!(page.update(Key::from("large"), Payload::from_u32(1)).unwrap());
+            assert_eq!(page.value_at(0).unwrap().to_bytes(), &1u32.to_le_bytes());
+        } else {
+            assert!(page.delete(Key::from("large")).unwrap());
+        }
+        let (list_pages, free_pages) = freelist::pages().unwrap();
+        let freed = |id: &Offset| free_pages.contains(id) || list_pages.contains(id);
+        assert!(overflow_page_ids.iter().all(freed));
+    }
+}
+
+#[test]
+#[serial]
+fn verify_slot_tables_at_the_end_of_pages() {
+    delete_index();
+    config::update_slot_layout(SlotLayout::TableAtEnd);
+    let mut index = Index::open().unwrap();
+    for i in 0..300u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    // including the pages split in place.
+    for page_id in treefile::page_ids().unwrap() {
+        assert_eq!(load(page_id).unwrap().slot_layout(), SlotLayout::TableAtEnd);
+    }
+    // the pages split from now on have the table at the start, pages of both layouts are read
+    // alike.
+    config::update_slot_layout(SlotLayout::TableAtStart);
+    for i in (0..300u32).step_by(3) {
+        index.delete(Key::from(format!("{:03}", i).as_str())).unwrap();
+    }
+    for i in 300..400u32 {
+        index.insert(Key::from(format!("{:03}", i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    for i in 0..400u32 {
+        let payload = index.get(Key::from(format!("{:03}", i).as_str())).unwrap();
+        match i % 3 == 0 && i < 300 {
+            true => assert!(payload.is_none()),
+            false => assert_eq!(payload.unwrap().to_bytes(), &i.to_le_bytes()),
+        }
+    }
+    assert_eq!(index.scan(..).unwrap().count(), 300);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    config::update_slot_layout(SlotLayout::TableAtEnd);
+    let mut page = Page::new_data(&io::page_allocator()).unwrap();
+    assert_eq!(page.slot_layout(), SlotLayout::TableAtEnd);
+    for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+        page.add_key_data(Key::from(key), Payload::from_u32(i as u32)).unwrap();
+    }
+    // the slots follow the header, the slot table ends the page.
+    assert_eq!(page.slot_offset(0), TOTAL_HEADER_SIZE);
+    assert_eq!(page.free_end().get(), PAGE_SIZE_USIZE - 3 * S_SLOT_TABLE_ITEM);
+    let free_size = page.free_size().unwrap();
+    page.set_high_key(Some(b"d")).unwrap();
+    assert_eq!(page.key_at(2).unwrap(), b"c");
+    assert_eq!(page.high_key(), Some(b"d".as_slice()));
+
+    page.delete_slot(0).unwrap();
+    assert_eq!(page.key_at(0).unwrap(), b"b");
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), &2u32.to_le_bytes());
+    assert_eq!(page.slot_offset(0), TOTAL_HEADER_SIZE);
+    let value = "b".repeat(100);
+    assert!(page.update(Key::from("b"), Payload::from_str(value.clone())).unwrap());
+    // the bytes the slot left behind.
+    assert!(page.compact().unwrap() > 0);
+    assert_eq!(page.value_at(0).unwrap().to_bytes(), value.as_bytes());
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), &2u32.to_le_bytes());
+    page.delete_slot(0).unwrap();
+    page.set_high_key(None).unwrap();
+    page.add_key_data(Key::from("a"), Payload::from_u32(0)).unwrap();
+    page.add_key_data(Key::from("b"), Payload::from_u32(1)).unwrap();
+    assert_eq!(page.free_size().unwrap(), free_size);
+}
+
+#[test]
+fn verify_high_keys_move_the_slots() {
+    let mut page = Page::new_page(DATA_PAGE, Offset(1));
+    assert_eq!(page.high_key(), None);
+    page.add_key_data(Key::from("apple"), Payload::from_u32(1)).unwrap();
+    page.add_key_data(Key::from("banana"), Payload::from_u32(2)).unwrap();
+    let free_size = page.free_size().unwrap();
+
+    page.set_high_key(Some(b"cherry")).unwrap();
+    assert_eq!(page.high_key(), Some(b"cherry".as_slice()));
+    assert_eq!(page.free_size().unwrap(), free_size - Offset::from_usize(6 + S_HIGH_KEY_LENGTH));
+    assert_eq!(page.key_at(0).unwrap(), b"apple");
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), &2u32.to_le_bytes().to_vec());
+
+    // slots added and deleted afterwards stay in front of the high key.
+    page.add_key_data(Key::from("avocado"), Payload::from_u32(3)).unwrap();
+    page.delete_slot(0).unwrap();
+    page.set_high_key(Some(b"c")).unwrap();
+    assert_eq!(page.high_key(), Some(b"c".as_slice()));
+    assert_eq!(page.key_at(0).unwrap(), b"avocado");
+    assert_eq!(page.value_at(1).unwrap().to_bytes(), &2u32.to_le_bytes().to_vec());
+
+    page.set_high_key(None).unwrap();
+    assert_eq!(page.high_key(), None);
+    assert_eq!(page.key_at(1).unwrap(), b"banana");
+    assert!(matches!(
+        page.set_high_key(Some(&vec![0u8; MAX_KEY_SIZE + 1])),
+        Err(Error::OutOfRange)
+    ));
+    page.mark_deleted();
+    assert_eq!(page.high_key(), None);
+}
+
+#[test]
+fn verify_slots_are_kept_in_key_order() {
+    let mut page = Page::new_page(DATA_PAGE, Offset(1));
+    assert!(page.has_sorted_slots());
+    for (i, key) in ["d", "b", "e", "a", "c"].into_iter().enumerate() {
+        page.add_key_data(Key::from(key), Payload::from_u32(i as u32)).unwrap();
+    }
+    for (i, key) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+        assert_eq!(page.key_at(i).unwrap(), key.as_bytes());
+        let slot = page.find(Key::from(key)).unwrap().unwrap();
+        assert_eq!(slot.index, i);
+        assert_eq!((slot.offset, slot.offset + slot.len), page.get_slot_boundaries(i).unwrap());
+    }
+    assert_eq!(page.value_at(2).unwrap().to_bytes(), &4u32.to_le_bytes());
+    assert_eq!(page.find(Key::from("bb")).unwrap(), None);
+    assert_eq!(page.find(Key::from("f")).unwrap(), None);
+    page.delete_slot(1).unwrap();
+    assert_eq!(page.find_slot(Key::from("c")).unwrap(), Some(1));
+    assert_eq!(page.find_slot(Key::from("b")).unwrap(), None);
+
+    // interned keys don't sort by their bytes, the page falls back to insertion order.
+    let mut page = Page::new_page(INNER_PAGE, Offset(2));
+    page.add_key_ref(Key::from("b"), Offset(3)).unwrap();
+    page.add_interned_key_ref(Key::from(&[0u8, 1]), Offset(4)).unwrap();
+    page.add_key_ref(Key::from("a"), Offset(5)).unwrap();
+    assert!(!page.has_sorted_slots());
+    assert_eq!(page.key_at(2).unwrap(), b"a");
+    assert_eq!(page.find_slot(Key::from("a")).unwrap(), Some(2));
+}
+
+#[test]
+#[serial]
+fn verify_spilled_values_lead_with_their_length() {
+    delete_index();
+    let mut page = Page::new_data(&io::page_allocator()).unwrap();
+    let value = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    page.add(Key::from("large"), Payload::from_buffer(&value, PayloadType::Bytes)).unwrap();
+    page.add(Key::from("small"), Payload::from_u32(1)).unwrap();
+    let overflow_page_ids = page.overflow_page_ids(0).unwrap();
+    assert!(overflow_page_ids.len() > 1);
+    assert!(overflow_page_ids.iter().all(|id| load(*id).unwrap().type_name() == "overflow"));
+    assert_eq!(page.value_len_at(0).unwrap(), value.len());
+    assert_eq!(page.value_len_at(1).unwrap(), size_of::<u32>());
+    let payload = page.value_at(0).unwrap();
+    assert_eq!(payload.payload_type, PayloadType::Bytes);
+    assert_eq!(payload.to_bytes(), value.as_slice());
+    assert_eq!(page.payload_type_at(0).unwrap(), PayloadType::Bytes);
+
+    // a chain which doesn't add up to the length is malformed.
+    let length_offset = page.slot_offset(0) + SINGLE_SLOT_HEADER_SIZE + "large".len();
+    page.buffer[length_offset..length_offset + S_TOTAL_LENGTH]
+        .copy_from_slice(&(value.len() as u32 + 1).to_le_bytes());
+    assert!(matches!(page.value_at(0), Err(Error::MalformedPayload)));
+}
+
+#[test]
+#[serial]
+fn verify_pages_are_sealed_with_magic_and_version() {
+    delete_index();
+    let page = Page::new_data(&io::page_allocator()).unwrap();
+    assert_eq!(page.verify(), Err(Violation::UnknownMagic(0)));
+    assert_eq!(Page::new_from(page.sealed()).verify(), Ok(()));
+    assert_eq!(Page::new_from([0u8; PAGE_SIZE_USIZE]).verify(), Ok(()));
+
+    // pages of a newer version are refused even if their checksum matches.
+    let mut newer = Page::new_from(page.sealed());
+    newer.buffer[OFFSET_FORMAT_VERSION] = PAGE_FORMAT_VERSION + 1;
+    let checksum = newer.checksum();
+    newer.buffer[OFFSET_CHECKSUM..OFFSET_CHECKSUM + S_CHECKSUM]
+        .copy_from_slice(&checksum.to_le_bytes());
+    let violation = newer.verify().unwrap_err();
+    assert_eq!(violation, Violation::UnsupportedPageVersion(PAGE_FORMAT_VERSION + 1));
+    let error = violation.error(newer.page_id());
+    assert!(matches!(
+        error,
+        Error::UnsupportedPageVersion { supported: PAGE_FORMAT_VERSION, .. }
+    ));
+    assert!(error.is_fatal() && !error.is_corruption());
+    assert_eq!(error.page_id(), Some(newer.page_id()));
+    let mut foreign = page.sealed();
+    foreign[OFFSET_MAGIC] ^= 1;
+    assert!(matches!(Page::new_from(foreign).verify(), Err(Violation::UnknownMagic(_))));
+}
+
+#[test]
+#[serial]
+fn verify_slots_are_walked_in_slot_order() {
+    delete_index();
+    let mut page = Page::new_data(&io::page_allocator()).unwrap();
+    assert_eq!(page.iter().count(), 0);
+    let value = vec![7u8; 20_000];
+    page.add(Key::from("b"), Payload::from_u32(2)).unwrap();
+    page.add(Key::from("a"), Payload::from_u32(1)).unwrap();
+    page.add(Key::from("c"), Payload::from_buffer(&value, PayloadType::Bytes)).unwrap();
+    let slots = page.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    let one = 1u32.to_le_bytes();
+    let two = 2u32.to_le_bytes();
+    assert_eq!(
+        slots,
+        vec![(&b"a"[..], Some(&one[..])), (&b"b"[..], Some(&two[..])), (&b"c"[..], None)]
+    );
+    assert_eq!(page.value_at(2).unwrap().to_bytes(), value.as_slice());
+}
+
+#[test]
+#[serial]
+fn verify_keys_are_looked_up_in_a_page() {
+    delete_index();
+    let mut leaf = Page::new_data(&io::page_allocator()).unwrap();
+    leaf.add(Key::from("a"), Payload::from_u32(1)).unwrap();
+    leaf.add(Key::from("b"), Payload::tombstone(7)).unwrap();
+    assert_eq!(leaf.get(b"a").unwrap().unwrap().to_bytes(), 1u32.to_le_bytes());
+    assert!(leaf.get(b"b").unwrap().is_none());
+    assert!(leaf.get(b"c").unwrap().is_none());
+
+    let mut inner = Page::new_inner(&io::page_allocator()).unwrap();
+    inner.set_left_most_page_id(Offset(3));
+    inner.add_key_ref(Key::from("m"), Offset(4)).unwrap();
+    inner.add_key_ref(Key::from("t"), Offset(5)).unwrap();
+    assert_eq!(inner.child(b"a").unwrap(), Some(Offset(3)));
+    assert_eq!(inner.child(b"m").unwrap(), Some(Offset(4)));
+    assert_eq!(inner.child(b"s").unwrap(), Some(Offset(4)));
+    assert_eq!(inner.child(b"z").unwrap(), Some(Offset(5)));
+}
+
+#[test]
+fn verify_keys_share_the_prefix_of_the_page() {
+    for layout in [SlotLayout::TableAtStart, SlotLayout::TableAtEnd] {
+        let mut page = Page::new_page(DATA_PAGE, Offset(1));
+        page.set_slot_layout(layout).unwrap();
+        page.set_high_key(Some(b"user:9")).unwrap();
+        for (i, key) in ["user:0003", "user:0001", "user:0002"].into_iter().enumerate() {
+            page.add_key_data(Key::from(key), Payload::from_u32(i as u32)).unwrap();
+        }
+        let free_size = page.free_size().unwrap().get();
+        assert_eq!(page.compress_prefix().unwrap(), 3 * 8 - 8 - S_PREFIX_LENGTH);
+        assert_eq!(page.free_size().unwrap().get(), free_size + 3 * 8 - 8 - S_PREFIX_LENGTH);
+        assert_eq!(page.prefix(), b"user:000");
+        assert_eq!(page.key_slice_at(0).unwrap(), b"1");
+        assert_eq!(page.key_at(2).unwrap(), b"user:0003");
+        assert_eq!(page.high_key(), Some(b"user:9".as_slice()));
+        assert_eq!(page.get(b"user:0002").unwrap().unwrap().to_bytes(), &2u32.to_le_bytes());
+        assert!(page.get(b"user:1").unwrap().is_none());
+        assert_eq!(page.rank(b"user:0002").unwrap(), 2);
+        assert_eq!(page.rank(b"a").unwrap(), 0);
+        assert_eq!(page.rank(b"user:1").unwrap(), 3);
+        assert_eq!(page.compress_prefix().unwrap(), 0);
+
+        // the high key and the prefix move independently.
+        page.set_high_key(None).unwrap();
+        assert_eq!(page.prefix(), b"user:000");
+        assert!(page.update(Key::from("user:0001"), Payload::from_u32(7)).unwrap());
+        assert_eq!(page.get(b"user:0001").unwrap().unwrap().to_bytes(), &7u32.to_le_bytes());
+
+        // a key not sharing the prefix shortens it.
+        assert!(page.has_room_for(b"user:1").unwrap());
+        page.add_key_data(Key::from("user:1"), Payload::from_u32(3)).unwrap();
+        assert_eq!(page.prefix(), b"user:");
+        page.add_key_data(Key::from("admin"), Payload::from_u32(4)).unwrap();
+        assert_eq!(page.prefix(), b"");
+        assert_eq!(page.flags() & F_PREFIX, 0);
+        let keys = (0..5).map(|i| page.key_at(i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys, [&b"admin"[..], b"user:0001", b"user:0002", b"user:0003", b"user:1"]);
+        assert_eq!(page.get(b"user:0003").unwrap().unwrap().to_bytes(), &0u32.to_le_bytes());
+    }
+}
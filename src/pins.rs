@@ -0,0 +1,185 @@
+#[cfg(test)]
+use crate::btree::{Index, ScanOptions};
+#[cfg(test)]
+use crate::freelist;
+#[cfg(test)]
+use crate::io::{self, delete_index};
+#[cfg(test)]
+use crate::paging::Page;
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A scan holds the id of the next leaf between calls, so a page freed while the scan is open
+/// mustn't be reused until the scan moved past it. Such pages are pinned by the open cursors
+/// instead of being pushed to the free list. A cursor whose consumer is slow would pin every page
+/// freed meanwhile, so once it pins more than its budget it's released: its pins are dropped and
+/// it re-seeks by key from the root before reading the next leaf.
+struct Cursor {
+    pinned: Vec<Offset>,
+    budget: usize,
+    released: bool,
+}
+
+#[derive(Default)]
+struct Pins {
+    next_id: u64,
+    cursors: HashMap<u64, Cursor>,
+    // page id → number of cursors pinning it.
+    pages: HashMap<Offset, usize>,
+}
+
+impl Pins {
+    // Returns the pages no cursor pins anymore.
+    fn unpin(&mut self, pinned: Vec<Offset>) -> Vec<Offset> {
+        pinned
+            .into_iter()
+            .filter(|page_id| match self.pages.get_mut(page_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                _ => {
+                    self.pages.remove(page_id);
+                    true
+                }
+            })
+            .collect()
+    }
+}
+
+static PINS: Lazy<Mutex<Pins>> = Lazy::new(|| Mutex::new(Pins::default()));
+
+/// Registers a cursor pinning up to budget freed pages, returns its id.
+pub(crate) fn open(budget: usize) -> u64 {
+    let mut pins = pins();
+    pins.next_id += 1;
+    let id = pins.next_id;
+    pins.cursors.insert(
+        id,
+        Cursor {
+            pinned: Vec::new(),
+            budget,
+            released: false,
+        },
+    );
+    id
+}
+
+/// Pins the freed page for the open cursors. Returns whether any cursor pins it, along with the
+/// pages unpinned by cursors which exceeded their budget, which are free to be reused now.
+pub(crate) fn pin_freed(page_id: Offset) -> (bool, Vec<Offset>) {
+    let mut pins = pins();
+    let (mut pinning, mut over_budget) = (0, Vec::new());
+    for cursor in pins.cursors.values_mut().filter(|cursor| !cursor.released) {
+        if cursor.pinned.len() < cursor.budget {
+            cursor.pinned.push(page_id);
+            pinning += 1;
+        } else {
+            cursor.released = true;
+            over_budget.push(std::mem::take(&mut cursor.pinned));
+        }
+    }
+    if pinning > 0 {
+        *pins.pages.entry(page_id).or_default() += pinning;
+    }
+    let unpinned = over_budget
+        .into_iter()
+        .flat_map(|pinned| pins.unpin(pinned))
+        .collect();
+    (pinning > 0, unpinned)
+}
+
+/// Returns true once if the cursor was released since the last call. The cursor has to re-seek
+/// then, and pins pages again.
+pub(crate) fn take_released(id: u64) -> bool {
+    match pins().cursors.get_mut(&id) {
+        Some(cursor) => std::mem::replace(&mut cursor.released, false),
+        None => true,
+    }
+}
+
+/// Unregisters the cursor, returns the pages no cursor pins anymore.
+pub(crate) fn close(id: u64) -> Vec<Offset> {
+    let mut pins = pins();
+    match pins.cursors.remove(&id) {
+        Some(cursor) => pins.unpin(cursor.pinned),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the number of pages pinned by the open cursors.
+#[cfg(test)]
+pub(crate) fn pinned_pages() -> usize {
+    pins().pages.len()
+}
+
+/// Releases all cursors, dropping their pins without freeing the pages: after a rollback the
+/// pages were never freed, after a close they belong to another database. Pages pinned when the
+/// database is closed are lost to the free list, fsck reclaims them.
+pub(crate) fn clear() {
+    let mut pins = pins();
+    for cursor in pins.cursors.values_mut() {
+        cursor.pinned.clear();
+        cursor.released = true;
+    }
+    pins.pages.clear();
+}
+
+fn pins() -> std::sync::MutexGuard<'static, Pins> {
+    PINS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+#[serial]
+fn verify_scans_pin_freed_pages_up_to_their_budget() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let keys: Vec<Vec<u8>> = (0..200u32)
+        .map(|i| format!("pinned/key/{:03}", i).into_bytes())
+        .collect();
+    for (i, key) in keys.iter().enumerate() {
+        index.insert(Key::from(key.as_slice()), Payload::from_u32(i as u32)).unwrap();
+    }
+    let free_pages = || freelist::pages().unwrap().1.len();
+    let allocate = |pages: usize| -> Vec<Offset> {
+        (0..pages).map(|_| Page::new_data(&io::page_allocator()).unwrap().page_id()).collect()
+    };
+
+    // pages freed while the scan is open are kept from the free list until it's dropped.
+    let options = ScanOptions {
+        max_pinned_pages: 4,
+    };
+    let mut scan = index.scan_with_options(.., options).unwrap();
+    let mut scanned = vec![scan.next().unwrap().unwrap().0];
+    for page_id in allocate(3) {
+        freelist::push(page_id).unwrap();
+    }
+    assert_eq!(pinned_pages(), 3);
+    assert_eq!(free_pages(), 0);
+    scanned.extend(scan.by_ref().map(|entry| entry.unwrap().0));
+    assert_eq!(scanned, keys);
+    drop(scan);
+    assert_eq!(pinned_pages(), 0);
+    assert_eq!(free_pages(), 3);
+
+    // past the budget the scan drops its pins and re-seeks by key.
+    let options = ScanOptions {
+        max_pinned_pages: 1,
+    };
+    let pages = allocate(2);
+    let mut scan = index.scan_with_options(.., options).unwrap();
+    let mut scanned: Vec<Vec<u8>> = scan.by_ref().take(35).map(|entry| entry.unwrap().0).collect();
+    freelist::push(pages[0]).unwrap();
+    assert_eq!(pinned_pages(), 1);
+    freelist::push(pages[1]).unwrap();
+    assert_eq!(pinned_pages(), 0);
+    assert_eq!(free_pages(), 3);
+    scanned.extend(scan.map(|entry| entry.unwrap().0));
+    assert_eq!(scanned, keys);
+}
@@ -0,0 +1,137 @@
+use crate::config;
+use crate::errors::Error;
+use crate::events;
+use crate::paging::{Page, PAGE_FORMAT_VERSION};
+use crate::types::Offset;
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::sync::Mutex;
+
+// Bytes of the page header included in the report.
+const REPORT_HEADER_BYTES: usize = 32;
+
+/// Violation is the consistency check a page failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Violation {
+    UnknownPageType(u8),
+    /// The page stores another page id than the one it was read from.
+    PageIdMismatch { stored: Offset },
+    /// The CRC32 of the page doesn't match the one stored in its header.
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// The page doesn't carry the magic of pages, e.g. it was written by an older layout.
+    UnknownMagic(u16),
+    /// The page was written in a newer format version of pages than this build reads.
+    UnsupportedPageVersion(u8),
+}
+
+impl Violation {
+    /// Returns the error the read of the page failing the check fails with, along with the last
+    /// log index applied so far.
+    pub(crate) fn error(&self, page_id: Offset) -> Error {
+        let lsn = config::get_last_applied_index();
+        match self {
+            Violation::ChecksumMismatch { .. } => Error::ChecksumMismatch { page_id, lsn },
+            Violation::UnsupportedPageVersion(found) => {
+                Error::UnsupportedPageVersion {
+                    page_id,
+                    found: *found,
+                    supported: PAGE_FORMAT_VERSION,
+                }
+            }
+            _ => Error::CorruptPage { page_id, lsn },
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UnknownPageType(page_type) => write!(f, "unknown page type {}", page_type),
+            Violation::PageIdMismatch { stored } => write!(f, "stores page id {}", stored),
+            Violation::ChecksumMismatch { stored, computed } => {
+                write!(f, "stores checksum {:08x} but reads as {:08x}", stored, computed)
+            }
+            Violation::UnknownMagic(magic) => write!(f, "unknown magic {:04x}", magic),
+            Violation::UnsupportedPageVersion(version) => {
+                write!(f, "unsupported page format version {}", version)
+            }
+        }
+    }
+}
+
+/// CorruptionReport describes the corruption which poisoned the database.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CorruptionReport {
+    pub(crate) page_id: Offset,
+    pub(crate) violation: Violation,
+    pub(crate) parent: Offset,
+    pub(crate) left_sibling: Offset,
+    pub(crate) right_sibling: Offset,
+    /// The first bytes of the page as they were read.
+    pub(crate) header: Vec<u8>,
+}
+
+impl CorruptionReport {
+    pub(crate) fn new(page: &Page, page_id: Offset, violation: Violation) -> Self {
+        CorruptionReport {
+            page_id,
+            violation,
+            parent: page.parent(),
+            left_sibling: page.left_sibling(),
+            right_sibling: page.right_sibling(),
+            header: page.buffer()[..REPORT_HEADER_BYTES].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for CorruptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: {}", self.page_id, self.violation)?;
+        write!(
+            f,
+            ", parent {}, siblings {} and {}, header ",
+            self.parent, self.left_sibling, self.right_sibling
+        )?;
+        for byte in &self.header {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// the first corruption detected since the database was opened or last checked.
+static POISON: Lazy<Mutex<Option<CorruptionReport>>> = Lazy::new(|| Mutex::new(None));
+
+/// Poisons the database after a page failed a consistency check, and notifies the event
+/// listeners. Writes are refused with Poisoned from then on, as they could spread the damage, e.g.
+/// by splitting a page into a corrupt sibling. Reads go on, the corrupt page fails each time it's
+/// read. Only the first report is kept.
+pub(crate) fn poison(report: CorruptionReport) {
+    events::emit(|listener| listener.on_poisoned(&report));
+    let mut poison = POISON.lock().unwrap_or_else(|e| e.into_inner());
+    poison.get_or_insert(report);
+}
+
+/// Reports the page failing the check to the event listeners and poisons the database. Returns the
+/// error the read of the page fails with.
+pub(crate) fn corrupted(
+    page: &Page,
+    page_id: Offset,
+    violation: Violation,
+) -> Error {
+    let error = violation.error(page_id);
+    events::emit(|listener| listener.on_corruption(page_id, &error));
+    poison(CorruptionReport::new(page, page_id, violation));
+    error
+}
+
+/// Returns the report of the corruption which poisoned the database, None if it isn't poisoned.
+pub(crate) fn report() -> Option<CorruptionReport> {
+    POISON.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Lifts the poison, after a pass over the whole database didn't run into corruption or when the
+/// database is closed.
+pub(crate) fn clear() {
+    POISON.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
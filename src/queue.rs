@@ -0,0 +1,168 @@
+use crate::btree::{Index, KeyLayout};
+use crate::clock;
+#[cfg(test)]
+use crate::clock::{SystemClock, VirtualClock};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use std::sync::Arc;
+use std::time::Duration;
+
+const S_VISIBLE_AT: usize = size_of::<u64>();
+
+/// Queue is a durable FIFO on top of an index with u64 keys. Messages are keyed by a monotonic id
+/// and consumed from the front. The payload of each message is prefixed with the time it becomes
+/// visible, so a message received with a visibility timeout is redelivered unless it's acknowledged
+/// in time, as told by `clock`:
+///  __________________________
+/// | visible at (ms) | payload |
+///  --------------------------
+pub(crate) struct Queue {
+    index: Index,
+    next_id: u64,
+}
+
+impl Queue {
+    /// Opens the queue persisted in the database files, ids continue after the last message.
+    pub(crate) fn open() -> Result<Self, Error> {
+        let index = Index::open_with_layout(KeyLayout::U64)?;
+        let next_id = match index.last()? {
+            Some((key, _)) => decode_id(&key)? + 1,
+            None => 0,
+        };
+        Ok(Queue { index, next_id })
+    }
+
+    /// Appends the payload to the end of the queue and returns the id of the message.
+    pub(crate) fn push(&mut self, payload: Payload) -> Result<u64, Error> {
+        let id = self.next_id;
+        self.index
+            .insert(Key::from(&id.to_be_bytes()), envelope(0, &payload))?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// Removes and returns the first visible message.
+    pub(crate) fn pop(&mut self) -> Result<Option<(u64, Payload)>, Error> {
+        let Some((id, _, payload)) = self.first_visible()? else {
+            return Ok(None);
+        };
+        self.index.delete(Key::from(&id.to_be_bytes()))?;
+        Ok(Some((id, payload)))
+    }
+
+    /// Returns the first visible message and hides it for the visibility timeout. The message has
+    /// to be acknowledged with `ack`, otherwise it is delivered again once the timeout expires.
+    pub(crate) fn receive(
+        &mut self,
+        visibility_timeout: Duration,
+    ) -> Result<Option<(u64, Payload)>, Error> {
+        let Some((id, now, payload)) = self.first_visible()? else {
+            return Ok(None);
+        };
+        let visible_at = now + visibility_timeout.as_millis() as u64;
+        self.index
+            .insert(Key::from(&id.to_be_bytes()), envelope(visible_at, &payload))?;
+        Ok(Some((id, payload)))
+    }
+
+    /// Removes a received message, returns false if it was removed already.
+    pub(crate) fn ack(&mut self, id: u64) -> Result<bool, Error> {
+        self.index.delete(Key::from(&id.to_be_bytes()))
+    }
+
+    fn first_visible(&self) -> Result<Option<(u64, u64, Payload)>, Error> {
+        let now = now_millis();
+        for entry in self.index.scan(..)? {
+            let (key, stored) = entry?;
+            let bytes = stored.to_bytes();
+            if bytes.len() < S_VISIBLE_AT {
+                return Err(Error::MalformedPayload);
+            }
+            let visible_at = &bytes[..S_VISIBLE_AT];
+            if u64::from_le_bytes(visible_at.try_into().unwrap()) <= now {
+                let payload = stored.slice(S_VISIBLE_AT..bytes.len(), stored.payload_type);
+                return Ok(Some((decode_id(&key)?, now, payload)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn envelope(visible_at: u64, payload: &Payload) -> Payload {
+    let mut buffer = Vec::with_capacity(S_VISIBLE_AT + payload.len());
+    buffer.extend_from_slice(&visible_at.to_le_bytes());
+    buffer.extend_from_slice(payload.to_bytes());
+    Payload::from_vec(buffer, payload.payload_type)
+}
+
+fn decode_id(key: &[u8]) -> Result<u64, Error> {
+    key.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| Error::MalformedPayload)
+}
+
+fn now_millis() -> u64 {
+    clock::now().as_millis() as u64
+}
+
+#[test]
+#[serial]
+fn verify_queue_is_fifo_across_reopen() {
+    delete_index();
+    let mut queue = Queue::open().unwrap();
+    for i in 0..20u32 {
+        assert_eq!(queue.push(Payload::from_u32(i)).unwrap(), u64::from(i));
+    }
+    let (id, payload) = queue.pop().unwrap().unwrap();
+    assert_eq!(id, 0);
+    assert_eq!(payload.to_bytes(), &0u32.to_le_bytes().to_vec());
+
+    let mut queue = Queue::open().unwrap();
+    assert_eq!(queue.push(Payload::from_str("last".to_string())).unwrap(), 20);
+    let ids: Vec<u64> = std::iter::from_fn(|| queue.pop().unwrap().map(|(id, _)| id)).collect();
+    assert_eq!(ids, (1..=20).collect::<Vec<u64>>());
+}
+
+#[test]
+#[serial]
+fn verify_received_messages_are_hidden_until_timeout() {
+    delete_index();
+    let mut queue = Queue::open().unwrap();
+    queue.push(Payload::from_str("a".to_string())).unwrap();
+    queue.push(Payload::from_str("b".to_string())).unwrap();
+
+    let (id, payload) = queue.receive(Duration::from_secs(3600)).unwrap().unwrap();
+    assert_eq!((id, payload.to_str()), (0, "a".to_string()));
+    let (id, _) = queue.receive(Duration::ZERO).unwrap().unwrap();
+    assert_eq!(id, 1);
+    // the expired message is delivered again, the hidden one is not.
+    let (id, payload) = queue.receive(Duration::ZERO).unwrap().unwrap();
+    assert_eq!((id, payload.to_str()), (1, "b".to_string()));
+    assert!(queue.ack(1).unwrap());
+    assert!(queue.pop().unwrap().is_none());
+    assert!(queue.ack(0).unwrap());
+    assert!(!queue.ack(0).unwrap());
+}
+
+#[test]
+#[serial]
+fn verify_visibility_timeouts_follow_the_clock() {
+    delete_index();
+    let virtual_clock = Arc::new(VirtualClock::new(Duration::from_secs(1_000)));
+    clock::set_clock(virtual_clock.clone());
+    let mut queue = Queue::open().unwrap();
+    queue.push(Payload::from_str("a".to_string())).unwrap();
+    let (id, _) = queue.receive(Duration::from_secs(3600)).unwrap().unwrap();
+    assert!(queue.receive(Duration::from_secs(3600)).unwrap().is_none());
+
+    virtual_clock.advance(Duration::from_secs(3599));
+    assert!(queue.receive(Duration::from_secs(3600)).unwrap().is_none());
+    virtual_clock.advance(Duration::from_secs(1));
+    assert_eq!(queue.receive(Duration::from_secs(3600)).unwrap().unwrap().0, id);
+    clock::set_clock(Arc::new(SystemClock));
+}
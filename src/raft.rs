@@ -0,0 +1,329 @@
+use crate::btree::Index;
+#[cfg(test)]
+use crate::btree::load;
+use crate::config::{get_last_applied_index, update_last_applied_index};
+use crate::errors::Error;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::snapshot;
+use crate::txn::{encode_record, read_record, Record};
+use crate::types::Key;
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use crate::types::Payload;
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// HistoryRetention is how much of the history left behind by deletes is kept for lagging replicas
+/// and readers of snapshots, see `collect_history`. Zero disables a limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct HistoryRetention {
+    /// Keeps the tombstones of the deletes of the last log entries applied.
+    pub(crate) log_entries: u64,
+    /// Keeps the tombstones of the deletes applied since the oldest of the last snapshots exported.
+    pub(crate) snapshots: usize,
+    /// Caps the bytes of the tombstones kept, the oldest ones are removed first.
+    pub(crate) bytes: u64,
+}
+
+/// HistoryStats describes the tombstones retained.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct HistoryStats {
+    pub(crate) tombstones: usize,
+    pub(crate) bytes: u64,
+    /// Index of the oldest delete retained, None without tombstones.
+    pub(crate) oldest: Option<u64>,
+}
+
+static RETENTION: Lazy<Mutex<HistoryRetention>> =
+    Lazy::new(|| Mutex::new(HistoryRetention::default()));
+// Last applied indexes of the snapshots exported, the most recent one last.
+static SNAPSHOTS: Lazy<Mutex<Vec<u64>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub(crate) fn set_history_retention(retention: HistoryRetention) {
+    *RETENTION.lock().unwrap_or_else(|e| e.into_inner()) = retention;
+}
+
+pub(crate) fn history_retention() -> HistoryRetention {
+    *RETENTION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Encodes the writes into a log entry for `apply_log_entry`, deletes have no payload.
+#[allow(dead_code)]
+pub(crate) fn encode_log_entry(writes: &[Record]) -> Vec<u8> {
+    writes
+        .iter()
+        .flat_map(|(key, write)| encode_record(key, write))
+        .collect()
+}
+
+/// Applies the writes of the log entry at the index, and persists the index as the last applied
+/// one, so that the database can be the state machine behind a Raft log. Entries at or below the
+/// last applied index were applied before and are skipped, returning false. The entry is decoded
+/// as a whole before anything is written, a malformed entry fails with MalformedPayload. In shadow
+/// paging mode the writes and the index are committed at once. Writing in place, a crash may leave
+/// a part of the writes without the index, the entry is then applied again, which is safe as its
+/// writes replace or remove keys wholesale. Deletes leave tombstones stamped with the index, see
+/// `purge_tombstones`.
+pub(crate) fn apply_log_entry(index: u64, entry: &[u8]) -> Result<bool, Error> {
+    // the writes and the index are exported together.
+    let _write = io::write_operation();
+    io::check_writable()?;
+    if index <= get_last_applied_index() {
+        return Ok(false);
+    }
+    let mut writes = Vec::new();
+    let mut reader = entry;
+    while !reader.is_empty() {
+        let record = read_record(&mut reader).map_err(|e| match e {
+            Error::Io(_) => Error::MalformedPayload,
+            e => e,
+        })?;
+        writes.push(record);
+    }
+    let mut tree = Index::open()?;
+    for (key, write) in writes {
+        match write {
+            Some(payload) => tree.insert(Key::from(key.as_slice()), payload)?,
+            None => {
+                tree.tombstone(Key::from(key.as_slice()), index)?;
+            }
+        }
+    }
+    update_last_applied_index(index);
+    io::commit();
+    io::check_writable()?;
+    Ok(true)
+}
+
+/// Removes the tombstones of the deletes applied below the watermark, the lowest index applied by
+/// all replicas and held by the snapshots which are kept. Returns the number of tombstones removed.
+pub(crate) fn purge_tombstones(watermark: u64) -> Result<usize, Error> {
+    let purged = Index::open()?.purge_tombstones(watermark)?;
+    io::commit();
+    io::check_writable()?;
+    Ok(purged)
+}
+
+/// Removes the tombstones the history retention doesn't keep anymore, returning their number. The
+/// log entries and snapshot limits keep the history back to whichever of them reaches further, the
+/// bytes limit then cuts it down. Without any limit all history is kept.
+pub(crate) fn collect_history() -> Result<usize, Error> {
+    let retention = history_retention();
+    let last_applied = get_last_applied_index();
+    let mut watermarks = Vec::new();
+    if retention.log_entries > 0 {
+        watermarks.push((last_applied + 1).saturating_sub(retention.log_entries));
+    }
+    if retention.snapshots > 0 {
+        let snapshots = SNAPSHOTS.lock().unwrap_or_else(|e| e.into_inner());
+        // history is kept in full until as many snapshots were exported.
+        let oldest = snapshots.len().checked_sub(retention.snapshots).map(|i| snapshots[i]);
+        watermarks.push(oldest.map_or(0, |index| index + 1));
+    }
+    let mut watermark = watermarks.into_iter().min().unwrap_or(0);
+    if retention.bytes > 0 {
+        let mut tombstones = Index::open()?.tombstones()?;
+        tombstones.retain(|(stamp, _)| *stamp >= watermark);
+        tombstones.sort_unstable_by(|a, b| b.cmp(a));
+        let mut bytes = 0;
+        for (stamp, size) in tombstones {
+            bytes += size as u64;
+            if bytes > retention.bytes {
+                watermark = stamp + 1;
+                break;
+            }
+        }
+    }
+    if watermark == 0 {
+        return Ok(0);
+    }
+    purge_tombstones(watermark)
+}
+
+pub(crate) fn history_stats() -> Result<HistoryStats, Error> {
+    let tombstones = Index::open()?.tombstones()?;
+    Ok(HistoryStats {
+        tombstones: tombstones.len(),
+        bytes: tombstones.iter().map(|(_, size)| *size as u64).sum(),
+        oldest: tombstones.iter().map(|(stamp, _)| *stamp).min(),
+    })
+}
+
+/// Writes a snapshot of the state machine for the Raft library to send to lagging followers. The
+/// snapshot holds the last applied index, which is returned.
+pub(crate) fn export_snapshot(path: &Path) -> Result<u64, Error> {
+    io::commit();
+    let index = snapshot::write(path)?;
+    let retained = history_retention().snapshots;
+    let mut snapshots = SNAPSHOTS.lock().unwrap_or_else(|e| e.into_inner());
+    snapshots.push(index);
+    if snapshots.len() > retained.max(1) {
+        snapshots.remove(0);
+    }
+    Ok(index)
+}
+
+/// Replaces the contents of the database with a snapshot written by `export_snapshot`, and returns
+/// its last applied index. Index handles opened before are stale afterwards.
+pub(crate) fn install_snapshot(path: &Path) -> Result<u64, Error> {
+    let _write = io::write_operation();
+    io::check_writable()?;
+    let (config, pages) = snapshot::read(path)?;
+    snapshot::install(&config, &pages)?;
+    Ok(get_last_applied_index())
+}
+
+#[test]
+#[serial]
+fn verify_log_entries_are_applied_once() {
+    delete_index();
+    let entry = encode_log_entry(&[
+        (b"a".to_vec(), Some(Payload::from_u32(1))),
+        (b"b".to_vec(), Some(Payload::from_u32(2))),
+    ]);
+    assert!(apply_log_entry(1, &entry).unwrap());
+    let entry = encode_log_entry(&[
+        (b"a".to_vec(), None),
+        (b"c".to_vec(), Some(Payload::from_u32(3))),
+    ]);
+    assert!(apply_log_entry(2, &entry).unwrap());
+    // a redelivered entry is skipped.
+    assert!(!apply_log_entry(1, &encode_log_entry(&[(b"a".to_vec(), None)])).unwrap());
+    assert!(matches!(
+        apply_log_entry(3, &entry[..entry.len() - 1]),
+        Err(Error::MalformedPayload)
+    ));
+    assert_eq!(get_last_applied_index(), 2);
+    io::close();
+    assert_eq!(get_last_applied_index(), 2);
+
+    let path = Path::new("raft.test");
+    assert_eq!(export_snapshot(path).unwrap(), 2);
+    let entry = encode_log_entry(&[(b"d".to_vec(), Some(Payload::from_u32(4)))]);
+    assert!(apply_log_entry(3, &entry).unwrap());
+    assert_eq!(install_snapshot(path).unwrap(), 2);
+    fs::remove_file(path).unwrap();
+    let index = Index::open().unwrap();
+    let keys: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+    // the entry after the snapshot is applied again.
+    assert!(apply_log_entry(3, &entry).unwrap());
+}
+
+#[test]
+#[serial]
+fn verify_snapshots_are_consistent_with_the_applied_index() {
+    delete_index();
+    let writer = std::thread::spawn(|| {
+        for i in 1..=100u32 {
+            let entry = encode_log_entry(&[
+                (format!("a{:03}", i).into_bytes(), Some(Payload::from_u32(i))),
+                (format!("b{:03}", i).into_bytes(), Some(Payload::from_u32(i))),
+            ]);
+            apply_log_entry(u64::from(i), &entry).unwrap();
+        }
+    });
+    let paths: Vec<String> = (0..3).map(|i| format!("raft.consistent.{}", i)).collect();
+    let mut indices = Vec::new();
+    for path in &paths {
+        indices.push(snapshot::write(Path::new(path)).unwrap());
+    }
+    writer.join().unwrap();
+    // each snapshot holds both keys of every entry up to its index and none after.
+    for (path, applied) in paths.iter().zip(indices) {
+        assert_eq!(install_snapshot(Path::new(path)).unwrap(), applied);
+        assert_eq!(Index::open().unwrap().scan(..).unwrap().count() as u64, 2 * applied);
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+#[serial]
+fn verify_tombstones_are_purged_below_the_watermark() {
+    delete_index();
+    let entry = encode_log_entry(&[
+        (b"a".to_vec(), Some(Payload::from_u32(1))),
+        (b"b".to_vec(), Some(Payload::from_u32(2))),
+        (b"c".to_vec(), Some(Payload::from_u32(3))),
+    ]);
+    assert!(apply_log_entry(1, &entry).unwrap());
+    assert!(apply_log_entry(2, &encode_log_entry(&[(b"a".to_vec(), None)])).unwrap());
+    assert!(apply_log_entry(3, &encode_log_entry(&[(b"b".to_vec(), None)])).unwrap());
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_none());
+    let keys: Vec<Vec<u8>> = index.scan(..).unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(keys, vec![b"c".to_vec()]);
+    assert_eq!(index.last().unwrap().unwrap().0, b"c".to_vec());
+
+    // only the tombstones of deletes below the watermark are removed.
+    let leaf = |index: &Index| load(index.root()).unwrap();
+    assert_eq!(leaf(&index).num_of_slots().get(), 3);
+    assert_eq!(purge_tombstones(3).unwrap(), 1);
+    assert_eq!(leaf(&index).num_of_slots().get(), 2);
+    assert!(leaf(&index).find_slot(Key::from("a")).unwrap().is_none());
+    let b = leaf(&index).find_slot(Key::from("b")).unwrap().unwrap();
+    assert!(leaf(&index).is_tombstone_at(b).unwrap());
+    assert_eq!(purge_tombstones(4).unwrap(), 1);
+    assert_eq!(leaf(&index).num_of_slots().get(), 1);
+
+    // a key written over its tombstone is present again.
+    assert!(apply_log_entry(4, &encode_log_entry(&[(b"c".to_vec(), None)])).unwrap());
+    let entry = encode_log_entry(&[(b"c".to_vec(), Some(Payload::from_u32(4)))]);
+    assert!(apply_log_entry(5, &entry).unwrap());
+    let payload = index.get(Key::from("c")).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &4u32.to_le_bytes().to_vec());
+    assert_eq!(purge_tombstones(6).unwrap(), 0);
+}
+
+#[cfg(test)]
+fn delete(index: u64, key: &str) {
+    let key = key.as_bytes().to_vec();
+    let entry = encode_log_entry(&[(key.clone(), Some(Payload::from_u32(0)))]);
+    assert!(apply_log_entry(index, &entry).unwrap());
+    assert!(apply_log_entry(index + 1, &encode_log_entry(&[(key, None)])).unwrap());
+}
+
+#[test]
+#[serial]
+fn verify_history_is_collected_by_the_retention() {
+    delete_index();
+    // deletes at 2, 4, .., 20, each leaving a tombstone of a two byte key and an eight byte stamp.
+    for i in 0..10u64 {
+        delete(i * 2 + 1, &format!("{:02}", i));
+    }
+    assert_eq!(
+        history_stats().unwrap(),
+        HistoryStats { tombstones: 10, bytes: 100, oldest: Some(2) }
+    );
+    set_history_retention(HistoryRetention::default());
+    assert_eq!(collect_history().unwrap(), 0);
+
+    set_history_retention(HistoryRetention { log_entries: 10, ..Default::default() });
+    assert_eq!(collect_history().unwrap(), 5);
+    assert_eq!(history_stats().unwrap().oldest, Some(12));
+
+    set_history_retention(HistoryRetention { bytes: 25, ..Default::default() });
+    assert_eq!(collect_history().unwrap(), 3);
+    assert_eq!(history_stats().unwrap().oldest, Some(18));
+
+    // history is kept in full until the snapshots were exported.
+    set_history_retention(HistoryRetention { snapshots: 2, ..Default::default() });
+    SNAPSHOTS.lock().unwrap().clear();
+    let path = Path::new("history.test");
+    export_snapshot(path).unwrap();
+    assert_eq!(collect_history().unwrap(), 0);
+    delete(21, "20");
+    export_snapshot(path).unwrap();
+    delete(23, "21");
+    // only the deletes after the older of the snapshots, taken at 20, are kept.
+    assert_eq!(collect_history().unwrap(), 2);
+    assert_eq!(history_stats().unwrap().oldest, Some(22));
+    fs::remove_file(path).unwrap();
+    set_history_retention(HistoryRetention::default());
+}
@@ -0,0 +1,112 @@
+use crate::clock;
+use crate::events::StallReason;
+use crate::stats;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// RateLimiter throttles background IO such as checkpoints, scrubbing and garbage collection, so
+/// that it doesn't starve foreground operations. It holds one token bucket for bytes and one for IO
+/// operations, each refilled at its rate per second and holding up to one second worth of tokens.
+/// A zero rate disables the bucket. The limits can be changed while the limiter is in use. Time is
+/// read from `clock`, so throttling runs at the pace of a virtual clock in simulations.
+pub(crate) struct RateLimiter {
+    buckets: Mutex<(Bucket, Bucket)>,
+}
+
+struct Bucket {
+    rate: u64,
+    // tokens may become negative, the debt is paid off by the caller waiting for the refill.
+    tokens: f64,
+    refilled_at: Duration,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Duration) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn take(&mut self, amount: u64, now: Duration) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_sub(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled_at = now;
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64, iops: u64) -> Self {
+        let now = clock::now();
+        RateLimiter {
+            buckets: Mutex::new((Bucket::new(bytes_per_sec, now), Bucket::new(iops, now))),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn unlimited() -> Self {
+        Self::new(0, 0)
+    }
+
+    pub(crate) fn limits(&self) -> (u64, u64) {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        (buckets.0.rate, buckets.1.rate)
+    }
+
+    /// Changes the limits, the buckets start over full.
+    pub(crate) fn set_limits(&self, bytes_per_sec: u64, iops: u64) {
+        let now = clock::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        *buckets = (Bucket::new(bytes_per_sec, now), Bucket::new(iops, now));
+    }
+
+    /// Blocks until a single IO operation of the given size is allowed. Waits are reported as write
+    /// stalls.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let delay = self.reserve(bytes, clock::now());
+        if !delay.is_zero() {
+            clock::sleep(delay);
+            stats::record_stall(StallReason::Throttled, delay);
+        }
+    }
+
+    // Takes the tokens for the operation and returns how long the caller has to wait for them.
+    fn reserve(&self, bytes: u64, now: Duration) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes_delay = buckets.0.take(bytes, now);
+        let ops_delay = buckets.1.take(1, now);
+        bytes_delay.max(ops_delay)
+    }
+}
+
+#[test]
+fn verify_token_bucket_delays() {
+    let limiter = RateLimiter::new(1000, 0);
+    let start = clock::now();
+    // the initial burst is one second worth of bytes.
+    assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+    assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+    // half a second later the debt is paid off.
+    let later = start + Duration::from_millis(500);
+    assert_eq!(limiter.reserve(0, later), Duration::ZERO);
+
+    let limiter = RateLimiter::new(0, 10);
+    for _ in 0..10 {
+        assert_eq!(limiter.reserve(1 << 20, start), Duration::ZERO);
+    }
+    assert_eq!(limiter.reserve(0, start), Duration::from_millis(100));
+
+    limiter.set_limits(0, 0);
+    assert_eq!(limiter.reserve(1 << 30, start), Duration::ZERO);
+    assert_eq!(limiter.limits(), (0, 0));
+}
@@ -0,0 +1,146 @@
+use crate::btree::load;
+use crate::config::{get_sequence_page_id, update_sequence_page_id};
+use crate::errors::Error;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, MAX_KEY_SIZE, ZERO};
+use crate::types::{Key, Offset, Payload, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+
+// Number of ids reserved with a single write to the catalog.
+const RESERVATION_BATCH: u64 = 1024;
+
+/// Sequence is a persistent counter handing out monotonically increasing ids. Ids are reserved in
+/// batches, the catalog only records the upper bound of the reservation, so only one in
+/// RESERVATION_BATCH calls writes to disk. After a crash the sequence continues from the recorded
+/// bound; the ids which were reserved but not handed out are skipped, none is handed out twice.
+///
+/// The catalog is a chain of data pages, each slot maps a sequence name to its bound.
+pub(crate) struct Sequence {
+    name: String,
+    next: u64,
+    reserved: u64,
+    page: Offset,
+}
+
+impl Sequence {
+    /// Loads the named sequence from the catalog, a new sequence starts at zero.
+    pub(crate) fn load(name: &str) -> Result<Self, Error> {
+        if name.len() > MAX_KEY_SIZE {
+            return Err(Error::OutOfRange);
+        }
+        let mut next = get_sequence_page_id();
+        while next != ZERO {
+            let page = load(next)?;
+            if let Some(index) = page.find_slot(Key::from(name))? {
+                let reserved = u64::from_le_bytes(
+                    page.value_at(index)?
+                        .to_bytes()
+                        .try_into()
+                        .map_err(|_| Error::MalformedPayload)?,
+                );
+                return Ok(Sequence {
+                    name: name.to_string(),
+                    next: reserved,
+                    reserved,
+                    page: next,
+                });
+            }
+            next = page.right_sibling();
+        }
+        Ok(Sequence {
+            name: name.to_string(),
+            next: 0,
+            reserved: 0,
+            page: ZERO,
+        })
+    }
+
+    /// Returns the next id of the sequence.
+    pub(crate) fn next_id(&mut self) -> Result<u64, Error> {
+        if self.next == self.reserved {
+            self.reserve(self.next + RESERVATION_BATCH)?;
+        }
+        let id = self.next;
+        self.next += 1;
+        Ok(id)
+    }
+
+    /// Returns the id the next call to next_id hands out, without reserving it.
+    pub(crate) fn peek_id(&self) -> u64 {
+        self.next
+    }
+
+    // Records the new bound in the catalog, the slot of a known sequence is replaced in place.
+    fn reserve(&mut self, reserved: u64) -> Result<(), Error> {
+        let _write = io::write_operation();
+        io::check_writable()?;
+        let key = Key::from(self.name.as_str());
+        let payload = Payload::from_buffer(&reserved.to_le_bytes(), PayloadType::Bytes);
+        if self.page == ZERO {
+            self.page = append_to_catalog(key, payload)?;
+        } else {
+            let mut page = load(self.page)?;
+            if let Some(index) = page.find_slot(key)? {
+                page.delete_slot(index)?;
+            }
+            page.add(key, payload)?;
+        }
+        io::check_writable()?;
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+fn append_to_catalog(key: Key, payload: Payload) -> Result<Offset, Error> {
+    let mut tail = match get_sequence_page_id() {
+        ZERO => {
+            let page = Page::new_data(&io::page_allocator())?;
+            update_sequence_page_id(page.page_id());
+            page
+        }
+        head => load(head)?,
+    };
+    while tail.right_sibling() != ZERO {
+        tail = load(tail.right_sibling())?;
+    }
+    if tail.is_full()? {
+        let mut next = Page::new_data(&io::page_allocator())?;
+        next.set_left_sibling(tail.page_id());
+        tail.set_right_sibling(next.page_id());
+        io::write(&tail);
+        tail = next;
+    }
+    tail.add(key, payload)
+}
+
+#[test]
+#[serial]
+fn verify_sequence_survives_reopen() {
+    delete_index();
+    let mut sequence = Sequence::load("ids").unwrap();
+    for expected in 0..RESERVATION_BATCH + 10 {
+        assert_eq!(sequence.next_id().unwrap(), expected);
+    }
+    // the reopened sequence skips the rest of the reservation.
+    let mut sequence = Sequence::load("ids").unwrap();
+    assert_eq!(sequence.next_id().unwrap(), 2 * RESERVATION_BATCH);
+}
+
+#[test]
+#[serial]
+fn verify_catalog_grows_beyond_a_page() {
+    delete_index();
+    for i in 0..12u64 {
+        let mut sequence = Sequence::load(&format!("sequence-{}", i)).unwrap();
+        for _ in 0..=i {
+            sequence.next_id().unwrap();
+        }
+    }
+    for i in 0..12u64 {
+        let mut sequence = Sequence::load(&format!("sequence-{}", i)).unwrap();
+        assert_eq!(sequence.next_id().unwrap(), RESERVATION_BATCH);
+    }
+}
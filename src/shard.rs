@@ -0,0 +1,387 @@
+use crate::btree::{load, Index};
+use crate::config::{get_shard_catalog_page_id, update_shard_catalog_page_id};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::fsck;
+use crate::freelist;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, MAX_KEY_SIZE, ZERO};
+#[cfg(test)]
+use crate::treestats;
+use crate::types::{Key, Payload, PayloadType};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const CATALOG_KEY: &str = "shards";
+// Bytes of shard records per catalog page, small enough to be stored inline in a fresh page.
+const CATALOG_PAGE_BYTES: usize = 3072;
+const S_SHARD_ID: usize = size_of::<u32>();
+// the id and the statistics following the name of a record.
+const RECORD_FIELDS_SIZE: usize = S_SHARD_ID + 3 * size_of::<u64>();
+
+/// ShardStats counts the entries of a shard and the bytes of their keys, without the shard prefix,
+/// and values.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ShardStats {
+    pub(crate) entries: u64,
+    pub(crate) key_bytes: u64,
+    pub(crate) value_bytes: u64,
+}
+
+/// Micro-shards are many small trees, e.g. one per user or device, kept in the index of the
+/// database. The keys of a shard are prefixed with its id, so thousands of shards share the pages
+/// of a single tree, the page cache and the checkpoints rather than taking a root page each. In
+/// micro-shard mode the index holds the keys of the shards only.
+///
+/// A shard is created with its first write, reading a shard which was never written allocates
+/// nothing. The catalog maps the shard names onto their ids and statistics, it's a chain of data
+/// pages each packing the records of many shards into a single slot. New shards are written to the
+/// catalog right away, the statistics are kept up to date by the writes and written at the next
+/// checkpoint like the tree statistics.
+#[derive(Default)]
+struct Catalog {
+    shards: HashMap<String, (u32, ShardStats)>,
+    next_id: u32,
+    // written at the next checkpoint.
+    dirty: bool,
+}
+
+// None until the catalog was read from its pages.
+static CATALOG: Lazy<Mutex<Option<Catalog>>> = Lazy::new(|| Mutex::new(None));
+
+fn with_catalog<T>(
+    f: impl FnOnce(&mut Catalog) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut catalog = CATALOG.lock().unwrap_or_else(|e| e.into_inner());
+    if catalog.is_none() {
+        *catalog = Some(read()?);
+    }
+    f(catalog.as_mut().unwrap())
+}
+
+fn shard_key(id: u32, key: Key) -> Result<Vec<u8>, Error> {
+    if key.len() + S_SHARD_ID > MAX_KEY_SIZE {
+        return Err(Error::OutOfRange);
+    }
+    let mut buffer = Vec::with_capacity(S_SHARD_ID + key.len());
+    buffer.extend_from_slice(&id.to_be_bytes());
+    buffer.extend_from_slice(key.as_bytes());
+    Ok(buffer)
+}
+
+fn shard_id(shard: &str) -> Result<Option<u32>, Error> {
+    with_catalog(|catalog| Ok(catalog.shards.get(shard).map(|(id, _)| *id)))
+}
+
+/// Inserts the key into the shard, creating the shard if it doesn't exist yet.
+pub(crate) fn insert(
+    index: &mut Index,
+    shard: &str,
+    key: Key,
+    payload: Payload,
+) -> Result<(), Error> {
+    let id = match shard_id(shard)? {
+        Some(id) => id,
+        None => create(shard)?,
+    };
+    let shard_key = shard_key(id, key)?;
+    let replaced = index.get(Key::from(shard_key.as_slice()))?.map(|payload| payload.len());
+    let value_len = payload.len() as u64;
+    index.insert(Key::from(shard_key.as_slice()), payload)?;
+    update(shard, |stats| {
+        match replaced {
+            Some(replaced) => stats.value_bytes -= replaced as u64,
+            None => {
+                stats.entries += 1;
+                stats.key_bytes += key.len() as u64;
+            }
+        }
+        stats.value_bytes += value_len;
+    })
+}
+
+#[allow(dead_code)]
+pub(crate) fn get(
+    index: &Index,
+    shard: &str,
+    key: Key,
+) -> Result<Option<Payload>, Error> {
+    match shard_id(shard)? {
+        Some(id) => index.get(Key::from(shard_key(id, key)?.as_slice())),
+        None => Ok(None),
+    }
+}
+
+/// Deletes the key from the shard, returns false if the shard doesn't hold it. The shard stays in
+/// the catalog once it's empty, see `drop_shard`.
+pub(crate) fn delete(
+    index: &mut Index,
+    shard: &str,
+    key: Key,
+) -> Result<bool, Error> {
+    let Some(id) = shard_id(shard)? else {
+        return Ok(false);
+    };
+    let shard_key = shard_key(id, key)?;
+    let Some(payload) = index.get(Key::from(shard_key.as_slice()))? else {
+        return Ok(false);
+    };
+    if !index.delete(Key::from(shard_key.as_slice()))? {
+        return Ok(false);
+    }
+    update(shard, |stats| {
+        stats.entries -= 1;
+        stats.key_bytes -= key.len() as u64;
+        stats.value_bytes -= payload.len() as u64;
+    })?;
+    Ok(true)
+}
+
+/// Returns the key-payload pairs of the shard in key order, with the keys as they were inserted.
+pub(crate) fn scan(
+    index: &Index,
+    shard: &str,
+) -> Result<Vec<(Vec<u8>, Payload)>, Error> {
+    let Some(id) = shard_id(shard)? else {
+        return Ok(Vec::new());
+    };
+    let (start, end) = (id.to_be_bytes(), (id + 1).to_be_bytes());
+    index
+        .scan(Key::from(start.as_slice())..Key::from(end.as_slice()))?
+        .map(|entry| entry.map(|(key, payload)| (key[S_SHARD_ID..].to_vec(), payload)))
+        .collect()
+}
+
+/// Deletes the keys of the shard and removes it from the catalog, returns false if it doesn't
+/// exist.
+#[allow(dead_code)]
+pub(crate) fn drop_shard(index: &mut Index, shard: &str) -> Result<bool, Error> {
+    let Some(id) = shard_id(shard)? else {
+        return Ok(false);
+    };
+    for (key, _) in scan(index, shard)? {
+        index.delete(Key::from(shard_key(id, Key::from(key.as_slice()))?.as_slice()))?;
+    }
+    with_catalog(|catalog| {
+        catalog.shards.remove(shard);
+        write(catalog)
+    })?;
+    Ok(true)
+}
+
+/// Returns the statistics of the shard, None if it doesn't exist.
+pub(crate) fn stats(shard: &str) -> Result<Option<ShardStats>, Error> {
+    with_catalog(|catalog| Ok(catalog.shards.get(shard).map(|(_, stats)| *stats)))
+}
+
+/// Returns the names of the shards in the catalog, sorted.
+pub(crate) fn shards() -> Result<Vec<String>, Error> {
+    with_catalog(|catalog| {
+        let mut names = catalog.shards.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    })
+}
+
+// New shards are written to the catalog right away, so that their ids aren't handed out again
+// after a crash.
+fn create(shard: &str) -> Result<u32, Error> {
+    if shard.len() > u8::MAX as usize {
+        return Err(Error::OutOfRange);
+    }
+    let _write = io::write_operation();
+    io::check_writable()?;
+    with_catalog(|catalog| {
+        let id = catalog.next_id;
+        catalog.next_id += 1;
+        catalog.shards.insert(shard.to_string(), (id, ShardStats::default()));
+        write(catalog)?;
+        Ok(id)
+    })
+}
+
+fn update(shard: &str, f: impl FnOnce(&mut ShardStats)) -> Result<(), Error> {
+    with_catalog(|catalog| {
+        if let Some((_, stats)) = catalog.shards.get_mut(shard) {
+            f(stats);
+            catalog.dirty = true;
+        }
+        Ok(())
+    })
+}
+
+/// Writes the statistics changed since the last checkpoint into the catalog, called by the
+/// checkpoint. Does nothing if the catalog wasn't read.
+pub(crate) fn persist() -> Result<(), Error> {
+    let mut catalog = CATALOG.lock().unwrap_or_else(|e| e.into_inner());
+    match catalog.as_mut() {
+        Some(catalog) if catalog.dirty => write(catalog),
+        _ => Ok(()),
+    }
+}
+
+/// Drops the catalog held in memory, it's read again from its pages.
+pub(crate) fn reset() {
+    *CATALOG.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+fn read() -> Result<Catalog, Error> {
+    let mut catalog = Catalog::default();
+    let mut next = get_shard_catalog_page_id();
+    while next != ZERO {
+        let page = load(next)?;
+        if let Some(index) = page.find_slot(Key::from(CATALOG_KEY))? {
+            decode(page.value_at(index)?.to_bytes(), &mut catalog)?;
+        }
+        next = page.right_sibling();
+    }
+    catalog.next_id = catalog.shards.values().map(|(id, _)| id + 1).max().unwrap_or(0);
+    Ok(catalog)
+}
+
+// The records are written into the pages of the chain in turn, the chain grows by the pages
+// missing and the pages left over are returned to the free list.
+fn write(catalog: &mut Catalog) -> Result<(), Error> {
+    let mut names = catalog.shards.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut chunks = vec![Vec::new()];
+    for name in names {
+        let record = encode(name, &catalog.shards[name]);
+        if chunks.last().unwrap().len() + record.len() > CATALOG_PAGE_BYTES {
+            chunks.push(Vec::new());
+        }
+        chunks.last_mut().unwrap().extend_from_slice(&record);
+    }
+    let mut previous: Option<Page> = None;
+    let mut next = get_shard_catalog_page_id();
+    for chunk in chunks {
+        let mut page = match next {
+            ZERO => {
+                let page = Page::new_data(&io::page_allocator())?;
+                match previous.as_mut() {
+                    Some(previous) => {
+                        previous.set_right_sibling(page.page_id());
+                        io::write(previous);
+                    }
+                    None => update_shard_catalog_page_id(page.page_id()),
+                }
+                page
+            }
+            page_id => load(page_id)?,
+        };
+        next = page.right_sibling();
+        if let Some(index) = page.find_slot(Key::from(CATALOG_KEY))? {
+            page.delete_slot(index)?;
+        }
+        page.add(Key::from(CATALOG_KEY), Payload::from_vec(chunk, PayloadType::Bytes))?;
+        previous = Some(page);
+    }
+    if next != ZERO
+        && let Some(mut last) = previous
+    {
+        last.set_right_sibling(ZERO);
+        io::write(&last);
+    }
+    while next != ZERO {
+        let mut page = load(next)?;
+        next = page.right_sibling();
+        page.mark_deleted();
+        io::write(&page);
+        freelist::push(page.page_id())?;
+    }
+    catalog.dirty = false;
+    Ok(())
+}
+
+//  ____________________________________________________________
+// | name length | name | id | entries | key bytes | value bytes |
+//  ------------------------------------------------------------
+fn encode(name: &str, (id, stats): &(u32, ShardStats)) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(1 + name.len() + RECORD_FIELDS_SIZE);
+    buffer.push(name.len() as u8);
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.extend_from_slice(&id.to_le_bytes());
+    buffer.extend_from_slice(&stats.entries.to_le_bytes());
+    buffer.extend_from_slice(&stats.key_bytes.to_le_bytes());
+    buffer.extend_from_slice(&stats.value_bytes.to_le_bytes());
+    buffer
+}
+
+fn decode(mut buffer: &[u8], catalog: &mut Catalog) -> Result<(), Error> {
+    let mut take = |len: usize| {
+        if buffer.len() < len {
+            return Err(Error::MalformedPayload);
+        }
+        let (bytes, rest) = buffer.split_at(len);
+        buffer = rest;
+        Ok(bytes)
+    };
+    let u64_at = |bytes: &[u8], i: usize| {
+        u64::from_le_bytes(bytes[S_SHARD_ID + 8 * i..S_SHARD_ID + 8 * (i + 1)].try_into().unwrap())
+    };
+    while let Ok(&[name_len]) = take(1) {
+        let name = std::str::from_utf8(take(name_len as usize)?)
+            .map_err(|_| Error::MalformedPayload)?
+            .to_string();
+        let fields = take(RECORD_FIELDS_SIZE)?;
+        let id = u32::from_le_bytes(fields[..S_SHARD_ID].try_into().unwrap());
+        let stats = ShardStats {
+            entries: u64_at(fields, 0),
+            key_bytes: u64_at(fields, 1),
+            value_bytes: u64_at(fields, 2),
+        };
+        catalog.shards.insert(name, (id, stats));
+    }
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn verify_shards_are_created_lazily_and_counted() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    assert!(get(&index, "missing", Key::from("a")).unwrap().is_none());
+    assert!(!delete(&mut index, "missing", Key::from("a")).unwrap());
+    assert!(scan(&index, "missing").unwrap().is_empty());
+    assert_eq!(get_shard_catalog_page_id(), ZERO);
+
+    // enough shards for a catalog of several pages.
+    for i in 0..300u32 {
+        let shard = format!("device-{:03}", i);
+        for j in 0..=i % 3 {
+            let key = format!("key-{}", j);
+            insert(&mut index, &shard, Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+        }
+    }
+    let payload = Payload::from_str("two".to_string());
+    insert(&mut index, "device-002", Key::from("key-0"), payload).unwrap();
+    assert!(delete(&mut index, "device-002", Key::from("key-1")).unwrap());
+    assert_ne!(load(get_shard_catalog_page_id()).unwrap().right_sibling(), ZERO);
+    io::commit();
+
+    // the catalog is read from its pages once the database is opened again.
+    io::close();
+    let mut index = Index::open().unwrap();
+    assert_eq!(shards().unwrap().len(), 300);
+    let stats = stats("device-002").unwrap().unwrap();
+    assert_eq!(stats, ShardStats { entries: 2, key_bytes: 10, value_bytes: 7 });
+    let entries = scan(&index, "device-005").unwrap();
+    let keys = entries.iter().map(|(key, _)| key.as_slice()).collect::<Vec<_>>();
+    assert_eq!(keys, [b"key-0", b"key-1", b"key-2"]);
+    let payload = get(&index, "device-005", Key::from("key-2")).unwrap().unwrap();
+    assert_eq!(payload.to_bytes(), &5u32.to_le_bytes());
+    assert!(get(&index, "device-006", Key::from("key-2")).unwrap().is_none());
+    assert_eq!(treestats::stats().unwrap().entries, 599);
+
+    for i in 0..300u32 {
+        assert!(drop_shard(&mut index, &format!("device-{:03}", i)).unwrap());
+    }
+    assert!(shards().unwrap().is_empty());
+    assert_eq!(index.scan(..).unwrap().count(), 0);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+}
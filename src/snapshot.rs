@@ -0,0 +1,146 @@
+#[cfg(test)]
+use crate::btree::{load, Index};
+use crate::config;
+use crate::config::get_next_page_id;
+use crate::crypt::{self, Sink};
+use crate::errors::Error;
+use crate::events::{self, BulkOperation};
+use crate::fsm;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::latch;
+use crate::misses;
+use crate::sys;
+use crate::treestats;
+use crate::paging::{Page, PAGE_SIZE_USIZE};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"TELESNAP";
+
+/// Writes a copy of the database into a single file:
+///  _________________________________________________________________________
+/// | magic | config size | config | number of pages | page[0] | page[1] | .. |
+///  -------------------------------------------------------------------------
+/// Pages are read through the page cache, so the copy includes the changes not committed yet. The
+/// copy is compacted, it ends with the last allocated page and pages marked deleted are zeroed.
+/// It's written into a temporary file first which is then renamed, so the path either holds the
+/// previous file or the complete snapshot. The file is encrypted if a key provider is set, see
+/// `crypt::Sink`.
+///
+/// The snapshot holds all structures of the database, the index, the hash index and the sequences,
+/// at a single point: write operations are held off while the pages are copied, see
+/// `io::quiesce`. Returns the last applied log index the snapshot is consistent with.
+pub(crate) fn write(path: &Path) -> Result<u64, Error> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let gate = io::quiesce();
+    let config = config::snapshot();
+    let applied_index = config::get_last_applied_index();
+    let page_count = get_next_page_id().get() + 1;
+
+    let mut file = Sink::new(BufWriter::new(File::create(&temp_path)?))?;
+    file.write_all(MAGIC)?;
+    file.write_all(&(config.len() as u64).to_le_bytes())?;
+    file.write_all(&config)?;
+    file.write_all(&(page_count as u64).to_le_bytes())?;
+    for page_id in Offset(0).through(get_next_page_id()) {
+        let page = io::read_verified(page_id)?;
+        let page = *latch::lock(page_id, &page);
+        if page.is_marked_deleted() {
+            file.write_all(&[0u8; PAGE_SIZE_USIZE])?;
+        } else {
+            file.write_all(page.buffer())?;
+        }
+        events::progress(BulkOperation::Export, page_id.get() + 1, Some(page_count));
+    }
+    drop(gate);
+    let file = file.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sys::sync_dir(path.parent().unwrap_or(Path::new("")))?;
+    Ok(applied_index)
+}
+
+/// Reads the config and the pages of a snapshot.
+pub(crate) fn read(path: &Path) -> Result<(Vec<u8>, Vec<Page>), Error> {
+    let mut file = crypt::source(File::open(path)?)?;
+    let mut magic = [0u8; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::MalformedPayload);
+    }
+    let mut length = [0u8; size_of::<u64>()];
+    file.read_exact(&mut length)?;
+    let mut config = vec![0u8; u64::from_le_bytes(length) as usize];
+    file.read_exact(&mut config)?;
+    file.read_exact(&mut length)?;
+    let mut pages = Vec::new();
+    for _ in 0..u64::from_le_bytes(length) {
+        let mut buffer = [0u8; PAGE_SIZE_USIZE];
+        file.read_exact(&mut buffer)?;
+        pages.push(Page::new_from(buffer));
+    }
+    Ok((config, pages))
+}
+
+/// Returns the pages of a snapshot which are neither zeroed nor a page of a known type stored at
+/// its own id. Page 0 isn't allocated and is left out.
+pub(crate) fn malformed_pages(pages: &[Page]) -> Vec<Offset> {
+    pages
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(page_id, page)| {
+            let zeroed = page.buffer().iter().all(|byte| *byte == 0);
+            !zeroed && (page.page_id().get() != *page_id || !page.has_known_page_type())
+        })
+        .map(|(page_id, _)| Offset::from_usize(page_id))
+        .collect()
+}
+
+/// Writes the config and the pages of a snapshot over the database, and commits them.
+pub(crate) fn install(config: &[u8], pages: &[Page]) -> Result<(), Error> {
+    // page 0 isn't allocated, and pages zeroed by the snapshot were marked deleted, so that they
+    // aren't reachable whatever they hold now.
+    for (page_id, page) in pages.iter().enumerate().skip(1) {
+        if page.page_id().get() == page_id {
+            io::write(page);
+        }
+        events::progress(BulkOperation::Import, page_id + 1, Some(pages.len()));
+    }
+    config::restore(config);
+    misses::clear();
+    treestats::reset();
+    fsm::reset();
+    io::commit();
+    io::check_writable()
+}
+
+#[test]
+#[serial]
+fn verify_snapshot_round_trip() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..30u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let path = Path::new("snapshot.test");
+    write(path).unwrap();
+    let (config, pages) = read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(config, config::snapshot());
+    assert_eq!(pages.len(), get_next_page_id().get() + 1);
+    let root = &pages[index.root().get()];
+    assert_eq!(root.buffer(), load(index.root()).unwrap().buffer());
+    assert!(!Path::new("snapshot.test.tmp").exists());
+}
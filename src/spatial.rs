@@ -0,0 +1,225 @@
+use crate::btree::Index;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::ops::Bound;
+
+// Upper bound of the Z-order ranges a bounding box is decomposed into, partially covered cells are
+// scanned entirely and filtered once the budget is used up.
+const MAX_BBOX_RANGES: usize = 64;
+const COORDINATE_BITS: u32 = u32::BITS;
+
+/// Encodes the point into its Morton code, the bits of x and y are interleaved with x in the lowest
+/// bit, so that points close in space tend to be close in the key order.
+pub(crate) fn morton_encode(x: u32, y: u32) -> u64 {
+    spread(x) | (spread(y) << 1)
+}
+
+pub(crate) fn morton_decode(z: u64) -> (u32, u32) {
+    (compact(z), compact(z >> 1))
+}
+
+/// Returns the index key of the point. Callers may append a suffix to store several entries at the
+/// same point, `scan_bbox` only looks at the leading Morton code.
+pub(crate) fn point_key(x: u32, y: u32) -> [u8; 8] {
+    morton_encode(x, y).to_be_bytes()
+}
+
+/// Maps latitude and longitude in degrees onto the coordinate grid.
+pub(crate) fn lat_lon_to_xy(lat: f64, lon: f64) -> (u32, u32) {
+    let quantize = |value: f64, min: f64, span: f64| {
+        (((value - min) / span).clamp(0.0, 1.0) * f64::from(u32::MAX)) as u32
+    };
+    (quantize(lon, -180.0, 360.0), quantize(lat, -90.0, 180.0))
+}
+
+pub(crate) fn geo_key(lat: f64, lon: f64) -> [u8; 8] {
+    let (x, y) = lat_lon_to_xy(lat, lon);
+    point_key(x, y)
+}
+
+/// Returns the entries whose point lies within the inclusive bounding box. The box is decomposed
+/// into Z-order ranges which are scanned one after the other, points of partially covered ranges
+/// falling outside of the box are filtered.
+pub(crate) fn scan_bbox(
+    index: &Index,
+    min: (u32, u32),
+    max: (u32, u32),
+) -> Result<
+    impl Iterator<Item = Result<(Vec<u8>, Payload), Error>>,
+    Error,
+> {
+    let mut scans = Vec::new();
+    for (start, end) in bbox_ranges(min, max) {
+        let start = start.to_be_bytes();
+        let end = end.checked_add(1).map(u64::to_be_bytes);
+        let scan = index.scan((
+            Bound::Included(Key::from(&start)),
+            match &end {
+                Some(end) => Bound::Excluded(Key::from(end)),
+                None => Bound::Unbounded,
+            },
+        ))?;
+        scans.push(scan);
+    }
+    Ok(scans
+        .into_iter()
+        .flatten()
+        .filter(move |entry| match entry {
+            Ok((key, _)) => key
+                .get(..size_of::<u64>())
+                .map(|code| {
+                    let (x, y) = morton_decode(u64::from_be_bytes(code.try_into().unwrap()));
+                    (min.0..=max.0).contains(&x) && (min.1..=max.1).contains(&y)
+                })
+                .unwrap_or(false),
+            Err(_) => true,
+        }))
+}
+
+/// Decomposes the bounding box into sorted, disjoint and inclusive Z-order ranges. Cells of the
+/// quadtree over the coordinate grid are subdivided level by level, a cell within the box becomes a
+/// range, a cell crossing its border is subdivided further while the range budget allows.
+fn bbox_ranges(min: (u32, u32), max: (u32, u32)) -> Vec<(u64, u64)> {
+    let (min, max) = (
+        (min.0.min(max.0), min.1.min(max.1)),
+        (min.0.max(max.0), min.1.max(max.1)),
+    );
+    let mut ranges = Vec::new();
+    // cells as their origin and the number of bits of their side length.
+    let mut crossing = vec![(0u64, 0u64, COORDINATE_BITS)];
+    while !crossing.is_empty() {
+        if crossing[0].2 == 0 || ranges.len() + crossing.len() * 4 > MAX_BBOX_RANGES {
+            ranges.extend(crossing.iter().map(|cell| cell_range(*cell)));
+            break;
+        }
+        let mut next = Vec::new();
+        for (x, y, bits) in crossing {
+            let half = 1u64 << (bits - 1);
+            for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                let cell = (x + dx, y + dy, bits - 1);
+                let side = 1u64 << cell.2;
+                let (x0, y0, x1, y1) = (cell.0, cell.1, cell.0 + side - 1, cell.1 + side - 1);
+                if x1 < u64::from(min.0)
+                    || x0 > u64::from(max.0)
+                    || y1 < u64::from(min.1)
+                    || y0 > u64::from(max.1)
+                {
+                    continue;
+                }
+                if x0 >= u64::from(min.0)
+                    && x1 <= u64::from(max.0)
+                    && y0 >= u64::from(min.1)
+                    && y1 <= u64::from(max.1)
+                {
+                    ranges.push(cell_range(cell));
+                } else {
+                    next.push(cell);
+                }
+            }
+        }
+        crossing = next;
+    }
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if last.1.checked_add(1) == Some(start) => last.1 = end,
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// All points of a cell share the Morton code prefix of its origin.
+fn cell_range((x, y, bits): (u64, u64, u32)) -> (u64, u64) {
+    let start = morton_encode(x as u32, y as u32);
+    let len = (1u128 << (2 * bits)) - 1;
+    (start, start + len as u64)
+}
+
+fn spread(value: u32) -> u64 {
+    let mut x = u64::from(value);
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+fn compact(value: u64) -> u32 {
+    let mut x = value & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    ((x | (x >> 16)) & 0xffff_ffff) as u32
+}
+
+#[test]
+fn verify_morton_round_trip() {
+    assert_eq!(morton_encode(0b11, 0b00), 0b0101);
+    assert_eq!(morton_encode(0b00, 0b11), 0b1010);
+    for (x, y) in [
+        (0, 0),
+        (1, 2),
+        (u32::MAX, 0),
+        (12345, u32::MAX),
+        (u32::MAX, u32::MAX),
+    ] {
+        assert_eq!(morton_decode(morton_encode(x, y)), (x, y));
+    }
+}
+
+#[test]
+fn verify_bbox_ranges_cover_the_box() {
+    let ranges = bbox_ranges((3, 5), (20, 9));
+    assert!(ranges.len() <= MAX_BBOX_RANGES);
+    for x in 0..32u32 {
+        for y in 0..32u32 {
+            let z = morton_encode(x, y);
+            let covered = ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&z));
+            if (3..=20).contains(&x) && (5..=9).contains(&y) {
+                assert!(covered);
+            }
+        }
+    }
+    assert_eq!(
+        bbox_ranges((0, 0), (u32::MAX, u32::MAX)),
+        vec![(0, u64::MAX)]
+    );
+}
+
+#[test]
+#[serial]
+fn verify_scan_bbox() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for x in 0..24u32 {
+        for y in 0..24u32 {
+            index
+                .insert(Key::from(&point_key(x, y)), Payload::from_u32(x * 100 + y))
+                .unwrap();
+        }
+    }
+    let mut found: Vec<u32> = scan_bbox(&index, (5, 2), (9, 17))
+        .unwrap()
+        .map(|entry| u32::from_le_bytes(entry.unwrap().1.to_bytes().try_into().unwrap()))
+        .collect();
+    found.sort();
+    let expected: Vec<u32> = (5..=9u32)
+        .flat_map(|x| (2..=17u32).map(move |y| x * 100 + y))
+        .collect();
+    assert_eq!(found, expected);
+
+    let (x, y) = lat_lon_to_xy(52.52, 13.40);
+    assert_eq!(
+        morton_decode(u64::from_be_bytes(geo_key(52.52, 13.40))),
+        (x, y)
+    );
+}
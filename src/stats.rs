@@ -0,0 +1,209 @@
+use crate::events::{self, StallReason};
+#[cfg(test)]
+use serial_test::serial;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Operation is a logical operation of an index, the pages it touches are accounted to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Operation {
+    Get = 0,
+    Insert = 1,
+    Delete = 2,
+    Scan = 3,
+}
+
+const OPERATIONS: usize = 4;
+
+struct Counters {
+    operations: AtomicU64,
+    logical_bytes: AtomicU64,
+    pages_read: AtomicU64,
+    pages_written: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNTERS: Counters = Counters {
+    operations: AtomicU64::new(0),
+    logical_bytes: AtomicU64::new(0),
+    pages_read: AtomicU64::new(0),
+    pages_written: AtomicU64::new(0),
+    bytes_written: AtomicU64::new(0),
+};
+
+static COUNTERS: [Counters; OPERATIONS] = [ZERO_COUNTERS; OPERATIONS];
+static STALL_COUNT: AtomicU64 = AtomicU64::new(0);
+static STALL_MS: AtomicU64 = AtomicU64::new(0);
+static NEXT_OPERATION_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // the operation running on the thread, pages touched outside of operations aren't accounted.
+    static CURRENT: Cell<Option<Operation>> = const { Cell::new(None) };
+    static CURRENT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// OperationStats are the totals of an operation kind since the start of the process.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct OperationStats {
+    pub(crate) operations: u64,
+    /// Bytes of the keys and values passed in by the callers.
+    pub(crate) logical_bytes: u64,
+    /// Pages read including cache hits.
+    pub(crate) pages_read: u64,
+    pub(crate) pages_written: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl OperationStats {
+    /// Returns the pages touched per operation.
+    #[allow(dead_code)]
+    pub(crate) fn read_amplification(&self) -> f64 {
+        if self.operations == 0 {
+            return 0.0;
+        }
+        self.pages_read as f64 / self.operations as f64
+    }
+
+    /// Returns the bytes written per logical byte.
+    #[allow(dead_code)]
+    pub(crate) fn write_amplification(&self) -> f64 {
+        if self.logical_bytes == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.logical_bytes as f64
+    }
+}
+
+/// Stats holds the amplification counters of each operation kind, and the write stalls.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) get: OperationStats,
+    pub(crate) insert: OperationStats,
+    pub(crate) delete: OperationStats,
+    pub(crate) scan: OperationStats,
+    /// Number of times writes were held up by throttling or checkpoints.
+    pub(crate) stall_count: u64,
+    /// Total time writes were held up for, in milliseconds.
+    pub(crate) stall_ms: u64,
+}
+
+/// OperationGuard accounts the pages touched on the thread to the operation until it's dropped.
+pub(crate) struct OperationGuard {
+    outermost: bool,
+}
+
+/// Starts accounting to the operation. Operations nested into another one, e.g. the lookups of an
+/// insert, are accounted to the outer operation.
+pub(crate) fn begin(operation: Operation, logical_bytes: usize) -> OperationGuard {
+    let guard = resume(operation);
+    if guard.outermost {
+        let counters = &COUNTERS[operation as usize];
+        counters.operations.fetch_add(1, Ordering::Relaxed);
+        counters
+            .logical_bytes
+            .fetch_add(logical_bytes as u64, Ordering::Relaxed);
+    }
+    guard
+}
+
+/// Continues accounting to an operation started earlier, e.g. the leaves loaded by a scan while it
+/// is iterated.
+pub(crate) fn resume(operation: Operation) -> OperationGuard {
+    let outermost = CURRENT.with(|current| {
+        if current.get().is_some() {
+            return false;
+        }
+        current.set(Some(operation));
+        true
+    });
+    if outermost {
+        let id = NEXT_OPERATION_ID.fetch_add(1, Ordering::Relaxed);
+        CURRENT_ID.with(|current_id| current_id.set(id));
+    }
+    OperationGuard { outermost }
+}
+
+/// Returns the operation running on the thread along with its id, ids are unique within the
+/// process. None outside of operations.
+pub(crate) fn current_operation() -> Option<(Operation, u64)> {
+    let operation = CURRENT.with(|current| current.get())?;
+    Some((operation, CURRENT_ID.with(|current_id| current_id.get())))
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        if self.outermost {
+            CURRENT.with(|current| current.set(None));
+        }
+    }
+}
+
+fn current() -> Option<&'static Counters> {
+    CURRENT
+        .with(|current| current.get())
+        .map(|operation| &COUNTERS[operation as usize])
+}
+
+pub(crate) fn record_read() {
+    if let Some(counters) = current() {
+        counters.pages_read.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn record_write(bytes: usize) {
+    if let Some(counters) = current() {
+        counters.pages_written.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Records that writes were held up on purpose, and notifies the event listeners.
+pub(crate) fn record_stall(reason: StallReason, duration: Duration) {
+    STALL_COUNT.fetch_add(1, Ordering::Relaxed);
+    STALL_MS.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    events::emit(|listener| listener.on_write_stall(reason, duration));
+}
+
+fn load(operation: Operation) -> OperationStats {
+    let counters = &COUNTERS[operation as usize];
+    OperationStats {
+        operations: counters.operations.load(Ordering::Relaxed),
+        logical_bytes: counters.logical_bytes.load(Ordering::Relaxed),
+        pages_read: counters.pages_read.load(Ordering::Relaxed),
+        pages_written: counters.pages_written.load(Ordering::Relaxed),
+        bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn snapshot() -> Stats {
+    Stats {
+        get: load(Operation::Get),
+        insert: load(Operation::Insert),
+        delete: load(Operation::Delete),
+        scan: load(Operation::Scan),
+        stall_count: STALL_COUNT.load(Ordering::Relaxed),
+        stall_ms: STALL_MS.load(Ordering::Relaxed),
+    }
+}
+
+#[test]
+#[serial]
+fn verify_nested_operations_are_accounted_to_the_outer_one() {
+    let before = snapshot();
+    {
+        let _insert = begin(Operation::Insert, 10);
+        let _get = begin(Operation::Get, 0);
+        record_read();
+        record_write(100);
+    }
+    record_write(100);
+    let after = snapshot();
+    assert_eq!(after.get.operations, before.get.operations);
+    assert_eq!(after.insert.operations, before.insert.operations + 1);
+    assert_eq!(after.insert.pages_read, before.insert.pages_read + 1);
+    assert_eq!(after.insert.bytes_written, before.insert.bytes_written + 100);
+}
@@ -0,0 +1,14 @@
+//! Synchronization primitives of the page cache and the page latches. Building with
+//! `RUSTFLAGS="--cfg loom"` replaces them with the loom ones, so that the interleavings of the
+//! concurrent paths can be model-checked with `cargo test --release loom_`. Only the loom tests
+//! can run in such a build, the global page cache is created outside of a loom model.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Arc, Mutex, MutexGuard};
@@ -0,0 +1,126 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/// Takes the exclusive lock of the file without waiting, fails with WouldBlock if another handle
+/// holds it. The lock is flock on Unix, advisory and held by the open file. On Windows it is
+/// LockFileEx, which is mandatory: other handles, even of the same process, can neither read nor
+/// write the locked range, so a file of its own must be locked rather than a database file.
+pub(crate) fn try_lock(file: &File) -> io::Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(ErrorKind::WouldBlock.into()),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Reserves disk space for the first len bytes of the file without changing its length, so that
+/// pages written later don't fail for lack of space and are laid out contiguously. Linux uses
+/// fallocate keeping the size, Windows sets the allocation size with SetFileInformationByHandle.
+/// Other platforms can't reserve space without extending the file, there it's a no-op.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let len =
+        libc::off_t::try_from(len).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    // SAFETY: the descriptor is owned by the file, which outlives the call.
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        // file systems without fallocate, e.g. some network file systems.
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        e => Err(e),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_ALLOCATION_INFO, FileAllocationInfo, SetFileInformationByHandle,
+    };
+    let info = FILE_ALLOCATION_INFO {
+        AllocationSize: i64::try_from(len)
+            .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?,
+    };
+    // SAFETY: the handle is owned by the file, and the buffer holds a FILE_ALLOCATION_INFO.
+    let done = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            (&info as *const FILE_ALLOCATION_INFO).cast(),
+            size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+    if done == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Opens the file for reading and writing, creating it if it doesn't exist. The directory of a
+/// created file is synced, as on Linux the file may be gone after a crash otherwise, no matter how
+/// often its data was synced.
+pub(crate) fn open_or_create(path: &Path) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            sync_dir(path.parent().unwrap_or(Path::new("")))?;
+            Ok(file)
+        }
+        result => result,
+    }
+}
+
+/// Syncs the written data of the file to the disk. On Windows this is FlushFileBuffers, which
+/// syncs the metadata as well and needs a handle opened for writing.
+pub(crate) fn sync_data(file: &File) -> io::Result<()> {
+    file.sync_data()
+}
+
+/// Syncs the directory, so that a file renamed into it survives a crash. Windows journals renames
+/// along with the file, and directories can't be opened as files there, so it's a no-op.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[test]
+fn verify_lock_and_preallocation() {
+    let path = std::env::temp_dir().join(format!("teleport-sys-{}", std::process::id()));
+    let (first, second) = (open_or_create(&path).unwrap(), open_or_create(&path).unwrap());
+    try_lock(&first).unwrap();
+    assert_eq!(
+        try_lock(&second).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    drop(first);
+    try_lock(&second).unwrap();
+    preallocate(&second, 1 << 20).unwrap();
+    assert_eq!(second.metadata().unwrap().len(), 0);
+    sync_data(&second).unwrap();
+    sync_dir(Path::new("")).unwrap();
+    drop(second);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,189 @@
+use crate::errors::Error;
+#[cfg(test)]
+use crate::btree::Index;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::sys;
+#[cfg(test)]
+use crate::treefile;
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+pub(crate) const TIERS_FILE: &str = "tiers";
+const MAGIC: &[u8; 8] = b"TELETIER";
+pub(crate) const DEFAULT_COLD_FILE: &str = "index.cold";
+
+/// Tier is the storage a page is kept in. Hot pages are stored in the index file, cold pages in
+/// the cold file, which can be put on a cheaper and slower disk. Pages are hot until they are
+/// migrated, the ids of the cold pages are kept in a file next to the index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Tier {
+    Hot,
+    Cold,
+}
+
+static COLD_PATH: Lazy<Mutex<PathBuf>> = Lazy::new(|| Mutex::new(PathBuf::from(DEFAULT_COLD_FILE)));
+// None until the cold pages were read from their file.
+static COLD_PAGES: Lazy<RwLock<Option<HashSet<Offset>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Sets the path of the cold file, relative paths are resolved against the database. The path
+/// must stay the same for the life of the database, cold pages are only looked up in it.
+pub(crate) fn set_cold_path(path: PathBuf) {
+    *COLD_PATH.lock().unwrap_or_else(|e| e.into_inner()) = path;
+}
+
+pub(crate) fn cold_path() -> PathBuf {
+    COLD_PATH.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn with_cold_pages<T>(f: impl FnOnce(&HashSet<Offset>) -> T) -> T {
+    if let Some(cold_pages) = COLD_PAGES.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return f(cold_pages);
+    }
+    let mut cold_pages = COLD_PAGES.write().unwrap_or_else(|e| e.into_inner());
+    // cold pages which can't be listed are read from the index file, which fails their reads with
+    // page id mismatches.
+    f(cold_pages.get_or_insert_with(|| read().unwrap_or_default()))
+}
+
+/// Returns the tier holding the page.
+pub(crate) fn tier(page_id: usize) -> Tier {
+    let Ok(page_id) = Offset::try_from(page_id) else {
+        return Tier::Hot;
+    };
+    match with_cold_pages(|cold_pages| cold_pages.contains(&page_id)) {
+        true => Tier::Cold,
+        false => Tier::Hot,
+    }
+}
+
+/// Returns the number of cold pages.
+pub(crate) fn cold_pages() -> usize {
+    with_cold_pages(HashSet::len)
+}
+
+/// Drops the cold pages read from the file, so that the ones of another database are read.
+pub(crate) fn reset() {
+    *COLD_PAGES.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub(crate) fn delete() {
+    reset();
+    let _ = fs::remove_file(TIERS_FILE);
+    let _ = fs::remove_file(cold_path());
+}
+
+/// Moves the pages into the tier and returns the number of pages moved, pages already in the tier
+/// are skipped. Changes are committed first. The pages are copied into the file of the tier before
+/// the cold pages are written, so a crash leaves at most stale copies behind, and the copies they
+/// left in the file of the other tier become stale in turn.
+pub(crate) fn migrate(page_ids: &[Offset], tier: Tier) -> Result<usize, Error> {
+    io::check_writable()?;
+    io::commit();
+    // pages are read before the cold pages are locked, reads look the tier up.
+    let mut pages = Vec::new();
+    for page_id in page_ids {
+        if self::tier(page_id.get()) != tier {
+            let page = io::read_verified(*page_id)?;
+            pages.push(*page.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+    if pages.is_empty() {
+        return Ok(0);
+    }
+    let mut cold_pages = COLD_PAGES.write().unwrap_or_else(|e| e.into_inner());
+    let cold_pages = cold_pages.get_or_insert_with(|| read().unwrap_or_default());
+    for page in &pages {
+        io::write_tier(tier, page)?;
+        match tier {
+            Tier::Hot => cold_pages.remove(&page.page_id()),
+            Tier::Cold => cold_pages.insert(page.page_id()),
+        };
+    }
+    write(cold_pages)?;
+    io::check_writable()?;
+    Ok(pages.len())
+}
+
+fn read() -> std::io::Result<HashSet<Offset>> {
+    let mut bytes = Vec::new();
+    match File::open(TIERS_FILE) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    let malformed = || std::io::Error::from(ErrorKind::InvalidData);
+    let page_ids = bytes.strip_prefix(MAGIC.as_slice()).ok_or_else(malformed)?;
+    if page_ids.len() % size_of::<u16>() != 0 {
+        return Err(malformed());
+    }
+    Ok(page_ids
+        .chunks_exact(size_of::<u16>())
+        .map(|bytes| Offset(u16::from_le_bytes([bytes[0], bytes[1]])))
+        .collect())
+}
+
+// Written like the page map, into a temporary file which is renamed over the previous one.
+fn write(cold_pages: &HashSet<Offset>) -> std::io::Result<()> {
+    let temp_path = format!("{}.tmp", TIERS_FILE);
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    file.write_all(MAGIC)?;
+    for page_id in cold_pages {
+        file.write_all(&page_id.0.to_le_bytes())?;
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, TIERS_FILE)?;
+    sys::sync_dir(Path::new("."))
+}
+
+#[test]
+#[serial]
+fn verify_pages_migrate_between_tiers() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    let value = "cold value ".repeat(10);
+    for i in 0..300u32 {
+        let key = format!("{:03}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_str(value.clone())).unwrap();
+    }
+    let range = index.range_pages(Key::from("100")..Key::from("200")).unwrap();
+    assert_eq!(migrate(&range, Tier::Cold).unwrap(), range.len());
+    assert_eq!(migrate(&range, Tier::Cold).unwrap(), 0);
+    assert!(range.iter().all(|page_id| tier(page_id.get()) == Tier::Cold));
+    assert!(fs::metadata(cold_path()).unwrap().len() > 0);
+
+    // the cold pages are read from the cold file once the database is opened again, and written
+    // there.
+    io::close();
+    let mut index = Index::open().unwrap();
+    assert_eq!(cold_pages(), range.len());
+    index.insert(Key::from("150"), Payload::from_u32(150)).unwrap();
+    for i in 0..300u32 {
+        let payload = index.get(Key::from(format!("{:03}", i).as_str())).unwrap().unwrap();
+        match i {
+            150 => assert_eq!(payload.to_bytes(), &150u32.to_le_bytes()),
+            _ => assert_eq!(payload.to_bytes(), value.as_bytes()),
+        }
+    }
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    let tree = treefile::page_ids().unwrap();
+    assert_eq!(migrate(&tree, Tier::Hot).unwrap(), range.len());
+    assert_eq!(cold_pages(), 0);
+    io::close();
+    let index = Index::open().unwrap();
+    assert_eq!(index.scan(..).unwrap().count(), 300);
+}
@@ -0,0 +1,305 @@
+use crate::btree::{children, load};
+#[cfg(test)]
+use crate::btree::Index;
+use crate::config::{
+    get_dictionary_page_id, get_key_layout, get_root_page_id, update_dictionary_page_id,
+    update_key_layout, update_root_page_id,
+};
+use crate::crypt::{self, Sink};
+use crate::errors::Error;
+use crate::events::{self, BulkOperation};
+use crate::freelist;
+#[cfg(test)]
+use crate::fsck;
+#[cfg(test)]
+use crate::hash::HashIndex;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{self, Page, PAGE_SIZE_USIZE, ZERO};
+use crate::sys;
+use crate::treestats;
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::{Key, Payload};
+#[cfg(test)]
+use serial_test::serial;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"TELETREE";
+// Pages of the tree and of the key dictionary, overflow pages have a slot layout of their own.
+const TREE_PAGE: u8 = 0;
+const OVERFLOW_PAGE: u8 = 1;
+
+/// TreeFile holds the pages of an index exported by `detach`, with the page ids they had in the
+/// database they were detached from.
+struct TreeFile {
+    key_layout: u8,
+    root: Offset,
+    dictionary: Offset,
+    pages: Vec<(u8, Page)>,
+}
+
+/// Moves the index into a file of its own, leaving an empty index behind:
+///  ____________________________________________________________________________________________
+/// | magic | key layout | root | dictionary | number of pages | kind[0] | page[0] | kind[1] | .. |
+///  --------------------------------------------------------------------------------------------
+/// The file holds the pages of the tree, its overflow pages and the key dictionary, the pages are
+/// returned to the free list. The file is written into a temporary file which is then renamed, so
+/// the path either holds the previous file or the complete tree. It's encrypted if a key provider
+/// is set.
+pub(crate) fn detach(path: &Path) -> Result<(), Error> {
+    // no write may change the tree between its export and the release of its pages.
+    let _gate = io::quiesce();
+    io::check_writable()?;
+    let tree = TreeFile {
+        key_layout: get_key_layout(),
+        root: get_root_page_id(),
+        dictionary: get_dictionary_page_id(),
+        pages: collect()?,
+    };
+    write(path, &tree)?;
+    release(&tree.pages)?;
+    io::check_writable()
+}
+
+/// Drops the index like `detach` without writing it anywhere, e.g. to build it anew, see
+/// `archive::freeze`.
+pub(crate) fn clear() -> Result<(), Error> {
+    io::check_writable()?;
+    release(&collect()?)?;
+    io::check_writable()
+}
+
+// Returns the pages of the index to the free list, leaving an empty index behind.
+fn release(pages: &[(u8, Page)]) -> Result<(), Error> {
+    for (_, page) in pages {
+        let mut page = *page;
+        page.mark_deleted();
+        io::write(&page);
+        freelist::push(page.page_id())?;
+    }
+    update_root_page_id(ZERO);
+    update_dictionary_page_id(ZERO);
+    update_key_layout(0);
+    treestats::invalidate();
+    Ok(())
+}
+
+/// Attaches a tree written by `detach` as the index of the database, which must be empty and must
+/// not have interned any keys. The pages are copied as they are, only the page ids they hold are
+/// remapped to pages allocated in this database, so no record is inserted again.
+pub(crate) fn attach(path: &Path) -> Result<(), Error> {
+    let _write = io::write_operation();
+    io::check_writable()?;
+    let tree = read(path)?;
+    let root = get_root_page_id();
+    if root != ZERO {
+        let root_page = load(root)?;
+        if !root_page.is_leaf() || root_page.num_of_slots() != ZERO {
+            return Err(Error::IndexNotEmpty);
+        }
+    }
+    if get_dictionary_page_id() != ZERO {
+        return Err(Error::IndexNotEmpty);
+    }
+
+    // pages referring to pages missing in the file are rejected before anything is allocated.
+    let ids: HashMap<Offset, Offset> = tree
+        .pages
+        .iter()
+        .map(|(_, page)| (page.page_id(), page.page_id()))
+        .collect();
+    remap_all(&tree, &ids)?;
+
+    if root != ZERO {
+        freelist::push(root)?;
+    }
+    let allocator = io::page_allocator();
+    let mapping: HashMap<Offset, Offset> = tree
+        .pages
+        .iter()
+        .map(|(_, page)| Ok((page.page_id(), paging::next_page(&allocator)?)))
+        .collect::<Result<_, Error>>()?;
+    for (i, page) in remap_all(&tree, &mapping)?.iter().enumerate() {
+        io::write(page);
+        events::progress(BulkOperation::Import, i + 1, Some(tree.pages.len()));
+    }
+    update_key_layout(tree.key_layout);
+    update_root_page_id(mapping[&tree.root]);
+    if tree.dictionary != ZERO {
+        update_dictionary_page_id(mapping[&tree.dictionary]);
+    }
+    treestats::invalidate();
+    io::check_writable()
+}
+
+/// Returns the ids of the pages of the index, its overflow pages and the key dictionary.
+pub(crate) fn page_ids() -> Result<Vec<Offset>, Error> {
+    Ok(collect()?.iter().map(|(_, page)| page.page_id()).collect())
+}
+
+// The pages of the index in the order they are reached, starting with the root.
+fn collect() -> Result<Vec<(u8, Page)>, Error> {
+    let mut pages = Vec::new();
+    let mut pending = vec![get_root_page_id()];
+    while let Some(page_id) = pending.pop() {
+        if page_id == ZERO {
+            continue;
+        }
+        let page = load(page_id)?;
+        if page.is_leaf() {
+            collect_overflow_pages(&page, &mut pages)?;
+        } else {
+            pending.extend(children(&page)?);
+        }
+        pages.push((TREE_PAGE, page));
+    }
+    let mut next = get_dictionary_page_id();
+    while next != ZERO {
+        let page = load(next)?;
+        collect_overflow_pages(&page, &mut pages)?;
+        next = page.right_sibling();
+        pages.push((TREE_PAGE, page));
+    }
+    Ok(pages)
+}
+
+fn collect_overflow_pages(page: &Page, pages: &mut Vec<(u8, Page)>) -> Result<(), Error> {
+    for i in 0..page.num_of_slots().get() {
+        for page_id in page.overflow_page_ids(i)? {
+            pages.push((OVERFLOW_PAGE, load(page_id)?));
+        }
+    }
+    Ok(())
+}
+
+// Returns copies of the pages with their page ids remapped, MalformedPayload if a page refers to a
+// page which isn't mapped.
+fn remap_all(tree: &TreeFile, mapping: &HashMap<Offset, Offset>) -> Result<Vec<Page>, Error> {
+    let missing = Cell::new(false);
+    let map = |page_id: Offset| match mapping.get(&page_id) {
+        Some(mapped) => *mapped,
+        None => {
+            missing.set(true);
+            page_id
+        }
+    };
+    let mut pages = Vec::with_capacity(tree.pages.len());
+    for (kind, page) in &tree.pages {
+        let mut page = *page;
+        match *kind {
+            TREE_PAGE => page.remap(map)?,
+            OVERFLOW_PAGE => page.remap_overflow(map),
+            _ => return Err(Error::MalformedPayload),
+        }
+        pages.push(page);
+    }
+    if missing.get() || !mapping.contains_key(&tree.root) {
+        return Err(Error::MalformedPayload);
+    }
+    Ok(pages)
+}
+
+fn write(path: &Path, tree: &TreeFile) -> Result<(), Error> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = Sink::new(BufWriter::new(File::create(&temp_path)?))?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[tree.key_layout])?;
+    file.write_all(&(tree.root.get() as u64).to_le_bytes())?;
+    file.write_all(&(tree.dictionary.get() as u64).to_le_bytes())?;
+    file.write_all(&(tree.pages.len() as u64).to_le_bytes())?;
+    for (i, (kind, page)) in tree.pages.iter().enumerate() {
+        file.write_all(&[*kind])?;
+        file.write_all(page.buffer())?;
+        events::progress(BulkOperation::Export, i + 1, Some(tree.pages.len()));
+    }
+    let file = file.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sys::sync_dir(path.parent().unwrap_or(Path::new("")))?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<TreeFile, Error> {
+    let mut file = crypt::source(File::open(path)?)?;
+    let mut magic = [0u8; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::MalformedPayload);
+    }
+    let mut byte = [0u8; 1];
+    file.read_exact(&mut byte)?;
+    let key_layout = byte[0];
+    let mut read_u64 = || -> Result<u64, Error> {
+        let mut bytes = [0u8; size_of::<u64>()];
+        file.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+    let root = Offset::from_usize(read_u64()? as usize);
+    let dictionary = Offset::from_usize(read_u64()? as usize);
+    let page_count = read_u64()?;
+    let mut pages = Vec::new();
+    for _ in 0..page_count {
+        let mut buffer = [0u8; PAGE_SIZE_USIZE];
+        file.read_exact(&mut byte)?;
+        file.read_exact(&mut buffer)?;
+        pages.push((byte[0], Page::new_from(buffer)));
+    }
+    Ok(TreeFile {
+        key_layout,
+        root,
+        dictionary,
+        pages,
+    })
+}
+
+#[test]
+#[serial]
+fn verify_tree_is_moved_between_databases() {
+    delete_index();
+    let key = |i: u32| format!("attached/key/{:03}", i);
+    let mut index = Index::open().unwrap();
+    for i in 0..60u32 {
+        index.insert(Key::from(key(i).as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let large = "x".repeat(20_000);
+    index
+        .insert(Key::from("large"), Payload::from_str(large.clone()))
+        .unwrap();
+    assert_ne!(get_dictionary_page_id(), ZERO);
+    let path = Path::new("tree.test");
+    detach(path).unwrap();
+    assert_eq!(Index::open().unwrap().scan(..).unwrap().count(), 0);
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    // another database, whose pages are taken by a hash index, so that all page ids change.
+    delete_index();
+    let mut hash_index = HashIndex::open().unwrap();
+    for i in 0..20u32 {
+        hash_index
+            .insert(Key::from(format!("hash/{:02}", i).as_str()), Payload::from_u32(i))
+            .unwrap();
+    }
+    attach(path).unwrap();
+    let index = Index::open().unwrap();
+    for (i, entry) in (0..).zip(index.scan(..).unwrap().take(60)) {
+        let (current, payload) = entry.unwrap();
+        assert_eq!(current, key(i).as_bytes());
+        assert_eq!(*payload.to_bytes(), i.to_le_bytes());
+    }
+    let payload = index.get(Key::from("large")).unwrap().unwrap();
+    assert_eq!(payload.to_str(), large);
+    let payload = HashIndex::open().unwrap().get(Key::from("hash/07")).unwrap().unwrap();
+    assert_eq!(*payload.to_bytes(), 7u32.to_le_bytes());
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    // the index isn't empty anymore.
+    assert!(matches!(attach(path), Err(Error::IndexNotEmpty)));
+    fs::remove_file(path).unwrap();
+}
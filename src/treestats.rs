@@ -0,0 +1,298 @@
+use crate::btree::{children, load};
+#[cfg(test)]
+use crate::btree::Index;
+use crate::config::{get_root_page_id, get_tree_stats_page_id, update_tree_stats_page_id};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::paging::{Page, ZERO};
+use crate::types::{Key, Offset, Payload, PayloadType};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::sync::Mutex;
+
+const STATS_KEY: &str = "tree";
+
+/// TreeStats describes the shape of the index: the number of entries, the bytes of their keys and
+/// values, and the pages on each level, leaves first. The height is the number of levels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TreeStats {
+    pub(crate) entries: u64,
+    pub(crate) key_bytes: u64,
+    pub(crate) value_bytes: u64,
+    pub(crate) level_pages: Vec<u64>,
+}
+
+impl TreeStats {
+    pub(crate) fn height(&self) -> usize {
+        self.level_pages.len()
+    }
+}
+
+enum State {
+    // not read since the database was opened, or dropped by a rollback.
+    Unloaded,
+    // to be recomputed by walking the tree, writes aren't counted meanwhile.
+    Stale,
+    // kept up to date by the writes, dirty until it's written at the next checkpoint.
+    Current { stats: TreeStats, dirty: bool },
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::Unloaded));
+
+/// Reads the statistics written at the last checkpoint, so that writes from now on are counted.
+/// Statistics written for another root, or missing in files of older versions, are recomputed by
+/// the next call to `stats`. Does nothing if the statistics are loaded already.
+pub(crate) fn open() -> Result<(), Error> {
+    let mut state = lock();
+    if matches!(*state, State::Unloaded) {
+        *state = read()?;
+    }
+    Ok(())
+}
+
+/// Returns the statistics of the index, walking the whole tree only if they are stale.
+pub(crate) fn stats() -> Result<TreeStats, Error> {
+    let mut state = lock();
+    if matches!(*state, State::Unloaded) {
+        *state = read()?;
+    }
+    if matches!(*state, State::Stale) {
+        *state = State::Current {
+            stats: crawl()?,
+            dirty: true,
+        };
+    }
+    match &*state {
+        State::Current { stats, .. } => Ok(stats.clone()),
+        _ => unreachable!(),
+    }
+}
+
+/// Applies a write of the index to the statistics, unless they are stale.
+pub(crate) fn update(f: impl FnOnce(&mut TreeStats)) {
+    if let State::Current { stats, dirty } = &mut *lock() {
+        f(stats);
+        *dirty = true;
+    }
+}
+
+/// Counts the entry written into a leaf, replacing a value of replaced_len bytes if there was one.
+pub(crate) fn record_insert(key_len: usize, value_len: usize, replaced_len: Option<usize>) {
+    update(|stats| {
+        match replaced_len {
+            Some(replaced_len) => stats.value_bytes -= replaced_len as u64,
+            None => {
+                stats.entries += 1;
+                stats.key_bytes += key_len as u64;
+            }
+        }
+        stats.value_bytes += value_len as u64;
+    });
+}
+
+pub(crate) fn record_delete(key_len: usize, value_len: usize) {
+    update(|stats| {
+        stats.entries -= 1;
+        stats.key_bytes -= key_len as u64;
+        stats.value_bytes -= value_len as u64;
+    });
+}
+
+/// Counts the page added to the level by a split, a split of the root adds a level on top.
+pub(crate) fn record_split(level: usize) {
+    update(|stats| match stats.level_pages.get_mut(level) {
+        Some(pages) => *pages += 1,
+        None => stats.level_pages.push(1),
+    });
+}
+
+/// Marks the statistics for a walk of the tree, after the tree was replaced as a whole.
+pub(crate) fn invalidate() {
+    *lock() = State::Stale;
+}
+
+/// Drops the statistics held in memory, they are read again from the last checkpoint.
+pub(crate) fn reset() {
+    *lock() = State::Unloaded;
+}
+
+/// Writes the statistics changed since the last checkpoint into the stats page. Called by the
+/// checkpoint, so that the page is consistent with the tree in shadow paging mode. In write
+/// through mode the page lags behind the tree after a crash, `invalidate` has them recomputed.
+pub(crate) fn persist() -> Result<(), Error> {
+    let mut state = lock();
+    let State::Current { stats, dirty } = &mut *state else {
+        return Ok(());
+    };
+    if !*dirty {
+        return Ok(());
+    }
+    let mut page = match get_tree_stats_page_id() {
+        ZERO => {
+            let page = Page::new_data(&io::page_allocator())?;
+            update_tree_stats_page_id(page.page_id());
+            page
+        }
+        page_id => load(page_id)?,
+    };
+    if let Some(index) = page.find_slot(Key::from(STATS_KEY))? {
+        page.delete_slot(index)?;
+    }
+    page.add(Key::from(STATS_KEY), encode(stats, get_root_page_id()))?;
+    *dirty = false;
+    Ok(())
+}
+
+fn lock() -> std::sync::MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// An empty database starts with empty statistics, rather than with a walk.
+fn read() -> Result<State, Error> {
+    let root = get_root_page_id();
+    let page_id = get_tree_stats_page_id();
+    if page_id == ZERO {
+        if root == ZERO {
+            return Ok(State::Current {
+                stats: TreeStats::default(),
+                dirty: true,
+            });
+        }
+        return Ok(State::Stale);
+    }
+    let page = load(page_id)?;
+    let Some(index) = page.find_slot(Key::from(STATS_KEY))? else {
+        return Ok(State::Stale);
+    };
+    match decode(page.value_at(index)?.to_bytes())? {
+        (stats, stats_root) if stats_root == root => Ok(State::Current { stats, dirty: false }),
+        _ => Ok(State::Stale),
+    }
+}
+
+//  ______________________________________________________________________________________
+// | root | entries | key bytes | value bytes | height | level[0] pages | level[1] pages | .. |
+//  --------------------------------------------------------------------------------------
+fn encode(stats: &TreeStats, root: Offset) -> Payload {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&(root.get() as u64).to_le_bytes());
+    buffer.extend_from_slice(&stats.entries.to_le_bytes());
+    buffer.extend_from_slice(&stats.key_bytes.to_le_bytes());
+    buffer.extend_from_slice(&stats.value_bytes.to_le_bytes());
+    buffer.extend_from_slice(&(stats.height() as u64).to_le_bytes());
+    for pages in &stats.level_pages {
+        buffer.extend_from_slice(&pages.to_le_bytes());
+    }
+    Payload::from_vec(buffer, PayloadType::Bytes)
+}
+
+fn decode(buffer: &[u8]) -> Result<(TreeStats, Offset), Error> {
+    let mut fields = buffer
+        .chunks(size_of::<u64>())
+        .map(|chunk| chunk.try_into().map(u64::from_le_bytes));
+    let mut next = || {
+        fields
+            .next()
+            .and_then(Result::ok)
+            .ok_or(Error::MalformedPayload)
+    };
+    let root = Offset::from_usize(next()? as usize);
+    let entries = next()?;
+    let key_bytes = next()?;
+    let value_bytes = next()?;
+    let height = next()?;
+    let level_pages = (0..height).map(|_| next()).collect::<Result<_, _>>()?;
+    Ok((
+        TreeStats {
+            entries,
+            key_bytes,
+            value_bytes,
+            level_pages,
+        },
+        root,
+    ))
+}
+
+/// Returns the length of the value at the slot index of the leaf, see `Page::value_len_at`.
+pub(crate) fn value_len(leaf: &Page, index: usize) -> Result<usize, Error> {
+    leaf.value_len_at(index)
+}
+
+// Walks the tree level by level from the root down.
+fn crawl() -> Result<TreeStats, Error> {
+    let mut stats = TreeStats::default();
+    let root = get_root_page_id();
+    if root == ZERO {
+        return Ok(stats);
+    }
+    let mut level = vec![root];
+    loop {
+        stats.level_pages.push(level.len() as u64);
+        let mut next_level = Vec::new();
+        for page_id in level {
+            let page = load(page_id)?;
+            if !page.is_leaf() {
+                next_level.extend(children(&page)?);
+                continue;
+            }
+            for i in 0..page.num_of_slots().get() {
+                if page.is_tombstone_at(i)? {
+                    continue;
+                }
+                stats.entries += 1;
+                stats.key_bytes += page.key_at(i)?.len() as u64;
+                stats.value_bytes += value_len(&page, i)? as u64;
+            }
+        }
+        if next_level.is_empty() {
+            break;
+        }
+        level = next_level;
+    }
+    stats.level_pages.reverse();
+    Ok(stats)
+}
+
+#[test]
+#[serial]
+fn verify_tree_stats_survive_reopen() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    for i in 0..300u32 {
+        let key = format!("stats/key/{:04}", i);
+        index.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    let large = "x".repeat(20_000);
+    index.insert(Key::from("stats/large"), Payload::from_str(large.clone())).unwrap();
+    index.insert(Key::from("stats/key/0001"), Payload::from_str("one".to_string())).unwrap();
+    assert!(index.delete(Key::from("stats/key/0002")).unwrap());
+    let expected = crawl().unwrap();
+    assert_eq!(expected.entries, 300);
+    assert_eq!(expected.value_bytes, 298 * 4 + 3 + 20_000);
+    assert!(expected.height() > 1);
+    assert_eq!(stats().unwrap(), expected);
+
+    // the checkpoint writes the stats page, which is read instead of walking the tree.
+    io::commit();
+    io::close();
+    open().unwrap();
+    assert!(matches!(&*lock(), State::Current { stats, dirty: false } if *stats == expected));
+    assert!(fsck::check(false).unwrap().orphans.is_empty());
+
+    let mut index = Index::open().unwrap();
+    assert!(index.delete(Key::from("stats/large")).unwrap());
+    assert_eq!(stats().unwrap(), crawl().unwrap());
+
+    // a database without a stats page walks the tree.
+    io::commit();
+    io::close();
+    update_tree_stats_page_id(ZERO);
+    open().unwrap();
+    assert!(matches!(&*lock(), State::Stale));
+    assert_eq!(stats().unwrap().entries, 299);
+}
@@ -0,0 +1,1080 @@
+use crate::btree::Index;
+#[cfg(test)]
+use crate::clock::{SystemClock, VirtualClock};
+use crate::clock;
+use crate::crypt::{self, Sink};
+use crate::errors::Error;
+#[cfg(test)]
+use crate::events::EventListener;
+use crate::events::{self, TransactionLimit};
+use crate::sys;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload, PayloadType};
+use once_cell::sync::Lazy;
+#[cfg(test)]
+use serial_test::serial;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+const PREPARED_FILE_PREFIX: &str = "prepared.";
+
+pub(crate) const DEFAULT_SPILL_THRESHOLD: usize = 64 << 20;
+
+// Bytes of buffered writes a transaction keeps in memory before spilling them.
+static SPILL_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_SPILL_THRESHOLD);
+// numbers the spill files of the process.
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the bytes of keys and payloads a transaction buffers in memory before spilling them into a
+/// temporary file.
+pub(crate) fn set_spill_threshold(bytes: usize) {
+    SPILL_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// TransactionLimits bound how long a transaction runs and how many keys it writes, so that a
+/// transaction left open doesn't keep the values read by its snapshot, and the keys committed
+/// since it began, forever. A transaction past half of a limit is reported to the event listeners,
+/// and it's aborted once it runs for the maximum age or writes more keys than the maximum write
+/// set: its operations and its commit fail with `TransactionAborted`. Transactions are checked for
+/// their age whenever a transaction begins, reads, writes or commits, so that forgotten ones are
+/// aborted by the others. None for no limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct TransactionLimits {
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) max_write_set: Option<usize>,
+}
+
+static LIMITS: Lazy<Mutex<TransactionLimits>> =
+    Lazy::new(|| Mutex::new(TransactionLimits::default()));
+
+pub(crate) fn set_limits(limits: TransactionLimits) {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner()) = limits;
+}
+
+pub(crate) fn limits() -> TransactionLimits {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// IsolationLevel selects the committed state a transaction reads, and the writes of other
+/// transactions it's checked against when it commits. Only writes made through transactions are
+/// seen as committed after a transaction began, see `Transaction`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum IsolationLevel {
+    /// Each read sees the latest committed state, so that reading a key twice may return two
+    /// values. The transaction only fails on keys written by a prepared transaction, otherwise the
+    /// last transaction to commit a key wins.
+    ReadCommitted,
+    /// Reads see the state committed when the transaction began. The transaction fails if another
+    /// transaction committed a key it writes since it began, the first one to commit wins.
+    #[default]
+    Snapshot,
+    /// Reads see the state committed when the transaction began, like with `Snapshot`. The
+    /// transaction fails as well if another transaction committed a key it read or a key within a
+    /// range it scanned since it began, so that keys inserted into a scanned range, the phantoms,
+    /// are noticed too. Transactions which commit are serializable in commit order.
+    Serializable,
+}
+
+/// CommitError is returned by `Transaction::commit`.
+#[derive(Debug)]
+pub(crate) enum CommitError {
+    /// The key written, or with serializable isolation read, by the transaction was committed by
+    /// another transaction since this one began. The transaction is rolled back and may be retried.
+    Conflict { key: Vec<u8> },
+    Storage(Error),
+}
+
+impl From<Error> for CommitError {
+    fn from(error: Error) -> Self {
+        CommitError::Storage(error)
+    }
+}
+
+// The commit clock, and the commit timestamp of the keys written while transactions are running.
+struct Commits {
+    clock: u64,
+    keys: HashMap<Vec<u8>, u64>,
+    // the values the keys had before the commits at the timestamps, oldest first, read by the
+    // transactions which began before.
+    history: HashMap<Vec<u8>, Vec<(u64, Option<Payload>)>>,
+    // the running transactions by id. Ids are handed out in the order the transactions begin, the
+    // first one began first.
+    active: BTreeMap<u64, Running>,
+    // the transactions aborted for reaching a limit, until they end.
+    aborted: HashMap<u64, TransactionLimit>,
+    next_id: u64,
+    // keys written by prepared transactions, to the id of the transaction.
+    prepared: HashMap<Vec<u8>, u64>,
+    next_prepared: u64,
+}
+
+struct Running {
+    start: u64,
+    // the time of the clock the transaction began at.
+    began: Duration,
+    warned: bool,
+}
+
+// Notice is an event about a transaction, emitted once the commit lock is released, so that the
+// listeners may use transactions.
+enum Notice {
+    Long(u64, TransactionLimit),
+    Aborted(u64, TransactionLimit),
+}
+
+fn notify(notices: Vec<Notice>) {
+    for notice in notices {
+        match notice {
+            Notice::Long(id, limit) => {
+                events::emit(|listener| listener.on_long_transaction(id, limit))
+            }
+            Notice::Aborted(id, limit) => {
+                events::emit(|listener| listener.on_transaction_aborted(id, limit))
+            }
+        }
+    }
+}
+
+impl Commits {
+    // Keys committed before the oldest running transaction began can't conflict anymore, nor are
+    // the values they had before read.
+    fn prune(&mut self) {
+        match self.active.values().next() {
+            Some(oldest) => {
+                let oldest = oldest.start;
+                self.keys.retain(|_, committed| *committed > oldest);
+                self.history.retain(|_, versions| {
+                    versions.retain(|(committed, _)| *committed > oldest);
+                    !versions.is_empty()
+                });
+            }
+            None => {
+                self.keys.clear();
+                self.history.clear();
+            }
+        }
+    }
+
+    // Keeps the value of the key before the commit at the next timestamp for the running
+    // transactions, which read the state committed when they began.
+    fn keep_before_image(
+        &mut self,
+        index: &Index,
+        key: &[u8],
+    ) -> Result<(), Error> {
+        if self.active.is_empty() {
+            return Ok(());
+        }
+        let before = index.get(Key::from(key))?;
+        self.history.entry(key.to_vec()).or_default().push((self.clock + 1, before));
+        Ok(())
+    }
+
+    fn finish(&mut self, id: u64) {
+        if self.active.remove(&id).is_none() {
+            self.aborted.remove(&id);
+        }
+        self.prune();
+    }
+
+    // Aborts the running transaction for reaching the limit.
+    fn abort(&mut self, id: u64, limit: TransactionLimit) -> Notice {
+        self.active.remove(&id);
+        self.aborted.insert(id, limit);
+        self.prune();
+        Notice::Aborted(id, limit)
+    }
+
+    // Aborts the transactions which reached the age limit and returns the notices of those which
+    // passed half of it since they were checked last.
+    fn expire(&mut self) -> Vec<Notice> {
+        let Some(max_age) = limits().max_age else {
+            return Vec::new();
+        };
+        let now = clock::now();
+        let mut notices = Vec::new();
+        let mut expired = Vec::new();
+        for (id, running) in self.active.iter_mut() {
+            let age = now.saturating_sub(running.began);
+            if age >= max_age {
+                expired.push(*id);
+            } else if age >= max_age / 2 && !running.warned {
+                running.warned = true;
+                notices.push(Notice::Long(*id, TransactionLimit::Age));
+            }
+        }
+        for id in expired {
+            notices.push(self.abort(id, TransactionLimit::Age));
+        }
+        notices
+    }
+
+    // Fails if the transaction was aborted.
+    fn check(&self, id: u64) -> Result<(), Error> {
+        match self.aborted.get(&id) {
+            Some(limit) => Err(Error::TransactionAborted(*limit)),
+            None => Ok(()),
+        }
+    }
+}
+
+static COMMITS: Lazy<Mutex<Commits>> = Lazy::new(|| {
+    Mutex::new(Commits {
+        clock: 0,
+        keys: HashMap::new(),
+        history: HashMap::new(),
+        active: BTreeMap::new(),
+        aborted: HashMap::new(),
+        next_id: 1,
+        prepared: HashMap::new(),
+        next_prepared: 1,
+    })
+});
+
+/// Transaction buffers writes until commit, where they are applied to the index all at once. Two
+/// transactions writing the same key conflict, the first one to commit wins, and the other one
+/// fails with `CommitError::Conflict` naming the key. Reads see the transaction's own writes and
+/// the committed state selected by the isolation level otherwise, see `IsolationLevel`. Only writes
+/// made through transactions are checked for conflicts and kept for the snapshots of running
+/// transactions, other writes are seen by all reads once they're made. Transactions running too
+/// long or writing too many keys are aborted, see `TransactionLimits`.
+pub(crate) struct Transaction {
+    id: u64,
+    start: u64,
+    isolation: IsolationLevel,
+    // keys read and ranges scanned, recorded with serializable isolation only.
+    reads: RefCell<Reads>,
+    writes: BTreeMap<Vec<u8>, Option<Payload>>,
+    // bytes of the keys and payloads in writes.
+    buffered: usize,
+    // the number of keys written, spilled ones included.
+    write_set: usize,
+    spill: Option<Spill>,
+    finished: bool,
+}
+
+/// Spill is the temporary file holding the writes of a transaction which outgrew the spill
+/// threshold. Writes are appended as records:
+///  ____________________________________________________________________________
+/// | key size | key | write type | payload type | payload size | payload |
+///  ----------------------------------------------------------------------------
+/// The write type is zero for deletes, which have neither payload type nor payload. Only the keys
+/// stay in memory, along with the offset of their latest record. The file is removed when the
+/// transaction ends.
+struct Spill {
+    path: PathBuf,
+    file: File,
+    offsets: BTreeMap<Vec<u8>, u64>,
+    end: u64,
+}
+
+impl Spill {
+    fn create() -> Result<Self, Error> {
+        let path = std::env::temp_dir().join(format!(
+            "teleport-txn-{}-{}",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Spill {
+            path,
+            file,
+            offsets: BTreeMap::new(),
+            end: 0,
+        })
+    }
+
+    fn append(&mut self, writes: &BTreeMap<Vec<u8>, Option<Payload>>) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(self.end))?;
+        let mut file = BufWriter::new(&self.file);
+        for (key, write) in writes {
+            self.offsets.insert(key.clone(), self.end);
+            let record = encode_record(key, write);
+            file.write_all(&record)?;
+            self.end += record.len() as u64;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    // Reads the write of the record at the offset.
+    fn read(&self, offset: u64) -> Result<Option<Payload>, Error> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(read_record(&mut file)?.1)
+    }
+}
+
+/// Encodes the write as a record, the format of spill files, prepared transactions and log entries.
+pub(crate) fn encode_record(key: &[u8], write: &Option<Payload>) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    match write {
+        Some(payload) => {
+            record.push(1);
+            record.push(payload.payload_type as u8);
+            record.extend_from_slice(&(payload.to_bytes().len() as u32).to_le_bytes());
+            record.extend_from_slice(payload.to_bytes());
+        }
+        None => record.push(0),
+    }
+    record
+}
+
+pub(crate) type Record = (Vec<u8>, Option<Payload>);
+
+pub(crate) fn read_record(reader: &mut impl Read) -> Result<Record, Error> {
+    let mut length = [0u8; size_of::<u32>()];
+    reader.read_exact(&mut length)?;
+    let mut key = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut key)?;
+    let mut types = [0u8; 1];
+    reader.read_exact(&mut types)?;
+    if types[0] == 0 {
+        return Ok((key, None));
+    }
+    reader.read_exact(&mut types)?;
+    let payload_type = PayloadType::try_from(types[0])?;
+    reader.read_exact(&mut length)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok((key, Some(Payload::from_vec(payload, payload_type))))
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+#[derive(Default)]
+struct Reads {
+    keys: BTreeSet<Vec<u8>>,
+    ranges: Vec<KeyRange>,
+}
+
+impl Reads {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(key) || self.ranges.iter().any(|range| in_range(range, key))
+    }
+}
+
+fn in_range((start, end): &KeyRange, key: &[u8]) -> bool {
+    (start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice)).contains(key)
+}
+
+fn write_size(key: &[u8], write: &Option<Payload>) -> usize {
+    key.len() + write.as_ref().map_or(0, |payload| payload.to_bytes().len())
+}
+
+impl Transaction {
+    pub(crate) fn begin() -> Result<Self, Error> {
+        Self::begin_with(IsolationLevel::default())
+    }
+
+    pub(crate) fn begin_with(isolation: IsolationLevel) -> Result<Self, Error> {
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let notices = commits.expire();
+        let (id, start) = (commits.next_id, commits.clock);
+        commits.next_id += 1;
+        let began = clock::now();
+        commits.active.insert(id, Running { start, began, warned: false });
+        drop(commits);
+        notify(notices);
+        Ok(Transaction {
+            id,
+            start,
+            isolation,
+            reads: RefCell::new(Reads::default()),
+            writes: BTreeMap::new(),
+            buffered: 0,
+            write_set: 0,
+            spill: None,
+            finished: false,
+        })
+    }
+
+    /// Returns the id of the transaction, the one the event listeners are told about.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Aborts the transactions which reached the age limit and fails if this one was aborted, the
+    // notices are emitted once the commit lock is released.
+    fn check(
+        &self,
+        commits: &mut Commits,
+        notices: &mut Vec<Notice>,
+    ) -> Result<(), Error> {
+        notices.extend(commits.expire());
+        commits.check(self.id)
+    }
+
+    pub(crate) fn get(&self, key: Key) -> Result<Option<Payload>, Error> {
+        if let Some(write) = self.writes.get(key.as_bytes()) {
+            return Ok(write.clone());
+        }
+        if let Some(spill) = &self.spill
+            && let Some(offset) = spill.offsets.get(key.as_bytes())
+        {
+            return spill.read(*offset);
+        }
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.borrow_mut().keys.insert(key.as_bytes().to_vec());
+        }
+        // no transaction commits while the key is read, so that the history and the index agree.
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut notices = Vec::new();
+        let result = self.check(&mut commits, &mut notices).and_then(|_| {
+            match self.before_image(&commits, key.as_bytes()) {
+                Some(before) => Ok(before.clone()),
+                None => Index::open()?.get(key),
+            }
+        });
+        drop(commits);
+        notify(notices);
+        result
+    }
+
+    // The value the key had when the transaction began, if it was committed since. None if the
+    // transaction reads the latest committed state.
+    fn before_image<'c>(&self, commits: &'c Commits, key: &[u8]) -> Option<&'c Option<Payload>> {
+        if self.isolation == IsolationLevel::ReadCommitted {
+            return None;
+        }
+        let versions = commits.history.get(key)?;
+        versions
+            .iter()
+            .find(|(committed, _)| *committed > self.start)
+            .map(|(_, before)| before)
+    }
+
+    /// Returns the key-payload pairs within the range in key order, the writes of the transaction
+    /// applied over the committed ones.
+    pub(crate) fn scan<'a>(
+        &self,
+        range: impl RangeBounds<Key<'a>>,
+    ) -> Result<Vec<(Vec<u8>, Payload)>, Error> {
+        let owned = |bound: Bound<&Key>| bound.map(|key| key.as_bytes().to_vec());
+        let bounds = (owned(range.start_bound()), owned(range.end_bound()));
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut notices = Vec::new();
+        let entries = self.check(&mut commits, &mut notices).and_then(|_| {
+            let mut entries = Index::open()?.scan(range)?.collect::<Result<BTreeMap<_, _>, _>>()?;
+            for key in commits.history.keys().filter(|key| in_range(&bounds, key)) {
+                match self.before_image(&commits, key) {
+                    Some(Some(before)) => entries.insert(key.clone(), before.clone()),
+                    Some(None) => entries.remove(key),
+                    None => None,
+                };
+            }
+            Ok(entries)
+        });
+        drop(commits);
+        notify(notices);
+        let mut entries = entries?;
+        self.for_each_write(|key, write| {
+            if in_range(&bounds, key) {
+                match write {
+                    Some(payload) => entries.insert(key.to_vec(), payload),
+                    None => entries.remove(key),
+                };
+            }
+            Ok(())
+        })?;
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.borrow_mut().ranges.push(bounds);
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Buffers the insert, writes are spilled into a temporary file once the buffered writes exceed
+    /// the spill threshold, which fails if the file can't be written.
+    pub(crate) fn insert(&mut self, key: Key, payload: Payload) -> Result<(), Error> {
+        self.buffer(key, Some(payload))
+    }
+
+    pub(crate) fn delete(&mut self, key: Key) -> Result<(), Error> {
+        self.buffer(key, None)
+    }
+
+    fn buffer(&mut self, key: Key, write: Option<Payload>) -> Result<(), Error> {
+        let spilled = |spill: &Spill| spill.offsets.contains_key(key.as_bytes());
+        let added =
+            !self.writes.contains_key(key.as_bytes()) && !self.spill.as_ref().is_some_and(spilled);
+        self.check_limits(added)?;
+        self.write_set += usize::from(added);
+        self.buffered += write_size(key.as_bytes(), &write);
+        if let Some(replaced) = self.writes.insert(key.as_bytes().to_vec(), write) {
+            self.buffered -= write_size(key.as_bytes(), &replaced);
+        }
+        if self.buffered > SPILL_THRESHOLD.load(Ordering::Relaxed) {
+            let spill = match &mut self.spill {
+                Some(spill) => spill,
+                None => self.spill.insert(Spill::create()?),
+            };
+            spill.append(&self.writes)?;
+            self.writes.clear();
+            self.buffered = 0;
+        }
+        Ok(())
+    }
+
+    // Fails if the transaction was aborted, or is aborted as the key added to its write set takes
+    // it past the limit.
+    fn check_limits(&self, added: bool) -> Result<(), Error> {
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut notices = Vec::new();
+        let mut result = self.check(&mut commits, &mut notices);
+        if let (Ok(()), Some(max), true) = (&result, limits().max_write_set, added) {
+            let write_set = self.write_set + 1;
+            if write_set > max {
+                notices.push(commits.abort(self.id, TransactionLimit::WriteSet));
+                result = commits.check(self.id);
+            } else if write_set > max / 2 && self.write_set <= max / 2 {
+                notices.push(Notice::Long(self.id, TransactionLimit::WriteSet));
+            }
+        }
+        drop(commits);
+        notify(notices);
+        result
+    }
+
+    /// Returns true if the writes of the transaction were spilled into a temporary file.
+    pub(crate) fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    // The written keys, in key order but not deduplicated.
+    fn written_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        let spilled = self.spill.iter().flat_map(|spill| spill.offsets.keys());
+        self.writes.keys().chain(spilled)
+    }
+
+    // Passes the writes to f in key order, spilled writes are streamed from the file one at a time
+    // and those overwritten since are skipped.
+    fn for_each_write(
+        &self,
+        mut f: impl FnMut(&[u8], Option<Payload>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if let Some(spill) = &self.spill {
+            for (key, offset) in &spill.offsets {
+                if !self.writes.contains_key(key) {
+                    f(key, spill.read(*offset)?)?;
+                }
+            }
+        }
+        for (key, write) in &self.writes {
+            f(key, write.clone())?;
+        }
+        Ok(())
+    }
+
+    // The first written key which was committed by another transaction since this one began, or
+    // is held by a prepared transaction. With serializable isolation, the keys read and those
+    // within the ranges scanned are checked as well.
+    fn conflict(&self, commits: &Commits) -> Option<Vec<u8>> {
+        let written = self.written_keys().find(|key| {
+            let committed = commits.keys.get(*key).is_some_and(|committed| *committed > self.start);
+            (committed && self.isolation != IsolationLevel::ReadCommitted)
+                || commits.prepared.contains_key(*key)
+        });
+        if written.is_some() || self.isolation != IsolationLevel::Serializable {
+            return written.cloned();
+        }
+        let reads = self.reads.borrow();
+        let committed = commits.keys.iter().filter(|(_, committed)| **committed > self.start);
+        committed
+            .map(|(key, _)| key)
+            .chain(commits.prepared.keys())
+            .find(|key| reads.contains(key))
+            .cloned()
+    }
+
+    /// Applies the writes unless one of the written keys was committed by another transaction since
+    /// this one began.
+    pub(crate) fn commit(mut self) -> Result<(), CommitError> {
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        self.finished = true;
+        let mut notices = Vec::new();
+        let result = match self.check(&mut commits, &mut notices) {
+            Ok(()) => self.apply(&mut commits),
+            Err(error) => Err(error.into()),
+        };
+        commits.finish(self.id);
+        drop(commits);
+        notify(notices);
+        result
+    }
+
+    // Runs under the commit lock, so that no other transaction commits in between the conflict
+    // check and the writes.
+    fn apply(&self, commits: &mut Commits) -> Result<(), CommitError> {
+        if let Some(key) = self.conflict(commits) {
+            return Err(CommitError::Conflict { key });
+        }
+        // the index is opened here, as the root may have moved since the transaction began.
+        let mut index = Index::open()?;
+        self.for_each_write(|key, write| {
+            commits.keep_before_image(&index, key)?;
+            apply_write(&mut index, key, write)
+        })?;
+        commits.clock += 1;
+        let clock = commits.clock;
+        for key in self.written_keys() {
+            commits.keys.insert(key.clone(), clock);
+        }
+        Ok(())
+    }
+
+    /// Drops the writes of the transaction.
+    pub(crate) fn rollback(self) {}
+
+    /// Prepares the transaction for a two-phase commit coordinated by the application. The conflict
+    /// check of `commit` is run and the writes are persisted, so that the transaction can be
+    /// committed with `commit_prepared` or rolled back with `rollback_prepared`, even after a
+    /// restart. The written keys stay locked until then, transactions writing them fail with
+    /// `CommitError::Conflict`. Returns the id of the prepared transaction. The persisted writes are
+    /// encrypted if a key provider is set.
+    pub(crate) fn prepare(mut self) -> Result<u64, CommitError> {
+        let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        self.finished = true;
+        let mut notices = Vec::new();
+        let result = match self.check(&mut commits, &mut notices) {
+            Ok(()) => self.persist(&mut commits),
+            Err(error) => Err(error.into()),
+        };
+        commits.finish(self.id);
+        drop(commits);
+        notify(notices);
+        result
+    }
+
+    fn persist(&self, commits: &mut Commits) -> Result<u64, CommitError> {
+        if let Some(key) = self.conflict(commits) {
+            return Err(CommitError::Conflict { key });
+        }
+        let id = commits.next_prepared.max(last_prepared_id()? + 1);
+        self.write_prepared(id)?;
+        commits.next_prepared = id + 1;
+        for key in self.written_keys() {
+            commits.prepared.insert(key.clone(), id);
+        }
+        Ok(id)
+    }
+
+    // Writes the records of the writes into a temporary file, which is renamed once it's synced.
+    fn write_prepared(&self, id: u64) -> Result<(), Error> {
+        let path = prepared_path(id);
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut file = Sink::new(BufWriter::new(File::create(&temp_path)?))?;
+        self.for_each_write(|key, write| Ok(file.write_all(&encode_record(key, &write))?))?;
+        let file = file.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        sys::sync_dir(Path::new(""))?;
+        Ok(())
+    }
+}
+
+pub(crate) fn prepared_path(id: u64) -> PathBuf {
+    PathBuf::from(format!("{}{}", PREPARED_FILE_PREFIX, id))
+}
+
+/// Returns the ids of the prepared transactions in the working directory, in ascending order.
+pub(crate) fn prepared_ids() -> Result<Vec<u64>, Error> {
+    let mut ids: Vec<u64> = fs::read_dir(".")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_prefix(PREPARED_FILE_PREFIX)?.parse().ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn last_prepared_id() -> Result<u64, Error> {
+    Ok(prepared_ids()?.last().copied().unwrap_or(0))
+}
+
+// Passes the writes of the prepared transaction to f in the order they were persisted.
+fn read_prepared(
+    id: u64,
+    mut f: impl FnMut(Record) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let file = match File::open(prepared_path(id)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::UnknownTransaction(id));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(crypt::source(file)?);
+    while !reader.fill_buf()?.is_empty() {
+        f(read_record(&mut reader)?)?;
+    }
+    Ok(())
+}
+
+// Removes the file of the prepared transaction and unlocks its keys.
+fn finish_prepared(commits: &mut Commits, id: u64) -> Result<(), Error> {
+    fs::remove_file(prepared_path(id))?;
+    sys::sync_dir(Path::new(""))?;
+    commits.prepared.retain(|_, prepared| *prepared != id);
+    Ok(())
+}
+
+/// Applies the writes of the prepared transaction. A commit interrupted by a crash can be run
+/// again, as the writes replace the keys wholesale.
+pub(crate) fn commit_prepared(id: u64) -> Result<(), Error> {
+    let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut index = Index::open()?;
+    let mut keys = Vec::new();
+    read_prepared(id, |(key, write)| {
+        commits.keep_before_image(&index, &key)?;
+        apply_write(&mut index, &key, write)?;
+        keys.push(key);
+        Ok(())
+    })?;
+    commits.clock += 1;
+    let clock = commits.clock;
+    for key in keys {
+        commits.keys.insert(key, clock);
+    }
+    commits.prune();
+    finish_prepared(&mut commits, id)
+}
+
+/// Drops the writes of the prepared transaction.
+pub(crate) fn rollback_prepared(id: u64) -> Result<(), Error> {
+    let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+    if !prepared_path(id).exists() {
+        return Err(Error::UnknownTransaction(id));
+    }
+    finish_prepared(&mut commits, id)
+}
+
+/// Locks the keys of the transactions left prepared when the database was closed, run when it's
+/// opened.
+pub(crate) fn recover_prepared() -> Result<(), Error> {
+    let mut commits = COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+    commits.prepared.clear();
+    for id in prepared_ids()? {
+        read_prepared(id, |(key, _)| {
+            commits.prepared.insert(key, id);
+            Ok(())
+        })?;
+        commits.next_prepared = commits.next_prepared.max(id + 1);
+    }
+    Ok(())
+}
+
+fn apply_write(index: &mut Index, key: &[u8], write: Option<Payload>) -> Result<(), Error> {
+    match write {
+        Some(payload) => index.insert(Key::from(key), payload),
+        None => index.delete(Key::from(key)).map(|_| ()),
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            COMMITS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .finish(self.id);
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn verify_first_committer_wins() {
+    delete_index();
+    let mut first = Transaction::begin().unwrap();
+    let mut second = Transaction::begin().unwrap();
+    first.insert(Key::from("k1"), Payload::from_u32(1)).unwrap();
+    second.insert(Key::from("k2"), Payload::from_u32(2)).unwrap();
+    second.insert(Key::from("k1"), Payload::from_u32(3)).unwrap();
+    assert_eq!(second.get(Key::from("k1")).unwrap().unwrap().to_bytes(), &3u32.to_le_bytes());
+    assert!(first.get(Key::from("k2")).unwrap().is_none());
+    first.commit().unwrap();
+    match second.commit() {
+        Err(CommitError::Conflict { key }) => assert_eq!(key, b"k1"),
+        other => panic!("{:?}", other),
+    }
+    let index = Index::open().unwrap();
+    assert_eq!(index.get(Key::from("k1")).unwrap().unwrap().to_bytes(), &1u32.to_le_bytes());
+    assert!(index.get(Key::from("k2")).unwrap().is_none());
+    // a retry begins after the winner committed and goes through.
+    let mut retry = Transaction::begin().unwrap();
+    retry.insert(Key::from("k1"), Payload::from_u32(3)).unwrap();
+    retry.commit().unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_disjoint_transactions_commit() {
+    delete_index();
+    let mut first = Transaction::begin().unwrap();
+    let mut second = Transaction::begin().unwrap();
+    first.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    second.insert(Key::from("b"), Payload::from_u32(2)).unwrap();
+    second.delete(Key::from("c")).unwrap();
+    second.commit().unwrap();
+    first.commit().unwrap();
+    let index = Index::open().unwrap();
+    assert!(index.get(Key::from("a")).unwrap().is_some());
+    assert!(index.get(Key::from("b")).unwrap().is_some());
+}
+
+#[test]
+#[serial]
+fn verify_serializable_transactions_notice_phantoms() {
+    delete_index();
+    let scan = |txn: &Transaction| txn.scan(Key::from("a")..Key::from("n")).unwrap();
+    let mut reader = Transaction::begin_with(IsolationLevel::Serializable).unwrap();
+    let mut snapshot = Transaction::begin().unwrap();
+    reader.insert(Key::from("z"), Payload::from_u32(0)).unwrap();
+    snapshot.insert(Key::from("y"), Payload::from_u32(0)).unwrap();
+    assert!(scan(&reader).is_empty());
+    assert!(scan(&snapshot).is_empty());
+    let mut writer = Transaction::begin().unwrap();
+    writer.insert(Key::from("m"), Payload::from_u32(1)).unwrap();
+    writer.commit().unwrap();
+    // the key inserted into the scanned range fails the serializable transaction only, although
+    // it reads the state committed when it began.
+    assert!(scan(&reader).is_empty());
+    match reader.commit() {
+        Err(CommitError::Conflict { key }) => assert_eq!(key, b"m"),
+        other => panic!("{:?}", other),
+    }
+    snapshot.commit().unwrap();
+
+    // keys read are checked, keys outside the ranges scanned aren't.
+    let mut reader = Transaction::begin_with(IsolationLevel::Serializable).unwrap();
+    assert!(reader.get(Key::from("k")).unwrap().is_none());
+    reader.insert(Key::from("m"), Payload::from_u32(2)).unwrap();
+    assert_eq!(scan(&reader)[0].1.to_bytes(), &2u32.to_le_bytes());
+    let mut writer = Transaction::begin().unwrap();
+    writer.insert(Key::from("x"), Payload::from_u32(3)).unwrap();
+    writer.commit().unwrap();
+    let mut writer = Transaction::begin().unwrap();
+    writer.insert(Key::from("k"), Payload::from_u32(4)).unwrap();
+    writer.commit().unwrap();
+    assert!(matches!(reader.commit(), Err(CommitError::Conflict { key }) if key == b"k"));
+}
+
+#[test]
+#[serial]
+fn verify_isolation_levels() {
+    delete_index();
+    let mut index = Index::open().unwrap();
+    index.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    index.insert(Key::from("b"), Payload::from_u32(1)).unwrap();
+    let value = |txn: &Transaction, key: &str| {
+        txn.get(Key::from(key)).unwrap().map(|payload| payload.to_bytes()[0])
+    };
+    let mut read_committed = Transaction::begin_with(IsolationLevel::ReadCommitted).unwrap();
+    let mut snapshot = Transaction::begin_with(IsolationLevel::Snapshot).unwrap();
+    let mut writer = Transaction::begin().unwrap();
+    writer.insert(Key::from("a"), Payload::from_u32(2)).unwrap();
+    writer.delete(Key::from("b")).unwrap();
+    writer.insert(Key::from("c"), Payload::from_u32(2)).unwrap();
+    writer.commit().unwrap();
+
+    // the snapshot reads the keys as they were when it began, scans included.
+    assert_eq!(value(&snapshot, "a"), Some(1));
+    assert_eq!(value(&snapshot, "b"), Some(1));
+    assert_eq!(value(&snapshot, "c"), None);
+    let keys = |entries: Vec<(Vec<u8>, Payload)>| -> Vec<Vec<u8>> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys(snapshot.scan(..).unwrap()), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(value(&read_committed, "a"), Some(2));
+    assert_eq!(value(&read_committed, "b"), None);
+    assert_eq!(keys(read_committed.scan(..).unwrap()), vec![b"a".to_vec(), b"c".to_vec()]);
+
+    // the last writer wins with read committed, the first committer with a snapshot.
+    read_committed.insert(Key::from("a"), Payload::from_u32(3)).unwrap();
+    read_committed.commit().unwrap();
+    snapshot.insert(Key::from("a"), Payload::from_u32(4)).unwrap();
+    assert!(matches!(snapshot.commit(), Err(CommitError::Conflict { key }) if key == b"a"));
+    assert_eq!(index.get(Key::from("a")).unwrap().unwrap().to_bytes()[0], 3);
+    // the values kept for the snapshots are dropped once no transaction reads them.
+    assert!(COMMITS.lock().unwrap().history.is_empty());
+}
+
+#[test]
+#[serial]
+fn verify_large_transactions_spill() {
+    delete_index();
+    set_spill_threshold(1000);
+    let mut txn = Transaction::begin().unwrap();
+    for i in 0..100u32 {
+        let key = format!("{:03}", i);
+        txn.insert(Key::from(key.as_str()), Payload::from_str("x".repeat(50))).unwrap();
+    }
+    assert!(txn.spilled());
+    txn.insert(Key::from("007"), Payload::from_u32(7)).unwrap();
+    txn.delete(Key::from("008")).unwrap();
+    for i in 100..103u32 {
+        let key = format!("{:03}", i);
+        txn.insert(Key::from(key.as_str()), Payload::from_u32(i)).unwrap();
+    }
+    assert_eq!(txn.get(Key::from("001")).unwrap().unwrap().to_str(), "x".repeat(50));
+    assert_eq!(txn.get(Key::from("007")).unwrap().unwrap().to_bytes(), &7u32.to_le_bytes());
+    assert!(txn.get(Key::from("008")).unwrap().is_none());
+    let path = txn.spill.as_ref().unwrap().path.clone();
+    txn.commit().unwrap();
+    set_spill_threshold(DEFAULT_SPILL_THRESHOLD);
+    assert!(!path.exists());
+
+    let index = Index::open().unwrap();
+    assert_eq!(index.scan(..).unwrap().count(), 102);
+    assert_eq!(index.get(Key::from("099")).unwrap().unwrap().to_str(), "x".repeat(50));
+    assert_eq!(index.get(Key::from("007")).unwrap().unwrap().to_bytes(), &7u32.to_le_bytes());
+    assert!(index.get(Key::from("008")).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn verify_prepared_transactions_survive_restarts() {
+    delete_index();
+    let mut first = Transaction::begin().unwrap();
+    first.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    first.delete(Key::from("b")).unwrap();
+    let first = first.prepare().unwrap();
+    let mut second = Transaction::begin().unwrap();
+    second.insert(Key::from("c"), Payload::from_u32(3)).unwrap();
+    let second = second.prepare().unwrap();
+    assert!(first < second);
+    assert!(Index::open().unwrap().get(Key::from("a")).unwrap().is_none());
+
+    // the keys of prepared transactions are locked.
+    let mut blocked = Transaction::begin().unwrap();
+    blocked.insert(Key::from("a"), Payload::from_u32(2)).unwrap();
+    match blocked.commit() {
+        Err(CommitError::Conflict { key }) => assert_eq!(key, b"a"),
+        other => panic!("{:?}", other),
+    }
+
+    // a restart forgets the locks, which are recovered from the prepared files.
+    COMMITS.lock().unwrap().prepared.clear();
+    recover_prepared().unwrap();
+    assert_eq!(prepared_ids().unwrap(), vec![first, second]);
+    let mut blocked = Transaction::begin().unwrap();
+    blocked.insert(Key::from("c"), Payload::from_u32(4)).unwrap();
+    assert!(matches!(blocked.prepare(), Err(CommitError::Conflict { .. })));
+
+    commit_prepared(first).unwrap();
+    rollback_prepared(second).unwrap();
+    assert!(prepared_ids().unwrap().is_empty());
+    let index = Index::open().unwrap();
+    assert_eq!(index.get(Key::from("a")).unwrap().unwrap().to_bytes(), &1u32.to_le_bytes());
+    assert!(index.get(Key::from("c")).unwrap().is_none());
+    assert!(matches!(
+        commit_prepared(first),
+        Err(Error::UnknownTransaction(_))
+    ));
+    let mut unblocked = Transaction::begin().unwrap();
+    unblocked.insert(Key::from("c"), Payload::from_u32(4)).unwrap();
+    unblocked.commit().unwrap();
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct LimitRecorder(Mutex<Vec<(&'static str, u64, TransactionLimit)>>);
+
+#[cfg(test)]
+impl EventListener for LimitRecorder {
+    fn on_long_transaction(&self, id: u64, limit: TransactionLimit) {
+        self.0.lock().unwrap().push(("long", id, limit));
+    }
+
+    fn on_transaction_aborted(&self, id: u64, limit: TransactionLimit) {
+        self.0.lock().unwrap().push(("aborted", id, limit));
+    }
+}
+
+#[test]
+#[serial]
+fn verify_transactions_are_aborted_at_their_limits() {
+    delete_index();
+    let virtual_clock = Arc::new(VirtualClock::new(Duration::from_secs(100)));
+    clock::set_clock(virtual_clock.clone());
+    set_limits(TransactionLimits {
+        max_age: Some(Duration::from_secs(10)),
+        max_write_set: Some(4),
+    });
+    let recorder = Arc::new(LimitRecorder::default());
+    let listener = events::register(recorder.clone());
+
+    // the forgotten transaction is reported and aborted by the others.
+    let forgotten = Transaction::begin().unwrap();
+    assert!(forgotten.get(Key::from("a")).unwrap().is_none());
+    virtual_clock.advance(Duration::from_secs(6));
+    let mut writer = Transaction::begin().unwrap();
+    writer.insert(Key::from("a"), Payload::from_u32(1)).unwrap();
+    writer.commit().unwrap();
+    assert!(!COMMITS.lock().unwrap().history.is_empty());
+    virtual_clock.advance(Duration::from_secs(4));
+    Transaction::begin().unwrap().rollback();
+    assert!(COMMITS.lock().unwrap().history.is_empty());
+    assert!(matches!(
+        forgotten.get(Key::from("a")),
+        Err(Error::TransactionAborted(TransactionLimit::Age))
+    ));
+    assert!(matches!(
+        forgotten.commit(),
+        Err(CommitError::Storage(Error::TransactionAborted(_)))
+    ));
+
+    // rewriting a key doesn't grow the write set.
+    let mut large = Transaction::begin().unwrap();
+    for key in ["a", "b", "c", "a", "d"] {
+        large.insert(Key::from(key), Payload::from_u32(2)).unwrap();
+    }
+    assert!(matches!(
+        large.delete(Key::from("e")),
+        Err(Error::TransactionAborted(TransactionLimit::WriteSet))
+    ));
+    assert!(large.insert(Key::from("a"), Payload::from_u32(3)).is_err());
+    let large_id = large.id();
+    large.rollback();
+    assert!(COMMITS.lock().unwrap().aborted.is_empty());
+
+    events::unregister(listener);
+    set_limits(TransactionLimits::default());
+    clock::set_clock(Arc::new(SystemClock));
+    let events = recorder.0.lock().unwrap().clone();
+    let forgotten_id = large_id - 3;
+    assert_eq!(
+        events,
+        vec![
+            ("long", forgotten_id, TransactionLimit::Age),
+            ("aborted", forgotten_id, TransactionLimit::Age),
+            ("long", large_id, TransactionLimit::WriteSet),
+            ("aborted", large_id, TransactionLimit::WriteSet),
+        ]
+    );
+}
@@ -0,0 +1,253 @@
+use crate::btree::Index;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::io::delete_index;
+use crate::types::{Key, Payload, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// KeyEncode converts typed keys into index keys. The encoding must preserve the order of the type
+/// under byte-wise comparison, that's why integers are encoded big-endian.
+pub(crate) trait KeyEncode: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error>;
+}
+
+/// ValueEncode converts typed values into payloads persisted in the leaf pages.
+pub(crate) trait ValueEncode: Sized {
+    fn encode_value(&self) -> Payload;
+
+    fn decode_value(payload: Payload) -> Result<Self, Error>;
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N], Error> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::MalformedPayload)
+}
+
+impl KeyEncode for u16 {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(u16::from_be_bytes(fixed(bytes)?))
+    }
+}
+
+impl KeyEncode for u32 {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(u32::from_be_bytes(fixed(bytes)?))
+    }
+}
+
+impl KeyEncode for u64 {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(u64::from_be_bytes(fixed(bytes)?))
+    }
+}
+
+// Flipping the sign bit moves the negative numbers in front of the positive ones.
+impl KeyEncode for i64 {
+    fn encode_key(&self) -> Vec<u8> {
+        ((*self as u64) ^ (1 << 63)).to_be_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok((u64::from_be_bytes(fixed(bytes)?) ^ (1 << 63)) as i64)
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::MalformedPayload)
+    }
+}
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ValueEncode for u16 {
+    fn encode_value(&self) -> Payload {
+        Payload::from_u16(*self)
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        Ok(u16::from_le_bytes(fixed(payload.to_bytes())?))
+    }
+}
+
+impl ValueEncode for u32 {
+    fn encode_value(&self) -> Payload {
+        Payload::from_u32(*self)
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        Ok(u32::from_le_bytes(fixed(payload.to_bytes())?))
+    }
+}
+
+impl ValueEncode for u64 {
+    fn encode_value(&self) -> Payload {
+        Payload::from_buffer(&self.to_le_bytes(), PayloadType::Bytes)
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        Ok(u64::from_le_bytes(fixed(payload.to_bytes())?))
+    }
+}
+
+impl ValueEncode for i64 {
+    fn encode_value(&self) -> Payload {
+        Payload::from_i64(*self)
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        Ok(i64::from_le_bytes(fixed(payload.to_bytes())?))
+    }
+}
+
+impl ValueEncode for String {
+    fn encode_value(&self) -> Payload {
+        Payload::from_str(self.clone())
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        String::from_utf8(payload.into_vec())
+            .map_err(|_| Error::MalformedPayload)
+    }
+}
+
+impl ValueEncode for Vec<u8> {
+    fn encode_value(&self) -> Payload {
+        Payload::from_buffer(self, PayloadType::Bytes)
+    }
+
+    fn decode_value(payload: Payload) -> Result<Self, Error> {
+        Ok(payload.into_vec())
+    }
+}
+
+/// TypedIndex wraps an index so that callers work with their own key and value types instead of
+/// encoding payloads manually.
+pub(crate) struct TypedIndex<K: KeyEncode, V: ValueEncode> {
+    index: Index,
+    types: PhantomData<(K, V)>,
+}
+
+impl<K: KeyEncode, V: ValueEncode> TypedIndex<K, V> {
+    pub(crate) fn new(index: Index) -> Self {
+        TypedIndex {
+            index,
+            types: PhantomData,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> Index {
+        self.index
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        let key = key.encode_key();
+        match self.index.get(Key::from(key.as_slice()))? {
+            Some(payload) => Ok(Some(V::decode_value(payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        let key = key.encode_key();
+        self.index
+            .insert(Key::from(key.as_slice()), value.encode_value())
+    }
+
+    pub(crate) fn delete(&mut self, key: &K) -> Result<bool, Error> {
+        let key = key.encode_key();
+        self.index.delete(Key::from(key.as_slice()))
+    }
+
+    /// Returns the decoded entries within the range in key order.
+    pub(crate) fn scan(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<impl Iterator<Item = Result<(K, V), Error>>, Error>
+    {
+        let start = range.start_bound().map(|key| key.encode_key());
+        let end = range.end_bound().map(|key| key.encode_key());
+        let scan = self.index.scan((
+            start.as_ref().map(|key| Key::from(key.as_slice())),
+            end.as_ref().map(|key| Key::from(key.as_slice())),
+        ))?;
+        Ok(scan.map(|entry| {
+            let (key, payload) = entry?;
+            Ok((K::decode_key(&key)?, V::decode_value(payload)?))
+        }))
+    }
+}
+
+#[test]
+#[serial]
+fn verify_typed_get_and_insert() {
+    delete_index();
+    let mut index: TypedIndex<u64, String> = TypedIndex::new(Index::open().unwrap());
+    index.insert(&42, &"answer".to_string()).unwrap();
+    index.insert(&7, &"seven".to_string()).unwrap();
+    assert_eq!(index.get(&42).unwrap(), Some("answer".to_string()));
+    assert_eq!(index.get(&8).unwrap(), None);
+    assert!(index.delete(&7).unwrap());
+    assert_eq!(index.get(&7).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn verify_typed_scan_preserves_integer_order() {
+    delete_index();
+    let mut index: TypedIndex<i64, u32> = TypedIndex::new(Index::open().unwrap());
+    for n in [300i64, -2, 5, -700, 0, 1 << 40] {
+        index.insert(&n, &(n.unsigned_abs() as u32)).unwrap();
+    }
+    let keys: Vec<i64> = index
+        .scan(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![-700, -2, 0, 5, 300, 1 << 40]);
+    let keys: Vec<i64> = index
+        .scan(-2..300)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![-2, 0, 5]);
+}
+
+#[test]
+fn verify_decode_rejects_malformed_payload() {
+    let payload = Payload::from_buffer(&[1, 2, 3], PayloadType::Bytes);
+    assert!(matches!(
+        u64::decode_value(payload),
+        Err(Error::MalformedPayload)
+    ));
+}
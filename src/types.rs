@@ -1,8 +1,10 @@
-use crate::errors::InvalidPageOffsetError;
+use crate::errors::Error;
 use core::fmt::Debug;
+use std::fmt::{self, Display, Formatter};
 use std::cmp::min;
 use std::io::Read;
-use std::ops::{Add, Mul, Sub};
+use std::ops::{Add, Mul, Range, Sub};
+use std::sync::Arc;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Hash, Ord)]
 pub(crate) struct OffsetType<T>(pub T);
@@ -13,6 +15,7 @@ impl OffsetType<u16> {
         self.0 as usize
     }
 
+    #[allow(dead_code)]
     pub(crate) fn from_u16(i: u16) -> Self {
         OffsetType(i)
     }
@@ -22,29 +25,77 @@ impl OffsetType<u16> {
         offset
     }
 
+    #[allow(dead_code)]
     pub(crate) fn size() -> usize {
         size_of::<Self>()
     }
+
+    pub(crate) fn checked_add(self, rhs: usize) -> Result<Self, Error> {
+        let rhs: u16 = rhs.try_into().map_err(|_| Error::OutOfRange)?;
+        self.0
+            .checked_add(rhs)
+            .map(OffsetType)
+            .ok_or(Error::OutOfRange)
+    }
+
+    pub(crate) fn checked_sub(self, rhs: usize) -> Result<Self, Error> {
+        let rhs: u16 = rhs.try_into().map_err(|_| Error::OutOfRange)?;
+        self.0
+            .checked_sub(rhs)
+            .map(OffsetType)
+            .ok_or(Error::OutOfRange)
+    }
+
+    pub(crate) fn saturating_sub(self, rhs: usize) -> Self {
+        OffsetType(self.0.saturating_sub(rhs.try_into().unwrap_or(u16::MAX)))
+    }
+
+    /// Returns the offsets from self up to and including end, e.g. the page ids allocated so far.
+    /// Offsets can't be stepped through with `start..=end` directly, the Step trait isn't stable.
+    pub(crate) fn through(self, end: Offset) -> impl Iterator<Item = Offset> {
+        (self.0..=end.0).map(OffsetType)
+    }
 }
 
 /// Offset is a type alias representing the offset type in a page.
 pub(crate) type Offset = OffsetType<u16>;
 
+/// Offset32 is the offset type of positions which may exceed a single page, e.g. in files.
+pub(crate) type Offset32 = OffsetType<u32>;
+
 // A convenience function to create Offset types from u16.
+#[allow(non_snake_case)]
 pub(crate) const fn Offset(value: u16) -> Offset {
     OffsetType(value)
 }
 
+// A convenience function to create Offset32 types from u32.
+pub(crate) const fn o32(value: u32) -> Offset32 {
+    OffsetType(value)
+}
+
+impl From<u16> for Offset {
+    fn from(value: u16) -> Self {
+        OffsetType(value)
+    }
+}
+
+impl<T: Display> Display for OffsetType<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 impl<T> TryFrom<usize> for OffsetType<T>
 where
     T: TryFrom<usize>,
 {
-    type Error = InvalidPageOffsetError;
+    type Error = Error;
 
-    fn try_from(value: usize) -> Result<Self, InvalidPageOffsetError> {
+    fn try_from(value: usize) -> Result<Self, Error> {
         T::try_from(value)
             .map(OffsetType)
-            .map_err(|_| InvalidPageOffsetError::OutOfRange)
+            .map_err(|_| Error::OutOfRange)
     }
 }
 
@@ -52,13 +103,13 @@ impl<T> TryFrom<OffsetType<T>> for usize
 where
     usize: TryFrom<T>,
 {
-    type Error = InvalidPageOffsetError;
+    type Error = Error;
 
-    fn try_from(value: OffsetType<T>) -> Result<Self, InvalidPageOffsetError> {
+    fn try_from(value: OffsetType<T>) -> Result<Self, Error> {
         if let Ok(ok) = usize::try_from(value.0) {
             return Ok(ok);
         }
-        Err(InvalidPageOffsetError::OutOfRange)
+        Err(Error::OutOfRange)
     }
 }
 
@@ -85,24 +136,6 @@ where
     }
 }
 
-impl Add<i32> for Offset {
-    type Output = Offset;
-
-    fn add(self, rhs: i32) -> Self::Output {
-        let right_value: u16 = rhs.try_into().expect("overflow");
-        OffsetType::<u16>(self.0 + right_value)
-    }
-}
-
-impl Add<usize> for Offset {
-    type Output = Offset;
-
-    fn add(self, rhs: usize) -> Self::Output {
-        let rhs_u16: u16 = rhs.try_into().expect("rhs exceeds u16::MAX");
-        OffsetType::<u16>(self.0.checked_add(rhs_u16).expect("overflow"))
-    }
-}
-
 impl Mul<usize> for Offset {
     type Output = Offset;
 
@@ -120,16 +153,6 @@ impl Mul<Offset> for Offset {
     }
 }
 
-impl Sub<usize> for Offset {
-    type Output = Offset;
-
-    fn sub(self, rhs: usize) -> Self::Output {
-        //TODO Downsizing may introduce correctness issue.
-        OffsetType(self.0 - rhs as u16)
-    }
-}
-
-
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 pub(crate) trait ToLeBytes {
     fn to_bytes(&self) -> Vec<u8>;
@@ -153,29 +176,122 @@ impl ToLeBytes for Offset {
     }
 }
 
+impl ToLeBytes for Offset32 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 pub(crate) trait FromLeBytes {
-    fn from_bytes(bytes: Vec<u8>) -> Self;
+    const SIZE: usize;
+
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+/// Decodes the value stored at the offset of the buffer, e.g. a header field of a page.
+pub(crate) fn read_at<T: FromLeBytes>(buf: &[u8], offset: usize) -> T {
+    T::from_bytes(&buf[offset..offset + T::SIZE])
 }
 
 impl FromLeBytes for Offset {
-    fn from_bytes(bytes: Vec<u8>) -> Offset {
+    const SIZE: usize = size_of::<u16>();
+
+    fn from_bytes(bytes: &[u8]) -> Offset {
         OffsetType(u16::from_le_bytes(bytes.try_into().unwrap()))
     }
 }
 
+impl FromLeBytes for Offset32 {
+    const SIZE: usize = size_of::<u32>();
+
+    fn from_bytes(bytes: &[u8]) -> Offset32 {
+        o32(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+impl FromLeBytes for u64 {
+    const SIZE: usize = size_of::<u64>();
+
+    fn from_bytes(bytes: &[u8]) -> u64 {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
 impl FromLeBytes for u32 {
-    fn from_bytes(bytes: Vec<u8>) -> u32 {
+    const SIZE: usize = size_of::<u32>();
+
+    fn from_bytes(bytes: &[u8]) -> u32 {
         u32::from_le_bytes(bytes.try_into().unwrap())
     }
 }
 
 impl FromLeBytes for u8 {
-    fn from_bytes(bytes: Vec<u8>) -> u8 {
+    const SIZE: usize = size_of::<u8>();
+
+    fn from_bytes(bytes: &[u8]) -> u8 {
         u8::from_le_bytes(bytes.try_into().unwrap())
     }
 }
 
+////////////////////////////////////////////////////////////////////////////////////////////////////
+/// PagePayload is implemented by the types stored in the payload of a slot, e.g. the child page
+/// ids of the separators in inner pages.
+pub(crate) trait PagePayload: Sized {
+    fn to_payload(&self) -> Payload;
+
+    fn from_payload(payload: &Payload) -> Result<Self, Error>;
+
+    /// Decodes the payload from the bytes stored in a page. Types which can are decoded without
+    /// copying the bytes into a payload first.
+    fn from_stored(
+        bytes: &[u8],
+        payload_type: PayloadType,
+    ) -> Result<Self, Error> {
+        Self::from_payload(&Payload::from_buffer(bytes, payload_type))
+    }
+}
+
+impl PagePayload for Payload {
+    fn to_payload(&self) -> Payload {
+        self.clone()
+    }
+
+    fn from_payload(payload: &Payload) -> Result<Self, Error> {
+        Ok(payload.clone())
+    }
+}
+
+impl PagePayload for Offset {
+    fn to_payload(&self) -> Payload {
+        Payload::from_u16(self.0)
+    }
+
+    fn from_payload(payload: &Payload) -> Result<Self, Error> {
+        Self::from_stored(payload.to_bytes(), payload.payload_type)
+    }
+
+    fn from_stored(bytes: &[u8], _: PayloadType) -> Result<Self, Error> {
+        let bytes = bytes.try_into().map_err(|_| Error::MalformedPayload)?;
+        Ok(OffsetType(u16::from_le_bytes(bytes)))
+    }
+}
+
+impl PagePayload for Offset32 {
+    fn to_payload(&self) -> Payload {
+        Payload::from_u32(self.0)
+    }
+
+    fn from_payload(payload: &Payload) -> Result<Self, Error> {
+        Self::from_stored(payload.to_bytes(), payload.payload_type)
+    }
+
+    fn from_stored(bytes: &[u8], _: PayloadType) -> Result<Self, Error> {
+        let bytes = bytes.try_into().map_err(|_| Error::MalformedPayload)?;
+        Ok(OffsetType(u32::from_le_bytes(bytes)))
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 #[repr(u8)]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
@@ -185,88 +301,230 @@ pub(crate) enum PayloadType {
     U16 = 3,
     I64 = 4,
     U8 = 5,
+    Bytes = 6,
+    Interned = 7,
+    /// Marks a logically deleted key, the payload holds the stamp of the delete as a little-endian
+    /// u64.
+    Tombstone = 8,
+    /// A value compressed by the compression policy of the tree, the payload holds the type of the
+    /// value followed by its snappy compressed bytes.
+    Compressed = 9,
+    /// A value stored with its checksum, the payload holds the type of the value, the XXH64 of the
+    /// value as a little-endian u64, and the value.
+    Checksummed = 10,
+    /// A reference to a value stored in a blob file outside of the index file, the payload holds
+    /// the XXH64 of the blob and its length as little-endian u64s, and the path of the file.
+    BlobRef = 11,
+}
+
+impl TryFrom<u8> for PayloadType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            1 => Ok(PayloadType::Str),
+            2 => Ok(PayloadType::U32),
+            3 => Ok(PayloadType::U16),
+            4 => Ok(PayloadType::I64),
+            5 => Ok(PayloadType::U8),
+            6 => Ok(PayloadType::Bytes),
+            7 => Ok(PayloadType::Interned),
+            8 => Ok(PayloadType::Tombstone),
+            9 => Ok(PayloadType::Compressed),
+            10 => Ok(PayloadType::Checksummed),
+            11 => Ok(PayloadType::BlobRef),
+            _ => Err(Error::UnknownPayloadType(value)),
+        }
+    }
 }
 
-/// Payload represents a key or data payload which is persisted as pages in a database.
+/// Payload represents a key or data payload which is persisted as pages in a database. Its bytes
+/// live in storage shared by the clones of the payload and the payloads sliced out of it, so that
+/// values can be handed out, cached and unwrapped from their encoding without copying them.
 #[derive(Clone, Debug)]
 pub(crate) struct Payload {
-    buffer: Vec<u8>,
+    buffer: Arc<Vec<u8>>,
+    // the bytes of the payload within the buffer.
+    range: Range<usize>,
     cursor_pos: usize,
     pub payload_type: PayloadType,
 }
 
-fn stringify(data: Vec<u8>) -> String {
-    String::from_utf8_lossy(data.as_slice()).to_string()
+fn stringify(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).to_string()
 }
 
 impl Payload {
-    pub(crate) fn to_bytes(&self) -> &Vec<u8> {
-        &self.buffer
+    pub(crate) fn to_bytes(&self) -> &[u8] {
+        &self.buffer[self.range.clone()]
     }
 
     pub(crate) fn to_str(&self) -> String {
-        stringify(self.buffer.clone())
+        stringify(self.to_bytes())
     }
 
     /// Converts a String object into a Payload instance.
     pub(crate) fn from_str(payload: String) -> Self {
-        Payload {
-            buffer: payload.into_bytes(),
-            cursor_pos: 0,
-            payload_type: PayloadType::Str,
-        }
+        Self::from_vec(payload.into_bytes(), PayloadType::Str)
     }
 
     /// Converts a u32 integer into a Payload instance.
     pub(crate) fn from_u32(payload: u32) -> Self {
-        Payload {
-            buffer: payload.to_le_bytes().to_vec(),
-            cursor_pos: 0,
-            payload_type: PayloadType::U32,
-        }
+        Self::from_vec(payload.to_le_bytes().to_vec(), PayloadType::U32)
     }
 
     /// Converts a u16 integer into a Payload instance.
     pub(crate) fn from_u16(payload: u16) -> Self {
-        Payload {
-            buffer: payload.to_le_bytes().to_vec(),
-            cursor_pos: 0,
-            payload_type: PayloadType::U16,
-        }
+        Self::from_vec(payload.to_le_bytes().to_vec(), PayloadType::U16)
     }
 
     /// Converts a i64 integer into a Payload instance.
     pub(crate) fn from_i64(payload: i64) -> Self {
+        Self::from_vec(payload.to_le_bytes().to_vec(), PayloadType::I64)
+    }
+
+    /// Creates the payload of a tombstone for a delete with the given stamp.
+    pub(crate) fn tombstone(stamp: u64) -> Self {
+        Self::from_vec(stamp.to_le_bytes().to_vec(), PayloadType::Tombstone)
+    }
+
+    pub(crate) fn from_buffer(buffer: &[u8], payload_type: PayloadType) -> Self {
+        Self::from_vec(buffer.to_vec(), payload_type)
+    }
+
+    /// Takes the buffer over without copying it.
+    pub(crate) fn from_vec(buffer: Vec<u8>, payload_type: PayloadType) -> Self {
         Payload {
-            buffer: payload.to_le_bytes().to_vec(),
+            range: 0..buffer.len(),
+            buffer: Arc::new(buffer),
             cursor_pos: 0,
-            payload_type: PayloadType::I64,
+            payload_type,
         }
     }
 
-    pub(crate) fn from_buffer(buffer: &[u8], payload_type: PayloadType) -> Self {
+    /// Returns the bytes within the range as a payload of the given type, sharing the storage of
+    /// this payload.
+    pub(crate) fn slice(&self, range: Range<usize>, payload_type: PayloadType) -> Self {
+        assert!(range.start <= range.end && range.end <= self.range.len());
         Payload {
-            buffer: buffer.to_vec(),
+            buffer: Arc::clone(&self.buffer),
+            range: self.range.start + range.start..self.range.start + range.end,
             cursor_pos: 0,
             payload_type,
         }
     }
 
+    /// Returns the bytes, copying them only if the storage is shared.
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        if self.range == (0..self.buffer.len()) {
+            return Arc::unwrap_or_clone(self.buffer);
+        }
+        self.to_bytes().to_vec()
+    }
+
     pub(crate) fn len(&self) -> usize {
-        self.buffer.len() - self.cursor_pos
+        self.range.len() - self.cursor_pos
     }
 }
 
 impl Read for Payload {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let available = min(buf.len(), self.buffer.len() - self.cursor_pos);
+        let available = min(buf.len(), self.range.len() - self.cursor_pos);
         if available == 0 {
             return Ok(self.cursor_pos);
         }
-        buf.copy_from_slice(&self.buffer[self.cursor_pos..self.cursor_pos + available]);
+        let start = self.range.start + self.cursor_pos;
+        buf.copy_from_slice(&self.buffer[start..start + available]);
         self.cursor_pos += buf.len();
         Ok(self.cursor_pos)
     }
 }
 
-pub(crate) type Key = Payload;
+/// Key is a borrowed view of the raw key bytes. Keys are compared and persisted byte-wise, so UUIDs,
+/// encoded integers and composite binary keys can be indexed without a lossy string conversion.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub(crate) struct Key<'a>(&'a [u8]);
+
+impl<'a> Key<'a> {
+    pub(crate) fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<'a> From<&'a [u8]> for Key<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Key(value)
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for Key<'a> {
+    fn from(value: &'a [u8; N]) -> Self {
+        Key(value.as_slice())
+    }
+}
+
+// Keys borrowed from a payload order and compare by the bytes of the payload alone, whatever its
+// type and wherever its storage is sliced from.
+impl<'a> From<&'a Payload> for Key<'a> {
+    fn from(value: &'a Payload) -> Self {
+        Key(value.to_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for Key<'a> {
+    fn from(value: &'a str) -> Self {
+        Key(value.as_bytes())
+    }
+}
+
+#[test]
+fn verify_checked_offset_arithmetic() {
+    assert_eq!(Offset(10).checked_add(5).unwrap(), Offset(15));
+    assert!(matches!(Offset(u16::MAX).checked_add(1), Err(Error::OutOfRange)));
+    assert!(matches!(Offset(1).checked_add(1 << 16), Err(Error::OutOfRange)));
+    assert!(matches!(Offset(1).checked_sub(2), Err(Error::OutOfRange)));
+    assert_eq!(Offset(1).saturating_sub(2), Offset(0));
+    let offsets: Vec<Offset> = Offset(3).through(Offset::from(5u16)).collect();
+    assert_eq!(offsets, vec![Offset(3), Offset(4), Offset(5)]);
+    assert_eq!(Offset(u16::MAX).through(Offset(u16::MAX)).count(), 1);
+    assert_eq!(format!("{}/{}", Offset(42), o32(1 << 20)), "42/1048576");
+}
+
+#[test]
+fn verify_page_payload_round_trip() {
+    let payload = Offset(513).to_payload();
+    assert_eq!(payload.payload_type, PayloadType::U16);
+    assert_eq!(Offset::from_payload(&payload).unwrap(), Offset(513));
+    assert_eq!(Offset32::from_payload(&o32(70_000).to_payload()).unwrap(), o32(70_000));
+    assert!(matches!(
+        Offset::from_payload(&Payload::from_u32(1)),
+        Err(Error::MalformedPayload)
+    ));
+}
+
+#[test]
+fn verify_sliced_payloads_share_their_storage() {
+    let payload = Payload::from_vec(b"header:value".to_vec(), PayloadType::Bytes);
+    let value = payload.slice(7..12, PayloadType::Str);
+    assert_eq!(value.to_str(), "value");
+    assert_eq!(value.len(), 5);
+    assert!(std::ptr::eq(value.to_bytes().as_ptr(), payload.to_bytes()[7..].as_ptr()));
+    assert_eq!(Key::from(&value), Key::from("value"));
+    assert!(Key::from(&value) > Key::from("header"));
+
+    let mut read = [0u8; 3];
+    let mut sliced = value.slice(1..5, PayloadType::Str);
+    sliced.read_exact(&mut read).unwrap();
+    assert_eq!(&read, b"alu");
+    assert_eq!(sliced.len(), 1);
+    assert_eq!(value.into_vec(), b"value".to_vec());
+    drop(sliced);
+    // the whole buffer of an unshared payload is taken over as it is.
+    let address = payload.to_bytes().as_ptr();
+    let bytes = payload.into_vec();
+    assert_eq!(bytes.as_ptr(), address);
+}
@@ -0,0 +1,268 @@
+#[cfg(test)]
+use crate::btree::Index;
+use crate::config::{
+    self, get_dictionary_page_id, get_free_list_page_id, get_hash_directory_page_id,
+    get_next_page_id, get_root_page_id, get_sequence_page_id, get_shard_catalog_page_id,
+    get_tree_stats_page_id, CHECKSUM_FORMAT_VERSION, FREE_LIST_SHARDS,
+};
+#[cfg(test)]
+use crate::config::FORMAT_VERSION;
+#[cfg(test)]
+use crate::db::Db;
+use crate::errors::Error;
+#[cfg(test)]
+use crate::fsck;
+use crate::io;
+#[cfg(test)]
+use crate::io::delete_index;
+#[cfg(test)]
+use crate::paging::PAGE_SIZE_USIZE;
+use crate::paging::{LegacyPage, Page, OVERFLOW_CAPACITY, ZERO};
+use crate::types::Offset;
+#[cfg(test)]
+use crate::types::ToLeBytes;
+#[cfg(test)]
+use crate::types::{Key, PayloadType};
+#[cfg(test)]
+use serial_test::serial;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+#[cfg(test)]
+use std::io::{Seek, SeekFrom, Write};
+
+/// Widens the pages of a database written before pages carried a checksum, so that this build
+/// reads them, see `LegacyPage`. Files of format versions before `CHECKSUM_FORMAT_VERSION` were
+/// written by such builds, unless their pages carry a checksum already, e.g. the fixtures of older
+/// versions written by this build, see `fixture::create`. The pages and the config, upgraded to
+/// this format version, are written through the journal, so that either all or none of them are.
+/// Called when the database is opened, before anything else is read from its files.
+///
+/// Leaves short of the bytes the header grew by make room by moving the last bytes of a payload
+/// which spilled to the front of its overflow pages, whose chains are rebuilt. Pages which can't
+/// make room fail with NoSpace, the files are left as they were.
+pub(crate) fn widen_pages() -> Result<(), Error> {
+    if config::checked_version()?.is_none_or(|version| version >= CHECKSUM_FORMAT_VERSION) {
+        return Ok(());
+    }
+    let last = get_next_page_id();
+    let mut legacy = BTreeMap::new();
+    for page_id in Offset(1).through(last) {
+        let buffer = io::read_stored(page_id)?;
+        // pages which were never written stay as they are.
+        if buffer.iter().any(|byte| *byte != 0) {
+            legacy.insert(page_id, buffer);
+        }
+    }
+    if legacy.values().next().is_some_and(|buffer| Page::new_from(*buffer).verify().is_ok()) {
+        return Ok(());
+    }
+    let legacy: BTreeMap<Offset, LegacyPage> = legacy
+        .into_iter()
+        .map(|(page_id, buffer)| (page_id, LegacyPage::new_from(buffer)))
+        .collect();
+    let reached = Reached::walk(&legacy)?;
+
+    let mut widened = HashMap::new();
+    // the bytes cut off the payloads of the leaves, by the first overflow page of the payload.
+    let mut carried = HashMap::new();
+    for page_id in &reached.leaves {
+        widened.insert(*page_id, widen_leaf(legacy[page_id], &mut carried)?);
+    }
+    let mut next_page_id = last;
+    for (head, chain) in &reached.chains {
+        let mut data = carried.remove(head).unwrap_or_default();
+        for page_id in chain {
+            data.extend_from_slice(legacy[page_id].overflow_data()?.0);
+        }
+        // pages left over by a chain which got shorter are reclaimed by fsck as orphans.
+        let mut page_ids = chain.clone();
+        let chunks: Vec<&[u8]> = match data.is_empty() {
+            true => vec![&[]],
+            false => data.chunks(OVERFLOW_CAPACITY).collect(),
+        };
+        while page_ids.len() < chunks.len() {
+            next_page_id = next_page_id.checked_add(1)?;
+            page_ids.push(next_page_id);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = if i + 1 < chunks.len() { page_ids[i + 1] } else { ZERO };
+            let page = Page::new_overflow_with(page_ids[i], next, chunk)?;
+            widened.insert(page_ids[i], page);
+        }
+    }
+    for (page_id, page) in &legacy {
+        if widened.contains_key(page_id) {
+            continue;
+        }
+        // pages which aren't reached, e.g. free pages, are never read.
+        let page = match reached.pages.contains(page_id) {
+            true => page.widen()?,
+            false => page.widen().unwrap_or_else(|_| page.widen_truncated()),
+        };
+        widened.insert(*page_id, page);
+    }
+    io::write_atomically(&widened, &config::upgraded_image(next_page_id))?;
+    Ok(())
+}
+
+// Widens the leaf, cutting the bytes the header grew by off a payload which spilled if the leaf is
+// short of them. The bytes cut are added to the carried bytes, by the first overflow page.
+fn widen_leaf(
+    mut page: LegacyPage,
+    carried: &mut HashMap<Offset, Vec<u8>>,
+) -> Result<Page, Error> {
+    match page.widen() {
+        Err(Error::NoSpace { needed, available }) => {
+            let (head, cut) =
+                page.cut_spilled_slot()?.ok_or(Error::NoSpace { needed, available })?;
+            carried.insert(head, cut);
+            page.widen()
+        }
+        result => result,
+    }
+}
+
+/// Reached holds the legacy pages reached from the roots in the config, walked like `fsck` walks
+/// them, and the free list pages.
+#[derive(Default)]
+struct Reached {
+    pages: BTreeSet<Offset>,
+    // the data pages, whose payloads may have spilled into overflow pages.
+    leaves: BTreeSet<Offset>,
+    // the overflow pages of each payload which spilled, by the first one.
+    chains: BTreeMap<Offset, Vec<Offset>>,
+}
+
+impl Reached {
+    fn walk(legacy: &BTreeMap<Offset, LegacyPage>) -> Result<Self, Error> {
+        let mut reached = Reached::default();
+        let mut pending = vec![get_root_page_id()];
+        while let Some(page_id) = pending.pop() {
+            let Some(page) = reached.visit(legacy, page_id) else {
+                continue;
+            };
+            if page.header().is_leaf() {
+                reached.leaf(legacy, page_id, page)?;
+            } else {
+                pending.extend(page.children()?);
+            }
+        }
+        let heads = [
+            get_dictionary_page_id(),
+            get_sequence_page_id(),
+            get_tree_stats_page_id(),
+            get_shard_catalog_page_id(),
+        ];
+        for head in heads {
+            reached.chain(legacy, head)?;
+        }
+        if let Some(directory) = reached.visit(legacy, get_hash_directory_page_id()) {
+            for bucket in directory.directory_buckets()? {
+                reached.chain(legacy, bucket)?;
+            }
+        }
+        for shard in 0..FREE_LIST_SHARDS {
+            let mut next = get_free_list_page_id(shard);
+            while let Some(page) = reached.visit(legacy, next) {
+                next = page.header().right_sibling();
+            }
+        }
+        Ok(reached)
+    }
+
+    // Returns the page unless it was reached before, or was never written.
+    fn visit<'a>(
+        &mut self,
+        legacy: &'a BTreeMap<Offset, LegacyPage>,
+        page_id: Offset,
+    ) -> Option<&'a LegacyPage> {
+        let page = legacy.get(&page_id)?;
+        self.pages.insert(page_id).then_some(page)
+    }
+
+    // Chains of data pages linked through their right siblings.
+    fn chain(&mut self, legacy: &BTreeMap<Offset, LegacyPage>, head: Offset) -> Result<(), Error> {
+        let mut next = head;
+        while let Some(page) = self.visit(legacy, next) {
+            if page.header().is_leaf() {
+                self.leaf(legacy, next, page)?;
+            }
+            next = page.header().right_sibling();
+        }
+        Ok(())
+    }
+
+    fn leaf(
+        &mut self,
+        legacy: &BTreeMap<Offset, LegacyPage>,
+        page_id: Offset,
+        page: &LegacyPage,
+    ) -> Result<(), Error> {
+        self.leaves.insert(page_id);
+        for head in page.overflow_refs()? {
+            let mut chain = Vec::new();
+            let mut next = head;
+            while next != ZERO {
+                let page = legacy
+                    .get(&next)
+                    .filter(|_| !chain.contains(&next))
+                    .ok_or(Error::MalformedPayload)?;
+                chain.push(next);
+                next = page.overflow_data()?.1;
+            }
+            self.chains.insert(head, chain);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn write_legacy_page(page_id: u16, buffer: &[u8; PAGE_SIZE_USIZE]) {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open("index.000")
+        .unwrap();
+    file.seek(SeekFrom::Start(u64::from(page_id) * PAGE_SIZE_USIZE as u64)).unwrap();
+    file.write_all(buffer).unwrap();
+}
+
+#[test]
+#[serial]
+fn verify_pages_without_checksums_are_widened_on_open() {
+    delete_index();
+    // a leaf left with 3 bytes of free space behind the legacy header of 18 bytes and its two slot
+    // table items, whose second value spilled into two full overflow pages.
+    let inline_len = PAGE_SIZE_USIZE - 18 - 2 * 2 - (8 + 1 + 5) - (8 + 1) - 3;
+    let overflow_len = PAGE_SIZE_USIZE - 18 - 2 - 2 * 2;
+    let value: Vec<u8> = (0..inline_len + 2 * overflow_len).map(|i| (i % 251) as u8).collect();
+    let (inline, overflow) = value.split_at(inline_len);
+    let (first, second) = overflow.split_at(overflow_len);
+    let slots = [
+        LegacyPage::encode_slot(b"a", PayloadType::Bytes, b"small", ZERO),
+        LegacyPage::encode_slot(b"b", PayloadType::Bytes, inline, Offset(2)),
+    ];
+    write_legacy_page(1, &LegacyPage::data_page(Offset(1), &slots));
+    for (page_id, next, data) in [(2, Offset(3), first), (3, ZERO, second)] {
+        let len = data.len() as u16;
+        let slot = [next.to_bytes(), len.to_le_bytes().to_vec(), data.to_vec()].concat();
+        write_legacy_page(page_id, &LegacyPage::data_page(Offset(page_id), &[slot]));
+    }
+    // a config of version 12, with the root on the first page and three pages allocated.
+    let mut config = vec![0u8; config::size_of_version(12) as usize];
+    config[..2].copy_from_slice(&3u16.to_le_bytes());
+    config[8..10].copy_from_slice(&1u16.to_le_bytes());
+    std::fs::write(config::CONFIG_FILE, config).unwrap();
+
+    for _ in 0..2 {
+        let _db = Db::open().unwrap();
+        assert_eq!(config::file_version().unwrap(), Some(FORMAT_VERSION));
+        // the bytes moved off the leaf took a third overflow page.
+        assert_eq!(get_next_page_id(), Offset(4));
+        let index = Index::open().unwrap();
+        assert_eq!(index.get(Key::from("a")).unwrap().unwrap().to_bytes(), b"small");
+        assert_eq!(index.get(Key::from("b")).unwrap().unwrap().to_bytes(), value.as_slice());
+        assert!(fsck::check(false).unwrap().orphans.is_empty());
+        io::close();
+    }
+}